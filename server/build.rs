@@ -0,0 +1,16 @@
+// compiles proto/proxchat.proto into prost types. uses protox (a pure-Rust protobuf
+// compiler) so building doesn't need protoc installed.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/proxchat.proto");
+    let file_descriptors = protox::compile(["proto/proxchat.proto"], ["proto"])?;
+    prost_build::Config::new().compile_fds(file_descriptors)?;
+
+    // the gRPC admin service (the grpc feature); server side only
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/admin.proto");
+        let file_descriptors = protox::compile(["proto/admin.proto"], ["proto"])?;
+        tonic_prost_build::configure().build_client(false).compile_fds(file_descriptors)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+/*
+ * proxchat client SDK - C interface (libproxchat, built from server/ffi).
+ *
+ * Every function returning int gives 0 on success and -1 on error; the error text is then
+ * available from proxchat_last_error() on the same thread.
+ *
+ * Usage:
+ *   ProxchatClient *client = proxchat_connect("ws://host:8080", "my-id", map, x, y, channel, 0);
+ *   each frame:  proxchat_update_position(client, map, x, y, channel, 0);
+ *                proxchat_poll_events(client, on_event, ctx);
+ *   on exit:     proxchat_disconnect(client);
+ */
+#ifndef PROXCHAT_H
+#define PROXCHAT_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PROXCHAT_EVENT_PEER_JOINED 1      /* peer_id came into range */
+#define PROXCHAT_EVENT_PEER_LEFT 2        /* peer_id went out of range */
+#define PROXCHAT_EVENT_ALL_PEERS_GONE 3   /* last peer left: tear down every connection */
+#define PROXCHAT_EVENT_OFFER 4            /* payload is an SDP offer from peer_id */
+#define PROXCHAT_EVENT_ANSWER 5           /* payload is an SDP answer from peer_id */
+#define PROXCHAT_EVENT_ICE_CANDIDATE 6    /* payload is an ICE candidate from peer_id */
+#define PROXCHAT_EVENT_REINTRODUCE 7      /* renegotiate the connection to peer_id */
+#define PROXCHAT_EVENT_MESSAGE 8          /* payload is any other server message as JSON */
+#define PROXCHAT_EVENT_CLOSED 9           /* connection ended; payload is the reason or NULL */
+
+typedef struct ProxchatClient ProxchatClient;
+
+/* strings are only valid for the duration of the callback; copy them to keep them */
+typedef struct ProxchatEvent {
+    int kind;
+    const char *peer_id;
+    const char *payload;
+} ProxchatEvent;
+
+typedef void (*ProxchatEventCallback)(void *user_data, const ProxchatEvent *event);
+
+/* connect and register at the given position; NULL on failure */
+ProxchatClient *proxchat_connect(const char *url, const char *client_id,
+                                 int32_t map_id, int64_t x, int64_t y, int32_t channel, int32_t game_id);
+
+/* never blocks; updates faster than the SDK's rate limit are coalesced */
+int proxchat_update_position(ProxchatClient *client,
+                             int32_t map_id, int64_t x, int64_t y, int32_t channel, int32_t game_id);
+
+int proxchat_send_offer(ProxchatClient *client, const char *target_id, const char *offer);
+int proxchat_send_answer(ProxchatClient *client, const char *target_id, const char *answer);
+int proxchat_send_ice_candidate(ProxchatClient *client, const char *target_id, const char *candidate);
+int proxchat_request_reintroduction(ProxchatClient *client, const char *peer_id);
+
+/* deliver queued events to callback on the calling thread; returns the number delivered or -1 */
+int proxchat_poll_events(ProxchatClient *client, ProxchatEventCallback callback, void *user_data);
+
+/* leave the server and free the handle */
+void proxchat_disconnect(ProxchatClient *client);
+
+/* last error on this thread, or NULL; valid until the next failing call */
+const char *proxchat_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PROXCHAT_H */
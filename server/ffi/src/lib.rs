@@ -0,0 +1,286 @@
+//! C bindings for the proxchat client SDK. The matching header is include/proxchat.h.
+//!
+//! Each connection owns a small tokio runtime, so callers need no async machinery of their own.
+//! Events are queued in the background and delivered on the caller's thread by
+//! `proxchat_poll_events`, which suits game loops that poll once per frame.
+
+use proxchat_client::{Client, ClientConfig, Event, Events, Position, ServerMessage};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+// how long proxchat_disconnect waits for the server to close the connection
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub const PROXCHAT_EVENT_PEER_JOINED: c_int = 1;
+pub const PROXCHAT_EVENT_PEER_LEFT: c_int = 2;
+pub const PROXCHAT_EVENT_ALL_PEERS_GONE: c_int = 3;
+pub const PROXCHAT_EVENT_OFFER: c_int = 4;
+pub const PROXCHAT_EVENT_ANSWER: c_int = 5;
+pub const PROXCHAT_EVENT_ICE_CANDIDATE: c_int = 6;
+pub const PROXCHAT_EVENT_REINTRODUCE: c_int = 7;
+pub const PROXCHAT_EVENT_MESSAGE: c_int = 8;
+pub const PROXCHAT_EVENT_CLOSED: c_int = 9;
+
+/// One event as seen from C. Strings are NUL-terminated and only valid during the callback.
+#[repr(C)]
+pub struct ProxchatEvent {
+    pub kind: c_int,
+    /// Peer the event is about (joined/left/reintroduce) or came from (offer/answer/ice), else NULL.
+    pub peer_id: *const c_char,
+    /// SDP or candidate for signaling events, message JSON for MESSAGE, close reason for CLOSED, else NULL.
+    pub payload: *const c_char,
+}
+
+pub type ProxchatEventCallback = Option<unsafe extern "C" fn(user_data: *mut c_void, event: *const ProxchatEvent)>;
+
+/// Opaque connection handle.
+pub struct ProxchatClient {
+    runtime: Runtime,
+    client: Client,
+    events: Events,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl ToString) {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// borrowed UTF-8 view of a C string argument; records an error and returns None when invalid
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Option<&'a str> {
+    if ptr.is_null() {
+        set_last_error(format!("{} is NULL", name));
+        return None;
+    }
+    match CStr::from_ptr(ptr).to_str() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            set_last_error(format!("{} is not valid UTF-8", name));
+            None
+        }
+    }
+}
+
+fn owned_c_string(value: &str) -> CString {
+    CString::new(value.replace('\0', " ")).unwrap_or_default()
+}
+
+/// Message from the last failed call on this thread, or NULL. Valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn proxchat_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Connect and register at the given position. Returns NULL on failure (see `proxchat_last_error`).
+///
+/// # Safety
+/// `url` and `client_id` must be valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_connect(
+    url: *const c_char,
+    client_id: *const c_char,
+    map_id: i32,
+    x: i64,
+    y: i64,
+    channel: i32,
+    game_id: i32,
+) -> *mut ProxchatClient {
+    let (Some(url), Some(client_id)) = (str_arg(url, "url"), str_arg(client_id, "client_id")) else {
+        return ptr::null_mut();
+    };
+    let runtime = match tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            set_last_error(format!("failed to start runtime: {}", e));
+            return ptr::null_mut();
+        }
+    };
+    let position = Position { map_id, x, y, channel, game_id, heading: None };
+    match runtime.block_on(Client::connect(ClientConfig::new(url, client_id), position)) {
+        Ok((client, events)) => Box::into_raw(Box::new(ProxchatClient { runtime, client, events })),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Queue a position update. Never blocks; rapid updates are coalesced. Returns 0, or -1 for a NULL client.
+///
+/// # Safety
+/// `client` must be NULL or a live handle from `proxchat_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_update_position(
+    client: *mut ProxchatClient,
+    map_id: i32,
+    x: i64,
+    y: i64,
+    channel: i32,
+    game_id: i32,
+) -> c_int {
+    let Some(client) = client.as_ref() else {
+        set_last_error("client is NULL");
+        return -1;
+    };
+    client.client.update_position(Position { map_id, x, y, channel, game_id, heading: None });
+    0
+}
+
+// shared body of the signaling senders: validate arguments, then send on the client's runtime
+unsafe fn send_signal(
+    client: *mut ProxchatClient,
+    target_id: *const c_char,
+    payload: *const c_char,
+    send: impl AsyncFnOnce(&Client, &str, &str) -> Result<(), proxchat_client::Error>,
+) -> c_int {
+    let Some(client) = client.as_ref() else {
+        set_last_error("client is NULL");
+        return -1;
+    };
+    let (Some(target_id), Some(payload)) = (str_arg(target_id, "target_id"), str_arg(payload, "payload")) else {
+        return -1;
+    };
+    match client.runtime.block_on(send(&client.client, target_id, payload)) {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Relay an SDP offer to `target_id`. Returns 0 on success, -1 on error.
+///
+/// # Safety
+/// `client` must be a live handle; `target_id` and `offer` valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_send_offer(client: *mut ProxchatClient, target_id: *const c_char, offer: *const c_char) -> c_int {
+    send_signal(client, target_id, offer, async |client, target, offer| client.send_offer(target, offer).await)
+}
+
+/// Relay an SDP answer to `target_id`. Returns 0 on success, -1 on error.
+///
+/// # Safety
+/// `client` must be a live handle; `target_id` and `answer` valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_send_answer(client: *mut ProxchatClient, target_id: *const c_char, answer: *const c_char) -> c_int {
+    send_signal(client, target_id, answer, async |client, target, answer| client.send_answer(target, answer).await)
+}
+
+/// Relay an ICE candidate to `target_id`. Returns 0 on success, -1 on error.
+///
+/// # Safety
+/// `client` must be a live handle; `target_id` and `candidate` valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_send_ice_candidate(
+    client: *mut ProxchatClient,
+    target_id: *const c_char,
+    candidate: *const c_char,
+) -> c_int {
+    send_signal(client, target_id, candidate, async |client, target, candidate| {
+        client.send_ice_candidate(target, candidate).await
+    })
+}
+
+/// Ask the server to redo the pairing with `peer_id`. Returns 0 on success, -1 on error.
+///
+/// # Safety
+/// `client` must be a live handle; `peer_id` a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_request_reintroduction(client: *mut ProxchatClient, peer_id: *const c_char) -> c_int {
+    let Some(client) = client.as_ref() else {
+        set_last_error("client is NULL");
+        return -1;
+    };
+    let Some(peer_id) = str_arg(peer_id, "peer_id") else {
+        return -1;
+    };
+    match client.runtime.block_on(client.client.request_reintroduction(peer_id)) {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+// call `callback` with one event, keeping the strings alive for the duration of the call
+unsafe fn deliver(callback: unsafe extern "C" fn(*mut c_void, *const ProxchatEvent), user_data: *mut c_void, kind: c_int, peer_id: Option<&str>, payload: Option<&str>) {
+    let peer_id = peer_id.map(owned_c_string);
+    let payload = payload.map(owned_c_string);
+    let event = ProxchatEvent {
+        kind,
+        peer_id: peer_id.as_ref().map_or(ptr::null(), |value| value.as_ptr()),
+        payload: payload.as_ref().map_or(ptr::null(), |value| value.as_ptr()),
+    };
+    callback(user_data, &event);
+}
+
+/// Deliver every queued event to `callback` on the calling thread. Returns the number of events
+/// delivered, or -1 for a NULL client or callback.
+///
+/// # Safety
+/// `client` must be a live handle; `callback` must be safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_poll_events(client: *mut ProxchatClient, callback: ProxchatEventCallback, user_data: *mut c_void) -> c_int {
+    let (Some(client), Some(callback)) = (client.as_mut(), callback) else {
+        set_last_error("client or callback is NULL");
+        return -1;
+    };
+    let mut delivered: c_int = 0;
+    while let Some(event) = client.events.try_next() {
+        match event {
+            Event::PeersChanged { joined, left, .. } => {
+                for peer_id in &joined {
+                    deliver(callback, user_data, PROXCHAT_EVENT_PEER_JOINED, Some(peer_id), None);
+                }
+                for peer_id in &left {
+                    deliver(callback, user_data, PROXCHAT_EVENT_PEER_LEFT, Some(peer_id), None);
+                }
+            }
+            Event::AllPeersGone => deliver(callback, user_data, PROXCHAT_EVENT_ALL_PEERS_GONE, None, None),
+            Event::Offer { sender_id, offer } => deliver(callback, user_data, PROXCHAT_EVENT_OFFER, Some(&sender_id), Some(&offer)),
+            Event::Answer { sender_id, answer } => {
+                deliver(callback, user_data, PROXCHAT_EVENT_ANSWER, Some(&sender_id), Some(&answer))
+            }
+            Event::IceCandidate { sender_id, candidate } => {
+                deliver(callback, user_data, PROXCHAT_EVENT_ICE_CANDIDATE, Some(&sender_id), Some(&candidate))
+            }
+            Event::Reintroduce { peer_id } => deliver(callback, user_data, PROXCHAT_EVENT_REINTRODUCE, Some(&peer_id), None),
+            Event::Message(message) => {
+                let json = serde_json::to_string::<ServerMessage>(&message).unwrap_or_default();
+                deliver(callback, user_data, PROXCHAT_EVENT_MESSAGE, None, Some(&json))
+            }
+            Event::Closed(reason) => deliver(callback, user_data, PROXCHAT_EVENT_CLOSED, None, reason.as_deref()),
+        }
+        delivered = delivered.saturating_add(1);
+    }
+    delivered
+}
+
+/// Leave the server and free the handle. The handle must not be used afterwards.
+///
+/// # Safety
+/// `client` must be NULL or a live handle from `proxchat_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn proxchat_disconnect(client: *mut ProxchatClient) {
+    if client.is_null() {
+        return;
+    }
+    let ProxchatClient { runtime, client, mut events } = *Box::from_raw(client);
+    // give the Disconnect a moment to reach the server before the background tasks are stopped
+    runtime.block_on(async {
+        if client.disconnect().await.is_ok() {
+            let closed = async { while !matches!(events.next().await, Some(Event::Closed(_)) | None) {} };
+            let _ = tokio::time::timeout(DISCONNECT_TIMEOUT, closed).await;
+        }
+    });
+    drop(client);
+    runtime.shutdown_background();
+}
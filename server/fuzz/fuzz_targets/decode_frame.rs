@@ -0,0 +1,15 @@
+// arbitrary bytes through the frame decoder of every encoding: text frames are JSON whatever the
+// subprotocol, binary frames are CBOR or protobuf. decoding may fail but must never panic
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prox_chat_server::fuzzing::{decode, Encoding, Message};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = decode(Encoding::Json, &Message::text(text));
+    }
+    for encoding in [Encoding::Cbor, Encoding::Protobuf] {
+        let _ = decode(encoding, &Message::binary(data.to_vec()));
+    }
+});
@@ -0,0 +1,83 @@
+// a few clients talking to one server at once. each frame is a structurally valid client message
+// (or, now and then, raw bytes) so the fuzzer gets past parsing into dispatch: relays, pairings,
+// preferences, signing, and the cleanup when a connection closes. ids and coordinates are folded
+// into small ranges, otherwise clients would practically never register, pair or address each other
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use prox_chat_server::fuzzing::{Encoding, Message, Server};
+use proxchat_protocol::ClientEnvelope;
+use serde_json::Value;
+use std::sync::LazyLock;
+
+const CLIENT_IDS: &[&str] = &["alice", "bob", "carol"];
+
+// keys whose values name a client
+const ID_KEYS: &[&str] = &["client_id", "target_id", "peer_id", "muted_peer_ids"];
+
+#[derive(Debug, Arbitrary)]
+enum Frame {
+    Message(ClientEnvelope),
+    Raw(Vec<u8>),
+}
+
+#[derive(Debug, Arbitrary)]
+struct Session {
+    cbor: bool,
+    frames: Vec<Frame>,
+}
+
+static RUNTIME: LazyLock<tokio::runtime::Runtime> =
+    LazyLock::new(|| tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap());
+
+fn client_id(fuzzed: &str) -> Value {
+    Value::from(CLIENT_IDS[fuzzed.len() % CLIENT_IDS.len()])
+}
+
+fn fold(value: &mut Value, key: Option<&str>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                fold(field, Some(key));
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| fold(item, key)),
+        Value::String(id) if key.is_some_and(|key| ID_KEYS.contains(&key)) => *value = client_id(id),
+        Value::Number(n) => match key {
+            Some("x" | "y") => *value = Value::from(n.as_i64().unwrap_or(0).rem_euclid(64)),
+            Some("map_id" | "channel" | "game_id") => *value = Value::from(n.as_i64().unwrap_or(0).rem_euclid(2)),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+fn encode(frame: Frame, cbor: bool) -> Message {
+    let envelope = match frame {
+        Frame::Raw(bytes) if cbor => return Message::binary(bytes),
+        Frame::Raw(bytes) => return Message::text(String::from_utf8_lossy(&bytes).into_owned()),
+        Frame::Message(envelope) => envelope,
+    };
+    let mut value = serde_json::to_value(&envelope).unwrap();
+    fold(&mut value, None);
+    if cbor {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).unwrap();
+        Message::binary(bytes)
+    } else {
+        Message::text(value.to_string())
+    }
+}
+
+fuzz_target!(|sessions: Vec<Session>| {
+    let sessions: Vec<(Encoding, Vec<Message>)> = sessions
+        .into_iter()
+        .take(CLIENT_IDS.len())
+        .map(|session| {
+            let encoding = if session.cbor { Encoding::Cbor } else { Encoding::Json };
+            (encoding, session.frames.into_iter().map(|frame| encode(frame, session.cbor)).collect())
+        })
+        .collect();
+    RUNTIME.block_on(Server::default().run_sessions(sessions));
+});
@@ -0,0 +1,55 @@
+// reading client envelopes of every version this crate still accepts. a frame is first brought up
+// to PROTOCOL_VERSION one step at a time, then parsed strictly: unknown envelope keys and unknown
+// payload fields are errors. when a message shape changes, bump PROTOCOL_VERSION and add the step
+// that rewrites the previous version's JSON, so older clients keep working without the current
+// types having to tolerate stray fields. the original version 0 messages are the exception: the
+// legacy shim parses them as leniently as the server always did (see legacy.rs)
+use crate::{legacy, ClientEnvelope, PROTOCOL_VERSION};
+use serde_json::{Map, Value};
+
+// top-level keys of a current envelope
+const ENVELOPE_KEYS: &[&str] = &["v", "type", "data", "request_id"];
+
+type Upgrade = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+// UPGRADES[n] rewrites a version n envelope into version n + 1
+const UPGRADES: &[Upgrade] = &[upgrade_v0];
+const _: () = assert!(UPGRADES.len() == PROTOCOL_VERSION as usize, "every older version needs an upgrade step");
+
+// version 0 is the unversioned {"type", "data"} envelope every client sent before "v" existed.
+// its messages have the v1 shapes, so only the version changes
+fn upgrade_v0(envelope: &mut Map<String, Value>) -> Result<(), String> {
+    envelope.insert("v".to_string(), Value::from(1));
+    Ok(())
+}
+
+// the returned envelope's v is the version the client sent, 0 if it sent none
+pub fn parse_client_envelope(value: Value) -> Result<ClientEnvelope, String> {
+    let Value::Object(mut envelope) = value else {
+        return Err("message must be a JSON object".to_string());
+    };
+    let version = match envelope.get("v") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| format!("invalid protocol version {}", v))?,
+    };
+    if version > PROTOCOL_VERSION {
+        return Err(format!("unsupported protocol version {} (this server speaks up to {})", version, PROTOCOL_VERSION));
+    }
+    if version == 0 {
+        if let Some(message) = legacy::translate(&envelope) {
+            let request_id = envelope.get("request_id").and_then(Value::as_str).map(str::to_string);
+            return Ok(ClientEnvelope { v: 0, message: message?, request_id });
+        }
+    }
+    for upgrade in &UPGRADES[version as usize..] {
+        upgrade(&mut envelope)?;
+    }
+    if let Some(key) = envelope.keys().find(|key| !ENVELOPE_KEYS.contains(&key.as_str())) {
+        return Err(format!("unknown envelope field `{}`", key));
+    }
+    let parsed: ClientEnvelope = serde_json::from_value(Value::Object(envelope)).map_err(|e| e.to_string())?;
+    Ok(ClientEnvelope { v: version, ..parsed })
+}
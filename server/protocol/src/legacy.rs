@@ -0,0 +1,68 @@
+// the unversioned (v0) messages of the original protocol, as sent by clients deployed before the
+// versioned envelope existed (the desktop client and anything written against the same six
+// messages). they are parsed the way the server always parsed them, ignoring fields it doesn't
+// know, and translated into the current ClientMessage so those clients keep working through the
+// migration. unversioned frames of any other type take the regular upgrade path in compat.rs
+use crate::{ClientMessage, ClientPosition};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+const TYPES: &[&str] = &["UpdatePosition", "RequestPeerRefresh", "SendOffer", "SendAnswer", "SendIceCandidate", "Disconnect"];
+
+#[derive(Deserialize)]
+struct Position {
+    client_id: String,
+    map_id: i32,
+    x: i64,
+    y: i64,
+    channel: i32,
+    game_id: i32,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "data")]
+enum Message {
+    UpdatePosition(Position),
+    RequestPeerRefresh,
+    SendOffer { target_id: String, offer: String },
+    SendAnswer { target_id: String, answer: String },
+    SendIceCandidate { target_id: String, candidate: String },
+    Disconnect,
+}
+
+impl From<Message> for ClientMessage {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::UpdatePosition(pos) => ClientMessage::UpdatePosition(ClientPosition {
+                client_id: pos.client_id,
+                map_id: pos.map_id,
+                x: pos.x,
+                y: pos.y,
+                channel: pos.channel,
+                game_id: pos.game_id,
+                heading: None,
+                session_token: None,
+            }),
+            Message::RequestPeerRefresh => ClientMessage::RequestPeerRefresh,
+            Message::SendOffer { target_id, offer } => ClientMessage::SendOffer { target_id, offer },
+            Message::SendAnswer { target_id, answer } => ClientMessage::SendAnswer { target_id, answer },
+            Message::SendIceCandidate { target_id, candidate } => ClientMessage::SendIceCandidate { target_id, candidate },
+            Message::Disconnect => ClientMessage::Disconnect,
+        }
+    }
+}
+
+// None unless the envelope holds one of the original messages
+pub(crate) fn translate(envelope: &Map<String, Value>) -> Option<Result<ClientMessage, String>> {
+    let message_type = envelope.get("type")?.as_str()?;
+    if !TYPES.contains(&message_type) {
+        return None;
+    }
+    // the original server only ever looked at these two keys
+    let frame: Map<String, Value> = envelope
+        .iter()
+        .filter(|(key, _)| *key == "type" || *key == "data")
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    Some(serde_json::from_value::<Message>(Value::Object(frame)).map(ClientMessage::from).map_err(|e| e.to_string()))
+}
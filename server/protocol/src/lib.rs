@@ -0,0 +1,389 @@
+// wire types shared by the server and the client SDK. every message is JSON (or CBOR/protobuf
+// with the matching subprotocol) shaped as {"v": <version>, "type": <variant>, "data": <payload>}.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub mod compat;
+mod legacy;
+#[cfg(test)]
+mod tests;
+
+// envelope version this crate speaks. client frames without "v" are version 0, which
+// compat::parse_client_envelope maps forward like any other older version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// subprotocol tokens, one per protocol version/encoding combination
+pub const SUBPROTOCOL_V1: &str = "proxchat.v1";
+pub const SUBPROTOCOL_V1_CBOR: &str = "proxchat.v1.cbor";
+pub const SUBPROTOCOL_V1_PROTO: &str = "proxchat.v1.proto";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ClientPosition {
+    pub client_id: String,
+    pub map_id: i32,
+    pub x: i64,
+    pub y: i64,
+    pub channel: i32,
+    pub game_id: i32, // int enum where NexusTK is value 0
+    // where the player faces, in whole degrees from the +x axis towards +y; only used by servers
+    // with directional_audio configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<u16>,
+    // the SessionToken the server gave this client_id's previous connection, sent on the
+    // UpdatePosition that registers a new one: a retry after a slow reply then takes over the
+    // registration as it stands instead of tearing the client's pairs down and starting over
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+}
+
+// positions of games with continuous coordinates (the server's float_coordinate_games) arrive as
+// UpdatePositionFloat and are kept as fixed point, in these fractions of a unit
+pub const FLOAT_COORDINATE_SCALE: f64 = 1000.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(deny_unknown_fields)]
+pub struct ClientPositionFloat {
+    pub client_id: String,
+    pub map_id: i32,
+    pub x: f32,
+    pub y: f32,
+    pub channel: i32,
+    pub game_id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>, // as on ClientPosition
+}
+
+impl ClientPositionFloat {
+    // non-finite coordinates end up at 0 (NaN) or the ends of the range (infinities)
+    pub fn to_fixed(&self) -> ClientPosition {
+        ClientPosition {
+            client_id: self.client_id.clone(),
+            map_id: self.map_id,
+            x: (f64::from(self.x) * FLOAT_COORDINATE_SCALE).round() as i64,
+            y: (f64::from(self.y) * FLOAT_COORDINATE_SCALE).round() as i64,
+            channel: self.channel,
+            game_id: self.game_id,
+            heading: self.heading.map(|heading| (heading.rem_euclid(360.0).round() as u16) % 360),
+            session_token: self.session_token.clone(),
+        }
+    }
+}
+
+impl ClientPosition {
+    // in f64 from the exact i128 offsets, so it can't overflow anywhere in the coordinate space
+    pub fn distance_squared(&self, other: &ClientPosition) -> f64 {
+        let dx = (other.x as i128 - self.x as i128) as f64;
+        let dy = (other.y as i128 - self.y as i128) as f64;
+        dx * dx + dy * dy
+    }
+}
+
+// unknown fields in any payload are refused rather than dropped, so a field the server
+// doesn't know yet fails loudly instead of being silently ignored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", content = "data", deny_unknown_fields)]
+pub enum ClientMessage {
+    UpdatePosition(ClientPosition),
+    UpdatePositionFloat(ClientPositionFloat), // instead of UpdatePosition, for games the server runs with float coordinates
+    RequestPeerRefresh, // request fresh nearby peer list (e.g., after failed connection)
+    SendOffer { target_id: String, offer: String }, // assuming offer is JSON string
+    SendAnswer { target_id: String, answer: String }, // assuming answer is JSON string
+    SendIceCandidate { target_id: String, candidate: String }, // assuming candidate is JSON string
+    ReportPeer { target_id: String, reason: String }, // stored for moderators to review
+    SetPreferences(ClientPreferences), // opt-in extras, can be sent any time after registering
+    GetPopulation, // player counts per map/channel for the client's game
+    ListServers, // other community servers from the registry this server announces to; answered with ServerList
+    GetServerStats, // connection diagnostics: uptime, players online in the client's game and its own update rate; answered with ServerStats
+    RequestReintroduction { peer_id: String }, // redo a single failed pairing instead of a full refresh
+    ReportIceFailure { peer_id: String }, // ICE to this peer failed; repeated failures get the pair a ForceRelay
+    ReportPeerConnected { peer_id: String }, // the connection to this peer is up; settled clients skip the periodic NearbyPeers resend
+    ReportNatType { nat_type: NatType }, // this client's NAT behaviour (from its own STUN probing), shared with peers
+    // this client's network quality, e.g. averaged from its WebRTC stats. servers with network_quality
+    // configured skip or flag (NearbyPeer.low_quality) pairs whose combined quality can't carry audio
+    ReportNetworkQuality { rtt_ms: u32, packet_loss_percent: f32 },
+    Pong { nonce: u64 }, // answer to Ping, sent straight away
+    RequestUdpSession, // opt in to sending positions as signed UDP datagrams (see the server's udp.rs)
+    RequestEchoPeer, // add the server's echo-test peer to the nearby list for a couple of minutes
+    SetMuteState { muted_peer_ids: Vec<String> }, // everyone this client has muted locally; replaces the previous list
+    SetTags(BTreeMap<String, String>), // small labels (clan, role) shown to peers; replaces the previous tags
+    SetElevatedConsent(ElevatedConsent), // who may hear this client beyond proximity; replaces what was set before, and is kept for later sessions
+    SetVehicle { vehicle_id: Option<String> }, // the boat/cart/mount this client rides; clients aboard the same one are always paired. None steps off
+    SetIdentityKey { public_key: String }, // hex-encoded identity public key; peers see its fingerprint. once per session
+    RequestMessageSigning, // switch this connection to signed messages; answered with SigningSession
+    Signed { nonce: u64, mac: String, message: String }, // another ClientMessage as JSON, with its HMAC (see the server's signing.rs)
+    Disconnect,
+}
+
+// what every client frame decodes to: a ClientMessage plus the envelope version and an optional
+// request_id next to "type" and "data". a message carrying a request_id is answered with exactly
+// one Ack or RequestError echoing it. parse frames with compat::parse_client_envelope, which
+// also refuses unknown envelope keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ClientEnvelope {
+    #[serde(default)]
+    pub v: u32,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl From<ClientMessage> for ClientEnvelope {
+    fn from(message: ClientMessage) -> Self {
+        ClientEnvelope {
+            v: PROTOCOL_VERSION,
+            message,
+            request_id: None,
+        }
+    }
+}
+
+// per-client opt-ins; anything not sent keeps the legacy behaviour
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientPreferences {
+    pub peer_details: bool, // also send NearbyPeerDetails alongside NearbyPeers
+    pub area_summary: bool, // also send AreaSummary alongside NearbyPeers
+    pub rtt_probes: bool, // receive a Ping every few seconds and answer each with Pong (the SDK does this itself)
+    pub spectator_opt_out: bool, // never be paired with spectators (listen-only clients such as a streamer's broadcast)
+    pub share_public_address: bool, // let peers see the IP:port the server sees this client at, and see theirs (PeerAddresses)
+}
+
+// who may hear this client beyond the usual proximity rules. everything is allowed until the
+// client says otherwise; the server keeps what it set across sessions and restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct ElevatedConsent {
+    pub spectators: bool, // listen-only spectators (a streamer's broadcast client) whose radius covers this client
+    pub announcers: bool, // the speaker of an announcer zone this client stands in
+}
+
+impl Default for ElevatedConsent {
+    fn default() -> Self {
+        ElevatedConsent { spectators: true, announcers: true }
+    }
+}
+
+// why a peer that isn't simply in range was paired with this client (with its consent)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ElevatedRole {
+    Spectator,
+    Announcer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceBucket {
+    Near,
+    Medium,
+    Far,
+}
+
+// distance info for one nearby peer. exact deltas are only included when the
+// server runs with position_privacy = "exact", so modified clients can't use it as a radar
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct NearbyPeer {
+    pub client_id: String,
+    pub bucket: DistanceBucket,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dx: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dy: Option<i64>,
+    // tags the peer set for itself, if any
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    // sha-256 of the peer's identity key (AB:CD:... form), if it registered one. compare it
+    // out-of-band to make sure the signaling server isn't sitting in the middle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_fingerprint: Option<String>,
+    // how loud to play the peer, from 0 (silent) to 1 (full volume), when the server's audibility
+    // plugin says; full volume when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attenuation: Option<f32>,
+    // how well the peer's facing carries its voice this way, as a multiple of the usual range
+    // (above 1 facing us, below 1 facing away), on servers with directional audio
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub facing_weight: Option<f32>,
+    // the two sides' round trips or packet loss together are past what the server considers usable
+    // for voice; expect choppy audio (show it in the UI) or skip the connection
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub low_quality: bool,
+    // the peer is a spectator: it hears you but sends no audio
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub listen_only: bool,
+    // the role the peer reaches you through, out of ordinary range, as your ElevatedConsent allows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elevated_role: Option<ElevatedRole>,
+}
+
+// NAT behaviour as classified by RFC 3489-style STUN probing. symmetric NATs map every
+// destination to a new port, so two of them practically never connect without TURN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum NatType {
+    Open,
+    FullCone,
+    RestrictedCone,
+    PortRestrictedCone,
+    Symmetric,
+    Unknown,
+}
+
+// why the server is closing a connection, sent in Closing just before it does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    // no UpdatePosition arrived within the server's registration deadline
+    RegistrationTimeout,
+    // the client kept sending binary frames on a connection that negotiated JSON text
+    UnexpectedBinaryFrames,
+    // the client's address registered too many times in quick succession; an Error with the wait comes first
+    RegistrationThrottled,
+    // the server's limit on clients registered from one address was reached; an Error comes first
+    TooManyFromAddress,
+    // an operator kicked or banned the client
+    Kicked,
+    // the server is shutting down, usually to restart: reconnect (with the SessionToken) to reach
+    // the next process
+    ServerRestart,
+}
+
+// one entry of RTCConfiguration.iceServers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PopulationEntry {
+    pub game_id: i32,
+    pub map_id: i32,
+    pub channel: i32,
+    pub count: usize,
+}
+
+// one server from the registry, as it last announced itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ListedServer {
+    pub name: String,
+    pub region: String,
+    pub url: String, // websocket url to connect to
+    pub population: usize,
+    pub game_ids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", content = "data")]
+pub enum ServerMessage {
+    NearbyPeers(Vec<String>),
+    AllPeersGone, // follows an empty NearbyPeers when the client's last peer leaves: tear down every connection
+    NearbyPeerDetails(Vec<NearbyPeer>), // sent after NearbyPeers to clients with peer_details enabled
+    AreaSummary { nearby_count: usize }, // anonymous headcount within audible range, for clients with area_summary enabled
+    Population(Vec<PopulationEntry>), // reply to GetPopulation
+    ServerList(Vec<ListedServer>), // reply to ListServers
+    // reply to GetServerStats: how long the server has been up, how many clients are on the
+    // sender's game, and how many position updates per second it gets from the sender (measured
+    // over the last few seconds, so 0 right after connecting)
+    ServerStats { uptime_secs: u64, online_in_game: usize, position_updates_per_sec: f64 },
+    ReintroducePeer { peer_id: String }, // tear down and renegotiate the connection to this one peer
+    // direct ICE to this peer keeps failing, or both sides are behind symmetric NATs: connect through these TURN servers with iceTransportPolicy "relay".
+    // sent just before every introduction of the pair
+    ForceRelay { peer_id: String, ice_servers: Vec<IceServer> },
+    // sent just before NearbyPeers to clients that reported their own NAT type: theirs, and that of
+    // every nearby peer that reported one
+    PeerNatTypes { own: NatType, peers: BTreeMap<String, NatType> },
+    // sent just before NearbyPeers to clients with share_public_address, on servers with
+    // reflexive_address_hints: the IP:port the server sees them at, and that of every nearby peer
+    // sharing its own. when STUN is blocked, the IP makes a server-reflexive candidate hint; the
+    // port is the signaling connection's, which port-preserving NATs often reuse for UDP
+    PeerAddresses { own: String, peers: BTreeMap<String, String> },
+    UdpSession { session_id: u32, key: String, port: u16 }, // reply to RequestUdpSession; key is hex
+    PeerMuteState { muted_by: Vec<String> }, // clients currently muting the receiver; may include peers no longer nearby
+    SigningSession { key: String }, // reply to RequestMessageSigning; key is hex. everything after it must be Signed
+    Ping { nonce: u64 }, // round-trip probe for clients with rtt_probes enabled; answer with Pong { nonce }
+    Closing { reason: CloseReason }, // the server closes the connection right after this; nothing else follows
+    Notice { text: String }, // a message from the server's operators to show the player (greetings, warnings)
+    // this client was made a spectator (or stopped being one): while enabled it hears its peers
+    // and must not send them audio. sent on the change and on registering as one
+    ListenOnly { enabled: bool },
+    // the audio partition of its map the client was placed in, on maps with a population cap: 0 is
+    // the main one, above 0 an overflow partition that only hears itself. sent on arriving at such
+    // a map, and with 0 on leaving an overflow partition for a map without a cap
+    MapPartition { partition: u32 },
+    // in a cluster too dense for a full mesh: send audio to and take it from hub_id only (a leaf),
+    // or forward between forward_for and the other hubs (a hub). sent before NearbyPeers and
+    // whenever it changes; both empty means back to the full mesh
+    TopologyHint {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hub_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        forward_for: Vec<String>,
+    },
+    ElevatedConsent(ElevatedConsent), // sent on registering when the client has refused something in an earlier session
+    // relayed_at_ms is when the server relayed it (unix milliseconds); servers drop offers and
+    // answers that waited too long in the receiver's queue rather than deliver a stale SDP
+    ReceiveOffer {
+        sender_id: String,
+        offer: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relayed_at_ms: Option<u64>,
+    },
+    ReceiveAnswer {
+        sender_id: String,
+        answer: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relayed_at_ms: Option<u64>,
+    },
+    ReceiveIceCandidate { sender_id: String, candidate: String },
+    // the client's offer to this peer crossed one of the peer's and lost the tie-break (the greater
+    // client id backs off): roll it back and answer the peer's offer, which follows if it hasn't
+    // already arrived
+    GlareRollback { peer_id: String },
+    // sent on registering: keep it for the client_id, and send it in the registering UpdatePosition
+    // of a reconnect to take the registration over as it stands
+    SessionToken { token: String },
+    Ack { request_id: String }, // the message with this request_id was handled (any reply to it comes first)
+    RequestError { request_id: String, error: String }, // replaces Error for messages that carried a request_id
+    Error(String), // optional: to send error messages back to client
+}
+
+// a ServerMessage as JSON/CBOR servers send it, with their PROTOCOL_VERSION next to "type".
+// clients that parse ServerMessage directly can ignore "v"
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ServerEnvelope {
+    #[serde(default)]
+    pub v: u32,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
@@ -0,0 +1,121 @@
+// unit tests of the position arithmetic at the ends of the coordinate space, and of reading
+// client envelopes: strictly when versioned, through the legacy shim when not
+use super::*;
+
+fn at(x: i64, y: i64) -> ClientPosition {
+    ClientPosition { client_id: "a".to_string(), map_id: 1, x, y, channel: 0, game_id: 0, heading: None, session_token: None }
+}
+
+#[test]
+fn distance_squared_spans_the_whole_coordinate_space() {
+    let span = (i64::MAX as f64) - (i64::MIN as f64);
+    assert_eq!(at(i64::MIN, 0).distance_squared(&at(i64::MAX, 0)), span * span);
+    assert_eq!(at(0, i64::MAX).distance_squared(&at(0, i64::MIN)), span * span);
+    // both axes at once stays finite
+    let corners = at(i64::MIN, i64::MIN).distance_squared(&at(i64::MAX, i64::MAX));
+    assert!(corners.is_finite());
+    assert_eq!(corners, 2.0 * span * span);
+}
+
+#[test]
+fn distance_squared_is_symmetric_at_the_extremes() {
+    for (a, b) in [(at(i64::MIN, 3), at(i64::MAX, -3)), (at(i64::MAX, i64::MIN), at(-1, 1)), (at(i64::MIN, i64::MAX), at(0, 0))] {
+        assert_eq!(a.distance_squared(&b), b.distance_squared(&a));
+    }
+}
+
+#[test]
+fn nearby_extremes_are_still_close() {
+    assert_eq!(at(i64::MAX, i64::MAX).distance_squared(&at(i64::MAX - 3, i64::MAX - 4)), 25.0);
+    assert_eq!(at(i64::MIN, i64::MIN).distance_squared(&at(i64::MIN + 3, i64::MIN + 4)), 25.0);
+}
+
+#[test]
+fn float_positions_beyond_the_range_clamp_to_its_ends() {
+    let float = ClientPositionFloat { client_id: "a".to_string(), map_id: 1, x: f32::INFINITY, y: f32::NEG_INFINITY, channel: 0, game_id: 0, heading: None, session_token: None };
+    let fixed = float.to_fixed();
+    assert_eq!((fixed.x, fixed.y), (i64::MAX, i64::MIN));
+    let nan = ClientPositionFloat { x: f32::NAN, ..float };
+    assert_eq!(nan.to_fixed().x, 0);
+}
+
+fn parse(json: &str) -> Result<ClientEnvelope, String> {
+    compat::parse_client_envelope(serde_json::from_str(json).unwrap())
+}
+
+#[test]
+fn versioned_envelopes_parse() {
+    let envelope = parse(r#"{"v": 1, "type": "SendOffer", "data": {"target_id": "b", "offer": "sdp"}, "request_id": "r1"}"#).unwrap();
+    assert_eq!(envelope.v, 1);
+    assert_eq!(envelope.request_id.as_deref(), Some("r1"));
+    assert!(matches!(envelope.message, ClientMessage::SendOffer { target_id, offer } if target_id == "b" && offer == "sdp"));
+}
+
+#[test]
+fn unknown_envelope_keys_are_refused() {
+    let error = parse(r#"{"v": 1, "type": "RequestPeerRefresh", "extra": true}"#).unwrap_err();
+    assert!(error.contains("extra"), "{}", error);
+    // unversioned messages outside the legacy six take the strict path too
+    assert!(parse(r#"{"type": "GetPopulation", "extra": true}"#).is_err());
+}
+
+#[test]
+fn unknown_payload_fields_are_refused() {
+    for json in [
+        r#"{"v": 1, "type": "SendOffer", "data": {"target_id": "b", "offer": "sdp", "extra": 1}}"#,
+        r#"{"v": 1, "type": "UpdatePosition", "data": {"client_id": "a", "map_id": 1, "x": 0, "y": 0, "channel": 0, "game_id": 0, "extra": 1}}"#,
+        r#"{"v": 1, "type": "SetPreferences", "data": {"extra": 1}}"#,
+        r#"{"v": 1, "type": "GetPopulation", "data": {"extra": 1}}"#,
+    ] {
+        assert!(parse(json).is_err(), "{} was accepted", json);
+    }
+}
+
+#[test]
+fn unsupported_versions_are_refused() {
+    assert!(parse(&format!(r#"{{"v": {}, "type": "RequestPeerRefresh"}}"#, PROTOCOL_VERSION + 1)).is_err());
+    assert!(parse(r#"{"v": "1", "type": "RequestPeerRefresh"}"#).is_err());
+    assert!(parse(r#"["RequestPeerRefresh"]"#).is_err());
+}
+
+#[test]
+fn legacy_positions_map_to_the_current_type() {
+    // the desktop client's frame, with a field it sent that the server never read
+    let envelope = parse(r#"{"type": "UpdatePosition", "data": {"client_id": "a", "map_id": 2, "x": 10, "y": -5, "channel": 3, "game_id": 1, "name": "old"}}"#).unwrap();
+    assert_eq!(envelope.v, 0);
+    let ClientMessage::UpdatePosition(pos) = envelope.message else {
+        panic!("not an UpdatePosition");
+    };
+    assert_eq!(
+        pos,
+        ClientPosition { client_id: "a".to_string(), map_id: 2, x: 10, y: -5, channel: 3, game_id: 1, heading: None, session_token: None }
+    );
+}
+
+#[test]
+fn legacy_signaling_maps_to_the_current_types() {
+    let message = |json: &str| parse(json).unwrap().message;
+    assert!(matches!(
+        message(r#"{"type": "SendOffer", "data": {"target_id": "b", "offer": "o"}}"#),
+        ClientMessage::SendOffer { target_id, offer } if target_id == "b" && offer == "o"
+    ));
+    assert!(matches!(
+        message(r#"{"type": "SendAnswer", "data": {"target_id": "b", "answer": "a"}}"#),
+        ClientMessage::SendAnswer { target_id, answer } if target_id == "b" && answer == "a"
+    ));
+    assert!(matches!(
+        message(r#"{"type": "SendIceCandidate", "data": {"target_id": "b", "candidate": "c"}}"#),
+        ClientMessage::SendIceCandidate { target_id, candidate } if target_id == "b" && candidate == "c"
+    ));
+    assert!(matches!(message(r#"{"type": "RequestPeerRefresh"}"#), ClientMessage::RequestPeerRefresh));
+    assert!(matches!(message(r#"{"type": "Disconnect", "data": null}"#), ClientMessage::Disconnect));
+}
+
+#[test]
+fn legacy_frames_keep_their_request_id_and_ignore_other_keys() {
+    let envelope = parse(r#"{"type": "Disconnect", "request_id": "r1", "sent_at": 12345}"#).unwrap();
+    assert_eq!(envelope.request_id.as_deref(), Some("r1"));
+    assert!(matches!(envelope.message, ClientMessage::Disconnect));
+    // but a frame missing what the original server needed is still an error
+    assert!(parse(r#"{"type": "UpdatePosition", "data": {"client_id": "a"}}"#).is_err());
+}
@@ -0,0 +1,153 @@
+//! Rust client for the proxchat signaling server.
+//!
+//! Connects over WebSocket (proxchat.v1, JSON), registers with an initial position and then
+//! hands back a [`Client`] for sending and an [`Events`] stream for everything the server says.
+//!
+//! On wasm32 the tokio client is replaced by a wasm-bindgen `ProxchatClient` built on the
+//! browser's WebSocket (see the `wasm` module); the event shapes are the same.
+//!
+//! ```no_run
+//! # #[cfg(not(target_arch = "wasm32"))]
+//! # async fn run() -> Result<(), proxchat_client::Error> {
+//! use proxchat_client::{Client, ClientConfig, Event, Position};
+//!
+//! let config = ClientConfig::new("ws://127.0.0.1:8080", "my-bot");
+//! let (client, mut events) = Client::connect(config, Position { map_id: 1, x: 10, y: 10, channel: 0, game_id: 0, heading: None }).await?;
+//! while let Some(event) = events.next().await {
+//!     if let Event::Offer { sender_id, .. } = event {
+//!         client.send_answer(&sender_id, "{...}").await?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+pub use proxchat_protocol as protocol;
+pub use proxchat_protocol::{ClientMessage, ClientPreferences, ElevatedConsent, NatType, ServerMessage};
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::{Client, Error, Events};
+
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+mod signing;
+
+/// Default minimum time between position updates sent to the server.
+pub const DEFAULT_POSITION_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Server URL, e.g. `ws://127.0.0.1:8080`.
+    pub url: String,
+    /// Stable id for this client; reconnecting with the same id replaces the old session.
+    pub client_id: String,
+    /// Minimum time between position updates. Faster updates are coalesced and only the latest is sent.
+    pub position_interval: Duration,
+    /// Switch to signed messages right after registering; required by servers running with
+    /// `require_message_signing`. `connect` then waits for the server's key before returning.
+    pub sign_messages: bool,
+    /// The token from an earlier connection's `ServerMessage::SessionToken` (delivered as
+    /// `Event::Message`). Reconnecting with it while the server still holds that connection's
+    /// registration takes the registration over, keeping every pair, instead of starting again.
+    pub session_token: Option<String>,
+}
+
+impl ClientConfig {
+    pub fn new(url: impl Into<String>, client_id: impl Into<String>) -> Self {
+        ClientConfig {
+            url: url.into(),
+            client_id: client_id.into(),
+            position_interval: DEFAULT_POSITION_INTERVAL,
+            sign_messages: false,
+            session_token: None,
+        }
+    }
+}
+
+/// Where the client is in the game world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub map_id: i32,
+    pub x: i64,
+    pub y: i64,
+    pub channel: i32,
+    /// 0 is NexusTK.
+    pub game_id: i32,
+    /// Facing in whole degrees from the +x axis towards +y, for servers with directional audio.
+    pub heading: Option<u16>,
+}
+
+impl Position {
+    pub(crate) fn to_message(self, client_id: &str) -> ClientMessage {
+        ClientMessage::UpdatePosition(protocol::ClientPosition {
+            client_id: client_id.to_string(),
+            map_id: self.map_id,
+            x: self.x,
+            y: self.y,
+            channel: self.channel,
+            game_id: self.game_id,
+            heading: self.heading,
+            session_token: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Event {
+    /// The nearby set changed. `peers` is the full set, `joined`/`left` the difference from the previous one.
+    PeersChanged {
+        peers: Vec<String>,
+        joined: Vec<String>,
+        left: Vec<String>,
+    },
+    /// The last nearby peer left; tear down every peer connection.
+    AllPeersGone,
+    Offer { sender_id: String, offer: String },
+    Answer { sender_id: String, answer: String },
+    IceCandidate { sender_id: String, candidate: String },
+    /// Renegotiate the connection to this one peer.
+    Reintroduce { peer_id: String },
+    /// Any other server message (peer details, summaries, population, errors, ...).
+    Message(ServerMessage),
+    /// The connection ended, with the reason if one is known. No events follow.
+    Closed(Option<String>),
+}
+
+
+// turns server messages into events, tracking the nearby set to report joins and leaves
+#[derive(Default)]
+pub(crate) struct PeerTracker {
+    peers: BTreeSet<String>,
+}
+
+impl PeerTracker {
+    pub(crate) fn event_for(&mut self, message: ServerMessage) -> Event {
+        match message {
+            ServerMessage::NearbyPeers(list) => {
+                let current: BTreeSet<String> = list.into_iter().collect();
+                let joined = current.difference(&self.peers).cloned().collect();
+                let left = self.peers.difference(&current).cloned().collect();
+                self.peers = current;
+                Event::PeersChanged {
+                    peers: self.peers.iter().cloned().collect(),
+                    joined,
+                    left,
+                }
+            }
+            ServerMessage::AllPeersGone => Event::AllPeersGone,
+            ServerMessage::ReceiveOffer { sender_id, offer, .. } => Event::Offer { sender_id, offer },
+            ServerMessage::ReceiveAnswer { sender_id, answer, .. } => Event::Answer { sender_id, answer },
+            ServerMessage::ReceiveIceCandidate { sender_id, candidate } => Event::IceCandidate { sender_id, candidate },
+            ServerMessage::ReintroducePeer { peer_id } => Event::Reintroduce { peer_id },
+            other => Event::Message(other),
+        }
+    }
+}
@@ -0,0 +1,421 @@
+// tokio/tungstenite implementation of the client, for everything except wasm32
+use crate::signing::Signer;
+use crate::protocol::ClientEnvelope;
+use crate::{protocol, ClientConfig, ClientMessage, ClientPreferences, ElevatedConsent, Event, NatType, PeerTracker, Position, ServerMessage};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, Stream, StreamExt};
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+// queued signaling messages and undelivered events per client
+const OUTBOUND_QUEUE: usize = 100;
+const EVENT_QUEUE: usize = 100;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug)]
+pub enum Error {
+    WebSocket(tungstenite::Error),
+    Encode(serde_json::Error),
+    /// The server answered the connection setup with an error.
+    Server(String),
+    /// The connection is gone.
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WebSocket(e) => write!(f, "websocket error: {}", e),
+            Error::Encode(e) => write!(f, "failed to encode message: {}", e),
+            Error::Server(e) => write!(f, "server error: {}", e),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::WebSocket(e)
+    }
+}
+
+/// Sending half of a connection. Cheap to clone; the connection closes once every clone is dropped.
+#[derive(Clone)]
+pub struct Client {
+    client_id: Arc<str>,
+    outbound: mpsc::Sender<ClientEnvelope>,
+    positions: Arc<watch::Sender<Position>>,
+}
+
+impl Client {
+    /// Connect, register at `position` and start the background tasks. Must be called inside a tokio runtime.
+    pub async fn connect(config: ClientConfig, position: Position) -> Result<(Client, Events), Error> {
+        let mut request = config.url.as_str().into_client_request()?;
+        request
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol::SUBPROTOCOL_V1));
+        let (stream, _) = tokio_tungstenite::connect_async(request).await?;
+        let (mut sink, mut stream) = stream.split();
+
+        // the first UpdatePosition registers the client id with the server
+        let mut register = position.to_message(&config.client_id);
+        if let ClientMessage::UpdatePosition(pos) = &mut register {
+            pos.session_token = config.session_token.clone();
+        }
+        send_message(&mut sink, &register.into(), &mut None).await?;
+
+        let mut early_messages = Vec::new();
+        let mut signer = None;
+        if config.sign_messages {
+            send_message(&mut sink, &ClientMessage::RequestMessageSigning.into(), &mut None).await?;
+            signer = Some(wait_for_signing_key(&mut stream, &mut early_messages).await?);
+        }
+
+        let client_id: Arc<str> = Arc::from(config.client_id);
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE);
+        let (positions, positions_rx) = watch::channel(position);
+        let (events_tx, events_rx) = mpsc::channel(EVENT_QUEUE);
+        tokio::spawn(write_loop(sink, Arc::clone(&client_id), outbound_rx, positions_rx, position, config.position_interval, signer));
+        tokio::spawn(read_loop(stream, events_tx, early_messages, outbound.downgrade()));
+
+        let client = Client {
+            client_id,
+            outbound,
+            positions: Arc::new(positions),
+        };
+        Ok((client, Events { receiver: events_rx }))
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Queue a position update. Never blocks; updates arriving faster than `position_interval`
+    /// replace each other and only the latest one is sent.
+    pub fn update_position(&self, position: Position) {
+        self.positions.send_replace(position);
+    }
+
+    /// Send any protocol message as-is.
+    pub async fn send(&self, message: ClientMessage) -> Result<(), Error> {
+        self.outbound.send(message.into()).await.map_err(|_| Error::Closed)
+    }
+
+    /// Send a message tagged with `request_id`. The server answers it with exactly one
+    /// `ServerMessage::Ack` or `ServerMessage::RequestError` carrying the same id.
+    pub async fn send_request(&self, message: ClientMessage, request_id: &str) -> Result<(), Error> {
+        let envelope = ClientEnvelope {
+            request_id: Some(request_id.to_string()),
+            ..message.into()
+        };
+        self.outbound.send(envelope).await.map_err(|_| Error::Closed)
+    }
+
+    pub async fn send_offer(&self, target_id: &str, offer: &str) -> Result<(), Error> {
+        self.send(ClientMessage::SendOffer {
+            target_id: target_id.to_string(),
+            offer: offer.to_string(),
+        })
+        .await
+    }
+
+    pub async fn send_answer(&self, target_id: &str, answer: &str) -> Result<(), Error> {
+        self.send(ClientMessage::SendAnswer {
+            target_id: target_id.to_string(),
+            answer: answer.to_string(),
+        })
+        .await
+    }
+
+    pub async fn send_ice_candidate(&self, target_id: &str, candidate: &str) -> Result<(), Error> {
+        self.send(ClientMessage::SendIceCandidate {
+            target_id: target_id.to_string(),
+            candidate: candidate.to_string(),
+        })
+        .await
+    }
+
+    /// Ask for the current nearby list again.
+    pub async fn refresh_peers(&self) -> Result<(), Error> {
+        self.send(ClientMessage::RequestPeerRefresh).await
+    }
+
+    /// Redo the pairing with one peer after its connection failed.
+    pub async fn request_reintroduction(&self, peer_id: &str) -> Result<(), Error> {
+        self.send(ClientMessage::RequestReintroduction {
+            peer_id: peer_id.to_string(),
+        })
+        .await
+    }
+
+    /// Report that ICE to this peer failed. After a few reports the server (if it has TURN servers)
+    /// reintroduces the pair with `ServerMessage::ForceRelay`: use its ICE servers relay-only.
+    pub async fn report_ice_failure(&self, peer_id: &str) -> Result<(), Error> {
+        self.send(ClientMessage::ReportIceFailure {
+            peer_id: peer_id.to_string(),
+        })
+        .await
+    }
+
+    /// Report that the connection to this peer is up. Once every pair of a client is reported, the
+    /// server stops resending it the same NearbyPeers list every few seconds.
+    pub async fn report_peer_connected(&self, peer_id: &str) -> Result<(), Error> {
+        self.send(ClientMessage::ReportPeerConnected {
+            peer_id: peer_id.to_string(),
+        })
+        .await
+    }
+
+    /// Share this client's NAT type (from its own STUN probing) with peers. From then on every
+    /// introduction is preceded by `ServerMessage::PeerNatTypes`; symmetric pairs get `ForceRelay`.
+    pub async fn report_nat_type(&self, nat_type: NatType) -> Result<(), Error> {
+        self.send(ClientMessage::ReportNatType { nat_type }).await
+    }
+
+    /// Report this client's round trip and packet loss, e.g. averaged from its WebRTC stats. Servers
+    /// that check network quality skip or flag (`NearbyPeer::low_quality`) pairs that can't carry audio.
+    pub async fn report_network_quality(&self, rtt_ms: u32, packet_loss_percent: f32) -> Result<(), Error> {
+        self.send(ClientMessage::ReportNetworkQuality { rtt_ms, packet_loss_percent }).await
+    }
+
+    /// Bring the server's echo-test peer into the nearby list for a while. Offer to it like any
+    /// other peer: it plays received audio back and echoes data channel messages.
+    pub async fn request_echo_peer(&self) -> Result<(), Error> {
+        self.send(ClientMessage::RequestEchoPeer).await
+    }
+
+    /// Tell the server who this client has muted (the full list). Those peers receive
+    /// `ServerMessage::PeerMuteState` and may stop sending audio to us.
+    pub async fn set_mute_state(&self, muted_peer_ids: &[String]) -> Result<(), Error> {
+        self.send(ClientMessage::SetMuteState {
+            muted_peer_ids: muted_peer_ids.to_vec(),
+        })
+        .await
+    }
+
+    /// Label this client for its peers, e.g. `{"clan": "Moonlit", "role": "healer"}`. At most 4 tags;
+    /// keys are lowercase identifiers up to 16 characters, values up to 32. An empty map clears them.
+    /// Peers with `peer_details` enabled see them in `NearbyPeerDetails`.
+    pub async fn set_tags(&self, tags: BTreeMap<String, String>) -> Result<(), Error> {
+        self.send(ClientMessage::SetTags(tags)).await
+    }
+
+    /// Board a vehicle (boat, cart, mount) shared with other players, or step off with `None`.
+    /// Clients aboard the same `vehicle_id` stay paired whatever their coordinates say.
+    pub async fn set_vehicle(&self, vehicle_id: Option<&str>) -> Result<(), Error> {
+        self.send(ClientMessage::SetVehicle {
+            vehicle_id: vehicle_id.map(str::to_string),
+        })
+        .await
+    }
+
+    /// Choose who may hear this client beyond ordinary range (spectators, announcers). The server
+    /// keeps refusals for later sessions and sends them back as `ServerMessage::ElevatedConsent`.
+    pub async fn set_elevated_consent(&self, consent: ElevatedConsent) -> Result<(), Error> {
+        self.send(ClientMessage::SetElevatedConsent(consent)).await
+    }
+
+    /// Register this client's identity public key (hex-encoded, 32-1024 bytes) once per session.
+    /// Peers receive its SHA-256 fingerprint in `NearbyPeerDetails` to verify out-of-band.
+    pub async fn set_identity_key(&self, public_key_hex: &str) -> Result<(), Error> {
+        self.send(ClientMessage::SetIdentityKey {
+            public_key: public_key_hex.to_string(),
+        })
+        .await
+    }
+
+    /// With `rtt_probes` set the server pings this client every few seconds to measure its round
+    /// trip; the pings are answered automatically and never show up as events.
+    pub async fn set_preferences(&self, preferences: ClientPreferences) -> Result<(), Error> {
+        self.send(ClientMessage::SetPreferences(preferences)).await
+    }
+
+    /// The answer arrives as `Event::Message(ServerMessage::Population(..))`.
+    pub async fn get_population(&self) -> Result<(), Error> {
+        self.send(ClientMessage::GetPopulation).await
+    }
+
+    /// Other community servers from the registry this server announces to, for a server browser.
+    /// The answer arrives as `Event::Message(ServerMessage::ServerList(..))`.
+    pub async fn list_servers(&self) -> Result<(), Error> {
+        self.send(ClientMessage::ListServers).await
+    }
+
+    /// Uptime, players online in this client's game and its own position update rate, for a
+    /// connection diagnostics panel. The answer arrives as `Event::Message(ServerMessage::ServerStats { .. })`.
+    pub async fn get_server_stats(&self) -> Result<(), Error> {
+        self.send(ClientMessage::GetServerStats).await
+    }
+
+    pub async fn report_peer(&self, target_id: &str, reason: &str) -> Result<(), Error> {
+        self.send(ClientMessage::ReportPeer {
+            target_id: target_id.to_string(),
+            reason: reason.to_string(),
+        })
+        .await
+    }
+
+    /// Tell the server we're leaving. The events stream ends with `Event::Closed` shortly after.
+    pub async fn disconnect(&self) -> Result<(), Error> {
+        self.send(ClientMessage::Disconnect).await
+    }
+}
+
+/// Everything the server sends, in order. Also usable as a `futures::Stream`.
+pub struct Events {
+    receiver: mpsc::Receiver<Event>,
+}
+
+impl Events {
+    /// Next event, or None after `Event::Closed` has been delivered.
+    pub async fn next(&mut self) -> Option<Event> {
+        self.receiver.recv().await
+    }
+
+    /// Next event if one is already queued, without waiting.
+    pub fn try_next(&mut self) -> Option<Event> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+async fn send_message(sink: &mut SplitSink<WsStream, Message>, message: &ClientEnvelope, signer: &mut Option<Signer>) -> Result<(), Error> {
+    let text = match signer {
+        Some(signer) => serde_json::to_string(&ClientEnvelope::from(signer.wrap(message).map_err(Error::Encode)?)),
+        None => serde_json::to_string(message),
+    }
+    .map_err(Error::Encode)?;
+    sink.send(Message::Text(text.into())).await?;
+    Ok(())
+}
+
+// reads until the reply to RequestMessageSigning; whatever else arrives first is kept for the event stream
+async fn wait_for_signing_key(stream: &mut SplitStream<WsStream>, early_messages: &mut Vec<ServerMessage>) -> Result<Signer, Error> {
+    while let Some(frame) = stream.next().await {
+        let Message::Text(text) = frame? else {
+            continue;
+        };
+        match serde_json::from_str::<ServerMessage>(&text) {
+            Ok(ServerMessage::SigningSession { key }) => {
+                return Signer::new(&key).ok_or_else(|| Error::Server("malformed signing key".to_string()));
+            }
+            Ok(ServerMessage::Error(e)) => return Err(Error::Server(e)),
+            Ok(message) => early_messages.push(message),
+            Err(e) => warn!("Ignoring unparseable server message: {} ({})", e, text),
+        }
+    }
+    Err(Error::Closed)
+}
+
+// forwards queued signaling messages straight away and position updates at most once per interval
+async fn write_loop(
+    mut sink: SplitSink<WsStream, Message>,
+    client_id: Arc<str>,
+    mut outbound: mpsc::Receiver<ClientEnvelope>,
+    mut positions: watch::Receiver<Position>,
+    mut last_sent: Position,
+    interval: Duration,
+    mut signer: Option<Signer>,
+) {
+    let mut next_position_at = Instant::now() + interval;
+    let mut position_pending = false;
+    loop {
+        tokio::select! {
+            message = outbound.recv() => {
+                let Some(message) = message else { break };
+                let disconnecting = matches!(message.message, ClientMessage::Disconnect);
+                if let Err(e) = send_message(&mut sink, &message, &mut signer).await {
+                    warn!("Failed to send message: {}", e);
+                    break;
+                }
+                if disconnecting {
+                    break;
+                }
+            }
+            changed = positions.changed(), if !position_pending => {
+                if changed.is_err() {
+                    break;
+                }
+                position_pending = true;
+            }
+            _ = time::sleep_until(next_position_at), if position_pending => {
+                position_pending = false;
+                let position = *positions.borrow_and_update();
+                if position != last_sent {
+                    if let Err(e) = send_message(&mut sink, &position.to_message(&client_id).into(), &mut signer).await {
+                        warn!("Failed to send position: {}", e);
+                        break;
+                    }
+                    last_sent = position;
+                    next_position_at = Instant::now() + interval;
+                }
+            }
+        }
+    }
+    let _ = sink.close().await;
+}
+
+// forwards server messages as events until the socket closes. Pings are answered here rather
+// than surfaced; the weak sender doesn't keep the connection open once the Client is dropped
+async fn read_loop(
+    mut stream: SplitStream<WsStream>,
+    events: mpsc::Sender<Event>,
+    early_messages: Vec<ServerMessage>,
+    outbound: mpsc::WeakSender<ClientEnvelope>,
+) {
+    let mut peers = PeerTracker::default();
+    for message in early_messages {
+        let _ = events.send(peers.event_for(message)).await;
+    }
+    let mut reason = None;
+    while let Some(frame) = stream.next().await {
+        let message = match frame {
+            Ok(Message::Text(text)) => match serde_json::from_str::<ServerMessage>(&text) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Ignoring unparseable server message: {} ({})", e, text);
+                    continue;
+                }
+            },
+            Ok(Message::Close(frame)) => {
+                reason = frame.map(|frame| frame.reason.to_string());
+                break;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                reason = Some(e.to_string());
+                break;
+            }
+        };
+        if let ServerMessage::Ping { nonce } = message {
+            if let Some(outbound) = outbound.upgrade() {
+                let _ = outbound.try_send(ClientMessage::Pong { nonce }.into());
+            }
+            continue;
+        }
+        // keep draining the socket even if nobody listens to events any more
+        let _ = events.send(peers.event_for(message)).await;
+    }
+    let _ = events.send(Event::Closed(reason)).await;
+}
@@ -0,0 +1,33 @@
+// client half of the server's signed-message mode: once the server has handed out a key
+// (ServerMessage::SigningSession), every message goes out wrapped in ClientMessage::Signed
+use crate::ClientMessage;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+pub(crate) struct Signer {
+    key: Vec<u8>,
+    next_nonce: u64,
+}
+
+impl Signer {
+    /// None if the key isn't valid hex.
+    pub(crate) fn new(key_hex: &str) -> Option<Self> {
+        Some(Signer {
+            key: hex::decode(key_hex).ok()?,
+            next_nonce: 1,
+        })
+    }
+
+    /// `message` is a `ClientEnvelope` (or a bare `ClientMessage`); its request_id stays under the signature.
+    pub(crate) fn wrap(&mut self, message: &impl Serialize) -> Result<ClientMessage, serde_json::Error> {
+        let message = serde_json::to_string(message)?;
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts any key length");
+        hmac.update(&nonce.to_be_bytes());
+        hmac.update(message.as_bytes());
+        let mac = hex::encode(hmac.finalize().into_bytes());
+        Ok(ClientMessage::Signed { nonce, mac, message })
+    }
+}
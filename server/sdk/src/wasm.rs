@@ -0,0 +1,353 @@
+// browser build of the client on top of web_sys::WebSocket, exported with wasm-bindgen.
+// events go to a JS callback as plain objects shaped like `Event`, e.g.
+// {type: "PeersChanged", data: {peers: [...], joined: [...], left: [...]}}.
+use crate::signing::Signer;
+use crate::protocol::ClientEnvelope;
+use crate::{protocol, ClientMessage, ClientPreferences, Event, PeerTracker, Position, ServerMessage, DEFAULT_POSITION_INTERVAL};
+use log::warn;
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{CloseEvent, MessageEvent, WebSocket};
+
+#[wasm_bindgen]
+extern "C" {
+    // global in both windows and workers
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &js_sys::Function, timeout_ms: i32) -> JsValue;
+}
+
+struct Shared {
+    socket: WebSocket,
+    client_id: String,
+    on_event: js_sys::Function,
+    peers: PeerTracker,
+    // newest position not sent yet; the first one is the registration and goes out on open
+    pending_position: Option<Position>,
+    last_sent: Option<Position>,
+    last_sent_at_ms: f64,
+    flush_scheduled: bool,
+    // set once the server answers requestMessageSigning; every later message is signed
+    signer: Option<Signer>,
+}
+
+impl Shared {
+    fn send(&mut self, message: &ClientMessage) -> Result<(), JsValue> {
+        if self.socket.ready_state() != WebSocket::OPEN {
+            return Err(JsValue::from_str("not connected"));
+        }
+        let envelope = ClientEnvelope::from(message.clone());
+        let text = match self.signer.as_mut() {
+            Some(signer) => signer.wrap(&envelope).and_then(|signed| serde_json::to_string(&ClientEnvelope::from(signed))),
+            None => serde_json::to_string(&envelope),
+        }
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.socket.send_with_str(&text)
+    }
+}
+
+#[wasm_bindgen]
+pub struct ProxchatClient {
+    shared: Rc<RefCell<Shared>>,
+    // the socket calls back into these, so they live exactly as long as the client
+    _on_open: Closure<dyn FnMut()>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+#[wasm_bindgen]
+impl ProxchatClient {
+    /// Connect and register at the given position. `on_event` receives every event as an object.
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::too_many_arguments)] // flat arguments keep the JS side simple
+    pub fn new(
+        url: &str,
+        client_id: &str,
+        map_id: i32,
+        x: f64,
+        y: f64,
+        channel: i32,
+        game_id: i32,
+        on_event: js_sys::Function,
+    ) -> Result<ProxchatClient, JsValue> {
+        let socket = WebSocket::new_with_str(url, protocol::SUBPROTOCOL_V1)?;
+        let shared = Rc::new(RefCell::new(Shared {
+            socket: socket.clone(),
+            client_id: client_id.to_string(),
+            on_event,
+            peers: PeerTracker::default(),
+            pending_position: Some(Position { map_id, x: x as i64, y: y as i64, channel, game_id, heading: None }),
+            last_sent: None,
+            last_sent_at_ms: 0.0,
+            flush_scheduled: false,
+            signer: None,
+        }));
+
+        let on_open = {
+            let shared = Rc::clone(&shared);
+            Closure::<dyn FnMut()>::new(move || flush_position(&shared))
+        };
+        let on_message = {
+            let shared = Rc::clone(&shared);
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                let Some(text) = event.data().as_string() else {
+                    return;
+                };
+                match serde_json::from_str::<ServerMessage>(&text) {
+                    Ok(message) => {
+                        if let ServerMessage::SigningSession { key } = &message {
+                            shared.borrow_mut().signer = Signer::new(key);
+                        }
+                        // answered here, never surfaced as an event
+                        if let ServerMessage::Ping { nonce } = message {
+                            if let Err(e) = shared.borrow_mut().send(&ClientMessage::Pong { nonce }) {
+                                warn!("Failed to answer Ping: {:?}", e);
+                            }
+                            return;
+                        }
+                        let event = shared.borrow_mut().peers.event_for(message);
+                        emit(&shared, &event);
+                    }
+                    Err(e) => warn!("Ignoring unparseable server message: {} ({})", e, text),
+                }
+            })
+        };
+        let on_close = {
+            let shared = Rc::clone(&shared);
+            Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+                let reason = Some(event.reason()).filter(|reason| !reason.is_empty());
+                emit(&shared, &Event::Closed(reason));
+            })
+        };
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        Ok(ProxchatClient {
+            shared,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_close: on_close,
+        })
+    }
+
+    #[wasm_bindgen(getter, js_name = clientId)]
+    pub fn client_id(&self) -> String {
+        self.shared.borrow().client_id.clone()
+    }
+
+    /// Rate limited like the native client: rapid updates collapse into the latest one. `x` and `y`
+    /// are whole numbers, exact up to `Number.MAX_SAFE_INTEGER`. `heading` (degrees) is optional.
+    #[wasm_bindgen(js_name = updatePosition)]
+    pub fn update_position(&self, map_id: i32, x: f64, y: f64, channel: i32, game_id: i32, heading: Option<u16>) {
+        self.shared.borrow_mut().pending_position = Some(Position { map_id, x: x as i64, y: y as i64, channel, game_id, heading });
+        flush_position(&self.shared);
+    }
+
+    #[wasm_bindgen(js_name = sendOffer)]
+    pub fn send_offer(&self, target_id: String, offer: String) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::SendOffer { target_id, offer })
+    }
+
+    #[wasm_bindgen(js_name = sendAnswer)]
+    pub fn send_answer(&self, target_id: String, answer: String) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::SendAnswer { target_id, answer })
+    }
+
+    #[wasm_bindgen(js_name = sendIceCandidate)]
+    pub fn send_ice_candidate(&self, target_id: String, candidate: String) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::SendIceCandidate { target_id, candidate })
+    }
+
+    #[wasm_bindgen(js_name = refreshPeers)]
+    pub fn refresh_peers(&self) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::RequestPeerRefresh)
+    }
+
+    #[wasm_bindgen(js_name = requestReintroduction)]
+    pub fn request_reintroduction(&self, peer_id: String) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::RequestReintroduction { peer_id })
+    }
+
+    /// After a few reports for the same peer the server may send ForceRelay: pass its iceServers to
+    /// the next RTCPeerConnection with iceTransportPolicy "relay".
+    #[wasm_bindgen(js_name = reportIceFailure)]
+    pub fn report_ice_failure(&self, peer_id: String) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::ReportIceFailure { peer_id })
+    }
+
+    /// Call once a peer's RTCPeerConnection reaches "connected"; settled clients no longer get the
+    /// periodic NearbyPeers resend.
+    #[wasm_bindgen(js_name = reportPeerConnected)]
+    pub fn report_peer_connected(&self, peer_id: String) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::ReportPeerConnected { peer_id })
+    }
+
+    /// `natType` is one of "open", "full_cone", "restricted_cone", "port_restricted_cone",
+    /// "symmetric" or "unknown". Introductions are then preceded by a PeerNatTypes event.
+    #[wasm_bindgen(js_name = reportNatType)]
+    pub fn report_nat_type(&self, nat_type: String) -> Result<(), JsValue> {
+        let nat_type = serde_json::from_value(serde_json::Value::String(nat_type))
+            .map_err(|e| JsValue::from_str(&format!("unknown NAT type: {}", e)))?;
+        self.shared.borrow_mut().send(&ClientMessage::ReportNatType { nat_type })
+    }
+
+    /// Round trip and packet loss from this client's WebRTC stats; pairs past the server's limits
+    /// come with low_quality set in NearbyPeerDetails, or aren't introduced at all.
+    #[wasm_bindgen(js_name = reportNetworkQuality)]
+    pub fn report_network_quality(&self, rtt_ms: u32, packet_loss_percent: f32) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::ReportNetworkQuality { rtt_ms, packet_loss_percent })
+    }
+
+    #[wasm_bindgen(js_name = requestEchoPeer)]
+    pub fn request_echo_peer(&self) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::RequestEchoPeer)
+    }
+
+    #[wasm_bindgen(js_name = setMuteState)]
+    pub fn set_mute_state(&self, muted_peer_ids: Vec<String>) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::SetMuteState { muted_peer_ids })
+    }
+
+    /// `tags` is a plain object of strings, e.g. `{clan: "Moonlit"}`; `{}` clears them.
+    #[wasm_bindgen(js_name = setTags)]
+    pub fn set_tags(&self, tags: JsValue) -> Result<(), JsValue> {
+        let json = js_sys::JSON::stringify(&tags)?.as_string().unwrap_or_default();
+        let tags = serde_json::from_str(&json).map_err(|e| JsValue::from_str(&format!("tags must map strings to strings: {}", e)))?;
+        self.shared.borrow_mut().send(&ClientMessage::SetTags(tags))
+    }
+
+    /// `vehicleId` is shared by everyone aboard the same transport; `null` steps off.
+    #[wasm_bindgen(js_name = setVehicle)]
+    pub fn set_vehicle(&self, vehicle_id: Option<String>) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::SetVehicle { vehicle_id })
+    }
+
+    /// Whether spectators and announcers may hear this client beyond ordinary range. Refusals are
+    /// kept by the server and come back in an ElevatedConsent event on later sessions.
+    #[wasm_bindgen(js_name = setElevatedConsent)]
+    pub fn set_elevated_consent(&self, spectators: bool, announcers: bool) -> Result<(), JsValue> {
+        let consent = protocol::ElevatedConsent { spectators, announcers };
+        self.shared.borrow_mut().send(&ClientMessage::SetElevatedConsent(consent))
+    }
+
+    /// `publicKey` is hex-encoded; peers see its SHA-256 fingerprint. Can be set once per session.
+    #[wasm_bindgen(js_name = setIdentityKey)]
+    pub fn set_identity_key(&self, public_key: String) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::SetIdentityKey { public_key })
+    }
+
+    /// Switch to signed messages. Everything sent after the SigningSession event is signed; on
+    /// servers that require signing, send nothing else until that event arrives.
+    #[wasm_bindgen(js_name = requestMessageSigning)]
+    pub fn request_message_signing(&self) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::RequestMessageSigning)
+    }
+
+    /// `rttProbes` (optional) lets the server measure this client's round trip; the pings are answered automatically.
+    /// `spectatorOptOut` (optional) keeps spectators (listen-only clients such as a streamer's) from hearing this one.
+    /// `sharePublicAddress` (optional) trades this client's public IP:port for its peers' in PeerAddresses events.
+    #[wasm_bindgen(js_name = setPreferences)]
+    pub fn set_preferences(
+        &self,
+        peer_details: bool,
+        area_summary: bool,
+        rtt_probes: Option<bool>,
+        spectator_opt_out: Option<bool>,
+        share_public_address: Option<bool>,
+    ) -> Result<(), JsValue> {
+        let preferences = ClientPreferences {
+            peer_details,
+            area_summary,
+            rtt_probes: rtt_probes.unwrap_or(false),
+            spectator_opt_out: spectator_opt_out.unwrap_or(false),
+            share_public_address: share_public_address.unwrap_or(false),
+        };
+        self.shared.borrow_mut().send(&ClientMessage::SetPreferences(preferences))
+    }
+
+    #[wasm_bindgen(js_name = getPopulation)]
+    pub fn get_population(&self) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::GetPopulation)
+    }
+
+    /// Other community servers, for a server browser; a ServerList event follows.
+    #[wasm_bindgen(js_name = listServers)]
+    pub fn list_servers(&self) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::ListServers)
+    }
+
+    /// Connection diagnostics: uptime, players online in this game, own update rate; a ServerStats event follows.
+    #[wasm_bindgen(js_name = getServerStats)]
+    pub fn get_server_stats(&self) -> Result<(), JsValue> {
+        self.shared.borrow_mut().send(&ClientMessage::GetServerStats)
+    }
+
+    /// Leave the server; a Closed event follows.
+    pub fn disconnect(&self) -> Result<(), JsValue> {
+        let mut shared = self.shared.borrow_mut();
+        let _ = shared.send(&ClientMessage::Disconnect);
+        shared.socket.close()
+    }
+}
+
+impl Drop for ProxchatClient {
+    fn drop(&mut self) {
+        // the closures die with us, so the socket must stop calling them
+        let shared = self.shared.borrow();
+        shared.socket.set_onopen(None);
+        shared.socket.set_onmessage(None);
+        shared.socket.set_onclose(None);
+        let _ = shared.socket.close();
+    }
+}
+
+// hand an event to the JS callback. no borrow is held during the call, so the callback may use the client
+fn emit(shared: &Rc<RefCell<Shared>>, event: &Event) {
+    let on_event = shared.borrow().on_event.clone();
+    let value = serde_json::to_string(event)
+        .ok()
+        .and_then(|json| js_sys::JSON::parse(&json).ok())
+        .unwrap_or(JsValue::NULL);
+    if let Err(e) = on_event.call1(&JsValue::NULL, &value) {
+        warn!("Event callback threw: {:?}", e);
+    }
+}
+
+// send the pending position if the socket is open and the rate limit allows; otherwise retry later
+fn flush_position(shared: &Rc<RefCell<Shared>>) {
+    let mut state = shared.borrow_mut();
+    let Some(position) = state.pending_position else {
+        return;
+    };
+    if state.socket.ready_state() != WebSocket::OPEN {
+        return; // on_open flushes
+    }
+    let now = js_sys::Date::now();
+    let wait_ms = state.last_sent_at_ms + DEFAULT_POSITION_INTERVAL.as_millis() as f64 - now;
+    if wait_ms > 0.0 {
+        if !state.flush_scheduled {
+            state.flush_scheduled = true;
+            let retry_shared = Rc::clone(shared);
+            let retry = Closure::once_into_js(move || {
+                retry_shared.borrow_mut().flush_scheduled = false;
+                flush_position(&retry_shared);
+            });
+            set_timeout(retry.unchecked_ref(), wait_ms.ceil() as i32);
+        }
+        return;
+    }
+    state.pending_position = None;
+    if state.last_sent == Some(position) {
+        return;
+    }
+    let message = position.to_message(&state.client_id);
+    match state.send(&message) {
+        Ok(()) => {
+            state.last_sent = Some(position);
+            state.last_sent_at_ms = now;
+        }
+        Err(e) => warn!("Failed to send position: {:?}", e),
+    }
+}
@@ -0,0 +1,50 @@
+// socket activation: a listening socket handed over by the process that started us, in the
+// systemd LISTEN_FDS convention (fd 3 onwards, LISTEN_PID naming the process they are meant for).
+// a systemd .socket unit, or a supervisor like systemfd, keeps the socket open across restarts, so
+// connections made while one server drains and the next starts wait in the backlog instead of
+// being refused
+use log::warn;
+use std::env;
+
+// first inherited fd, per sd_listen_fds(3)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// the inherited websocket listener, if we were started with one. the variables are cleared either
+// way so anything we spawn doesn't take the socket for its own
+pub fn inherited_listener() -> Option<std::net::TcpListener> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let fds: u32 = fds?.parse().ok()?;
+    if pid?.parse::<u32>().ok()? != std::process::id() || fds == 0 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("Started with {} inherited sockets, only the first is used", fds);
+    }
+    listener_from_fd()
+}
+
+#[cfg(unix)]
+fn listener_from_fd() -> Option<std::net::TcpListener> {
+    use std::os::fd::FromRawFd;
+    // SAFETY: LISTEN_PID names this process, so fd 3 was opened for us and nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    // tokio needs it non-blocking; this also weeds out an fd that isn't a socket at all
+    if let Err(e) = listener.set_nonblocking(true).and_then(|_| listener.local_addr()) {
+        warn!("Inherited fd {} isn't a usable listening socket: {}", LISTEN_FDS_START, e);
+        std::mem::forget(listener);
+        return None;
+    }
+    Some(listener)
+}
+
+#[cfg(not(unix))]
+fn listener_from_fd() -> Option<std::net::TcpListener> {
+    warn!("Inherited sockets are only supported on unix, ignoring LISTEN_FDS");
+    None
+}
@@ -0,0 +1,651 @@
+use crate::config::{self, Config, ProximityConfig, UsageFormat};
+use crate::db::Database;
+use crate::occlusion::{CollisionGrid, CollisionUpload};
+use crate::schedule::ScheduledEvent;
+use crate::spectators::Spectator;
+use crate::talkers::TalkerOrder;
+use crate::zones::AnnouncerZone;
+use crate::{mapevents, metrics, overload, scripting, send_nearby_updates, tls, usage, ServerState};
+use axum::body::Body;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use futures::stream::{self, Stream};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use log::{error, info, warn};
+use proxchat_protocol::ServerMessage;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, RwLock};
+use tokio_rustls::TlsAcceptor;
+
+// how long a connecting client gets to finish the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// request bodies handed to on_admin scripts are read whole, so they're capped
+const MAX_SCRIPTED_BODY: usize = 1 << 20;
+
+#[derive(Clone)]
+struct AdminState {
+    server: Arc<RwLock<ServerState>>,
+    config: Arc<Config>,
+    db: Arc<Database>,
+    token: Option<Arc<str>>,
+}
+
+// HTTP admin API for moderators and operators. runs on its own listener (config.admin_addr)
+// and every request must carry `Authorization: Bearer <admin_token>` when a token is configured.
+pub async fn serve(config: Config, server: Arc<RwLock<ServerState>>, db: Arc<Database>) {
+    let Some(addr) = config.admin_addr.clone() else {
+        return;
+    };
+    let mutual = config.admin_tls.as_ref().is_some_and(|admin_tls| admin_tls.client_ca_path.is_some());
+    if exposed_insecurely(&config) {
+        if !config.admin_allow_insecure {
+            error!(
+                "Not starting the admin API on {}: beyond loopback it needs admin_tls, with client_ca_path or admin_token (set admin_allow_insecure to serve it anyway)",
+                addr
+            );
+            return;
+        }
+        warn!("Admin API on {} is exposed without TLS or authentication because admin_allow_insecure is set", addr);
+    } else if config.admin_token.is_none() && !mutual {
+        warn!("Admin API on {} has no admin_token configured - anyone who can reach it has full access", addr);
+    }
+    let tls_config = match &config.admin_tls {
+        Some(admin_tls) => match tls::server_config(&admin_tls.cert_path, &admin_tls.key_path, admin_tls.client_ca_path.as_deref()) {
+            Ok(tls_config) => Some(tls_config),
+            Err(e) => {
+                error!("Not starting the admin API, its TLS setup failed: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let admin_state = AdminState {
+        server,
+        token: config.admin_token.clone().map(Arc::from),
+        config: Arc::new(config),
+        db,
+    };
+
+    let app = Router::new()
+        .route("/clients", get(list_clients))
+        .route("/clients/{client_id}/kick", post(kick_client))
+        .route("/clients/{client_id}/history", get(position_history))
+        .route("/bans", post(add_ban))
+        .route("/stats", get(stats))
+        .route("/config/{key}", put(set_config_key))
+        .route("/population", get(population))
+        .route("/maps/{game_id}/{map_id}/stream", get(map_event_stream))
+        .route("/maps/{game_id}/{map_id}/collision", get(collision_grid).put(set_collision_grid).delete(remove_collision_grid))
+        .route("/pairs", get(list_pairs))
+        .route("/consistency", get(consistency))
+        .route("/top-talkers", get(top_talkers))
+        .route("/usage", get(usage_report))
+        .route("/zones", get(list_zones).post(add_zone))
+        .route("/zones/{id}", delete(remove_zone))
+        .route("/spectators", get(list_spectators))
+        .route("/spectators/{client_id}", put(set_spectator).delete(remove_spectator))
+        .route("/proximity", get(proximity).patch(update_proximity))
+        .route("/events", get(list_events).post(add_event))
+        .route("/events/{id}", delete(remove_event))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/metrics/alert-rules", get(alert_rules))
+        .layer(middleware::from_fn_with_state(admin_state.clone(), script_hook))
+        .layer(middleware::from_fn_with_state(admin_state.clone(), require_token))
+        .with_state(admin_state);
+
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind admin API on {}: {}", addr, e);
+            return;
+        }
+    };
+    match tls_config {
+        Some(tls_config) => {
+            info!("Admin API listening on: {} (TLS{})", addr, if mutual { ", client certificates required" } else { "" });
+            serve_tls(listener, app, TlsAcceptor::from(tls_config)).await;
+        }
+        None => {
+            info!("Admin API listening on: {}", addr);
+            if let Err(e) = axum::serve(listener, app).await {
+                error!("Admin API stopped: {}", e);
+            }
+        }
+    }
+}
+
+// axum::serve only speaks plain TCP, so TLS connections are accepted here and handed to hyper directly
+async fn serve_tls(listener: TcpListener, app: Router, acceptor: TlsAcceptor) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Admin API failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    info!("Admin API TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    info!("Admin API TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+            if let Err(e) = auto::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service).await {
+                info!("Admin API connection from {} ended with an error: {}", peer, e);
+            }
+        });
+    }
+}
+
+// stores the ban, then kicks whoever is connected under the banned client id or ip.
+// returns the ban's id and how many connections were closed
+pub async fn ban_and_kick(
+    server: &Arc<RwLock<ServerState>>,
+    db: &Arc<Database>,
+    client_id: Option<&str>,
+    ip: Option<&str>,
+    reason: &str,
+    duration_secs: Option<i64>,
+) -> Result<(i64, usize), String> {
+    if client_id.is_none() && ip.is_none() {
+        return Err("a ban needs a client_id, an ip, or both".to_string());
+    }
+    let parsed_ip = ip.map(|ip| ip.parse::<IpAddr>().map_err(|e| format!("invalid ip {}: {}", ip, e))).transpose()?;
+    if duration_secs.is_some_and(|secs| secs <= 0) {
+        return Err("duration_secs must be positive (or unset for a permanent ban)".to_string());
+    }
+    let (ban_client_id, ban_ip, ban_reason) = (client_id.map(str::to_string), ip.map(str::to_string), reason.to_string());
+    let ban_id = db
+        .run(move |db| db.add_ban(ban_client_id.as_deref(), ban_ip.as_deref(), &ban_reason, duration_secs))
+        .await
+        .map_err(|e| e.to_string())?;
+    let state = metrics::timed_read(server, "admin").await;
+    let mut kicked = HashSet::new();
+    if let Some(ip) = parsed_ip {
+        kicked.extend(state.kick_address(ip));
+    }
+    if let Some(client_id) = client_id {
+        kicked.extend(state.kick(client_id));
+    }
+    drop(state);
+    let kicked = kicked.len();
+    info!("Admin banned client {:?} ip {:?} for {:?}s: {} ({} connections kicked)", client_id, ip, duration_secs, reason, kicked);
+    Ok((ban_id, kicked))
+}
+
+// checks and stores a config override for the next start
+pub async fn set_config_override(config: &Config, db: &Arc<Database>, key: &str, value: &Value) -> Result<(), String> {
+    config::check_override(config, key, value)?;
+    let (override_key, override_value) = (key.to_string(), value.clone());
+    db.run(move |db| db.set_config_override(&override_key, &override_value)).await.map_err(|e| e.to_string())?;
+    info!("Admin stored config override {} = {} (applies at the next start)", key, value);
+    Ok(())
+}
+
+// beyond this host, operators need a client certificate, or the token over TLS
+pub fn exposed_insecurely(config: &Config) -> bool {
+    let Some(addr) = &config.admin_addr else {
+        return false;
+    };
+    let loopback = addr.parse::<SocketAddr>().is_ok_and(|addr| addr.ip().is_loopback());
+    let mutual = config.admin_tls.as_ref().is_some_and(|admin_tls| admin_tls.client_ca_path.is_some());
+    !loopback && (config.admin_tls.is_none() || !mutual && config.admin_token.is_none())
+}
+
+// compares in constant time, so response timing doesn't reveal how much of a guess was right
+pub fn token_matches(provided: Option<&str>, token: &str) -> bool {
+    provided.is_some_and(|provided| bool::from(provided.as_bytes().ct_eq(token.as_bytes())))
+}
+
+async fn require_token(State(admin): State<AdminState>, request: Request, next: Next) -> Response {
+    if let Some(token) = admin.token.as_deref() {
+        let provided = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !token_matches(provided, token) {
+            return (StatusCode::UNAUTHORIZED, "Missing or invalid admin token").into_response();
+        }
+    }
+    next.run(request).await
+}
+
+// hands every change made through the API to the scripts' on_admin once it has succeeded
+async fn script_hook(State(admin): State<AdminState>, request: Request, next: Next) -> Response {
+    if !scripting::enabled() || request.method() == Method::GET {
+        return next.run(request).await;
+    }
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_SCRIPTED_BODY).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response();
+    };
+    let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+    let response = next.run(Request::from_parts(parts, Body::from(bytes))).await;
+    if response.status().is_success() {
+        let command = scripting::AdminCommand { method, path, status: response.status().as_u16(), body };
+        let actions = scripting::run(scripting::Hook::Admin, &command);
+        scripting::apply(actions, &admin.server, &admin.db).await;
+    }
+    response
+}
+
+#[derive(Deserialize)]
+struct ClientsQuery {
+    game_id: Option<i32>,
+}
+
+// connected clients by client id, optionally filtered with ?game_id=
+async fn list_clients(State(admin): State<AdminState>, Query(query): Query<ClientsQuery>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    Json(state.client_summaries(query.game_id)).into_response()
+}
+
+// closes the client's connection; it can reconnect unless it is banned as well
+async fn kick_client(State(admin): State<AdminState>, Path(client_id): Path<String>) -> Response {
+    match metrics::timed_read(&admin.server, "admin").await.kick(&client_id) {
+        Some(connection_id) => {
+            info!("Admin kicked {}", client_id);
+            Json(json!({ "kicked": connection_id })).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, format!("{} isn't connected", client_id)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    client_id: Option<String>,
+    ip: Option<String>,
+    #[serde(default)]
+    reason: String,
+    // omitted for a permanent ban
+    duration_secs: Option<i64>,
+}
+
+async fn add_ban(State(admin): State<AdminState>, Json(ban): Json<BanRequest>) -> Response {
+    match ban_and_kick(&admin.server, &admin.db, ban.client_id.as_deref(), ban.ip.as_deref(), &ban.reason, ban.duration_secs).await {
+        Ok((id, kicked)) => (StatusCode::CREATED, Json(json!({ "id": id, "kicked": kicked }))).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+async fn stats(State(admin): State<AdminState>) -> Response {
+    Json(metrics::timed_read(&admin.server, "admin").await.stats()).into_response()
+}
+
+// stores the body (any JSON value) as an override of one top-level config key, applied at the next start
+async fn set_config_key(State(admin): State<AdminState>, Path(key): Path<String>, Json(value): Json<Value>) -> Response {
+    match set_config_override(&admin.config, &admin.db, &key, &value).await {
+        Ok(()) => Json(json!({ "key": key, "value": value, "applies": "at the next start" })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+// recent positions of a client, oldest first
+async fn position_history(State(admin): State<AdminState>, Path(client_id): Path<String>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    match state.position_history.get(&client_id) {
+        Some(history) => Json(json!({
+            "client_id": client_id,
+            "online": state.positions.contains_key(&client_id),
+            "history": history,
+        }))
+        .into_response(),
+        None => (StatusCode::NOT_FOUND, format!("No position history for {}", client_id)).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct PopulationQuery {
+    game_id: Option<i32>,
+}
+
+// player counts per game/map/channel, optionally filtered with ?game_id=
+async fn population(State(admin): State<AdminState>, Query(query): Query<PopulationQuery>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    Json(state.population(query.game_id)).into_response()
+}
+
+// joins, leaves, introductions and separations on one map as server-sent events, each named after
+// the event with the JSON record as data. a subscriber that falls behind gets a "lagged" event
+// saying how many it missed
+async fn map_event_stream(Path((game_id, map_id)): Path<(i32, i32)>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = stream::unfold(mapevents::subscribe(), move |mut receiver| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(record) if record.game_id == game_id && record.map_id == map_id => {
+                    Event::default().event(record.event.name()).json_data(&record).ok()?
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(missed)) => Event::default().event("lagged").data(missed.to_string()),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), receiver));
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    format: Option<UsageFormat>,
+}
+
+// per-game usage for the current accounting period (since startup or the last usage_export),
+// as JSON or with ?format=csv
+async fn usage_report(Query(query): Query<UsageQuery>) -> Response {
+    let report = usage::snapshot();
+    match query.format.unwrap_or(UsageFormat::Json) {
+        UsageFormat::Json => Json(report).into_response(),
+        UsageFormat::Csv => (
+            [(header::CONTENT_TYPE, "text/csv")],
+            format!("{}{}", usage::CSV_HEADER, usage::to_csv(&report)),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct PairsQuery {
+    client_id: Option<String>,
+}
+
+// every pair and how far its connection has got; ?client_id= for one client's
+async fn list_pairs(State(admin): State<AdminState>, Query(query): Query<PairsQuery>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    Json(state.pairs.summaries(query.client_id.as_deref())).into_response()
+}
+
+// last orphan sweep result; non-zero counts mean a cleanup path is leaking
+async fn consistency(State(admin): State<AdminState>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    Json(json!({
+        "last_sweep": state.last_consistency_report,
+        "current": state.orphaned_client_ids().0,
+        "repaired_total": state.orphans_repaired_total,
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct TopTalkersQuery {
+    limit: Option<usize>,
+    by: Option<TalkerOrder>,
+}
+
+// connections sending the most over the last maintenance interval, by messages or with ?by=bytes
+async fn top_talkers(State(admin): State<AdminState>, Query(query): Query<TopTalkersQuery>) -> Response {
+    let inbound_traffic = Arc::clone(&metrics::timed_read(&admin.server, "admin").await.inbound_traffic);
+    Json(inbound_traffic.top(query.limit.unwrap_or(10), query.by.unwrap_or_default())).into_response()
+}
+
+// announcer zones that haven't ended yet, with whether each is live right now
+async fn list_zones(State(admin): State<AdminState>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    let zones: Vec<_> = state
+        .announcer_zones
+        .iter()
+        .map(|zone| json!({ "zone": zone, "live": state.live_zone_speakers.contains_key(&zone.id) }))
+        .collect();
+    Json(zones).into_response()
+}
+
+// schedule an announcer zone; the maintenance loop starts and ends it within a few seconds of its times
+async fn add_zone(State(admin): State<AdminState>, Json(mut zone): Json<AnnouncerZone>) -> Response {
+    if let Err(e) = zone.validate() {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let stored = zone.clone();
+    zone.id = match admin.db.run(move |db| db.add_announcer_zone(&stored)).await {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to store announcer zone: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to store zone").into_response();
+        }
+    };
+    info!("Admin scheduled announcer zone {} ({:?}) for speaker {}", zone.id, zone.name, zone.speaker_id);
+    metrics::timed_write(&admin.server, "admin").await.announcer_zones.push(zone.clone());
+    (StatusCode::CREATED, Json(zone)).into_response()
+}
+
+// cancel a zone; a live one stops pairing on the next maintenance tick
+async fn remove_zone(State(admin): State<AdminState>, Path(id): Path<i64>) -> Response {
+    match admin.db.run(move |db| db.remove_announcer_zone(id)).await {
+        Ok(removed) => {
+            let mut state = metrics::timed_write(&admin.server, "admin").await;
+            let was_loaded = state.announcer_zones.iter().any(|zone| zone.id == id);
+            state.announcer_zones.retain(|zone| zone.id != id);
+            if removed || was_loaded {
+                info!("Admin removed announcer zone {}", id);
+                StatusCode::NO_CONTENT.into_response()
+            } else {
+                (StatusCode::NOT_FOUND, format!("No announcer zone {}", id)).into_response()
+            }
+        }
+        Err(e) => {
+            error!("Failed to remove announcer zone {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to remove zone").into_response()
+        }
+    }
+}
+
+async fn list_spectators(State(admin): State<AdminState>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    let mut spectators: Vec<&Spectator> = state.spectators.values().collect();
+    spectators.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+    Json(spectators).into_response()
+}
+
+// make a client a spectator, or move an existing one's focus; it needn't be connected yet
+async fn set_spectator(State(admin): State<AdminState>, Path(client_id): Path<String>, Json(mut spectator): Json<Spectator>) -> Response {
+    spectator.client_id = client_id.clone();
+    let mut state = metrics::timed_write(&admin.server, "admin").await;
+    if let Err(e) = spectator.validate(state.spectators_config.max_radius) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    info!("Admin set spectator {}: {:?}, radius {:?}", client_id, spectator.focus, spectator.radius);
+    let newly = state.spectators.insert(client_id.clone(), spectator.clone()).is_none();
+    let Some(tx) = state.routed_sender(&client_id) else {
+        return Json(spectator).into_response();
+    };
+    let notifications = state.reevaluate_pairs(&client_id, &tx);
+    drop(state);
+    if newly {
+        let _ = tx.send(ServerMessage::ListenOnly { enabled: true }).await;
+    }
+    send_nearby_updates(&admin.server, notifications).await;
+    Json(spectator).into_response()
+}
+
+// the client pairs by proximity again
+async fn remove_spectator(State(admin): State<AdminState>, Path(client_id): Path<String>) -> Response {
+    let mut state = metrics::timed_write(&admin.server, "admin").await;
+    if state.spectators.remove(&client_id).is_none() {
+        return (StatusCode::NOT_FOUND, format!("No spectator {}", client_id)).into_response();
+    }
+    info!("Admin removed spectator {}", client_id);
+    let Some(tx) = state.routed_sender(&client_id) else {
+        return StatusCode::NO_CONTENT.into_response();
+    };
+    let notifications = state.reevaluate_pairs(&client_id, &tx);
+    drop(state);
+    let _ = tx.send(ServerMessage::ListenOnly { enabled: false }).await;
+    send_nearby_updates(&admin.server, notifications).await;
+    StatusCode::NO_CONTENT.into_response()
+}
+
+// the map's collision grid, without its rows
+async fn collision_grid(State(admin): State<AdminState>, Path((game_id, map_id)): Path<(i32, i32)>) -> Response {
+    match metrics::timed_read(&admin.server, "admin").await.collision_grids.get(&(game_id, map_id)) {
+        Some(grid) => Json(grid.summary()).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("No collision grid for game {} map {}", game_id, map_id)).into_response(),
+    }
+}
+
+// upload (or replace) the map's collision grid; pairs on the map are re-checked right away
+async fn set_collision_grid(
+    State(admin): State<AdminState>,
+    Path((game_id, map_id)): Path<(i32, i32)>,
+    Json(upload): Json<CollisionUpload>,
+) -> Response {
+    let grid = match CollisionGrid::new(game_id, map_id, upload) {
+        Ok(grid) => grid,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let upload = grid.upload.clone();
+    if let Err(e) = admin.db.run(move |db| db.set_collision_grid(game_id, map_id, &upload)).await {
+        error!("Failed to store the collision grid of game {} map {}: {}", game_id, map_id, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to store collision grid").into_response();
+    }
+    let summary = grid.summary();
+    info!("Admin set the collision grid of game {} map {}: {}x{}, {:?}", game_id, map_id, summary.width, summary.height, summary.mode);
+    let mut state = metrics::timed_write(&admin.server, "admin").await;
+    state.collision_grids.insert((game_id, map_id), grid);
+    let notifications = state.reevaluate_all_pairs();
+    drop(state);
+    send_nearby_updates(&admin.server, notifications).await;
+    Json(summary).into_response()
+}
+
+async fn remove_collision_grid(State(admin): State<AdminState>, Path((game_id, map_id)): Path<(i32, i32)>) -> Response {
+    match admin.db.run(move |db| db.remove_collision_grid(game_id, map_id)).await {
+        Ok(removed) => {
+            let mut state = metrics::timed_write(&admin.server, "admin").await;
+            let was_loaded = state.collision_grids.remove(&(game_id, map_id)).is_some();
+            if !(removed || was_loaded) {
+                return (StatusCode::NOT_FOUND, format!("No collision grid for game {} map {}", game_id, map_id)).into_response();
+            }
+            info!("Admin removed the collision grid of game {} map {}", game_id, map_id);
+            let notifications = state.reevaluate_all_pairs();
+            drop(state);
+            send_nearby_updates(&admin.server, notifications).await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Failed to remove the collision grid of game {} map {}: {}", game_id, map_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to remove collision grid").into_response()
+        }
+    }
+}
+
+// current pairing parameters (runtime changes included)
+async fn proximity(State(admin): State<AdminState>) -> Response {
+    Json(metrics::timed_read(&admin.server, "admin").await.proximity).into_response()
+}
+
+// change any of the pairing parameters; omitted fields keep their value, `"max_peers": null` removes the cap.
+// every client's pairs are re-checked right away. changes last until the next restart
+async fn update_proximity(State(admin): State<AdminState>, Json(changes): Json<Value>) -> Response {
+    let Value::Object(changes) = changes else {
+        return (StatusCode::BAD_REQUEST, "expected a JSON object").into_response();
+    };
+    let mut state = metrics::timed_write(&admin.server, "admin").await;
+    let mut merged = serde_json::to_value(state.proximity).unwrap_or_default();
+    if let Value::Object(fields) = &mut merged {
+        for (key, value) in changes {
+            if !fields.contains_key(&key) {
+                return (StatusCode::BAD_REQUEST, format!("unknown field {}", key)).into_response();
+            }
+            fields.insert(key, value);
+        }
+    }
+    let proximity: ProximityConfig = match serde_json::from_value(merged) {
+        Ok(proximity) => proximity,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    if let Err(e) = proximity.validate() {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    info!("Admin changed proximity settings: {:?}", proximity);
+    state.proximity = proximity;
+    let notifications = state.reevaluate_all_pairs();
+    drop(state);
+    send_nearby_updates(&admin.server, notifications).await;
+    Json(proximity).into_response()
+}
+
+// scheduled events that haven't ended yet, with whether each is running right now
+async fn list_events(State(admin): State<AdminState>) -> Response {
+    let state = metrics::timed_read(&admin.server, "admin").await;
+    let events: Vec<_> = state
+        .scheduled_events
+        .iter()
+        .map(|event| json!({ "event": event, "live": state.live_events.contains_key(&event.id) }))
+        .collect();
+    Json(events).into_response()
+}
+
+// schedule an event; the maintenance loop applies and lifts its overrides within a few seconds of its times
+async fn add_event(State(admin): State<AdminState>, Json(mut event): Json<ScheduledEvent>) -> Response {
+    let defaults = metrics::timed_read(&admin.server, "admin").await.proximity.ranges();
+    if let Err(e) = event.validate(defaults) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+    let stored = event.clone();
+    event.id = match admin.db.run(move |db| db.add_scheduled_event(&stored)).await {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to store scheduled event: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to store event").into_response();
+        }
+    };
+    info!("Admin scheduled event {} ({:?}) on game {} map {}", event.id, event.name, event.game_id, event.map_id);
+    metrics::timed_write(&admin.server, "admin").await.scheduled_events.push(event.clone());
+    (StatusCode::CREATED, Json(event)).into_response()
+}
+
+// cancel an event; a running one is lifted on the next maintenance tick
+async fn remove_event(State(admin): State<AdminState>, Path(id): Path<i64>) -> Response {
+    match admin.db.run(move |db| db.remove_scheduled_event(id)).await {
+        Ok(removed) => {
+            let mut state = metrics::timed_write(&admin.server, "admin").await;
+            let was_loaded = state.scheduled_events.iter().any(|event| event.id == id);
+            state.scheduled_events.retain(|event| event.id != id);
+            if removed || was_loaded {
+                info!("Admin removed scheduled event {}", id);
+                StatusCode::NO_CONTENT.into_response()
+            } else {
+                (StatusCode::NOT_FOUND, format!("No scheduled event {}", id)).into_response()
+            }
+        }
+        Err(e) => {
+            error!("Failed to remove scheduled event {}: {}", id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to remove event").into_response()
+        }
+    }
+}
+
+async fn prometheus_metrics() -> Response {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render()).into_response()
+}
+
+// prometheus alerting rules generated from the configured overload thresholds
+async fn alert_rules(State(admin): State<AdminState>) -> Response {
+    ([(header::CONTENT_TYPE, "application/yaml")], overload::alert_rules(&admin.config.overload)).into_response()
+}
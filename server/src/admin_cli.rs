@@ -0,0 +1,181 @@
+// `prox-chat-server admin list|kick|ban|stats`: the admin API from the server's own binary, for
+// operators on the box over SSH. reads admin_addr and admin_token from the same config file (and
+// overrides) the server uses, and talks plain HTTP, so it needs an admin listener without admin_tls
+use crate::config;
+use crate::db::Database;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const USAGE: &str = "usage: prox-chat-server admin list [--game <game_id>]
+       prox-chat-server admin kick <client_id>
+       prox-chat-server admin ban [--client <client_id>] [--ip <ip>] [--reason <text>] [--duration <secs>]
+       prox-chat-server admin stats";
+
+pub async fn run(args: &[String]) {
+    let Some((command, args)) = args.split_first() else {
+        usage();
+    };
+    let (method, path, body) = match (command.as_str(), args) {
+        ("list", []) => ("GET", "/clients".to_string(), None),
+        ("list", [flag, game_id]) if flag == "--game" => match game_id.parse::<i32>() {
+            Ok(game_id) => ("GET", format!("/clients?game_id={}", game_id), None),
+            Err(_) => usage(),
+        },
+        ("kick", [client_id]) => ("POST", format!("/clients/{}/kick", client_id), None),
+        ("ban", args) => ("POST", "/bans".to_string(), Some(ban_request(args))),
+        ("stats", []) => ("GET", "/stats".to_string(), None),
+        _ => usage(),
+    };
+    let target = match AdminTarget::from_config() {
+        Ok(target) => target,
+        Err(e) => fail(&e),
+    };
+    let (status, body) = match target.request(method, &path, body.as_ref()).await {
+        Ok(response) => response,
+        Err(e) => fail(&format!("Admin API at {} unreachable: {}", target.addr, e)),
+    };
+    if !(200..300).contains(&status) {
+        fail(&format!("Admin API refused ({}): {}", status, body.trim()));
+    }
+    let reply: Value = serde_json::from_str(&body).unwrap_or(Value::String(body));
+    match command.as_str() {
+        "list" => print_clients(&reply),
+        "kick" => println!("Kicked {} (connection {})", args[0], reply["kicked"].as_str().unwrap_or("?")),
+        "ban" => println!("Added ban {}, {} connections kicked", reply["id"], reply["kicked"]),
+        _ => print_fields(&reply),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn ban_request(args: &[String]) -> Value {
+    let mut ban = json!({ "reason": "" });
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else {
+            usage();
+        };
+        match flag.as_str() {
+            "--client" => ban["client_id"] = json!(value),
+            "--ip" => ban["ip"] = json!(value),
+            "--reason" => ban["reason"] = json!(value),
+            "--duration" => match value.parse::<i64>() {
+                Ok(secs) => ban["duration_secs"] = json!(secs),
+                Err(_) => usage(),
+            },
+            _ => usage(),
+        }
+    }
+    if ban.get("client_id").is_none() && ban.get("ip").is_none() {
+        usage();
+    }
+    ban
+}
+
+struct AdminTarget {
+    addr: SocketAddr,
+    token: Option<String>,
+}
+
+impl AdminTarget {
+    fn from_config() -> Result<Self, String> {
+        let config_path = config::config_path();
+        let mut raw_config = config::load_raw(&config_path)?;
+        let file_config = config::from_raw(raw_config.clone())?;
+        // admin_addr and admin_token can be overridden in the database like anything else
+        if let Some(database_path) = file_config.database_path.as_deref() {
+            let db = Database::open(Some(database_path)).map_err(|e| format!("Failed to open database: {}", e))?;
+            let overrides = db.config_overrides().map_err(|e| format!("Failed to read config overrides: {}", e))?;
+            config::apply_overrides(&mut raw_config, &overrides);
+        }
+        let config = config::from_raw(raw_config)?;
+        let Some(admin_addr) = config.admin_addr else {
+            return Err(format!("No admin_addr in {}: the admin API isn't enabled", config_path));
+        };
+        if config.admin_tls.is_some() {
+            return Err(format!("The admin API at {} uses TLS, which this command doesn't speak; use curl with its certificates", admin_addr));
+        }
+        let mut addr: SocketAddr = admin_addr.parse().map_err(|e| format!("Invalid admin_addr {}: {}", admin_addr, e))?;
+        // a wildcard listener is reached on loopback
+        if addr.ip().is_unspecified() {
+            addr.set_ip(if addr.is_ipv4() { [127, 0, 0, 1].into() } else { std::net::Ipv6Addr::LOCALHOST.into() });
+        }
+        Ok(AdminTarget { addr, token: config.admin_token })
+    }
+
+    // one HTTP/1.1 request on its own connection; returns the status and body
+    async fn request(&self, method: &str, path: &str, body: Option<&Value>) -> Result<(u16, String), String> {
+        let mut stream = TcpStream::connect(self.addr).await.map_err(|e| e.to_string())?;
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, path, self.addr);
+        if let Some(token) = &self.token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        if !body.is_empty() {
+            request.push_str("Content-Type: application/json\r\n");
+        }
+        request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.map_err(|e| e.to_string())?;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let body_start = match parsed.parse(&response) {
+            Ok(httparse::Status::Complete(body_start)) => body_start,
+            Ok(httparse::Status::Partial) => return Err("truncated response".to_string()),
+            Err(e) => return Err(format!("malformed response: {}", e)),
+        };
+        let status = parsed.code.unwrap_or(0);
+        Ok((status, String::from_utf8_lossy(&response[body_start..]).into_owned()))
+    }
+}
+
+fn print_clients(reply: &Value) {
+    let clients = reply.as_array().cloned().unwrap_or_default();
+    println!("{:<38} {:<22} {:>5} {:>6} {:>4} {:>13} {:>5} {:>6}", "CLIENT", "ADDRESS", "GAME", "MAP", "CH", "POSITION", "PEERS", "IDLE");
+    for client in &clients {
+        println!(
+            "{:<38} {:<22} {:>5} {:>6} {:>4} {:>13} {:>5} {:>5}s",
+            text(&client["client_id"]),
+            text(&client["addr"]),
+            text(&client["game_id"]),
+            text(&client["map_id"]),
+            text(&client["channel"]),
+            format!("{},{}", client["x"], client["y"]),
+            text(&client["peers"]),
+            text(&client["last_update_secs"]),
+        );
+    }
+    println!("{} clients", clients.len());
+}
+
+fn print_fields(reply: &Value) {
+    match reply.as_object() {
+        Some(fields) => {
+            for (key, value) in fields {
+                println!("{}: {}", key, value);
+            }
+        }
+        None => println!("{}", reply),
+    }
+}
+
+// strings without their quotes, and a dash for null
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => "-".to_string(),
+        other => other.to_string(),
+    }
+}
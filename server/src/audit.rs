@@ -0,0 +1,120 @@
+use crate::config::AuditLogConfig;
+use crate::db::{unix_now, Database};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::io::Write;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc;
+
+// events waiting to be written; when the writer falls this far behind, new events are dropped
+// (with a warning) rather than holding up the connection handlers
+const QUEUE_LEN: usize = 4096;
+
+// connection lifecycle events, kept so reports can be investigated after the fact.
+// a no-op unless audit_log is configured
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Registered { client_id: String, connection_id: String, ip: String, game_id: i32, map_id: i32 },
+    // a pair formed or dissolved; logged once per pair, from the side that triggered it
+    Introduced { client_id: String, peer_id: String },
+    Separated { client_id: String, peer_id: String },
+    // reason is "closed" (the connection ended), "panicked" (its handler crashed; state was still
+    // cleaned up) or "timed_out" (no position updates; its connection closes later). the connection id ties it back to the registration and its ip
+    Disconnected { client_id: String, connection_id: String, reason: &'static str },
+    BanRejected { client_id: String, ip: String, reason: String },
+    // an offer or answer that couldn't be passed on
+    RelayFailed { sender_id: String, target_id: String, kind: &'static str, error: String },
+}
+
+impl AuditEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            AuditEvent::Registered { .. } => "registered",
+            AuditEvent::Introduced { .. } => "introduced",
+            AuditEvent::Separated { .. } => "separated",
+            AuditEvent::Disconnected { .. } => "disconnected",
+            AuditEvent::BanRejected { .. } => "ban_rejected",
+            AuditEvent::RelayFailed { .. } => "relay_failed",
+        }
+    }
+
+    // the client the event is about, for the indexed database column
+    fn client_id(&self) -> &str {
+        match self {
+            AuditEvent::Registered { client_id, .. }
+            | AuditEvent::Introduced { client_id, .. }
+            | AuditEvent::Separated { client_id, .. }
+            | AuditEvent::Disconnected { client_id, .. }
+            | AuditEvent::BanRejected { client_id, .. } => client_id,
+            AuditEvent::RelayFailed { sender_id, .. } => sender_id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    // unix seconds
+    at: i64,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+static QUEUE: OnceLock<mpsc::Sender<AuditRecord>> = OnceLock::new();
+
+pub fn record(event: AuditEvent) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    if queue.try_send(AuditRecord { at: unix_now(), event }).is_err() {
+        warn!("Audit log queue full, dropping event");
+    }
+}
+
+// start the writer task; events recorded before this are not kept
+pub fn init(config: AuditLogConfig, db: Arc<Database>) {
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    if QUEUE.set(tx).is_err() {
+        return;
+    }
+    tokio::spawn(write_loop(config, db, rx));
+}
+
+async fn write_loop(config: AuditLogConfig, db: Arc<Database>, mut rx: mpsc::Receiver<AuditRecord>) {
+    match &config {
+        AuditLogConfig::Jsonl { path } => info!("Writing audit log to {}", path),
+        AuditLogConfig::Database => info!("Writing audit log to the audit_log database table"),
+    }
+    let mut batch = Vec::new();
+    while rx.recv_many(&mut batch, QUEUE_LEN).await > 0 {
+        let result = match &config {
+            AuditLogConfig::Jsonl { path } => append_jsonl(path, &batch).map_err(|e| e.to_string()),
+            AuditLogConfig::Database => insert_rows(&db, &batch).map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            error!("Failed to write {} audit events: {}", batch.len(), e);
+        }
+        batch.clear();
+    }
+}
+
+fn append_jsonl(path: &str, records: &[AuditRecord]) -> std::io::Result<()> {
+    let mut lines = String::new();
+    for record in records {
+        lines.push_str(&serde_json::to_string(record).map_err(std::io::Error::other)?);
+        lines.push('\n');
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(lines.as_bytes())
+}
+
+fn insert_rows(db: &Database, records: &[AuditRecord]) -> rusqlite::Result<()> {
+    let rows: Vec<(i64, &str, &str, String)> = records
+        .iter()
+        .map(|record| {
+            let details = serde_json::to_string(&record.event).unwrap_or_default();
+            (record.at, record.event.name(), record.event.client_id(), details)
+        })
+        .collect();
+    db.add_audit_events(&rows)
+}
@@ -0,0 +1,68 @@
+// per-address registration throttling (config registration_backoff), for clients stuck in a
+// connect/register/disconnect loop. an address gets free_registrations in a row, each within
+// window_secs of the one before; the next is refused and the address held off, for twice as long
+// every time it keeps at it. an address that stays quiet for max_delay_secs starts over
+use crate::config::RegistrationBackoffConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tokio::time::{Duration, Instant};
+
+pub struct RegistrationBackoff {
+    window: Duration,
+    free_registrations: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    addresses: HashMap<IpAddr, Streak>,
+}
+
+struct Streak {
+    registrations: u32,
+    // the last attempt, refused or not
+    last_attempt: Instant,
+    // hold-offs so far; the next one lasts base_delay * 2^holds
+    holds: u32,
+    held_until: Option<Instant>,
+}
+
+impl RegistrationBackoff {
+    pub fn new(config: &RegistrationBackoffConfig) -> Self {
+        RegistrationBackoff {
+            window: Duration::from_secs(config.window_secs),
+            free_registrations: config.free_registrations,
+            base_delay: Duration::from_secs(config.base_delay_secs),
+            max_delay: Duration::from_secs(config.max_delay_secs),
+            addresses: HashMap::new(),
+        }
+    }
+
+    // None if the address may register now, otherwise how long it is held off for
+    pub fn register(&mut self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let streak = self.addresses.entry(ip).or_insert(Streak { registrations: 0, last_attempt: now, holds: 0, held_until: None });
+        let quiet = now.saturating_duration_since(streak.last_attempt);
+        streak.last_attempt = now;
+        if let Some(until) = streak.held_until.filter(|until| *until > now) {
+            return Some(until - now);
+        }
+        if quiet > self.max_delay {
+            streak.holds = 0;
+        }
+        if quiet > self.window {
+            streak.registrations = 0;
+        }
+        streak.registrations += 1;
+        if streak.registrations <= self.free_registrations {
+            return None;
+        }
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(streak.holds)).min(self.max_delay);
+        streak.holds += 1;
+        streak.registrations = 0;
+        streak.held_until = Some(now + delay);
+        Some(delay)
+    }
+
+    // forgets addresses that would start over anyway; called from the maintenance loop
+    pub fn prune(&mut self, now: Instant) {
+        let max_age = self.window.max(self.max_delay);
+        self.addresses.retain(|_, streak| now.saturating_duration_since(streak.last_attempt) <= max_age);
+    }
+}
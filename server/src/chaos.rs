@@ -0,0 +1,47 @@
+use crate::config::ChaosConfig;
+use log::info;
+use proxchat_protocol::ServerMessage;
+use std::sync::OnceLock;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+// injected faults for testing client resilience (the chaos feature): relays held back, NearbyPeers
+// updates lost, connections dropped. never set in builds without the feature
+static CHAOS: OnceLock<ChaosConfig> = OnceLock::new();
+
+#[cfg_attr(not(feature = "chaos"), allow(dead_code))]
+pub fn init(config: ChaosConfig) {
+    info!("Chaos mode: injecting faults {:?}", config);
+    let _ = CHAOS.set(config);
+}
+
+// uniform in [0, 1), from the 48 random bits in front of a v4 uuid's version nibble
+fn random() -> f64 {
+    (Uuid::new_v4().as_u128() >> 80) as f64 / (1u64 << 48) as f64
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && random() < probability
+}
+
+// how long to hold back this message before sending it, if at all
+pub fn relay_delay(message: &ServerMessage) -> Option<Duration> {
+    let chaos = CHAOS.get()?;
+    let relayed = matches!(
+        message,
+        ServerMessage::ReceiveOffer { .. } | ServerMessage::ReceiveAnswer { .. } | ServerMessage::ReceiveIceCandidate { .. }
+    );
+    if !relayed || !roll(chaos.relay_delay_probability) {
+        return None;
+    }
+    Some(Duration::from_millis((random() * chaos.relay_delay_max_ms as f64) as u64))
+}
+
+pub fn drops(message: &ServerMessage) -> bool {
+    CHAOS.get().is_some_and(|chaos| matches!(message, ServerMessage::NearbyPeers(_)) && roll(chaos.drop_nearby_peers_probability))
+}
+
+// rolled for every message a client sends
+pub fn disconnects() -> bool {
+    CHAOS.get().is_some_and(|chaos| roll(chaos.disconnect_probability))
+}
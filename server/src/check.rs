@@ -0,0 +1,316 @@
+// `prox-chat-server check`: loads everything the server would load at startup, without serving,
+// so a bad deploy fails in CI instead of on the box. checks the config file with the database's
+// overrides on top, TLS material, TURN settings, the zones, events and grids stored in the
+// database, the files other sections point at, and that every configured port can be bound.
+// prints one line per check and exits with 1 when any failed
+use crate::admin;
+use crate::config::{self, Config};
+use crate::db::Database;
+use crate::tls;
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::path::Path;
+
+const USAGE: &str = "usage: prox-chat-server check
+       reads the config file the server would (PROXCHAT_CONFIG, else config.json)";
+
+// shorter TURN secrets are guessable offline from any one credential handed out
+const MIN_TURN_SECRET_LEN: usize = 16;
+
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&self, what: impl std::fmt::Display) {
+        println!("ok       {}", what);
+    }
+
+    fn warn(&mut self, what: impl std::fmt::Display) {
+        self.warnings += 1;
+        println!("warning  {}", what);
+    }
+
+    fn error(&mut self, what: impl std::fmt::Display) {
+        self.errors += 1;
+        println!("error    {}", what);
+    }
+}
+
+pub fn run(args: &[String]) {
+    if !args.is_empty() {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let mut report = Report::default();
+    if let Some(config) = check_config(&mut report) {
+        check_tls(&mut report, &config);
+        check_turn(&mut report, &config);
+        check_files(&mut report, &config);
+        check_ports(&mut report, &config);
+    }
+    println!("{} errors, {} warnings", report.errors, report.warnings);
+    if report.errors > 0 {
+        std::process::exit(1);
+    }
+}
+
+// the config as the server would run with it; None when it doesn't load, since nothing else can be checked then
+fn check_config(report: &mut Report) -> Option<Config> {
+    let path = config::config_path();
+    if !Path::new(&path).exists() {
+        report.warn(format!("config file {} not found; the server would start with defaults", path));
+    }
+    let mut raw = match config::load_raw(&path) {
+        Ok(raw) => raw,
+        Err(e) => {
+            report.error(e);
+            return None;
+        }
+    };
+    let config = match config::from_raw(raw.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            report.error(format!("{}: {}", path, e));
+            return None;
+        }
+    };
+    report.ok(format!("config {}", path));
+
+    let Some(database_path) = config.database_path.as_deref() else {
+        return Some(config);
+    };
+    if !Path::new(database_path).exists() {
+        report.ok(format!("database {} will be created on start", database_path));
+        return Some(config);
+    }
+    // read-only and unmigrated, so checking against a live database changes nothing
+    let db = match Database::open_read_only(database_path) {
+        Ok(db) => db,
+        Err(e) => {
+            report.error(format!("database {}: {}", database_path, e));
+            return Some(config);
+        }
+    };
+    match db.pending_migrations() {
+        Ok(0) => report.ok(format!("database {}", database_path)),
+        Ok(pending) => {
+            // older tables can't be read as this build expects; the server migrates them on start
+            report.ok(format!("database {} ({} migrations to apply on start)", database_path, pending));
+            return Some(config);
+        }
+        Err(e) => {
+            report.error(format!("database {}: {}", database_path, e));
+            return Some(config);
+        }
+    }
+    let config = match db.config_overrides() {
+        Ok(overrides) if overrides.is_empty() => config,
+        Ok(overrides) => {
+            config::apply_overrides(&mut raw, &overrides);
+            match config::from_raw(raw) {
+                Ok(config) => {
+                    report.ok(format!("{} config overrides from the database", overrides.len()));
+                    config
+                }
+                Err(e) => {
+                    report.error(format!("config with the database's overrides: {}", e));
+                    return Some(config);
+                }
+            }
+        }
+        Err(e) => {
+            report.error(format!("config overrides in {}: {}", database_path, e));
+            config
+        }
+    };
+    check_stored_areas(report, &config, &db);
+    Some(config)
+}
+
+// announcer zones, scheduled events and collision grids, which the admin API stores in the database
+fn check_stored_areas(report: &mut Report, config: &Config, db: &Database) {
+    match db.announcer_zones() {
+        Ok(zones) => {
+            let invalid: Vec<_> = zones.iter().filter_map(|zone| zone.validate().err().map(|e| (zone, e))).collect();
+            for (zone, e) in &invalid {
+                report.error(format!("announcer zone {} ({}): {}", zone.id, zone.name, e));
+            }
+            if invalid.is_empty() {
+                report.ok(format!("{} announcer zones", zones.len()));
+            }
+        }
+        Err(e) => report.error(format!("announcer zones: {}", e)),
+    }
+    match db.scheduled_events() {
+        Ok(events) => {
+            let defaults = config.proximity.ranges();
+            let invalid: Vec<_> = events.iter().filter_map(|event| event.validate(defaults).err().map(|e| (event, e))).collect();
+            for (event, e) in &invalid {
+                report.error(format!("scheduled event {} ({}): {}", event.id, event.name, e));
+            }
+            if invalid.is_empty() {
+                report.ok(format!("{} scheduled events", events.len()));
+            }
+        }
+        Err(e) => report.error(format!("scheduled events: {}", e)),
+    }
+    match db.collision_grids() {
+        Ok(grids) => report.ok(format!("{} collision grids", grids.len())),
+        Err(e) => report.error(format!("collision grids: {}", e)),
+    }
+}
+
+fn check_tls(report: &mut Report, config: &Config) {
+    if admin::exposed_insecurely(config) {
+        if config.admin_allow_insecure {
+            report.warn("admin API is exposed beyond loopback without TLS or authentication (admin_allow_insecure)");
+        } else {
+            report.error("admin API beyond loopback needs admin_tls, with client_ca_path or admin_token; it won't start");
+        }
+    }
+    if let Some(admin_tls) = &config.admin_tls {
+        match tls::server_config(&admin_tls.cert_path, &admin_tls.key_path, admin_tls.client_ca_path.as_deref()) {
+            Ok(_) => report.ok(format!("admin_tls certificate {}", admin_tls.cert_path)),
+            Err(e) => report.error(format!("admin_tls: {}", e)),
+        }
+    }
+    if let Some(webtransport) = &config.webtransport {
+        match (&webtransport.cert_path, &webtransport.key_path) {
+            (Some(cert_path), Some(key_path)) => match tls::server_config(cert_path, key_path, None) {
+                Ok(_) => report.ok(format!("webtransport certificate {}", cert_path)),
+                Err(e) => report.error(format!("webtransport: {}", e)),
+            },
+            (None, None) => {}
+            _ => report.error("webtransport needs both cert_path and key_path, or neither"),
+        }
+    }
+}
+
+fn check_turn(report: &mut Report, config: &Config) {
+    let Some(turn) = &config.turn else {
+        return;
+    };
+    let errors = report.errors;
+    if turn.shared_secret.len() < MIN_TURN_SECRET_LEN {
+        report.warn(format!("turn.shared_secret is {} characters; use at least {} random ones", turn.shared_secret.len(), MIN_TURN_SECRET_LEN));
+    }
+    for url in &turn.urls {
+        if !url.starts_with("turn:") && !url.starts_with("turns:") {
+            report.error(format!("turn.urls entry {} isn't a turn: or turns: url", url));
+        }
+    }
+    if let Some(turn_server) = &config.turn_server {
+        // checked when the config loads
+        if let Ok(relay_ip) = turn_server.relay_ip.parse::<IpAddr>() {
+            if !is_public(relay_ip) {
+                report.warn(format!("turn_server.relay_ip {} isn't a public address; clients outside that network can't reach the relay", relay_ip));
+            }
+        }
+        if !cfg!(feature = "turn-server") {
+            report.warn("turn_server is configured but this build lacks the turn-server feature");
+        }
+    }
+    if report.errors == errors {
+        report.ok(format!("turn ({} urls)", turn.urls.len()));
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_documentation()),
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local()),
+    }
+}
+
+// files and directories other sections read, or write into
+fn check_files(report: &mut Report, config: &Config) {
+    let mut inputs = Vec::new();
+    if let Some(wasm_plugin) = &config.wasm_plugin {
+        inputs.push(("wasm_plugin.path", wasm_plugin.path.as_str()));
+    }
+    if let Some(scripting) = &config.scripting {
+        inputs.push(("scripting.scripts_dir", scripting.scripts_dir.as_str()));
+    }
+    if let Some(geoip) = &config.geoip {
+        inputs.push(("geoip.database_path", geoip.database_path.as_str()));
+    }
+    if let Some(position_trace) = &config.position_trace {
+        inputs.push(("position_trace.path", position_trace.path.as_str()));
+    }
+    for (key, path) in inputs {
+        if Path::new(path).exists() {
+            report.ok(format!("{} {}", key, path));
+        } else {
+            report.error(format!("{} {} doesn't exist", key, path));
+        }
+    }
+
+    let mut outputs = Vec::new();
+    if let Some(database_path) = &config.database_path {
+        outputs.push(("database_path", database_path.as_str()));
+    }
+    if let Some(log_file) = &config.log_file {
+        outputs.push(("log_file.path", log_file.path.as_str()));
+    }
+    if let Some(usage_export) = &config.usage_export {
+        outputs.push(("usage_export.path", usage_export.path.as_str()));
+    }
+    if let Some(replay_recording) = &config.replay_recording {
+        outputs.push(("replay_recording.path", replay_recording.path.as_str()));
+    }
+    if let Some(state_snapshot) = &config.state_snapshot {
+        outputs.push(("state_snapshot.path", state_snapshot.path.as_str()));
+    }
+    for (key, path) in outputs {
+        let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        if !dir.is_dir() {
+            report.error(format!("{} {}: directory {} doesn't exist", key, path, dir.display()));
+        }
+    }
+}
+
+// binds every listener the config asks for, all at once so two sections sharing a port show up too
+fn check_ports(report: &mut Report, config: &Config) {
+    let mut tcp = vec![("listen_addr", Some(config.listen_addr.as_str())), ("admin_addr", config.admin_addr.as_deref())];
+    if cfg!(feature = "grpc") {
+        tcp.push(("grpc_addr", config.grpc_addr.as_deref()));
+    }
+    tcp.push(("federation.listen_addr", config.federation.as_ref().and_then(|federation| federation.listen_addr.as_deref())));
+    let mut udp = vec![("udp_addr", config.udp_addr.as_deref()), ("stun_addr", config.stun_addr.as_deref())];
+    if cfg!(feature = "turn-server") {
+        udp.push(("turn_server.listen_addr", config.turn_server.as_ref().map(|turn_server| turn_server.listen_addr.as_str())));
+    }
+    if cfg!(feature = "webtransport") {
+        udp.push(("webtransport.listen_addr", config.webtransport.as_ref().map(|webtransport| webtransport.listen_addr.as_str())));
+    }
+
+    let mut bound_tcp = Vec::new();
+    for (key, addr) in tcp.into_iter().filter_map(|(key, addr)| Some((key, addr?))) {
+        match bind(addr, |addr| TcpListener::bind(addr)) {
+            Ok(listener) => {
+                report.ok(format!("{} {} (tcp)", key, addr));
+                bound_tcp.push(listener);
+            }
+            Err(e) => report.error(format!("{} {} (tcp): {}", key, addr, e)),
+        }
+    }
+    let mut bound_udp = Vec::new();
+    for (key, addr) in udp.into_iter().filter_map(|(key, addr)| Some((key, addr?))) {
+        match bind(addr, |addr| UdpSocket::bind(addr)) {
+            Ok(socket) => {
+                report.ok(format!("{} {} (udp)", key, addr));
+                bound_udp.push(socket);
+            }
+            Err(e) => report.error(format!("{} {} (udp): {}", key, addr, e)),
+        }
+    }
+}
+
+fn bind<T>(addr: &str, bind: impl Fn(&str) -> std::io::Result<T>) -> Result<T, String> {
+    bind(addr).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AddrInUse => "in use (by a running server, or another section of this config?)".to_string(),
+        _ => e.to_string(),
+    })
+}
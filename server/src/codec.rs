@@ -0,0 +1,67 @@
+use crate::{proto, ClientEnvelope, ServerMessage};
+use proxchat_protocol::{compat, PROTOCOL_VERSION};
+use serde::Serialize;
+use tokio_tungstenite::tungstenite::Message;
+
+// wire encoding of protocol messages, fixed per connection by the negotiated subprotocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    // JSON in text frames (the original format)
+    Json,
+    // CBOR in binary frames: same message shapes, much cheaper for high-frequency position updates
+    Cbor,
+    // protobuf envelopes from proto/proxchat.proto in binary frames
+    Protobuf,
+}
+
+// JSON and CBOR messages carry the protocol version next to "type" (protobuf has its own envelope)
+#[derive(Serialize)]
+struct Versioned<'a> {
+    v: u32,
+    #[serde(flatten)]
+    message: &'a ServerMessage,
+}
+
+pub fn encode(encoding: Encoding, message: &ServerMessage) -> Result<Message, String> {
+    let versioned = Versioned { v: PROTOCOL_VERSION, message };
+    match encoding {
+        Encoding::Json => serde_json::to_string(&versioned)
+            .map(|text| Message::Text(text.into()))
+            .map_err(|e| e.to_string()),
+        Encoding::Cbor => {
+            let mut buffer = Vec::new();
+            ciborium::into_writer(&versioned, &mut buffer).map_err(|e| e.to_string())?;
+            Ok(Message::Binary(buffer.into()))
+        }
+        Encoding::Protobuf => proto::encode_server(message).map(|bytes| Message::Binary(bytes.into())),
+    }
+}
+
+// decode a data frame; None for frames that don't carry a message in this encoding.
+// binary encodings still accept JSON text frames, which keeps hand-debugging with text tools possible.
+// JSON and CBOR frames of older envelope versions are upgraded, then parsed strictly
+pub fn decode(encoding: Encoding, frame: &Message) -> Option<Result<ClientEnvelope, String>> {
+    match (encoding, frame) {
+        (_, Message::Text(text)) => Some(
+            serde_json::from_str(text)
+                .map_err(|e| e.to_string())
+                .and_then(compat::parse_client_envelope),
+        ),
+        (Encoding::Cbor, Message::Binary(bytes)) => Some(
+            ciborium::from_reader(bytes.as_ref())
+                .map_err(|e| e.to_string())
+                .and_then(compat::parse_client_envelope),
+        ),
+        (Encoding::Protobuf, Message::Binary(bytes)) => Some(proto::decode_client(bytes)),
+        _ => None,
+    }
+}
+
+// short printable form of a frame for error logs
+pub fn describe(frame: &Message) -> String {
+    match frame {
+        Message::Text(text) => format!("'{}'", text),
+        Message::Binary(bytes) => format!("<{} binary bytes>", bytes.len()),
+        other => format!("{:?}", other),
+    }
+}
@@ -0,0 +1,1391 @@
+use crate::names::{NameFilter, NameFilterConfig};
+use crate::overload::ShedLevel;
+use crate::schedule::{Area, PairingRanges};
+use proxchat_protocol::ClientPosition;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::Path;
+
+// server configuration, loaded from a JSON file at startup.
+// every field has a default so a missing or partial file still gives a working server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // plain ws:// only; wss:// is terminated in front of the server (fly's force_https, a load
+    // balancer, or a proxy such as Caddy that obtains and renews Let's Encrypt certificates itself)
+    pub listen_addr: String,
+    // sqlite file for bans, reports and config overrides; None keeps everything in memory
+    pub database_path: Option<String>,
+    // admin HTTP API; disabled unless an address is given
+    pub admin_addr: Option<String>,
+    // bearer token required on every admin request
+    pub admin_token: Option<String>,
+    // serve the admin API over TLS, optionally requiring client certificates; plain HTTP when unset
+    pub admin_tls: Option<AdminTlsConfig>,
+    // serve the admin API beyond loopback without TLS, or without either client certificates or
+    // admin_token; refused unless this is set
+    pub admin_allow_insecure: bool,
+    // the admin operations over gRPC, with the same admin_token; needs a build with the grpc feature
+    pub grpc_addr: Option<String>,
+    // number of distinct recent positions kept per client (0 disables history)
+    pub position_history_len: usize,
+    // how long a client's history is kept after its last recorded move
+    pub position_history_retention_secs: i64,
+    // how much distance information NearbyPeerDetails reveals
+    pub position_privacy: PositionPrivacy,
+    pub overload: OverloadConfig,
+    // accept clients that don't offer the proxchat.v1 subprotocol (everything released before negotiation)
+    pub allow_legacy_clients: bool,
+    // browser origins allowed to open websockets; empty allows any. clients without an Origin header are always allowed
+    pub allowed_origins: Vec<String>,
+    // identity from a reverse proxy with its own login in front of the server: upgrades have to
+    // come from the proxy and carry the user it authenticated, whose id the client then has to
+    // register as. off when unset
+    pub trusted_proxy: Option<TrustedProxyConfig>,
+    // UDP address for the position fast-path; disabled unless an address is given
+    pub udp_addr: Option<String>,
+    // UDP address of the built-in STUN server (Binding requests only, see src/stun.rs), for
+    // clients to list instead of a public one; off unless an address is given
+    pub stun_addr: Option<String>,
+    // experimental WebTransport (HTTP/3) signaling listener; needs a build with the webtransport feature
+    pub webtransport: Option<WebTransportConfig>,
+    // pairing parameters; the admin API can change them at runtime (until the next restart)
+    pub proximity: ProximityConfig,
+    // ranges for particular games or maps in place of proximity's (a map's entry beats its game's)
+    pub map_ranges: Vec<MapRanges>,
+    // most clients one audio partition of a map holds; arrivals past it go to overflow partitions
+    // of the same map (see src/partitions.rs). a map's entry beats its game's
+    pub map_population_caps: Vec<MapPopulationCap>,
+    // games whose clients send UpdatePositionFloat (continuous x/y) instead of UpdatePosition. their
+    // positions are kept in thousandths of a unit: ranges are still given in units, but shared area,
+    // zone and event bounds on these games are in thousandths
+    pub float_coordinate_games: Vec<i32>,
+    // social hubs where players on different channels of the same map still pair
+    pub shared_areas: Vec<SharedArea>,
+    // parts of maps with their own ranges and falloff, e.g. buildings and open fields
+    pub audio_regions: Vec<AudioRegion>,
+    // ranges stretched towards where players face and shrunk behind them, for clients sending a
+    // heading with their positions; off when unset
+    pub directional_audio: Option<DirectionalAudioConfig>,
+    // radius of the spectators the admin API designates (see src/spectators.rs) when none is given,
+    // and the largest one allowed
+    pub spectators: SpectatorsConfig,
+    // STUN/TURN urls the echo-test peer gathers candidates with; needs a build with the echo-peer feature
+    pub echo_ice_servers: Vec<String>,
+    // words and patterns rejected in the tags clients set for themselves
+    pub name_filter: NameFilterConfig,
+    // periodic per-game usage export; disabled unless configured (the admin API's /usage works either way)
+    pub usage_export: Option<UsageExportConfig>,
+    // refuse everything but registration and RequestMessageSigning from clients that haven't switched to signed messages
+    pub require_message_signing: bool,
+    // append-only log of registrations, pairings, disconnects, ban rejections and relay failures; off when unset
+    pub audit_log: Option<AuditLogConfig>,
+    // TURN servers suggested to pairs whose direct connection keeps failing; off when unset
+    pub turn: Option<TurnConfig>,
+    // a TURN relay inside this server (see src/relay.rs), accepting the credentials turn hands out;
+    // list it in turn.urls. needs a build with the turn-server feature; off when unset
+    pub turn_server: Option<TurnServerConfig>,
+    // tell clients that set share_public_address the IP:port the server sees them and their
+    // nearby peers at (PeerAddresses), a candidate hint for when their STUN is blocked
+    pub reflexive_address_hints: bool,
+    // per-connection limits on error replies and malformed frames
+    pub error_flood: ErrorFloodConfig,
+    // keep accepting frames without "v" from clients that predate the versioned envelope. turn off
+    // once proxchat_unversioned_messages_total stays at zero
+    pub accept_unversioned_messages: bool,
+    // anonymized recording of every signaling session, for `prox-chat-server replay`; off when unset
+    pub replay_recording: Option<ReplayRecordingConfig>,
+    // deliberate faults for testing clients against a misbehaving server; needs a build with the chaos feature
+    pub chaos: Option<ChaosConfig>,
+    // dev mode: plays a recorded trace of positions into the live state as fake clients, so a
+    // crowd can be tried out locally (see trace.rs); off when unset, and not for production
+    pub position_trace: Option<PositionTraceConfig>,
+    // refuse new connections from an address for this long after one of its connections crashed
+    // its handler; off when unset
+    pub panic_reconnect_cooldown_secs: Option<u64>,
+    // crash and error reports to Sentry (or a compatible service); needs a build with the sentry feature
+    pub sentry: Option<SentryConfig>,
+    // size of each connection's outbound message queue, and what a full one does
+    pub outbound_queue: OutboundQueueConfig,
+    // connections that haven't sent UpdatePosition this long after connecting are closed
+    pub registration_timeout_secs: u64,
+    // per-country connection counts and allow/deny lists; needs a build with the geoip feature
+    pub geoip: Option<GeoIpConfig>,
+    // holds off addresses that keep registering and dropping within seconds (reconnect storms);
+    // off when unset. clients behind one NAT share an address, so leave free_registrations room for them
+    pub registration_backoff: Option<RegistrationBackoffConfig>,
+    // several clients registered from one address (dual-boxing): counted in metrics, and capped or
+    // merged into one mesh member as the policy says; off when unset
+    pub duplicate_accounts: Option<DuplicateAccountsConfig>,
+    // spreads out the NearbyPeers fan-out when most clients reconnect at once
+    pub reconnect_storm: ReconnectStormConfig,
+    // positions and pairs saved on shutdown and restored on start; off when unset
+    pub state_snapshot: Option<StateSnapshotConfig>,
+    // on shutdown, stop accepting, send open connections Closing (server_restart) so they reconnect,
+    // and give them this long to close before exiting. with an inherited listener (socket
+    // activation) the next server picks up where this one stops
+    pub shutdown_drain_secs: u64,
+    // log to a rotated file as well as the console; off when unset
+    pub log_file: Option<LogFileConfig>,
+    // announce this server to a community server registry and answer ListServers from it; off when unset
+    pub registry: Option<RegistryConfig>,
+    // exchange positions on shared maps with other servers of the same game and relay signaling
+    // to their clients; off when unset
+    pub federation: Option<FederationConfig>,
+    // reintroduce pairs that report failure (or never report connecting) with backoff, then give up
+    // and flag them for TURN; off when unset. only for clients that send ReportPeerConnected
+    pub pair_retry: Option<PairRetryConfig>,
+    // pairs whose network quality (from ReportNetworkQuality, or the server's own Ping round trips)
+    // is too poor for voice are flagged to clients, or not introduced; off when unset
+    pub network_quality: Option<NetworkQualityConfig>,
+    // hub-and-leaf hints for clusters of paired clients too big for a full mesh (see
+    // src/topology.rs); off when unset
+    pub topology_hints: Option<TopologyHintsConfig>,
+    // event areas whose clients all connect to the server's own SFU instead of to each other (see
+    // src/sfu.rs); needs a build with the sfu feature
+    pub sfu: Option<SfuConfig>,
+    // a WASM module that decides which clients hear each other, and how loud (see src/plugin.rs);
+    // needs a build with the wasm-plugins feature
+    pub wasm_plugin: Option<WasmPluginConfig>,
+    // Rhai scripts with moderation and event hooks (see src/scripting.rs); needs a build with the
+    // scripting feature
+    pub scripting: Option<ScriptingConfig>,
+}
+
+// PEM files for the admin listener
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminTlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // CA that issues operator certificates; when set, connections without one are refused (mutual TLS)
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrustedProxyConfig {
+    // IP addresses the proxy connects from; upgrades from anywhere else are refused. every client
+    // shows up at one of these, so bans by IP are ignored and the per-address limits
+    // (registration_backoff, duplicate_accounts) can't be turned on alongside
+    pub proxy_addrs: Vec<String>,
+    // the authenticated user id, set by the proxy on every upgrade it lets through
+    pub user_id_header: String,
+    // the user's region, if the proxy knows it; shown in the admin client list
+    pub region_header: String,
+}
+
+impl Default for TrustedProxyConfig {
+    fn default() -> Self {
+        TrustedProxyConfig {
+            proxy_addrs: Vec::new(),
+            user_id_header: "X-Proxchat-User-Id".to_string(),
+            region_header: "X-Proxchat-Region".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebTransportConfig {
+    // UDP address for the QUIC endpoint
+    pub listen_addr: String,
+    // PEM certificate chain and private key; without them a short-lived self-signed
+    // certificate is generated and its hash logged for serverCertificateHashes
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+}
+
+impl Default for WebTransportConfig {
+    fn default() -> Self {
+        WebTransportConfig {
+            listen_addr: "0.0.0.0:4433".to_string(),
+            cert_path: None,
+            key_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageExportConfig {
+    // file each closed period is appended to
+    pub path: String,
+    // length of an accounting period
+    pub interval_secs: u64,
+    pub format: UsageFormat,
+}
+
+impl Default for UsageExportConfig {
+    fn default() -> Self {
+        UsageExportConfig {
+            path: "usage.csv".to_string(),
+            interval_secs: 3600,
+            format: UsageFormat::Csv,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageFormat {
+    Csv,
+    // one JSON report per line
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorFloodConfig {
+    // Error replies a connection gets per window; the rest are dropped. RequestError replies
+    // aren't limited, since every request_id is owed an answer
+    pub max_errors: u32,
+    // malformed frames per window before the connection is closed; 0 never closes
+    pub max_parse_failures: u32,
+    // binary frames per window on a connection that negotiated JSON before it is closed; 0 never closes
+    pub max_binary_frames: u32,
+    pub window_secs: u64,
+}
+
+impl Default for ErrorFloodConfig {
+    fn default() -> Self {
+        ErrorFloodConfig {
+            max_errors: 5,
+            max_parse_failures: 20,
+            max_binary_frames: 10,
+            window_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundQueueConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    // relayed offers and answers still queued this long after the server took them are dropped,
+    // and their sender told: a stale SDP only starts a doomed ICE attempt. None delivers them however late
+    pub signaling_ttl_ms: Option<u64>,
+}
+
+impl Default for OutboundQueueConfig {
+    fn default() -> Self {
+        OutboundQueueConfig {
+            capacity: 100,
+            overflow: OverflowPolicy::Block,
+            signaling_ttl_ms: Some(10_000),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    // senders wait for room; a stalled client slows down whoever is relaying to it
+    Block,
+    // make room by dropping the oldest informational message (peer details, area summaries,
+    // population replies, pings, errors); signaling is never dropped, and waits like block
+    DropOldestNoncritical,
+    // close the connection; the client reconnects and starts from a fresh peer list
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TurnConfig {
+    // e.g. "turn:turn.example.com:3478?transport=udp", "turns:turn.example.com:5349"
+    pub urls: Vec<String>,
+    // static-auth-secret of a TURN server running coturn's use-auth-secret (the TURN REST API scheme)
+    pub shared_secret: String,
+    // lifetime of the credentials handed out
+    pub credential_ttl_secs: u64,
+    // ICE failures one side reports for a pair before both are told to relay
+    pub relay_after_failures: u32,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        TurnConfig {
+            urls: Vec::new(),
+            shared_secret: String::new(),
+            credential_ttl_secs: 86400,
+            relay_after_failures: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TurnServerConfig {
+    // UDP address clients reach the relay on
+    pub listen_addr: String,
+    // the public IP relayed traffic leaves from, as clients' peers see it
+    pub relay_ip: String,
+    // ports relayed allocations take; the firewall has to let UDP through to all of them
+    pub min_relay_port: u16,
+    pub max_relay_port: u16,
+    pub realm: String,
+    // bytes each client may have relayed per hour and per day (unset: no limit). a client past
+    // either has its allocations closed and new ones refused until the window turns over
+    pub hourly_quota_bytes: Option<u64>,
+    pub daily_quota_bytes: Option<u64>,
+}
+
+impl Default for TurnServerConfig {
+    fn default() -> Self {
+        TurnServerConfig {
+            listen_addr: "0.0.0.0:3478".to_string(),
+            relay_ip: String::new(),
+            min_relay_port: 49152,
+            max_relay_port: 65535,
+            realm: "proxchat".to_string(),
+            hourly_quota_bytes: None,
+            daily_quota_bytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "sink", rename_all = "lowercase")]
+pub enum AuditLogConfig {
+    // one JSON object per line, appended to path
+    Jsonl { path: String },
+    // the audit_log table in the server database (in memory unless database_path is set)
+    Database,
+}
+
+// JSON lines appended to path; see replay.rs for what is kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayRecordingConfig {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PositionTraceConfig {
+    // JSON lines or CSV (by extension) of timestamped positions; see trace.rs for the columns
+    pub path: String,
+    // 2.0 plays the trace twice as fast
+    pub speed: f64,
+    // start over once the trace ends, rather than leaving its clients to time out
+    pub repeat: bool,
+}
+
+impl Default for PositionTraceConfig {
+    fn default() -> Self {
+        PositionTraceConfig {
+            path: "position_trace.jsonl".to_string(),
+            speed: 1.0,
+            repeat: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentryConfig {
+    pub dsn: String,
+    // e.g. "production"; shown on every event
+    #[serde(default)]
+    pub environment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    // a MaxMind GeoIP2/GeoLite2 Country or City .mmdb file
+    pub database_path: String,
+    // ISO 3166-1 alpha-2 codes. when allow_countries is set only those countries get in;
+    // deny_countries is checked first either way
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+    // addresses the database can't place (private ranges, new allocations)
+    pub allow_unknown: bool,
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        GeoIpConfig {
+            database_path: String::new(),
+            allow_countries: Vec::new(),
+            deny_countries: Vec::new(),
+            allow_unknown: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    // only counted
+    #[default]
+    Allow,
+    // registrations past max_per_ip are refused
+    Cap,
+    // only the address's first client pairs, hearing whatever any of them would (see src/duplicates.rs)
+    Merge,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuplicateAccountsConfig {
+    pub policy: DuplicatePolicy,
+    // for "cap"; players behind a shared NAT count too, so leave them room
+    pub max_per_ip: usize,
+}
+
+impl Default for DuplicateAccountsConfig {
+    fn default() -> Self {
+        DuplicateAccountsConfig { policy: DuplicatePolicy::Allow, max_per_ip: 4 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RegistrationBackoffConfig {
+    // registrations from one address, each within window_secs of the last, before it is held off
+    pub free_registrations: u32,
+    pub window_secs: u64,
+    // the first hold-off; each one after it doubles, up to max_delay_secs
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+}
+
+impl Default for RegistrationBackoffConfig {
+    fn default() -> Self {
+        RegistrationBackoffConfig {
+            free_registrations: 5,
+            window_secs: 10,
+            base_delay_secs: 2,
+            max_delay_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectStormConfig {
+    // a storm is at least this many registrations within window_secs, making up at least
+    // registered_fraction of the registered clients; 0 turns storm absorption off
+    pub min_registrations: usize,
+    pub registered_fraction: f64,
+    pub window_secs: u64,
+    // the NearbyPeers updates arrivals cause during a storm go out at random within this long
+    pub spread_ms: u64,
+}
+
+impl Default for ReconnectStormConfig {
+    fn default() -> Self {
+        ReconnectStormConfig {
+            min_registrations: 50,
+            registered_fraction: 0.25,
+            window_secs: 5,
+            spread_ms: 3000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StateSnapshotConfig {
+    pub path: String,
+    // an older snapshot is ignored on start: its clients have long given up reconnecting
+    pub max_age_secs: u64,
+}
+
+impl Default for StateSnapshotConfig {
+    fn default() -> Self {
+        StateSnapshotConfig {
+            path: "state_snapshot.json".to_string(),
+            max_age_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogFileConfig {
+    pub path: String,
+    // RUST_LOG syntax, e.g. "info" or "warn,prox_chat_server=debug"; the console keeps following RUST_LOG
+    pub level: String,
+    // rotate once the file is this big or this old; either can be null
+    pub max_size_mb: Option<u64>,
+    pub max_age_hours: Option<u64>,
+    // rotated files kept next to the current one
+    pub keep_files: usize,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        LogFileConfig {
+            path: "proxchat.log".to_string(),
+            level: "info".to_string(),
+            max_size_mb: Some(10),
+            max_age_hours: None,
+            keep_files: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RegistryConfig {
+    // announcements are POSTed here and the server list is a GET of it
+    pub url: String,
+    pub name: String,
+    pub region: String,
+    // the websocket url clients should connect to, e.g. "wss://voice.example.org"
+    pub public_url: String,
+    // games this server is for; any game with a player online is announced as well
+    pub game_ids: Vec<i32>,
+    pub interval_secs: u64,
+    // how long a fetched server list answers ListServers before it is fetched again
+    pub list_cache_secs: u64,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            url: String::new(),
+            name: String::new(),
+            region: String::new(),
+            public_url: String::new(),
+            game_ids: Vec::new(),
+            interval_secs: 60,
+            list_cache_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PairRetryConfig {
+    // an attempt that hasn't reported connected by then counts as failed; null waits for reports only
+    pub connect_timeout_secs: Option<u64>,
+    // wait before the first retry, doubling per retry up to max_backoff_secs
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    // retries before the pair is left alone (and relayed through TURN, if there is any)
+    pub max_retries: u32,
+}
+
+impl Default for PairRetryConfig {
+    fn default() -> Self {
+        PairRetryConfig { connect_timeout_secs: Some(30), initial_backoff_secs: 2, max_backoff_secs: 60, max_retries: 5 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LowQualityAction {
+    // introduced anyway, with NearbyPeer.low_quality set for the client's UI
+    #[default]
+    Hint,
+    // not introduced; pairs already connected stay until they'd separate anyway
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkQualityConfig {
+    // limits for the pair as a whole: the two sides' round trips added up (the path through the
+    // server, which a direct connection rarely beats by much) and their packet loss compounded
+    pub max_rtt_ms: u32,
+    pub max_packet_loss_percent: f32,
+    pub action: LowQualityAction,
+}
+
+impl Default for NetworkQualityConfig {
+    fn default() -> Self {
+        NetworkQualityConfig { max_rtt_ms: 800, max_packet_loss_percent: 15.0, action: LowQualityAction::Hint }
+    }
+}
+
+impl NetworkQualityConfig {
+    // each side as (round trip in ms, packet loss in percent)
+    pub fn is_low_quality(&self, (rtt_a, loss_a): (f64, f64), (rtt_b, loss_b): (f64, f64)) -> bool {
+        let loss = 100.0 * (1.0 - (1.0 - loss_a / 100.0) * (1.0 - loss_b / 100.0));
+        rtt_a + rtt_b > f64::from(self.max_rtt_ms) || loss > f64::from(self.max_packet_loss_percent)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TopologyHintsConfig {
+    // clusters smaller than this stay a full mesh
+    pub min_cluster_size: usize,
+    // leaves one hub forwards for; as many hubs are elected as that takes
+    pub max_leaves_per_hub: usize,
+}
+
+impl Default for TopologyHintsConfig {
+    fn default() -> Self {
+        TopologyHintsConfig { min_cluster_size: 12, max_leaves_per_hub: 6 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WasmPluginConfig {
+    // a .wasm (or .wat) module exporting `audible`
+    pub path: String,
+    // the games it decides for; every game when empty
+    pub game_ids: Vec<i32>,
+    // instructions one call may run before it's cut off and the built-in rules answer instead
+    pub fuel_per_call: u64,
+}
+
+impl Default for WasmPluginConfig {
+    fn default() -> Self {
+        WasmPluginConfig { path: String::new(), game_ids: Vec::new(), fuel_per_call: 100_000 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptingConfig {
+    // every *.rhai file in here is loaded, and reloaded when it changes
+    pub scripts_dir: String,
+    pub reload_interval_secs: u64,
+    // a hook running longer than this is stopped
+    pub max_operations: u64,
+}
+
+impl Default for ScriptingConfig {
+    fn default() -> Self {
+        ScriptingConfig { scripts_dir: "scripts".to_string(), reload_interval_secs: 5, max_operations: 100_000 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FederationConfig {
+    // this server's name on its links; unique among the federated servers
+    pub server_id: String,
+    // accepts links from other servers here, e.g. "0.0.0.0:3031"; only outbound links when unset
+    pub listen_addr: Option<String>,
+    // websocket urls of servers to link to, e.g. "ws://voice.example.org:3031"
+    pub peers: Vec<String>,
+    // the same on every federated server; links that can't prove they know it are refused
+    pub shared_secret: String,
+    // the game the servers share, and the maps of it whose positions are exchanged (all when empty)
+    pub game_id: i32,
+    pub map_ids: Vec<i32>,
+    pub digest_interval_ms: u64,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        FederationConfig {
+            server_id: String::new(),
+            listen_addr: None,
+            peers: Vec::new(),
+            shared_secret: String::new(),
+            game_id: 0,
+            map_ids: Vec::new(),
+            digest_interval_ms: 1000,
+        }
+    }
+}
+
+// probabilities are 0 to 1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosConfig {
+    // share of relayed offers, answers and ICE candidates held back, by up to relay_delay_max_ms
+    // (later messages to the same client wait behind them)
+    pub relay_delay_probability: f64,
+    pub relay_delay_max_ms: u64,
+    // share of NearbyPeers updates silently dropped
+    pub drop_nearby_peers_probability: f64,
+    // chance, for every message a client sends, that the server drops the connection
+    pub disconnect_probability: f64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            relay_delay_probability: 0.0,
+            relay_delay_max_ms: 2000,
+            drop_nearby_peers_probability: 0.0,
+            disconnect_probability: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProximityConfig {
+    // new peers are introduced within this many tiles...
+    pub introduction_range: f32,
+    // ...and existing pairs kept until they are further apart than this
+    pub disconnection_range: f32,
+    // when set, the buffer between the two as a percentage of introduction_range instead (for
+    // coordinate scales where a fixed number of tiles means nothing); replaces disconnection_range
+    pub disconnection_buffer_percent: Option<f32>,
+    // most peers a client is introduced to; None is unlimited
+    pub max_peers: Option<usize>,
+    // how often every client is resent its current pairs; 0 turns the resend off.
+    // checked on the 5 second maintenance tick, so it is effectively rounded up to a multiple of 5
+    pub reintroduction_interval_secs: u64,
+    // those resends only go to clients with a pair they haven't been sent for this long, so a pair
+    // that won't connect is repeated every cooldown rather than every interval
+    pub reintroduction_cooldown_secs: u64,
+    // clients whose measured round trip is above this get those resends half as often, after
+    // everyone else; None treats every client alike. only clients with rtt_probes are measured
+    pub slow_client_rtt_ms: Option<u64>,
+}
+
+impl ProximityConfig {
+    pub fn ranges(&self) -> PairingRanges {
+        PairingRanges {
+            introduction: self.introduction_range,
+            disconnection: self
+                .disconnection_buffer_percent
+                .map_or(self.disconnection_range, |percent| buffered(self.introduction_range, percent)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        check_buffer_percent(self.disconnection_buffer_percent)?;
+        let ranges = self.ranges();
+        if !(ranges.introduction > 0.0 && ranges.disconnection >= ranges.introduction) {
+            return Err("ranges must be positive with disconnection_range >= introduction_range".to_string());
+        }
+        if self.max_peers == Some(0) {
+            return Err("max_peers must be at least 1 (or null for no cap)".to_string());
+        }
+        if self.slow_client_rtt_ms == Some(0) {
+            return Err("slow_client_rtt_ms must be at least 1 (or null to turn it off)".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for ProximityConfig {
+    fn default() -> Self {
+        ProximityConfig {
+            introduction_range: 20.0,
+            disconnection_range: 25.0,
+            disconnection_buffer_percent: None,
+            max_peers: None,
+            reintroduction_interval_secs: 5,
+            reintroduction_cooldown_secs: 30,
+            slow_client_rtt_ms: Some(500),
+        }
+    }
+}
+
+fn buffered(introduction: f32, percent: f32) -> f32 {
+    introduction * (1.0 + percent / 100.0)
+}
+
+fn check_buffer_percent(percent: Option<f32>) -> Result<(), String> {
+    match percent {
+        Some(percent) if !(percent.is_finite() && percent >= 0.0) => Err("disconnection_buffer_percent must be 0 or more".to_string()),
+        _ => Ok(()),
+    }
+}
+
+// pairing ranges for one game, or one map of it. without a disconnection_range or
+// disconnection_buffer_percent the buffer is the same number of tiles as the global one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapRanges {
+    pub game_id: i32,
+    #[serde(default)]
+    pub map_id: Option<i32>,
+    pub introduction_range: f32,
+    #[serde(default)]
+    pub disconnection_range: Option<f32>,
+    #[serde(default)]
+    pub disconnection_buffer_percent: Option<f32>,
+}
+
+impl MapRanges {
+    pub fn covers(&self, pos: &ClientPosition) -> bool {
+        pos.game_id == self.game_id && self.map_id.is_none_or(|map_id| map_id == pos.map_id)
+    }
+
+    pub fn ranges(&self, defaults: PairingRanges) -> PairingRanges {
+        overridden_ranges(self.introduction_range, self.disconnection_range, self.disconnection_buffer_percent, defaults)
+    }
+
+    fn validate(&self, defaults: PairingRanges) -> Result<(), String> {
+        check_overridden_ranges(self.disconnection_range, self.disconnection_buffer_percent, self.ranges(defaults))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapPopulationCap {
+    pub game_id: i32,
+    // every map of the game when unset
+    #[serde(default)]
+    pub map_id: Option<i32>,
+    pub max_population: usize,
+}
+
+impl MapPopulationCap {
+    pub fn covers(&self, pos: &ClientPosition) -> bool {
+        pos.game_id == self.game_id && self.map_id.is_none_or(|map_id| map_id == pos.map_id)
+    }
+}
+
+fn overridden_ranges(introduction: f32, disconnection: Option<f32>, buffer_percent: Option<f32>, defaults: PairingRanges) -> PairingRanges {
+    let disconnection = match (disconnection, buffer_percent) {
+        (Some(disconnection), _) => disconnection,
+        (None, Some(percent)) => buffered(introduction, percent),
+        (None, None) => introduction + (defaults.disconnection - defaults.introduction),
+    };
+    PairingRanges { introduction, disconnection }
+}
+
+fn check_overridden_ranges(disconnection: Option<f32>, buffer_percent: Option<f32>, ranges: PairingRanges) -> Result<(), String> {
+    if disconnection.is_some() && buffer_percent.is_some() {
+        return Err("set disconnection_range or disconnection_buffer_percent, not both".to_string());
+    }
+    check_buffer_percent(buffer_percent)?;
+    if !(ranges.introduction > 0.0 && ranges.disconnection >= ranges.introduction) {
+        return Err("ranges must be positive with disconnection_range >= introduction_range".to_string());
+    }
+    Ok(())
+}
+
+// an indoor or outdoor part of a map with its own ranges, e.g. a small radius inside buildings
+// and a large one in open fields. a pair goes by whichever of the two clients' surroundings (its
+// region, or the map's ranges outside every region) has the smaller introduction range, so nobody
+// outside hears into a building further than the people inside it do. where regions overlap the
+// first listed counts. ranges fill in from the map's like map_ranges do from the global ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioRegion {
+    #[serde(default)]
+    pub name: String,
+    pub game_id: i32,
+    pub map_id: i32,
+    // x_min/y_min/x_max/y_max, or "polygon": [[x, y], ...]
+    #[serde(flatten)]
+    pub shape: RegionShape,
+    pub introduction_range: f32,
+    #[serde(default)]
+    pub disconnection_range: Option<f32>,
+    #[serde(default)]
+    pub disconnection_buffer_percent: Option<f32>,
+    #[serde(default)]
+    pub falloff: Falloff,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RegionShape {
+    Polygon { polygon: Vec<[i64; 2]> },
+    Rect(Area),
+}
+
+// how a pair's gain (NearbyPeer.attenuation) drops with distance inside a region
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Falloff {
+    // no gain sent; clients do their own
+    #[default]
+    None,
+    // full volume up close down to silence at the disconnection range
+    Linear,
+    // full volume within a quarter of the introduction range, then 1/distance: sound carries far
+    InverseDistance,
+}
+
+impl Falloff {
+    pub fn gain(self, distance: f64, ranges: PairingRanges) -> Option<f32> {
+        match self {
+            Falloff::None => None,
+            Falloff::Linear => Some((1.0 - distance / f64::from(ranges.disconnection)).clamp(0.0, 1.0) as f32),
+            Falloff::InverseDistance => {
+                let reference = f64::from(ranges.introduction) / 4.0;
+                Some((reference / distance.max(reference)) as f32)
+            }
+        }
+    }
+}
+
+impl AudioRegion {
+    pub fn contains(&self, pos: &ClientPosition) -> bool {
+        if pos.game_id != self.game_id || pos.map_id != self.map_id {
+            return false;
+        }
+        match &self.shape {
+            RegionShape::Rect(area) => area.contains(pos.x, pos.y),
+            RegionShape::Polygon { polygon } => polygon_contains(polygon, pos.x, pos.y),
+        }
+    }
+
+    pub fn ranges(&self, defaults: PairingRanges) -> PairingRanges {
+        overridden_ranges(self.introduction_range, self.disconnection_range, self.disconnection_buffer_percent, defaults)
+    }
+
+    fn validate(&self, defaults: PairingRanges) -> Result<(), String> {
+        match &self.shape {
+            RegionShape::Rect(area) if area.is_inverted() => return Err("inverted bounds".to_string()),
+            RegionShape::Polygon { polygon } if polygon.len() < 3 => return Err("a polygon needs at least 3 points".to_string()),
+            _ => {}
+        }
+        check_overridden_ranges(self.disconnection_range, self.disconnection_buffer_percent, self.ranges(defaults))
+    }
+}
+
+// even-odd ray casting; points on an edge may land either side
+fn polygon_contains(polygon: &[[i64; 2]], x: i64, y: i64) -> bool {
+    let (x, y) = (x as f64, y as f64);
+    let mut inside = false;
+    for (i, [x1, y1]) in polygon.iter().enumerate() {
+        let [x2, y2] = polygon[(i + 1) % polygon.len()];
+        let (x1, y1, x2, y2) = (*x1 as f64, *y1 as f64, x2 as f64, y2 as f64);
+        if (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DirectionalAudioConfig {
+    // the games it applies to; every game when empty
+    pub game_ids: Vec<i32>,
+    // range multiples straight ahead of and straight behind a player, eased between by the angle
+    pub front_scale: f32,
+    pub back_scale: f32,
+}
+
+impl Default for DirectionalAudioConfig {
+    fn default() -> Self {
+        DirectionalAudioConfig { game_ids: Vec::new(), front_scale: 1.5, back_scale: 0.5 }
+    }
+}
+
+impl DirectionalAudioConfig {
+    // how far `from`'s voice carries towards `to`, as a multiple of the range; None when `from`
+    // sent no heading or its game isn't covered
+    pub fn weight(&self, from: &ClientPosition, to: &ClientPosition) -> Option<f32> {
+        if !self.game_ids.is_empty() && !self.game_ids.contains(&from.game_id) {
+            return None;
+        }
+        let heading = f64::from(from.heading?).to_radians();
+        let dx = (to.x as i128 - from.x as i128) as f64;
+        let dy = (to.y as i128 - from.y as i128) as f64;
+        let distance = dx.hypot(dy);
+        // cosine of the angle between the heading and `to`; someone on the same spot is in front
+        let facing = if distance == 0.0 { 1.0 } else { (heading.cos() * dx + heading.sin() * dy) / distance };
+        Some(self.back_scale + (self.front_scale - self.back_scale) * ((1.0 + facing) / 2.0) as f32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpectatorsConfig {
+    pub default_radius: f32,
+    pub max_radius: f32,
+}
+
+impl Default for SpectatorsConfig {
+    fn default() -> Self {
+        SpectatorsConfig { default_radius: 60.0, max_radius: 500.0 }
+    }
+}
+
+// a "tavern": inside these bounds the channel filter is ignored, so two players pair if both
+// stand in the area (at the usual ranges) whatever channel each is on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedArea {
+    #[serde(default)]
+    pub name: String,
+    pub game_id: i32,
+    pub map_id: i32,
+    #[serde(flatten)]
+    pub bounds: Area,
+}
+
+impl SharedArea {
+    pub fn contains(&self, pos: &ClientPosition) -> bool {
+        pos.game_id == self.game_id && pos.map_id == self.map_id && self.bounds.contains(pos.x, pos.y)
+    }
+}
+
+// thresholds for the overload detector, checked on every maintenance tick
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SfuConfig {
+    // STUN/TURN urls the SFU gathers candidates with
+    pub ice_servers: Vec<String>,
+    pub areas: Vec<SfuArea>,
+}
+
+impl Default for SfuConfig {
+    fn default() -> Self {
+        SfuConfig { ice_servers: vec!["stun:stun.cloudflare.com:3478".to_string()], areas: Vec::new() }
+    }
+}
+
+// clients inside hear each other through the SFU. the name is part of the SFU's peer id, so it
+// must be unique
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SfuArea {
+    pub name: String,
+    pub game_id: i32,
+    pub map_id: i32,
+    #[serde(flatten)]
+    pub bounds: Area,
+}
+
+impl SfuArea {
+    pub fn contains(&self, pos: &ClientPosition) -> bool {
+        pos.game_id == self.game_id && pos.map_id == self.map_id && self.bounds.contains(pos.x, pos.y)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OverloadConfig {
+    // fullest outbound client queue, as a fraction of its capacity
+    pub max_queue_fill: f64,
+    // longest state write-lock wait since the previous tick
+    pub max_lock_wait_ms: u64,
+    // how late the maintenance tick itself may run
+    pub max_loop_lag_ms: u64,
+    // highest tier the server may shed while overloaded. "none", the default, only raises the
+    // gauge; operators opt into shedding with "reintroductions", "extras" or "ice_relay"
+    pub shedding: ShedLevel,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        OverloadConfig {
+            max_queue_fill: 0.5,
+            max_lock_wait_ms: 250,
+            max_loop_lag_ms: 1000,
+            shedding: ShedLevel::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionPrivacy {
+    // exact dx/dy deltas alongside the bucket
+    Exact,
+    // near/medium/far buckets only
+    Coarse,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: "0.0.0.0:8080".to_string(),
+            database_path: Some("proxchat.db".to_string()),
+            admin_addr: None,
+            admin_token: None,
+            admin_tls: None,
+            admin_allow_insecure: false,
+            grpc_addr: None,
+            position_history_len: 50,
+            position_history_retention_secs: 600,
+            position_privacy: PositionPrivacy::Coarse,
+            overload: OverloadConfig::default(),
+            allow_legacy_clients: true,
+            allowed_origins: Vec::new(),
+            trusted_proxy: None,
+            udp_addr: None,
+            stun_addr: None,
+            webtransport: None,
+            proximity: ProximityConfig::default(),
+            map_ranges: Vec::new(),
+            map_population_caps: Vec::new(),
+            float_coordinate_games: Vec::new(),
+            shared_areas: Vec::new(),
+            audio_regions: Vec::new(),
+            directional_audio: None,
+            spectators: SpectatorsConfig::default(),
+            echo_ice_servers: vec!["stun:stun.cloudflare.com:3478".to_string()],
+            name_filter: NameFilterConfig::default(),
+            usage_export: None,
+            require_message_signing: false,
+            audit_log: None,
+            turn: None,
+            turn_server: None,
+            reflexive_address_hints: false,
+            error_flood: ErrorFloodConfig::default(),
+            accept_unversioned_messages: true,
+            replay_recording: None,
+            chaos: None,
+            position_trace: None,
+            panic_reconnect_cooldown_secs: None,
+            sentry: None,
+            outbound_queue: OutboundQueueConfig::default(),
+            registration_timeout_secs: 10,
+            geoip: None,
+            registration_backoff: None,
+            duplicate_accounts: None,
+            reconnect_storm: ReconnectStormConfig::default(),
+            state_snapshot: None,
+            shutdown_drain_secs: 0,
+            log_file: None,
+            registry: None,
+            federation: None,
+            pair_retry: None,
+            network_quality: None,
+            topology_hints: None,
+            sfu: None,
+            wasm_plugin: None,
+            scripting: None,
+        }
+    }
+}
+
+// path comes from PROXCHAT_CONFIG, falling back to config.json in the working directory
+pub fn config_path() -> String {
+    std::env::var("PROXCHAT_CONFIG").unwrap_or_else(|_| "config.json".to_string())
+}
+
+// read the raw config file as JSON so overrides can be layered on before deserializing
+pub fn load_raw(path: &str) -> Result<Value, String> {
+    if !Path::new(path).exists() {
+        warn!("Config file {} not found, using defaults", path);
+        return Ok(Value::Object(Default::default()));
+    }
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let value: Value = serde_json::from_str(&text).map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+    if !value.is_object() {
+        return Err(format!("{} must contain a JSON object", path));
+    }
+    info!("Loaded config from {}", path);
+    Ok(value)
+}
+
+// apply top-level key overrides (e.g. from the database) on top of the file config
+pub fn apply_overrides(raw: &mut Value, overrides: &[(String, Value)]) {
+    if let Value::Object(map) = raw {
+        for (key, value) in overrides {
+            info!("Config override: {} = {}", key, value);
+            map.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+// checks that the running config with one key replaced would still load, before the override is stored
+pub fn check_override(config: &Config, key: &str, value: &Value) -> Result<(), String> {
+    let mut raw = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    match &raw {
+        Value::Object(fields) if fields.contains_key(key) => {}
+        _ => return Err(format!("unknown config key {}", key)),
+    }
+    apply_overrides(&mut raw, &[(key.to_string(), value.clone())]);
+    from_raw(raw).map(|_| ())
+}
+
+pub fn from_raw(raw: Value) -> Result<Config, String> {
+    let config: Config = serde_json::from_value(raw).map_err(|e| format!("Invalid config: {}", e))?;
+    config.proximity.validate().map_err(|e| format!("Invalid proximity config: {}", e))?;
+    for map_ranges in &config.map_ranges {
+        map_ranges
+            .validate(config.proximity.ranges())
+            .map_err(|e| format!("Invalid map_ranges for game {} map {:?}: {}", map_ranges.game_id, map_ranges.map_id, e))?;
+    }
+    if let Some(cap) = config.map_population_caps.iter().find(|cap| cap.max_population < 2) {
+        return Err(format!("map_population_caps for game {} map {:?} must allow at least 2 clients", cap.game_id, cap.map_id));
+    }
+    for region in &config.audio_regions {
+        let defaults = config
+            .map_ranges
+            .iter()
+            .filter(|map_ranges| map_ranges.game_id == region.game_id && map_ranges.map_id.is_none_or(|map_id| map_id == region.map_id))
+            .max_by_key(|map_ranges| map_ranges.map_id.is_some())
+            .map_or(config.proximity.ranges(), |map_ranges| map_ranges.ranges(config.proximity.ranges()));
+        region
+            .validate(defaults)
+            .map_err(|e| format!("Invalid audio region {:?} on game {} map {}: {}", region.name, region.game_id, region.map_id, e))?;
+    }
+    if let Some(directional) = &config.directional_audio {
+        if !(directional.back_scale > 0.0 && directional.back_scale <= directional.front_scale) {
+            return Err("directional_audio needs 0 < back_scale <= front_scale".to_string());
+        }
+    }
+    if !(config.spectators.default_radius > 0.0 && config.spectators.default_radius <= config.spectators.max_radius) {
+        return Err("spectators needs 0 < default_radius <= max_radius".to_string());
+    }
+    if let Some(area) = config.shared_areas.iter().find(|area| area.bounds.is_inverted()) {
+        return Err(format!("Shared area {:?} on map {} has inverted bounds", area.name, area.map_id));
+    }
+    NameFilter::new(&config.name_filter).map_err(|e| format!("Invalid name_filter: {}", e))?;
+    if config.usage_export.as_ref().is_some_and(|export| export.interval_secs == 0) {
+        return Err("usage_export.interval_secs must be at least 1".to_string());
+    }
+    if config.error_flood.window_secs == 0 {
+        return Err("error_flood.window_secs must be at least 1".to_string());
+    }
+    if let Some(geoip) = &config.geoip {
+        if geoip.database_path.is_empty() {
+            return Err("geoip needs a database_path".to_string());
+        }
+        let codes = geoip.allow_countries.iter().chain(&geoip.deny_countries);
+        if let Some(code) = codes.into_iter().find(|code| code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic())) {
+            return Err(format!("geoip country {:?} is not an ISO 3166-1 alpha-2 code", code));
+        }
+    }
+    if config.registration_timeout_secs == 0 {
+        return Err("registration_timeout_secs must be at least 1".to_string());
+    }
+    if config.outbound_queue.capacity == 0 {
+        return Err("outbound_queue.capacity must be at least 1".to_string());
+    }
+    if config.outbound_queue.signaling_ttl_ms == Some(0) {
+        return Err("outbound_queue.signaling_ttl_ms must be at least 1 (or null to turn it off)".to_string());
+    }
+    if let Some(backoff) = &config.registration_backoff {
+        if backoff.window_secs == 0 || backoff.base_delay_secs == 0 {
+            return Err("registration_backoff.window_secs and base_delay_secs must be at least 1".to_string());
+        }
+        if backoff.max_delay_secs < backoff.base_delay_secs {
+            return Err("registration_backoff.max_delay_secs can't be less than base_delay_secs".to_string());
+        }
+    }
+    if config.duplicate_accounts.is_some_and(|duplicates| duplicates.max_per_ip == 0) {
+        return Err("duplicate_accounts.max_per_ip must be at least 1".to_string());
+    }
+    if config.reconnect_storm.window_secs == 0 {
+        return Err("reconnect_storm.window_secs must be at least 1".to_string());
+    }
+    if !(0.0..=1.0).contains(&config.reconnect_storm.registered_fraction) {
+        return Err("reconnect_storm.registered_fraction must be between 0 and 1".to_string());
+    }
+    if let Some(position_trace) = &config.position_trace {
+        if position_trace.path.is_empty() {
+            return Err("position_trace needs a path".to_string());
+        }
+        if !(position_trace.speed.is_finite() && position_trace.speed > 0.0) {
+            return Err("position_trace.speed must be above 0".to_string());
+        }
+    }
+    if let Some(trusted_proxy) = &config.trusted_proxy {
+        if trusted_proxy.proxy_addrs.is_empty() {
+            return Err("trusted_proxy needs the proxy_addrs it connects from".to_string());
+        }
+        if let Some(addr) = trusted_proxy.proxy_addrs.iter().find(|addr| addr.parse::<IpAddr>().is_err()) {
+            return Err(format!("trusted_proxy.proxy_addrs entry {} isn't an IP address", addr));
+        }
+        let header_name = |name: &str| !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+        if !header_name(&trusted_proxy.user_id_header) || !header_name(&trusted_proxy.region_header) {
+            return Err("trusted_proxy.user_id_header and region_header must be header names".to_string());
+        }
+        // its sessions don't pass through the proxy, so nothing would vouch for them
+        if config.webtransport.is_some() {
+            return Err("trusted_proxy can't be combined with webtransport".to_string());
+        }
+        // they'd count every client behind the proxy as one address
+        if config.registration_backoff.is_some() || config.duplicate_accounts.is_some() {
+            return Err("trusted_proxy can't be combined with registration_backoff or duplicate_accounts".to_string());
+        }
+    }
+    if config.state_snapshot.as_ref().is_some_and(|snapshot| snapshot.path.is_empty()) {
+        return Err("state_snapshot needs a path".to_string());
+    }
+    if let Some(registry) = &config.registry {
+        if registry.url.is_empty() || registry.name.is_empty() || registry.public_url.is_empty() {
+            return Err("registry needs a url, a name and a public_url".to_string());
+        }
+        if registry.interval_secs == 0 {
+            return Err("registry.interval_secs must be at least 1".to_string());
+        }
+    }
+    if let Some(network_quality) = &config.network_quality {
+        if network_quality.max_rtt_ms == 0 || !(0.0..100.0).contains(&network_quality.max_packet_loss_percent) {
+            return Err("network_quality needs max_rtt_ms of at least 1 and max_packet_loss_percent from 0 to under 100".to_string());
+        }
+    }
+    if config.topology_hints.is_some_and(|hints| hints.min_cluster_size < 3 || hints.max_leaves_per_hub == 0) {
+        return Err("topology_hints needs min_cluster_size of at least 3 and max_leaves_per_hub of at least 1".to_string());
+    }
+    if let Some(sfu) = &config.sfu {
+        let mut names = HashSet::new();
+        for area in &sfu.areas {
+            if area.name.is_empty() || !names.insert(area.name.as_str()) {
+                return Err("every sfu area needs a name of its own".to_string());
+            }
+            if area.bounds.is_inverted() {
+                return Err(format!("sfu area {} has inverted bounds (x_min > x_max or y_min > y_max)", area.name));
+            }
+        }
+    }
+    if let Some(pair_retry) = &config.pair_retry {
+        if pair_retry.connect_timeout_secs == Some(0) || pair_retry.initial_backoff_secs == 0 {
+            return Err("pair_retry.connect_timeout_secs and initial_backoff_secs must be at least 1".to_string());
+        }
+        if pair_retry.max_backoff_secs < pair_retry.initial_backoff_secs {
+            return Err("pair_retry.max_backoff_secs must be at least initial_backoff_secs".to_string());
+        }
+    }
+    if let Some(federation) = &config.federation {
+        if federation.server_id.is_empty() || federation.shared_secret.is_empty() {
+            return Err("federation needs a server_id and a shared_secret".to_string());
+        }
+        if federation.listen_addr.is_none() && federation.peers.is_empty() {
+            return Err("federation needs a listen_addr, peers or both".to_string());
+        }
+        // remote clients time out after 15s without a digest
+        if !(100..=5000).contains(&federation.digest_interval_ms) {
+            return Err("federation.digest_interval_ms must be between 100 and 5000".to_string());
+        }
+    }
+    if let Some(wasm_plugin) = &config.wasm_plugin {
+        if wasm_plugin.path.is_empty() {
+            return Err("wasm_plugin needs a path".to_string());
+        }
+        if wasm_plugin.fuel_per_call == 0 {
+            return Err("wasm_plugin.fuel_per_call must be at least 1".to_string());
+        }
+    }
+    if let Some(scripting) = &config.scripting {
+        if scripting.reload_interval_secs == 0 || scripting.max_operations == 0 {
+            return Err("scripting.reload_interval_secs and max_operations must be at least 1".to_string());
+        }
+    }
+    if let Some(log_file) = &config.log_file {
+        if log_file.path.is_empty() {
+            return Err("log_file needs a path".to_string());
+        }
+        if log_file.max_size_mb == Some(0) || log_file.max_age_hours == Some(0) {
+            return Err("log_file.max_size_mb and max_age_hours must be at least 1 (or null)".to_string());
+        }
+    }
+    if config.panic_reconnect_cooldown_secs == Some(0) {
+        return Err("panic_reconnect_cooldown_secs must be at least 1 (or null to turn it off)".to_string());
+    }
+    if let Some(chaos) = &config.chaos {
+        let probabilities = [chaos.relay_delay_probability, chaos.drop_nearby_peers_probability, chaos.disconnect_probability];
+        if probabilities.iter().any(|p| !(0.0..=1.0).contains(p)) {
+            return Err("chaos probabilities must be between 0 and 1".to_string());
+        }
+    }
+    if let Some(turn) = &config.turn {
+        if turn.urls.is_empty() || turn.shared_secret.is_empty() {
+            return Err("turn needs urls and a shared_secret".to_string());
+        }
+        if turn.credential_ttl_secs == 0 || turn.relay_after_failures == 0 {
+            return Err("turn.credential_ttl_secs and turn.relay_after_failures must be at least 1".to_string());
+        }
+    }
+    if let Some(turn_server) = &config.turn_server {
+        if config.turn.is_none() {
+            return Err("turn_server checks the credentials of the turn section, which is missing".to_string());
+        }
+        if turn_server.relay_ip.parse::<IpAddr>().is_err() {
+            return Err(format!("turn_server.relay_ip {:?} is not an IP address", turn_server.relay_ip));
+        }
+        if turn_server.min_relay_port == 0 || turn_server.min_relay_port > turn_server.max_relay_port {
+            return Err("turn_server needs 0 < min_relay_port <= max_relay_port".to_string());
+        }
+        if turn_server.hourly_quota_bytes == Some(0) || turn_server.daily_quota_bytes == Some(0) {
+            return Err("turn_server.hourly_quota_bytes and daily_quota_bytes must be at least 1 (or unset for no limit)".to_string());
+        }
+    }
+    Ok(config)
+}
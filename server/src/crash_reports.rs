@@ -0,0 +1,64 @@
+use crate::config::SentryConfig;
+use crate::logfile::TeeLogger;
+use std::future::Future;
+use std::net::SocketAddr;
+
+// optional crash reporting to Sentry or anything speaking its protocol (the sentry feature).
+// panics and error-level log lines become events, earlier info and warn lines ride along as
+// breadcrumbs, and events raised while handling a connection carry its id, address and client id
+
+// installs the logger; with the sentry feature it also feeds Sentry once init has run, and the
+// log file once logfile::init has
+pub fn init_logging() {
+    let console = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).build();
+    let logger = TeeLogger::new(console);
+    let max_level = logger.filter();
+    #[cfg(feature = "sentry")]
+    let logger = sentry::integrations::log::SentryLogger::with_dest(logger);
+    log::set_boxed_logger(Box::new(logger)).expect("logger installed twice");
+    log::set_max_level(max_level);
+}
+
+// keep the guard alive for as long as reports should be sent; dropping it flushes the queue
+#[cfg(feature = "sentry")]
+pub fn init(config: &SentryConfig) -> sentry::ClientInitGuard {
+    log::info!("Reporting crashes and errors to Sentry ({})", config.environment.as_deref().unwrap_or("no environment"));
+    sentry::init((
+        config.dsn.as_str(),
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: config.environment.clone().map(Into::into),
+            ..Default::default()
+        },
+    ))
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init(config: &SentryConfig) {
+    log::warn!("sentry is configured ({}) but this build lacks the sentry feature, ignoring", config.dsn);
+}
+
+// runs a connection's handler with its own scope, so reports from it say which connection it was
+#[cfg(feature = "sentry")]
+pub fn connection_scope<F: Future>(connection_id: &str, addr: SocketAddr, future: F) -> impl Future<Output = F::Output> {
+    use sentry::SentryFutureExt;
+    let hub = std::sync::Arc::new(sentry::Hub::new_from_top(sentry::Hub::main()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("connection_id", connection_id);
+        scope.set_tag("peer_addr", addr);
+    });
+    future.bind_hub(hub)
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn connection_scope<F: Future>(_connection_id: &str, _addr: SocketAddr, future: F) -> impl Future<Output = F::Output> {
+    future
+}
+
+// called from inside connection_scope once the connection registers
+pub fn set_client_id(client_id: &str) {
+    #[cfg(feature = "sentry")]
+    sentry::configure_scope(|scope| scope.set_tag("client_id", client_id));
+    #[cfg(not(feature = "sentry"))]
+    let _ = client_id;
+}
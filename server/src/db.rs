@@ -0,0 +1,453 @@
+use crate::occlusion::{CollisionGrid, CollisionUpload};
+use crate::schedule::{Area, ScheduledEvent};
+use crate::zones::AnnouncerZone;
+use log::{info, warn};
+use proxchat_protocol::ElevatedConsent;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// schema migrations, applied in order at startup. the index of the last applied
+// migration is tracked in sqlite's user_version pragma, so only append to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: moderation data and runtime config overrides
+    "CREATE TABLE bans (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        client_id TEXT,
+        ip TEXT,
+        reason TEXT NOT NULL DEFAULT '',
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER
+    );
+    CREATE INDEX bans_client_id ON bans(client_id);
+    CREATE INDEX bans_ip ON bans(ip);
+    CREATE TABLE reports (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        reporter_id TEXT NOT NULL,
+        reporter_ip TEXT NOT NULL,
+        target_id TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE config_overrides (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 2: announcer zones scheduled through the admin API
+    "CREATE TABLE announcer_zones (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL DEFAULT '',
+        speaker_id TEXT NOT NULL,
+        game_id INTEGER NOT NULL,
+        map_id INTEGER NOT NULL,
+        channel INTEGER,
+        x_min INTEGER NOT NULL,
+        y_min INTEGER NOT NULL,
+        x_max INTEGER NOT NULL,
+        y_max INTEGER NOT NULL,
+        starts_at INTEGER NOT NULL,
+        ends_at INTEGER NOT NULL
+    );",
+    // 3: scheduled events with temporary pairing overrides
+    "CREATE TABLE scheduled_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL DEFAULT '',
+        game_id INTEGER NOT NULL,
+        map_id INTEGER NOT NULL,
+        channel INTEGER,
+        x_min INTEGER,
+        y_min INTEGER,
+        x_max INTEGER,
+        y_max INTEGER,
+        starts_at INTEGER NOT NULL,
+        duration_secs INTEGER NOT NULL,
+        introduction_range REAL,
+        disconnection_range REAL,
+        max_peers INTEGER
+    );",
+    // 4: connection lifecycle audit log, when audit_log uses the database
+    "CREATE TABLE audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        at INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        details TEXT NOT NULL
+    );
+    CREATE INDEX audit_log_client_id ON audit_log(client_id, at);",
+    // 5: per-map collision grids for line-of-sight occlusion, as uploaded (JSON)
+    "CREATE TABLE collision_grids (
+        game_id INTEGER NOT NULL,
+        map_id INTEGER NOT NULL,
+        grid TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (game_id, map_id)
+    );",
+    // 6: what clients refused to be heard by beyond proximity (ElevatedConsent); allowing everything drops the row
+    "CREATE TABLE elevated_consent (
+        client_id TEXT PRIMARY KEY,
+        spectators INTEGER NOT NULL,
+        announcers INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 7: looking up a reporter's recent reports, to keep ReportPeer from filling the table
+    "CREATE INDEX reports_reporter ON reports(reporter_id, created_at);",
+];
+
+// max stored length of free-text report reasons
+const MAX_REPORT_REASON_LEN: usize = 500;
+// reports one reporter may file per hour; a repeat about the same client within the hour isn't stored either
+const MAX_REPORTS_PER_HOUR: i64 = 20;
+
+// what became of a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportOutcome {
+    Stored,
+    // the reporter already reported that client in the last hour
+    Duplicate,
+    // the reporter filed MAX_REPORTS_PER_HOUR in the last hour
+    RateLimited,
+}
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// embedded sqlite store for data that must survive restarts.
+// queries are small and infrequent (registration, reports, startup), so a plain mutex is enough;
+// connection handlers still go through run(), so a slow disk never stalls an async worker
+pub struct Database {
+    conn: Mutex<Connection>,
+}
+
+impl Database {
+    // runs the queries on tokio's blocking pool
+    pub async fn run<T, F>(self: &Arc<Self>, queries: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let db = Arc::clone(self);
+        tokio::task::spawn_blocking(move || queries(&db)).await.expect("database queries panicked")
+    }
+
+    pub fn open(path: Option<&str>) -> rusqlite::Result<Self> {
+        let conn = match path {
+            Some(path) => {
+                info!("Opening database at {}", path);
+                Connection::open(path)?
+            }
+            None => {
+                info!("No database_path configured, using in-memory database (data will not persist)");
+                Connection::open_in_memory()?
+            }
+        };
+        let db = Database { conn: Mutex::new(conn) };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    // for `prox-chat-server check`: an existing database as it is, neither written nor migrated
+    pub fn open_read_only(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        Ok(Database { conn: Mutex::new(conn) })
+    }
+
+    // migrations the next open() would apply
+    pub fn pending_migrations(&self) -> rusqlite::Result<usize> {
+        let current: i64 = self.conn.lock().unwrap().query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(MIGRATIONS.len().saturating_sub(current as usize))
+    }
+
+    fn migrate(&self) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let current: usize = conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+        for (index, sql) in MIGRATIONS.iter().enumerate().skip(current) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(sql)?;
+            tx.pragma_update(None, "user_version", (index + 1) as i64)?;
+            tx.commit()?;
+            info!("Applied database migration {}", index + 1);
+        }
+        Ok(())
+    }
+
+    // returns the ban reason if either the client id or the ip has an active ban. with no ip
+    // (behind a trusted proxy, where every client has the proxy's) only the client id is checked
+    pub fn active_ban(&self, client_id: &str, ip: Option<&str>) -> rusqlite::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT reason FROM bans
+             WHERE (client_id = ?1 OR ip = ?2) AND (expires_at IS NULL OR expires_at > ?3)
+             ORDER BY created_at DESC LIMIT 1",
+            params![client_id, ip, unix_now()],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    pub fn add_report(&self, reporter_id: &str, reporter_ip: &str, target_id: &str, reason: &str) -> rusqlite::Result<ReportOutcome> {
+        let reason: String = reason.chars().take(MAX_REPORT_REASON_LEN).collect();
+        let now = unix_now();
+        let conn = self.conn.lock().unwrap();
+        let (last_hour, repeats): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COUNT(CASE WHEN target_id = ?2 THEN 1 END) FROM reports WHERE reporter_id = ?1 AND created_at > ?3",
+            params![reporter_id, target_id, now - 3600],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if repeats > 0 {
+            return Ok(ReportOutcome::Duplicate);
+        }
+        if last_hour >= MAX_REPORTS_PER_HOUR {
+            return Ok(ReportOutcome::RateLimited);
+        }
+        conn.execute(
+            "INSERT INTO reports (reporter_id, reporter_ip, target_id, reason, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![reporter_id, reporter_ip, target_id, reason, now],
+        )?;
+        Ok(ReportOutcome::Stored)
+    }
+
+    // how many different clients have reported this one
+    pub fn distinct_reporters(&self, target_id: &str) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT COUNT(DISTINCT reporter_id) FROM reports WHERE target_id = ?1", params![target_id], |row| row.get(0))
+    }
+
+    // bans the client id, the ip, or both; expires_in_secs None bans for good. returns the ban's id
+    pub fn add_ban(&self, client_id: Option<&str>, ip: Option<&str>, reason: &str, expires_in_secs: Option<i64>) -> rusqlite::Result<i64> {
+        let now = unix_now();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO bans (client_id, ip, reason, created_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![client_id, ip, reason, now, expires_in_secs.map(|secs| now + secs)],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    // stored as JSON text; layered onto the config file at the next start
+    pub fn set_config_override(&self, key: &str, value: &Value) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO config_overrides (key, value, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+            params![key, value.to_string(), unix_now()],
+        )?;
+        Ok(())
+    }
+
+    // stored overrides as (key, json value); rows that no longer parse are skipped
+    pub fn config_overrides(&self) -> rusqlite::Result<Vec<(String, Value)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT key, value FROM config_overrides ORDER BY key")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut overrides = Vec::new();
+        for row in rows {
+            let (key, value) = row?;
+            match serde_json::from_str(&value) {
+                Ok(value) => overrides.push((key, value)),
+                Err(e) => warn!("Ignoring unparseable config override {}: {}", key, e),
+            }
+        }
+        Ok(overrides)
+    }
+
+    // announcer zones that haven't ended yet, soonest first
+    pub fn announcer_zones(&self) -> rusqlite::Result<Vec<AnnouncerZone>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, speaker_id, game_id, map_id, channel, x_min, y_min, x_max, y_max, starts_at, ends_at
+             FROM announcer_zones WHERE ends_at > ?1 ORDER BY starts_at, id",
+        )?;
+        let rows = stmt.query_map(params![unix_now()], |row| {
+            Ok(AnnouncerZone {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                speaker_id: row.get(2)?,
+                game_id: row.get(3)?,
+                map_id: row.get(4)?,
+                channel: row.get(5)?,
+                x_min: row.get(6)?,
+                y_min: row.get(7)?,
+                x_max: row.get(8)?,
+                y_max: row.get(9)?,
+                starts_at: row.get(10)?,
+                ends_at: row.get(11)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    // stores the zone and returns its id
+    pub fn add_announcer_zone(&self, zone: &AnnouncerZone) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO announcer_zones (name, speaker_id, game_id, map_id, channel, x_min, y_min, x_max, y_max, starts_at, ends_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                zone.name,
+                zone.speaker_id,
+                zone.game_id,
+                zone.map_id,
+                zone.channel,
+                zone.x_min,
+                zone.y_min,
+                zone.x_max,
+                zone.y_max,
+                zone.starts_at,
+                zone.ends_at
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    // false if there was no such zone
+    pub fn remove_announcer_zone(&self, id: i64) -> rusqlite::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM announcer_zones WHERE id = ?1", params![id])? > 0)
+    }
+
+    // scheduled events that haven't ended yet, soonest first
+    pub fn scheduled_events(&self) -> rusqlite::Result<Vec<ScheduledEvent>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, game_id, map_id, channel, x_min, y_min, x_max, y_max, starts_at, duration_secs,
+                    introduction_range, disconnection_range, max_peers
+             FROM scheduled_events WHERE starts_at + duration_secs > ?1 ORDER BY starts_at, id",
+        )?;
+        let rows = stmt.query_map(params![unix_now()], |row| {
+            let bounds: (Option<i64>, Option<i64>, Option<i64>, Option<i64>) = (row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?);
+            let area = match bounds {
+                (Some(x_min), Some(y_min), Some(x_max), Some(y_max)) => Some(Area { x_min, y_min, x_max, y_max }),
+                _ => None,
+            };
+            Ok(ScheduledEvent {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                game_id: row.get(2)?,
+                map_id: row.get(3)?,
+                channel: row.get(4)?,
+                area,
+                starts_at: row.get(9)?,
+                duration_secs: row.get(10)?,
+                introduction_range: row.get::<_, Option<f64>>(11)?.map(|range| range as f32),
+                disconnection_range: row.get::<_, Option<f64>>(12)?.map(|range| range as f32),
+                max_peers: row.get::<_, Option<i64>>(13)?.map(|cap| cap as usize),
+            })
+        })?;
+        rows.collect()
+    }
+
+    // stores the event and returns its id
+    pub fn add_scheduled_event(&self, event: &ScheduledEvent) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO scheduled_events (name, game_id, map_id, channel, x_min, y_min, x_max, y_max, starts_at, duration_secs,
+                                           introduction_range, disconnection_range, max_peers)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                event.name,
+                event.game_id,
+                event.map_id,
+                event.channel,
+                event.area.map(|area| area.x_min),
+                event.area.map(|area| area.y_min),
+                event.area.map(|area| area.x_max),
+                event.area.map(|area| area.y_max),
+                event.starts_at,
+                event.duration_secs,
+                event.introduction_range.map(f64::from),
+                event.disconnection_range.map(f64::from),
+                event.max_peers.map(|cap| cap as i64)
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    // false if there was no such event
+    pub fn remove_scheduled_event(&self, id: i64) -> rusqlite::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM scheduled_events WHERE id = ?1", params![id])? > 0)
+    }
+
+    // every stored collision grid; ones that no longer parse are skipped with a warning
+    pub fn collision_grids(&self) -> rusqlite::Result<Vec<CollisionGrid>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT game_id, map_id, grid FROM collision_grids")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i32>(1)?, row.get::<_, String>(2)?)))?;
+        let mut grids = Vec::new();
+        for row in rows {
+            let (game_id, map_id, grid) = row?;
+            let parsed = serde_json::from_str::<CollisionUpload>(&grid).map_err(|e| e.to_string());
+            match parsed.and_then(|upload| CollisionGrid::new(game_id, map_id, upload)) {
+                Ok(grid) => grids.push(grid),
+                Err(e) => warn!("Skipping the stored collision grid of game {} map {}: {}", game_id, map_id, e),
+            }
+        }
+        Ok(grids)
+    }
+
+    // replaces the map's grid, if it had one
+    pub fn set_collision_grid(&self, game_id: i32, map_id: i32, upload: &CollisionUpload) -> rusqlite::Result<()> {
+        let grid = serde_json::to_string(upload).unwrap_or_default();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO collision_grids (game_id, map_id, grid, updated_at) VALUES (?1, ?2, ?3, ?4)",
+            params![game_id, map_id, grid, unix_now()],
+        )?;
+        Ok(())
+    }
+
+    // false if the map had no grid
+    pub fn remove_collision_grid(&self, game_id: i32, map_id: i32) -> rusqlite::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM collision_grids WHERE game_id = ?1 AND map_id = ?2", params![game_id, map_id])? > 0)
+    }
+
+    // every client that refused something, by client id
+    pub fn elevated_consents(&self) -> rusqlite::Result<Vec<(String, ElevatedConsent)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT client_id, spectators, announcers FROM elevated_consent")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, ElevatedConsent { spectators: row.get(1)?, announcers: row.get(2)? }))
+        })?;
+        rows.collect()
+    }
+
+    pub fn set_elevated_consent(&self, client_id: &str, consent: ElevatedConsent) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        if consent == ElevatedConsent::default() {
+            conn.execute("DELETE FROM elevated_consent WHERE client_id = ?1", params![client_id])?;
+        } else {
+            conn.execute(
+                "INSERT OR REPLACE INTO elevated_consent (client_id, spectators, announcers, updated_at) VALUES (?1, ?2, ?3, ?4)",
+                params![client_id, consent.spectators, consent.announcers, unix_now()],
+            )?;
+        }
+        Ok(())
+    }
+
+    // (at, event, client_id, details as JSON) rows, written in one transaction
+    pub fn add_audit_events(&self, rows: &[(i64, &str, &str, String)]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("INSERT INTO audit_log (at, event, client_id, details) VALUES (?1, ?2, ?3, ?4)")?;
+            for (at, event, client_id, details) in rows {
+                stmt.execute(params![at, event, client_id, details])?;
+            }
+        }
+        tx.commit()
+    }
+}
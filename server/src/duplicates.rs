@@ -0,0 +1,82 @@
+// clients registered from one address (config duplicate_accounts), for players running several
+// game clients at once. the first to register from an address is its primary, the rest its alts.
+// "cap" refuses registrations past max_per_ip; "merge" pairs only the primary, with everyone any
+// of the address's clients would pair with, so alts add reach instead of mesh connections.
+// remote (federated) and restored clients aren't counted until they register here
+use crate::config::{DuplicateAccountsConfig, DuplicatePolicy};
+use crate::metrics;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+pub struct DuplicateAccounts {
+    pub policy: DuplicatePolicy,
+    max_per_ip: usize,
+    // client ids in the order they registered, primary first
+    by_ip: HashMap<IpAddr, Vec<String>>,
+    by_client: HashMap<String, IpAddr>,
+}
+
+impl DuplicateAccounts {
+    pub fn new(config: &DuplicateAccountsConfig) -> Self {
+        DuplicateAccounts { policy: config.policy, max_per_ip: config.max_per_ip, by_ip: HashMap::new(), by_client: HashMap::new() }
+    }
+
+    // whether the policy lets one more client register from the address, besides `client_id`
+    // itself taking over its own registration
+    pub fn allows(&self, client_id: &str, ip: IpAddr) -> bool {
+        if self.policy != DuplicatePolicy::Cap {
+            return true;
+        }
+        let others = self.by_ip.get(&ip).map_or(0, |clients| clients.iter().filter(|id| *id != client_id).count());
+        others < self.max_per_ip
+    }
+
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip
+    }
+
+    // returns the address's primary when the client joins as an alt
+    pub fn register(&mut self, client_id: &str, ip: IpAddr) -> Option<String> {
+        self.remove(client_id);
+        let clients = self.by_ip.entry(ip).or_default();
+        clients.push(client_id.to_string());
+        self.by_client.insert(client_id.to_string(), ip);
+        let primary = (clients.len() > 1).then(|| clients[0].clone());
+        self.update_metrics();
+        primary
+    }
+
+    // drops the client; returns the primary of the clients left at its address, if any
+    pub fn remove(&mut self, client_id: &str) -> Option<String> {
+        let ip = self.by_client.remove(client_id)?;
+        let clients = self.by_ip.get_mut(&ip)?;
+        clients.retain(|id| id != client_id);
+        let primary = clients.first().cloned();
+        if clients.is_empty() {
+            self.by_ip.remove(&ip);
+        }
+        self.update_metrics();
+        primary
+    }
+
+    // every client registered from the same address as this one, primary first
+    pub fn group(&self, client_id: &str) -> Option<&[String]> {
+        self.by_ip.get(self.by_client.get(client_id)?).map(Vec::as_slice)
+    }
+
+    // the primary this client is an alt of, None for primaries and unknown clients
+    pub fn primary_of(&self, client_id: &str) -> Option<&str> {
+        let primary = self.group(client_id)?.first()?;
+        (primary != client_id).then_some(primary.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_client.len()
+    }
+
+    fn update_metrics(&self) {
+        let shared = self.by_ip.values().filter(|clients| clients.len() > 1);
+        metrics::DUPLICATE_IPS.set(shared.clone().count() as i64);
+        metrics::DUPLICATE_CLIENTS.set(shared.map(|clients| clients.len() - 1).sum::<usize>() as i64);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::outbound;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+// id of the server-hosted echo peer. '!' sorts before any GUID, so clients that only offer
+// to peers with smaller ids (the C# client) always take the initiator role with it.
+pub const ECHO_PEER_ID: &str = "!echo-test";
+// how long an echo test stays in the client's nearby list before it is taken out again
+#[cfg_attr(not(feature = "echo-peer"), allow(dead_code))]
+pub const ECHO_SESSION_DURATION: Duration = Duration::from_secs(120);
+
+// signaling a client sends to the echo peer
+#[cfg_attr(not(feature = "echo-peer"), allow(dead_code))]
+pub enum Signal {
+    Offer(String),
+    IceCandidate(String),
+}
+
+// one client's echo test. dropping it ends the test and closes the peer connection
+pub struct EchoSession {
+    pub expires_at: Instant,
+    signals: mpsc::UnboundedSender<Signal>,
+}
+
+impl EchoSession {
+    pub fn signal(&self, signal: Signal) {
+        let _ = self.signals.send(signal);
+    }
+}
+
+// start an echo test for a client; None when this build has no echo peer
+#[cfg(feature = "echo-peer")]
+pub fn start(client_id: &str, client_tx: outbound::Sender, ice_servers: Vec<String>) -> Option<EchoSession> {
+    let (signals, signals_rx) = mpsc::unbounded_channel();
+    tokio::spawn(rtc::run(client_id.to_string(), client_tx, ice_servers, signals_rx));
+    Some(EchoSession {
+        expires_at: Instant::now() + ECHO_SESSION_DURATION,
+        signals,
+    })
+}
+
+#[cfg(not(feature = "echo-peer"))]
+pub fn start(_client_id: &str, _client_tx: outbound::Sender, _ice_servers: Vec<String>) -> Option<EchoSession> {
+    None
+}
+
+// the WebRTC side: answers the client's offer, reflects received audio (opus) back on a
+// track of its own and echoes every data channel message to the sender
+#[cfg(feature = "echo-peer")]
+mod rtc {
+    use super::{Signal, ECHO_PEER_ID};
+    use crate::ServerMessage;
+    use log::{info, warn};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use webrtc::api::interceptor_registry::register_default_interceptors;
+    use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS};
+    use webrtc::api::APIBuilder;
+    use webrtc::data_channel::data_channel_message::DataChannelMessage;
+    use webrtc::data_channel::RTCDataChannel;
+    use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+    use webrtc::ice_transport::ice_server::RTCIceServer;
+    use webrtc::interceptor::registry::Registry;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+    use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+    use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+    use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+
+    // handles one client's signaling in order, so candidates sent while the answer is being
+    // built simply wait in the channel
+    pub async fn run(
+        client_id: String,
+        client_tx: crate::outbound::Sender,
+        ice_servers: Vec<String>,
+        mut signals: mpsc::UnboundedReceiver<Signal>,
+    ) {
+        let mut connection: Option<Arc<RTCPeerConnection>> = None;
+        while let Some(signal) = signals.recv().await {
+            match signal {
+                Signal::Offer(offer) => {
+                    if let Some(previous) = connection.take() {
+                        let _ = previous.close().await;
+                    }
+                    match answer_offer(&offer, &ice_servers).await {
+                        Ok((peer_connection, answer)) => {
+                            info!("Echo peer answering {}", client_id);
+                            connection = Some(peer_connection);
+                            let _ = client_tx
+                                .send(ServerMessage::ReceiveAnswer { sender_id: ECHO_PEER_ID.to_string(), answer, relayed_at_ms: None })
+                                .await;
+                        }
+                        Err(e) => {
+                            warn!("Echo peer failed to answer {}: {}", client_id, e);
+                            let _ = client_tx.send(ServerMessage::Error(format!("Echo test failed: {}", e))).await;
+                        }
+                    }
+                }
+                Signal::IceCandidate(candidate) => {
+                    let Some(peer_connection) = connection.as_ref() else {
+                        continue;
+                    };
+                    match serde_json::from_str::<RTCIceCandidateInit>(&candidate) {
+                        Ok(candidate) => {
+                            if let Err(e) = peer_connection.add_ice_candidate(candidate).await {
+                                warn!("Echo peer rejected ICE candidate from {}: {}", client_id, e);
+                            }
+                        }
+                        Err(e) => warn!("Unparseable ICE candidate from {} for the echo peer: {}", client_id, e),
+                    }
+                }
+            }
+        }
+        if let Some(peer_connection) = connection {
+            let _ = peer_connection.close().await;
+        }
+        info!("Echo test for {} ended", client_id);
+    }
+
+    // build a peer connection for the offer and return it with the JSON answer. candidates are
+    // gathered before answering, so the answer carries them and the echo peer never trickles
+    async fn answer_offer(offer: &str, ice_servers: &[String]) -> Result<(Arc<RTCPeerConnection>, String), String> {
+        let offer: RTCSessionDescription = serde_json::from_str(offer).map_err(|e| format!("invalid offer: {}", e))?;
+
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs().map_err(|e| e.to_string())?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine).map_err(|e| e.to_string())?;
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+        let mut configuration = RTCConfiguration::default();
+        if !ice_servers.is_empty() {
+            configuration.ice_servers = vec![RTCIceServer {
+                urls: ice_servers.to_vec(),
+                ..Default::default()
+            }];
+        }
+        let peer_connection = Arc::new(api.new_peer_connection(configuration).await.map_err(|e| e.to_string())?);
+
+        // added before the remote description so it pairs with the offered audio section
+        let output = Arc::new(TrackLocalStaticRTP::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: 48000,
+                channels: 2,
+                ..Default::default()
+            },
+            "audio".to_owned(),
+            "proxchat-echo".to_owned(),
+        ));
+        let sender = peer_connection
+            .add_track(Arc::clone(&output) as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .map_err(|e| e.to_string())?;
+        // RTCP has to be read for the interceptors to work
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; 1500];
+            while sender.read(&mut buffer).await.is_ok() {}
+        });
+
+        peer_connection.on_track(Box::new(move |track, _receiver, _transceiver| {
+            let output = Arc::clone(&output);
+            Box::pin(async move {
+                if track.kind() != RTPCodecType::Audio {
+                    return;
+                }
+                tokio::spawn(async move {
+                    while let Ok((packet, _)) = track.read_rtp().await {
+                        if output.write_rtp(&packet).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            })
+        }));
+
+        peer_connection.on_data_channel(Box::new(|channel: Arc<RTCDataChannel>| {
+            Box::pin(async move {
+                let reply = Arc::clone(&channel);
+                channel.on_message(Box::new(move |message: DataChannelMessage| {
+                    let reply = Arc::clone(&reply);
+                    Box::pin(async move {
+                        let _ = if message.is_string {
+                            reply.send_text(String::from_utf8_lossy(&message.data).into_owned()).await
+                        } else {
+                            reply.send(&message.data).await
+                        };
+                    })
+                }));
+            })
+        }));
+
+        peer_connection.set_remote_description(offer).await.map_err(|e| e.to_string())?;
+        let answer = peer_connection.create_answer(None).await.map_err(|e| e.to_string())?;
+        let mut gathering_complete = peer_connection.gathering_complete_promise().await;
+        peer_connection.set_local_description(answer).await.map_err(|e| e.to_string())?;
+        let _ = gathering_complete.recv().await;
+
+        let answer = peer_connection
+            .local_description()
+            .await
+            .ok_or_else(|| "no local description".to_string())?;
+        let answer = serde_json::to_string(&answer).map_err(|e| e.to_string())?;
+        Ok((peer_connection, answer))
+    }
+}
@@ -0,0 +1,373 @@
+// links between independently run servers of the same game (config federation), so a community
+// split across them still hears each other. each side sends the other a digest of its own clients
+// on the shared maps every digest_interval_ms; the clients in it stand in here the way restored
+// clients do, with a position and pairs but no route, until a digest leaves them out or the link
+// drops. pairs with them are decided here from the local side, and offers, answers and ICE
+// candidates for them go over the link to their server. links are websockets carrying JSON; both
+// ends prove they hold shared_secret by MACing the other's nonce before anything else is accepted
+use crate::config::FederationConfig;
+use crate::{db, metrics, send_nearby_updates, ServerState};
+use dashmap::DashMap;
+use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use proxchat_protocol::{ClientPosition, ServerMessage};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+// how long the other end has to prove itself
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// relayed signals queued per link before further ones are dropped
+const LINK_QUEUE_LEN: usize = 1024;
+// reconnect delays for outbound links, doubling from the first to the last
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    Offer(String),
+    Answer(String),
+    IceCandidate(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LinkMessage {
+    // first thing either end sends
+    Challenge { nonce: String },
+    // the answer to the other end's challenge
+    Hello { server_id: String, mac: String },
+    // every client of the sender on the shared maps
+    Digest { positions: Vec<ClientPosition> },
+    Relay { sender_id: String, target_id: String, signal: Signal },
+}
+
+struct Link {
+    // tells a link apart from the one that replaced it
+    link_id: u64,
+    // the server that opened it
+    initiator: String,
+    tx: mpsc::Sender<LinkMessage>,
+}
+
+struct Federation {
+    config: FederationConfig,
+    // open links by the other server's id
+    links: DashMap<String, Link>,
+    // client id to the server it is connected to, for every remote client with a position here.
+    // changed with the state write lock held, like the routing table
+    remote_clients: DashMap<String, String>,
+    next_link_id: AtomicU64,
+}
+
+static FEDERATION: OnceLock<Federation> = OnceLock::new();
+
+// starts listening for links and opening the configured ones
+pub fn init(config: FederationConfig, state: Arc<RwLock<ServerState>>) {
+    let listen_addr = config.listen_addr.clone();
+    let peers = config.peers.clone();
+    let federation = Federation { config, links: DashMap::new(), remote_clients: DashMap::new(), next_link_id: AtomicU64::new(0) };
+    if FEDERATION.set(federation).is_err() {
+        return;
+    }
+    if let Some(listen_addr) = listen_addr {
+        tokio::spawn(listen(listen_addr, Arc::clone(&state)));
+    }
+    for url in peers {
+        tokio::spawn(connect_loop(url, Arc::clone(&state)));
+    }
+}
+
+// whether positions on this map are exchanged
+pub fn shares_map(pos: &ClientPosition) -> bool {
+    FEDERATION.get().is_some_and(|federation| {
+        let config = &federation.config;
+        pos.game_id == config.game_id && (config.map_ids.is_empty() || config.map_ids.contains(&pos.map_id))
+    })
+}
+
+pub fn is_remote(client_id: &str) -> bool {
+    FEDERATION.get().is_some_and(|federation| federation.remote_clients.contains_key(client_id))
+}
+
+pub fn remote_count() -> usize {
+    FEDERATION.get().map_or(0, |federation| federation.remote_clients.len())
+}
+
+// the clients this server last sent
+pub fn remote_clients_of(server_id: &str) -> Vec<String> {
+    let Some(federation) = FEDERATION.get() else {
+        return Vec::new();
+    };
+    federation
+        .remote_clients
+        .iter()
+        .filter(|entry| entry.value() == server_id)
+        .map(|entry| entry.key().clone())
+        .collect()
+}
+
+// records the client as this server's; false when another server already has it
+pub fn claim_remote(client_id: &str, server_id: &str) -> bool {
+    let Some(federation) = FEDERATION.get() else {
+        return false;
+    };
+    let home = federation.remote_clients.entry(client_id.to_string()).or_insert_with(|| server_id.to_string());
+    *home == server_id
+}
+
+pub fn forget_remote(client_id: &str) {
+    if let Some(federation) = FEDERATION.get() {
+        federation.remote_clients.remove(client_id);
+    }
+}
+
+// hands a signal for a remote client to its server's link. false when the target isn't remote or
+// its link can't take more
+pub fn relay(sender_id: &str, target_id: &str, signal: Signal) -> bool {
+    let Some(federation) = FEDERATION.get() else {
+        return false;
+    };
+    let Some(server_id) = federation.remote_clients.get(target_id).map(|home| home.clone()) else {
+        return false;
+    };
+    let Some(link) = federation.links.get(&server_id) else {
+        return false;
+    };
+    let message = LinkMessage::Relay { sender_id: sender_id.to_string(), target_id: target_id.to_string(), signal };
+    if link.tx.try_send(message).is_err() {
+        warn!("Federation link to {} is backed up, dropping a signal from {} to {}", server_id, sender_id, target_id);
+        return false;
+    }
+    true
+}
+
+async fn listen(listen_addr: String, state: Arc<RwLock<ServerState>>) {
+    let listener = match TcpListener::bind(&listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Federation listener failed to bind {}: {}", listen_addr, e);
+            return;
+        }
+    };
+    info!("Federation listening on: {}", listen_addr);
+    loop {
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            match tokio_tungstenite::accept_async(stream).await {
+                Ok(ws) => {
+                    if let Err(e) = run_link(ws, false, state).await {
+                        info!("Federation link from {} closed: {}", addr, e);
+                    }
+                }
+                Err(e) => info!("Federation handshake with {} failed: {}", addr, e),
+            }
+        });
+    }
+}
+
+// keeps one link open to the url, reconnecting with backoff
+async fn connect_loop(url: String, state: Arc<RwLock<ServerState>>) {
+    let mut delay = RECONNECT_MIN;
+    loop {
+        match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((ws, _)) => {
+                info!("Federation link to {} connected", url);
+                let result = run_link(ws, true, Arc::clone(&state)).await;
+                // a link that got as far as carrying traffic starts the backoff over
+                if matches!(result, Ok(()) | Err(LinkError::Closed(_))) {
+                    delay = RECONNECT_MIN;
+                }
+                if let Err(e) = result {
+                    info!("Federation link to {} closed: {}", url, e);
+                }
+            }
+            Err(e) => info!("Federation link to {} failed: {}", url, e),
+        }
+        time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+    }
+}
+
+#[derive(Debug)]
+enum LinkError {
+    // before the other end was accepted
+    Handshake(String),
+    // after
+    Closed(String),
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Handshake(reason) => write!(f, "handshake failed: {}", reason),
+            LinkError::Closed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+async fn run_link<S>(mut ws: WebSocketStream<S>, initiated: bool, state: Arc<RwLock<ServerState>>) -> Result<(), LinkError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(federation) = FEDERATION.get() else {
+        return Ok(());
+    };
+    let config = &federation.config;
+    let server_id = match time::timeout(HANDSHAKE_TIMEOUT, authenticate(&mut ws, config)).await {
+        Ok(Ok(server_id)) => server_id,
+        Ok(Err(e)) => return Err(LinkError::Handshake(e)),
+        Err(_) => return Err(LinkError::Handshake("timed out".to_string())),
+    };
+
+    // servers that both list each other open two links; the one opened by the lower server id
+    // stays on both ends. a link opened by the same side as the existing one replaces it (that
+    // one is stale, its server reconnected)
+    let initiator = if initiated { config.server_id.clone() } else { server_id.clone() };
+    let preferred_initiator = config.server_id.as_str().min(server_id.as_str()).to_string();
+    let link_id = federation.next_link_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, mut rx) = mpsc::channel(LINK_QUEUE_LEN);
+    match federation.links.entry(server_id.clone()) {
+        dashmap::Entry::Occupied(mut existing) => {
+            if existing.get().initiator != initiator && initiator != preferred_initiator {
+                let _ = ws.close(None).await;
+                return Err(LinkError::Handshake(format!("already linked to {}", server_id)));
+            }
+            existing.insert(Link { link_id, initiator, tx });
+        }
+        dashmap::Entry::Vacant(vacant) => {
+            vacant.insert(Link { link_id, initiator, tx });
+        }
+    }
+    info!("Federated with {}", server_id);
+
+    let routing = Arc::clone(&metrics::timed_read(&state, "federation").await.routing);
+    let mut digest_ticker = time::interval(Duration::from_millis(config.digest_interval_ms));
+    let result = loop {
+        tokio::select! {
+            incoming = ws.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => break Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => break Err(LinkError::Closed(e.to_string())),
+                };
+                match serde_json::from_str::<LinkMessage>(&text) {
+                    Ok(LinkMessage::Digest { positions }) => {
+                        let positions: Vec<ClientPosition> = positions.into_iter().filter(shares_map).collect();
+                        let notifications = metrics::timed_write(&state, "federation").await.apply_federation_digest(&server_id, positions);
+                        send_nearby_updates(&state, notifications).await;
+                    }
+                    Ok(LinkMessage::Relay { sender_id, target_id, signal }) => {
+                        // only on behalf of the other server's own clients
+                        if federation.remote_clients.get(&sender_id).is_none_or(|home| *home != server_id) {
+                            continue;
+                        }
+                        let Some(target_tx) = routing.sender(&target_id) else {
+                            continue;
+                        };
+                        let message = match signal {
+                            Signal::Offer(offer) => ServerMessage::ReceiveOffer { sender_id, offer, relayed_at_ms: Some(db::unix_now_ms()) },
+                            Signal::Answer(answer) => ServerMessage::ReceiveAnswer { sender_id, answer, relayed_at_ms: Some(db::unix_now_ms()) },
+                            Signal::IceCandidate(candidate) => ServerMessage::ReceiveIceCandidate { sender_id, candidate },
+                        };
+                        let _ = target_tx.send(message).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Ignoring malformed federation message from {}: {}", server_id, e),
+                }
+            }
+            outgoing = rx.recv() => {
+                // the link was replaced
+                let Some(message) = outgoing else {
+                    break Ok(());
+                };
+                if let Err(e) = send(&mut ws, &message).await {
+                    break Err(LinkError::Closed(e));
+                }
+            }
+            _ = digest_ticker.tick() => {
+                let positions = metrics::timed_read(&state, "federation").await.federation_digest();
+                if let Err(e) = send(&mut ws, &LinkMessage::Digest { positions }).await {
+                    break Err(LinkError::Closed(e));
+                }
+            }
+        }
+    };
+
+    // a replacement link has the server's clients now
+    if federation.links.remove_if(&server_id, |_, link| link.link_id == link_id).is_some() {
+        info!("Federation with {} ended", server_id);
+        let notifications = metrics::timed_write(&state, "federation").await.forget_federated_server(&server_id);
+        send_nearby_updates(&state, notifications).await;
+    }
+    result
+}
+
+// exchanges challenges and hellos; returns the other server's id once its MAC checks out
+async fn authenticate<S>(ws: &mut WebSocketStream<S>, config: &FederationConfig) -> Result<String, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let nonce = Uuid::new_v4().simple().to_string();
+    send(ws, &LinkMessage::Challenge { nonce: nonce.clone() }).await?;
+    let mut answered = false;
+    loop {
+        let text = match ws.next().await {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | None => return Err("closed".to_string()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.to_string()),
+        };
+        match serde_json::from_str::<LinkMessage>(&text).map_err(|e| e.to_string())? {
+            LinkMessage::Challenge { nonce: their_nonce } if !answered => {
+                let mac = hex::encode(hello_mac(config, &their_nonce, &config.server_id).finalize().into_bytes());
+                send(ws, &LinkMessage::Hello { server_id: config.server_id.clone(), mac }).await?;
+                answered = true;
+            }
+            LinkMessage::Hello { server_id, mac } => {
+                let mac = hex::decode(mac).map_err(|_| "malformed mac".to_string())?;
+                hello_mac(config, &nonce, &server_id).verify_slice(&mac).map_err(|_| format!("{} doesn't know the shared secret", server_id))?;
+                if server_id == config.server_id {
+                    return Err("linked to itself".to_string());
+                }
+                // our Hello has to be out too before the other end can accept us
+                if !answered {
+                    return Err(format!("{} sent Hello before Challenge", server_id));
+                }
+                return Ok(server_id);
+            }
+            _ => return Err("unexpected message".to_string()),
+        }
+    }
+}
+
+fn hello_mac(config: &FederationConfig, nonce: &str, server_id: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.shared_secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(b"proxchat-federation\0");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\0");
+    mac.update(server_id.as_bytes());
+    mac
+}
+
+async fn send<S>(ws: &mut WebSocketStream<S>, message: &LinkMessage) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let text = serde_json::to_string(message).map_err(|e| e.to_string())?;
+    ws.send(Message::text(text)).await.map_err(|e| e.to_string())
+}
@@ -0,0 +1,67 @@
+use crate::config::ErrorFloodConfig;
+use tokio::time::{Duration, Instant};
+
+// per-connection error accounting over fixed windows. a client sending garbage gets a few
+// Error replies per window and then silence, and one that keeps sending malformed frames
+// is disconnected instead of costing a parse and a reply per frame
+pub struct ErrorBudget {
+    config: ErrorFloodConfig,
+    window_started: Instant,
+    errors_sent: u32,
+    parse_failures: u32,
+    binary_frames: u32,
+    suppressed: u32,
+}
+
+impl ErrorBudget {
+    pub fn new(config: ErrorFloodConfig) -> Self {
+        ErrorBudget {
+            config,
+            window_started: Instant::now(),
+            errors_sent: 0,
+            parse_failures: 0,
+            binary_frames: 0,
+            suppressed: 0,
+        }
+    }
+
+    fn roll_window(&mut self) {
+        if self.window_started.elapsed() >= Duration::from_secs(self.config.window_secs) {
+            self.window_started = Instant::now();
+            self.errors_sent = 0;
+            self.parse_failures = 0;
+            self.binary_frames = 0;
+        }
+    }
+
+    // whether another Error reply fits in this window; counts it if so
+    pub fn allow_error(&mut self) -> bool {
+        self.roll_window();
+        if self.errors_sent < self.config.max_errors {
+            self.errors_sent += 1;
+            true
+        } else {
+            self.suppressed += 1;
+            false
+        }
+    }
+
+    // count a malformed frame; true once the connection has sent too many this window
+    pub fn parse_failed(&mut self) -> bool {
+        self.roll_window();
+        self.parse_failures += 1;
+        self.config.max_parse_failures > 0 && self.parse_failures >= self.config.max_parse_failures
+    }
+
+    // count a binary frame on a JSON connection; true once it has sent too many this window
+    pub fn binary_frame(&mut self) -> bool {
+        self.roll_window();
+        self.binary_frames += 1;
+        self.config.max_binary_frames > 0 && self.binary_frames >= self.config.max_binary_frames
+    }
+
+    // Error replies dropped since the connection opened
+    pub fn suppressed(&self) -> u32 {
+        self.suppressed
+    }
+}
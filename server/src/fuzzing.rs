@@ -0,0 +1,47 @@
+// entry points for the cargo-fuzz targets in fuzz/ (the `fuzzing` feature). they run the same code
+// a real connection does, minus the sockets: frames go straight into serve_client and every reply
+// is dropped
+use crate::config::Config;
+use crate::db::Database;
+use crate::{codec, serve_client, ServerState};
+use futures_util::{future, sink, stream};
+use proxchat_protocol::ClientEnvelope;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub use codec::Encoding;
+pub use tokio_tungstenite::tungstenite::Message;
+
+pub fn decode(encoding: Encoding, frame: &Message) -> Option<Result<ClientEnvelope, String>> {
+    codec::decode(encoding, frame)
+}
+
+// a server with the default config and an empty in-memory database
+pub struct Server {
+    state: Arc<RwLock<ServerState>>,
+    db: Arc<Database>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        let db = Database::open(None).expect("in-memory database opens");
+        let state = ServerState::new(&Config::default(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        Server { state: Arc::new(RwLock::new(state)), db: Arc::new(db) }
+    }
+}
+
+impl Server {
+    // one client session per entry, all running concurrently (so relays and pairings between them
+    // happen); a session ends, and is cleaned up like a closed connection, after its last frame.
+    // needs a tokio runtime
+    pub async fn run_sessions(&self, sessions: Vec<(Encoding, Vec<Message>)>) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        future::join_all(sessions.into_iter().map(|(encoding, frames)| {
+            let frames_in = stream::iter(frames.into_iter().map(Ok::<_, Infallible>));
+            serve_client(Arc::clone(&self.state), Arc::clone(&self.db), addr, encoding, None, sink::drain(), frames_in)
+        }))
+        .await;
+    }
+}
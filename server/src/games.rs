@@ -0,0 +1,112 @@
+// per-game rules for positions: how coordinates are stored, which channels hear each other and
+// what counts as in range. the proximity code asks the adapter registered for a client's game_id,
+// so supporting a game with its own quirks is one GameAdapter impl registered in GameAdapters::new
+use crate::config::Config;
+use proxchat_protocol::{ClientMessage, ClientPosition, FLOAT_COORDINATE_SCALE};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub trait GameAdapter: Send + Sync {
+    // stored coordinate steps per unit of range
+    fn coordinate_scale(&self) -> f64 {
+        1.0
+    }
+
+    // whether the game sends UpdatePositionFloat rather than UpdatePosition
+    fn float_coordinates(&self) -> bool {
+        false
+    }
+
+    // the position as the server keeps it, applied to every update before it touches any state
+    fn normalize(&self, pos: ClientPosition) -> ClientPosition {
+        pos
+    }
+
+    // whether clients on these two channels can hear each other at all
+    fn same_channel(&self, a: &ClientPosition, b: &ClientPosition) -> bool {
+        a.channel == b.channel
+    }
+
+    // whether b is within `range` units of a. must be symmetric, or pairs flap
+    fn audible(&self, a: &ClientPosition, b: &ClientPosition, range: f32) -> bool {
+        a.distance_squared(b) <= (f64::from(range) * self.coordinate_scale()).powi(2)
+    }
+
+    // how loud b should play for a, from 0 to 1, for a pair within `range`; None leaves it to the client
+    fn attenuation(&self, _a: &ClientPosition, _b: &ClientPosition, _range: f32) -> Option<f32> {
+        None
+    }
+}
+
+// integer tiles, one channel each: the original rules
+pub struct Standard;
+
+impl GameAdapter for Standard {}
+
+// float positions kept in thousandths (see float_coordinate_games)
+pub struct FloatCoordinates;
+
+impl GameAdapter for FloatCoordinates {
+    fn coordinate_scale(&self) -> f64 {
+        FLOAT_COORDINATE_SCALE
+    }
+
+    fn float_coordinates(&self) -> bool {
+        true
+    }
+}
+
+// the adapter for each game_id; games without one get the fallback (Standard, unless a plugin
+// decides for every game)
+pub struct GameAdapters {
+    games: HashMap<i32, Arc<dyn GameAdapter>>,
+    fallback: Arc<dyn GameAdapter>,
+}
+
+impl GameAdapters {
+    pub fn new(config: &Config) -> Self {
+        let mut games: HashMap<i32, Arc<dyn GameAdapter>> = HashMap::new();
+        for game_id in &config.float_coordinate_games {
+            games.insert(*game_id, Arc::new(FloatCoordinates));
+        }
+        #[allow(unused_mut)]
+        let mut fallback: Arc<dyn GameAdapter> = Arc::new(Standard);
+        // the plugin goes on top of whatever the game would use without it
+        #[cfg(feature = "wasm-plugins")]
+        if let Some(plugin) = crate::plugin::get() {
+            if plugin.game_ids().is_empty() {
+                for adapter in games.values_mut() {
+                    *adapter = Arc::new(crate::plugin::PluginAdapter::new(plugin, Arc::clone(adapter)));
+                }
+                fallback = Arc::new(crate::plugin::PluginAdapter::new(plugin, fallback));
+            } else {
+                for game_id in plugin.game_ids() {
+                    let inner = games.get(game_id).map_or_else(|| Arc::clone(&fallback), Arc::clone);
+                    games.insert(*game_id, Arc::new(crate::plugin::PluginAdapter::new(plugin, inner)));
+                }
+            }
+        }
+        GameAdapters { games, fallback }
+    }
+
+    pub fn get(&self, game_id: i32) -> &dyn GameAdapter {
+        self.games.get(&game_id).unwrap_or(&self.fallback).as_ref()
+    }
+
+    // a position as the server takes it, whichever way it came in (websocket or UDP fast path):
+    // each game takes only the payload it is configured for, float positions are fixed point from
+    // here on, and every position is normalized by its game's adapter. other messages pass through
+    pub fn admit(&self, message: ClientMessage) -> Result<ClientMessage, String> {
+        match message {
+            ClientMessage::UpdatePositionFloat(pos) if self.get(pos.game_id).float_coordinates() => {
+                Ok(ClientMessage::UpdatePosition(self.get(pos.game_id).normalize(pos.to_fixed())))
+            }
+            ClientMessage::UpdatePositionFloat(pos) => Err(format!("Game {} uses integer coordinates: send UpdatePosition", pos.game_id)),
+            ClientMessage::UpdatePosition(pos) if self.get(pos.game_id).float_coordinates() => {
+                Err(format!("Game {} uses float coordinates: send UpdatePositionFloat", pos.game_id))
+            }
+            ClientMessage::UpdatePosition(pos) => Ok(ClientMessage::UpdatePosition(self.get(pos.game_id).normalize(pos))),
+            message => Ok(message),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::metrics;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+// country lookups for incoming connections from a MaxMind GeoIP2/GeoLite2 Country or City database
+// (the geoip feature): every connection is counted by country, and refused when the allow/deny
+// lists say so. addresses the database doesn't place (private ranges, new allocations) are "unknown"
+#[cfg_attr(not(feature = "geoip"), allow(dead_code))]
+struct Policy {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>,
+    allow_countries: Vec<String>,
+    deny_countries: Vec<String>,
+    allow_unknown: bool,
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+#[cfg(feature = "geoip")]
+pub fn init(config: &crate::config::GeoIpConfig) -> Result<(), String> {
+    let reader = maxminddb::Reader::open_readfile(&config.database_path)
+        .map_err(|e| format!("Failed to open GeoIP database {}: {}", config.database_path, e))?;
+    log::info!(
+        "GeoIP database loaded: {} ({}, {} allowed and {} denied countries)",
+        config.database_path,
+        reader.metadata.database_type,
+        config.allow_countries.len(),
+        config.deny_countries.len()
+    );
+    let _ = POLICY.set(Policy {
+        reader,
+        allow_countries: config.allow_countries.iter().map(|code| code.to_ascii_uppercase()).collect(),
+        deny_countries: config.deny_countries.iter().map(|code| code.to_ascii_uppercase()).collect(),
+        allow_unknown: config.allow_unknown,
+    });
+    Ok(())
+}
+
+impl Policy {
+    // ISO 3166-1 alpha-2 code, falling back to where the block is registered
+    #[cfg(feature = "geoip")]
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let record = self.reader.lookup::<maxminddb::geoip2::Country>(ip).ok()?;
+        let country = record.country.and_then(|country| country.iso_code);
+        let registered = record.registered_country.and_then(|country| country.iso_code);
+        country.or(registered).map(str::to_string)
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+
+    fn admits(&self, country: Option<&str>) -> bool {
+        match country {
+            Some(code) => {
+                let listed = |list: &[String]| list.iter().any(|listed| listed == code);
+                !listed(&self.deny_countries) && (self.allow_countries.is_empty() || listed(&self.allow_countries))
+            }
+            None => self.allow_unknown,
+        }
+    }
+}
+
+// whether a connection from this address may go ahead; always true without a loaded database
+pub fn admit(ip: IpAddr) -> bool {
+    let Some(policy) = POLICY.get() else {
+        return true;
+    };
+    let country = policy.country(ip);
+    let admitted = policy.admits(country.as_deref());
+    let outcome = if admitted { "accepted" } else { "refused" };
+    metrics::GEOIP_CONNECTIONS.with_label_values(&[country.as_deref().unwrap_or("unknown"), outcome]).inc();
+    admitted
+}
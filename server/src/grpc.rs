@@ -0,0 +1,123 @@
+// the admin operations over gRPC (config grpc_addr, proto/admin.proto), for orchestration tooling
+// that wants a typed interface. shares admin_token with the HTTP admin API, sent as
+// `authorization: Bearer <token>` metadata. plain HTTP/2 only: keep it on loopback or a private network
+use crate::config::Config;
+use crate::db::Database;
+use crate::{admin, metrics, ServerState};
+use log::{error, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tonic::{Request, Response, Status};
+
+pub mod pb {
+    tonic::include_proto!("proxchat.admin.v1");
+}
+
+use pb::admin_server::{Admin, AdminServer};
+
+struct AdminService {
+    server: Arc<RwLock<ServerState>>,
+    config: Arc<Config>,
+    db: Arc<Database>,
+}
+
+pub async fn serve(config: Config, server: Arc<RwLock<ServerState>>, db: Arc<Database>) {
+    let Some(addr) = config.grpc_addr.clone() else {
+        return;
+    };
+    let socket_addr: SocketAddr = match addr.parse() {
+        Ok(socket_addr) => socket_addr,
+        Err(e) => {
+            error!("Not starting the gRPC admin interface, invalid grpc_addr {}: {}", addr, e);
+            return;
+        }
+    };
+    if config.admin_token.is_none() {
+        warn!("gRPC admin interface on {} has no admin_token configured - anyone who can reach it has full access", addr);
+    }
+    if !socket_addr.ip().is_loopback() {
+        warn!("gRPC admin interface on {} is unencrypted - keep it off networks you don't trust", addr);
+    }
+    let token = config.admin_token.clone();
+    let service = AdminService { server, config: Arc::new(config), db };
+    let service = AdminServer::with_interceptor(service, move |request: Request<()>| {
+        if let Some(token) = &token {
+            let provided = request
+                .metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            if !admin::token_matches(provided, token) {
+                return Err(Status::unauthenticated("Missing or invalid admin token"));
+            }
+        }
+        Ok(request)
+    });
+
+    info!("gRPC admin interface listening on: {}", addr);
+    if let Err(e) = tonic::transport::Server::builder().add_service(service).serve(socket_addr).await {
+        error!("gRPC admin interface stopped: {}", e);
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn list_clients(&self, request: Request<pb::ListClientsRequest>) -> Result<Response<pb::ListClientsReply>, Status> {
+        let state = metrics::timed_read(&self.server, "admin").await;
+        let clients = state
+            .client_summaries(request.into_inner().game_id)
+            .into_iter()
+            .map(|client| pb::Client {
+                client_id: client.client_id,
+                connection_id: client.connection_id.unwrap_or_default(),
+                addr: client.addr.map(|addr| addr.to_string()).unwrap_or_default(),
+                game_id: client.game_id,
+                map_id: client.map_id,
+                channel: client.channel,
+                x: client.x,
+                y: client.y,
+                peers: client.peers as u32,
+                last_update_secs: client.last_update_secs,
+                partition: client.partition,
+                region: client.region,
+            })
+            .collect();
+        Ok(Response::new(pb::ListClientsReply { clients }))
+    }
+
+    async fn kick(&self, request: Request<pb::KickRequest>) -> Result<Response<pb::KickReply>, Status> {
+        let client_id = request.into_inner().client_id;
+        let kicked = metrics::timed_read(&self.server, "admin").await.kick(&client_id).is_some();
+        if kicked {
+            info!("Admin kicked {}", client_id);
+        }
+        Ok(Response::new(pb::KickReply { kicked }))
+    }
+
+    async fn ban(&self, request: Request<pb::BanRequest>) -> Result<Response<pb::BanReply>, Status> {
+        let ban = request.into_inner();
+        let (ban_id, kicked) = admin::ban_and_kick(&self.server, &self.db, ban.client_id.as_deref(), ban.ip.as_deref(), &ban.reason, ban.duration_secs)
+            .await
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(pb::BanReply { ban_id, kicked: kicked as u32 }))
+    }
+
+    async fn get_stats(&self, _request: Request<pb::StatsRequest>) -> Result<Response<pb::StatsReply>, Status> {
+        let stats = metrics::timed_read(&self.server, "admin").await.stats();
+        Ok(Response::new(pb::StatsReply {
+            uptime_secs: stats.uptime_secs,
+            connections: stats.connections as u64,
+            registered_clients: stats.registered_clients as u64,
+            pairs: stats.pairs as u64,
+            restored_clients: stats.restored_clients as u64,
+        }))
+    }
+
+    async fn set_config_override(&self, request: Request<pb::ConfigOverrideRequest>) -> Result<Response<pb::ConfigOverrideReply>, Status> {
+        let config_override = request.into_inner();
+        let value = serde_json::from_str(&config_override.value_json).map_err(|e| Status::invalid_argument(format!("value_json: {}", e)))?;
+        admin::set_config_override(&self.config, &self.db, &config_override.key, &value).await.map_err(Status::invalid_argument)?;
+        Ok(Response::new(pb::ConfigOverrideReply {}))
+    }
+}
@@ -0,0 +1,340 @@
+use crate::codec::Encoding;
+use crate::config::TrustedProxyConfig;
+use log::warn;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+pub use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+
+// how much of a request head we look at before leaving it to tungstenite, and how long we wait for it
+const MAX_REQUEST_HEAD: usize = 8192;
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+// plain GETs (and HEADs) of these paths are load balancer and uptime checks, answered 200
+const HEALTH_PATHS: &[&str] = &["/health", "/healthz", "/livez", "/readyz", "/ping"];
+
+// bits of paths only vulnerability scanners ask a signaling server for
+const SCANNER_MARKERS: &[&str] = &[".php", ".env", ".git", ".aws", "wp-", "cgi-bin", "phpmyadmin", "actuator", "/admin", "..", "%2e"];
+
+// the first byte of a TLS record carrying a handshake, i.e. a ClientHello
+const TLS_HANDSHAKE: u8 = 0x16;
+
+pub use proxchat_protocol::{SUBPROTOCOL_V1, SUBPROTOCOL_V1_CBOR, SUBPROTOCOL_V1_PROTO};
+
+// what a connection agreed to during the upgrade
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireProtocol {
+    // negotiated proxchat.v1 via Sec-WebSocket-Protocol
+    V1,
+    // proxchat.v1 messages encoded as CBOR in binary frames
+    V1Cbor,
+    // proxchat.v1 messages as protobuf envelopes in binary frames
+    V1Proto,
+    // no subprotocol offered (clients predating negotiation), treated as v1 JSON
+    Legacy,
+}
+
+impl WireProtocol {
+    fn token(self) -> Option<&'static str> {
+        match self {
+            WireProtocol::V1 => Some(SUBPROTOCOL_V1),
+            WireProtocol::V1Cbor => Some(SUBPROTOCOL_V1_CBOR),
+            WireProtocol::V1Proto => Some(SUBPROTOCOL_V1_PROTO),
+            WireProtocol::Legacy => None,
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token {
+            SUBPROTOCOL_V1 => Some(WireProtocol::V1),
+            SUBPROTOCOL_V1_CBOR => Some(WireProtocol::V1Cbor),
+            SUBPROTOCOL_V1_PROTO => Some(WireProtocol::V1Proto),
+            _ => None,
+        }
+    }
+
+    pub fn encoding(self) -> Encoding {
+        match self {
+            WireProtocol::V1 | WireProtocol::Legacy => Encoding::Json,
+            WireProtocol::V1Cbor => Encoding::Cbor,
+            WireProtocol::V1Proto => Encoding::Protobuf,
+        }
+    }
+}
+
+// pick a subprotocol from the client's Sec-WebSocket-Protocol offer.
+// clients that offer nothing are accepted as legacy when allowed; clients that offer
+// only protocols we don't speak are always refused, since they expect something else.
+#[allow(clippy::result_large_err)] // ErrorResponse is tungstenite's handshake callback type
+pub fn negotiate_subprotocol(
+    request: &Request,
+    mut response: Response,
+    allow_legacy: bool,
+) -> Result<(Response, WireProtocol), ErrorResponse> {
+    let offered: Vec<String> = request
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    // clients list protocols in preference order, so take the first one we speak
+    if let Some(protocol) = offered.iter().find_map(|token| WireProtocol::from_token(token)) {
+        if let Some(token) = protocol.token() {
+            response
+                .headers_mut()
+                .insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(token));
+        }
+        return Ok((response, protocol));
+    }
+
+    if offered.is_empty() && allow_legacy {
+        return Ok((response, WireProtocol::Legacy));
+    }
+
+    let reason = if offered.is_empty() {
+        format!("Sec-WebSocket-Protocol {} is required", SUBPROTOCOL_V1)
+    } else {
+        format!("Unsupported subprotocol(s) {}; this server speaks {}, {}, {}",
+                offered.join(", "), SUBPROTOCOL_V1, SUBPROTOCOL_V1_CBOR, SUBPROTOCOL_V1_PROTO)
+    };
+    warn!("Rejecting WebSocket upgrade: {}", reason);
+    Err(error_response(StatusCode::BAD_REQUEST, reason))
+}
+
+pub fn error_response(status: StatusCode, body: String) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(body));
+    *response.status_mut() = status;
+    response
+}
+
+// browsers always send Origin and native clients never do, so requests without one are allowed.
+// an empty allowlist accepts every origin; "*" in the list does the same explicitly.
+pub fn origin_allowed(origin: Option<&str>, allowed_origins: &[String]) -> bool {
+    match origin {
+        None => true,
+        Some(origin) => {
+            allowed_origins.is_empty()
+                || allowed_origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)] // ErrorResponse is tungstenite's handshake callback type
+pub fn check_origin(request: &Request, allowed_origins: &[String]) -> Result<(), ErrorResponse> {
+    let origin = request.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok());
+    if origin_allowed(origin, allowed_origins) {
+        return Ok(());
+    }
+    warn!("Rejecting WebSocket upgrade from disallowed origin {}", origin.unwrap_or_default());
+    Err(error_response(StatusCode::FORBIDDEN, "Origin not allowed".to_string()))
+}
+
+// who the trusted proxy says is connecting
+#[derive(Debug, Clone)]
+pub struct ProxyIdentity {
+    pub user_id: String,
+    pub region: Option<String>,
+}
+
+// with trusted_proxy set, only the proxy may upgrade, and only for a user it authenticated
+#[allow(clippy::result_large_err)] // ErrorResponse is tungstenite's handshake callback type
+pub fn check_proxy_identity(request: &Request, addr: SocketAddr, config: &TrustedProxyConfig) -> Result<ProxyIdentity, ErrorResponse> {
+    let from_proxy = config.proxy_addrs.iter().any(|proxy| proxy.parse::<IpAddr>().is_ok_and(|proxy| proxy.to_canonical() == addr.ip().to_canonical()));
+    if !from_proxy {
+        warn!("Rejecting WebSocket upgrade from {}: not the trusted proxy", addr);
+        return Err(error_response(StatusCode::FORBIDDEN, "Connect through this server's proxy".to_string()));
+    }
+    let header_value = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    };
+    let Some(user_id) = header_value(&config.user_id_header) else {
+        warn!("Rejecting WebSocket upgrade from the proxy at {}: no {} header", addr, config.user_id_header);
+        return Err(error_response(StatusCode::UNAUTHORIZED, "Not logged in".to_string()));
+    };
+    Ok(ProxyIdentity {
+        user_id: user_id.to_string(),
+        region: header_value(&config.region_header).map(str::to_string),
+    })
+}
+
+// the parts of a plain HTTP request we need to answer it ourselves
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub origin: Option<String>,
+    pub is_upgrade: bool,
+}
+
+// what a fresh connection opened with
+pub enum Sniffed {
+    Http(RequestHead),
+    // a TLS ClientHello: a wss:// url pointed at this plain listener with no TLS terminator in front
+    Tls,
+    // bytes that aren't HTTP at all
+    NotHttp,
+    // closed without sending anything, like TCP port checks do
+    Empty,
+    // no complete head in time, or one too big to look at: tungstenite gets the stream as before
+    // and reports the failure itself
+    Unknown,
+}
+
+// the requests that aren't websocket upgrades, by who is likely asking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Health,
+    Preflight,
+    PlainHttp,
+    Scanner,
+}
+
+impl RequestHead {
+    pub fn kind(&self) -> RequestKind {
+        let path = self.path.split('?').next().unwrap_or_default();
+        let lowercase_path = path.to_ascii_lowercase();
+        if !matches!(self.method.as_str(), "GET" | "HEAD" | "OPTIONS" | "POST")
+            || !path.starts_with('/')
+            || SCANNER_MARKERS.iter().any(|marker| lowercase_path.contains(marker))
+        {
+            RequestKind::Scanner
+        } else if self.method == "OPTIONS" {
+            RequestKind::Preflight
+        } else if matches!(self.method.as_str(), "GET" | "HEAD") && HEALTH_PATHS.contains(&path) {
+            RequestKind::Health
+        } else {
+            RequestKind::PlainHttp
+        }
+    }
+}
+
+impl RequestKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestKind::Health => "health",
+            RequestKind::Preflight => "preflight",
+            RequestKind::PlainHttp => "plain_http",
+            RequestKind::Scanner => "scanner",
+        }
+    }
+}
+
+// look at (without consuming) what a fresh connection sends first, so requests that aren't
+// websocket upgrades can get a proper HTTP answer and other traffic can be told apart
+pub async fn sniff(stream: &TcpStream) -> Sniffed {
+    let mut buffer = vec![0u8; MAX_REQUEST_HEAD];
+    let deadline = Instant::now() + REQUEST_HEAD_TIMEOUT;
+    let mut peeked = 0;
+    loop {
+        let len = match tokio::time::timeout_at(deadline, stream.peek(&mut buffer)).await {
+            Ok(Ok(0)) if peeked == 0 => return Sniffed::Empty,
+            Ok(Ok(len)) if len > 0 => len,
+            _ => return Sniffed::Unknown,
+        };
+        if buffer[0] == TLS_HANDSHAKE {
+            return Sniffed::Tls;
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buffer[..len]) {
+            Ok(httparse::Status::Complete(_)) => {
+                let header_value = |name: &str| {
+                    request
+                        .headers
+                        .iter()
+                        .find(|h| h.name.eq_ignore_ascii_case(name))
+                        .and_then(|h| std::str::from_utf8(h.value).ok())
+                };
+                return Sniffed::Http(RequestHead {
+                    method: request.method.unwrap_or_default().to_string(),
+                    path: request.path.unwrap_or_default().to_string(),
+                    origin: header_value("origin").map(str::to_string),
+                    is_upgrade: header_value("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket")),
+                });
+            }
+            Ok(httparse::Status::Partial) if len < buffer.len() => {}
+            Ok(httparse::Status::Partial) => return Sniffed::Unknown,
+            Err(_) => return Sniffed::NotHttp,
+        }
+        // peek returns straight away while unread data is waiting, so back off until more arrives
+        if len == peeked {
+            if Instant::now() >= deadline {
+                return Sniffed::Unknown;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        peeked = len;
+    }
+}
+
+// answer a request that isn't a websocket upgrade: CORS preflights, health checks and HEAD probes
+// succeed, scanners get a bare 404 and anything else is told what this port expects. returns the
+// status sent
+pub async fn answer_plain_http(stream: &mut TcpStream, head: &RequestHead, allowed_origins: &[String]) -> StatusCode {
+    let origin = head.origin.as_deref();
+    let mut headers = Vec::new();
+    if let Some(origin) = origin.filter(|_| origin_allowed(origin, allowed_origins)) {
+        headers.push(format!("Access-Control-Allow-Origin: {}", origin));
+        headers.push("Vary: Origin".to_string());
+    }
+
+    let (status, body) = if !origin_allowed(origin, allowed_origins) {
+        (StatusCode::FORBIDDEN, "Origin not allowed\n".to_string())
+    } else if head.kind() == RequestKind::Scanner {
+        (StatusCode::NOT_FOUND, String::new())
+    } else if head.kind() == RequestKind::Health {
+        (StatusCode::OK, "ok\n".to_string())
+    } else {
+        match head.method.as_str() {
+            "OPTIONS" => {
+                headers.push("Access-Control-Allow-Methods: GET, HEAD, OPTIONS".to_string());
+                headers.push("Access-Control-Allow-Headers: Sec-WebSocket-Protocol".to_string());
+                headers.push("Access-Control-Max-Age: 86400".to_string());
+                (StatusCode::NO_CONTENT, String::new())
+            }
+            "HEAD" => (StatusCode::OK, String::new()),
+            "GET" => {
+                headers.push("Upgrade: websocket".to_string());
+                let body = format!(
+                    "This is a proxchat signaling endpoint. Connect with a WebSocket client offering one of: {}, {}, {}\n",
+                    SUBPROTOCOL_V1, SUBPROTOCOL_V1_CBOR, SUBPROTOCOL_V1_PROTO
+                );
+                (StatusCode::UPGRADE_REQUIRED, body)
+            }
+            _ => {
+                headers.push("Allow: GET, HEAD, OPTIONS".to_string());
+                (StatusCode::METHOD_NOT_ALLOWED, format!("Method {} not allowed\n", head.method))
+            }
+        }
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        body.len()
+    );
+    for header in headers {
+        response.push_str(&header);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    if head.method != "HEAD" {
+        response.push_str(&body);
+    }
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        warn!("Failed to answer HTTP {} request: {}", head.method, e);
+    }
+    let _ = stream.shutdown().await;
+    status
+}
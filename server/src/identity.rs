@@ -0,0 +1,17 @@
+use sha2::{Digest, Sha256};
+
+// accepted encoded key sizes: a raw ed25519/x25519 key up to a DER-encoded RSA-4096 public key
+const MIN_KEY_BYTES: usize = 32;
+const MAX_KEY_BYTES: usize = 1024;
+
+// fingerprint of a hex-encoded identity public key, in the same form as an SDP
+// a=fingerprint line (sha-256, uppercase hex pairs joined by ':') so users can read it out
+// and compare it over another channel. the server never interprets the key itself
+pub fn fingerprint(public_key_hex: &str) -> Result<String, String> {
+    let key = hex::decode(public_key_hex).map_err(|_| "Identity key must be hex-encoded".to_string())?;
+    if !(MIN_KEY_BYTES..=MAX_KEY_BYTES).contains(&key.len()) {
+        return Err(format!("Identity key must be {}-{} bytes", MIN_KEY_BYTES, MAX_KEY_BYTES));
+    }
+    let digest = Sha256::digest(&key);
+    Ok(digest.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":"))
+}
@@ -0,0 +1,3465 @@
+mod activation;
+mod admin;
+mod admin_cli;
+mod audit;
+mod backoff;
+mod chaos;
+mod check;
+mod codec;
+mod config;
+mod crash_reports;
+mod db;
+mod duplicates;
+mod echo;
+mod federation;
+mod flood;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod games;
+mod geoip;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handshake;
+mod identity;
+mod logfile;
+mod mapevents;
+mod metrics;
+mod names;
+mod occlusion;
+mod offers;
+mod outbound;
+mod overload;
+mod pairgraph;
+mod pairs;
+mod partitions;
+#[cfg(feature = "wasm-plugins")]
+mod plugin;
+mod proto;
+mod routing;
+mod talkers;
+#[cfg(test)]
+mod tests;
+mod registry;
+#[cfg(feature = "turn-server")]
+mod relay;
+mod replay;
+mod schema;
+mod schedule;
+mod scripting;
+mod sfu;
+mod signing;
+mod simulate;
+mod snapshot;
+mod storm;
+mod stun;
+mod systemd;
+#[cfg(feature = "soak")]
+mod soak;
+mod spectators;
+mod tags;
+mod tls;
+mod topology;
+mod trace;
+mod turn;
+mod udp;
+mod usage;
+mod zones;
+#[cfg(feature = "webtransport")]
+mod webtransport;
+
+use audit::AuditEvent;
+use config::PositionPrivacy;
+use db::Database;
+use offers::OfferVerdict;
+use overload::{LoadSignals, OverloadDetector, ShedLevel};
+use proxchat_protocol::{
+    ClientEnvelope, ClientMessage, ClientPosition, ClientPreferences, CloseReason, DistanceBucket, ElevatedConsent, ElevatedRole,
+    NatType, NearbyPeer, PopulationEntry, ServerMessage, PROTOCOL_VERSION, SUBPROTOCOL_V1_CBOR, SUBPROTOCOL_V1_PROTO,
+};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+// longest mute list kept per client; anything beyond is ignored
+const MAX_MUTED_PEERS: usize = 500;
+
+// longest vehicle id accepted in SetVehicle
+const MAX_VEHICLE_ID_LEN: usize = 64;
+
+// how long a closing connection's send task may spend flushing what is left in its queue
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+// how often the maintenance loop runs (timeouts, sweeps, scheduled changes, reintroductions)
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5);
+
+// how often NearbyPeers updates held back during a reconnect storm are checked for being due
+const DEFERRED_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+// one entry in a client's position history, kept for moderators
+#[derive(Debug, Clone, Serialize)]
+struct PositionRecord {
+    map_id: i32,
+    x: i64,
+    y: i64,
+    channel: i32,
+    recorded_at: i64, // unix seconds
+}
+
+// a connected client as the admin interfaces list it
+#[derive(Debug, Clone, Serialize)]
+struct ClientSummary {
+    client_id: String,
+    connection_id: Option<String>,
+    addr: Option<SocketAddr>,
+    game_id: i32,
+    map_id: i32,
+    channel: i32,
+    x: i64,
+    y: i64,
+    peers: usize,
+    last_update_secs: u64,
+    // audio partition of its map; above 0 an overflow one
+    partition: u32,
+    // as the trusted proxy gave it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ServerStats {
+    uptime_secs: u64,
+    connections: usize,
+    registered_clients: usize,
+    // each pair counted once
+    pairs: usize,
+    // restored from a state snapshot and not back yet
+    restored_clients: usize,
+}
+
+// orphaned entries found across the per-client maps by the consistency checker.
+// every field should be zero; anything else means a cleanup path raced or was missed.
+#[derive(Debug, Clone, Default, Serialize)]
+struct ConsistencyReport {
+    positions_without_route: usize,
+    routes_without_connection: usize,
+    update_times_without_position: usize,
+    positions_without_update_time: usize,
+    nearby_lists_without_position: usize,
+    asymmetric_pairs: usize,
+}
+
+impl ConsistencyReport {
+    fn total(&self) -> usize {
+        self.positions_without_route
+            + self.routes_without_connection
+            + self.update_times_without_position
+            + self.positions_without_update_time
+            + self.nearby_lists_without_position
+            + self.asymmetric_pairs
+    }
+}
+
+// shared state between all connections
+struct ServerState {
+    // separate position data from connection channels
+    positions: HashMap<String, ClientPosition>,
+    // cache last sent nearby lists to avoid redundant updates
+    last_nearby_lists: HashMap<String, HashSet<String>>,
+    // clients whose latest NearbyPeers listed anyone: the first empty one sent them for a pairing
+    // change comes with AllPeersGone. behind its own lock, since lists go out under the read lock
+    listed_peers: std::sync::Mutex<HashSet<String>>,
+    // connection_id (server-generated UUID) to its outbound queue, and client_id (client-provided
+    // GUID) to connection_id. shared outside the lock so relays can read it without waiting
+    routing: Arc<routing::RoutingTable>,
+    // frames and bytes received per connection, also counted outside the lock
+    inbound_traffic: Arc<talkers::InboundTraffic>,
+    // recently relayed offers, shared outside the lock like the routing table
+    offers: Arc<offers::RecentOffers>,
+    last_update_time: HashMap<String, Instant>,
+    // short ring buffer of recent distinct positions per client, kept after disconnect
+    // until it ages out so reports can still be investigated
+    position_history: HashMap<String, VecDeque<PositionRecord>>,
+    position_history_len: usize,
+    position_history_retention_secs: i64,
+    preferences: HashMap<String, ClientPreferences>,
+    position_privacy: PositionPrivacy,
+    // result of the most recent orphan sweep, and how many entries sweeps have repaired in total
+    last_consistency_report: ConsistencyReport,
+    orphans_repaired_total: u64,
+    // UDP fast-path sessions by session id, and the port they send to (None when disabled)
+    udp_sessions: HashMap<u32, udp::UdpSession>,
+    udp_port: Option<u16>,
+    // echo tests in progress by client_id, and the ICE servers new ones use
+    echo_sessions: HashMap<String, echo::EchoSession>,
+    echo_ice_servers: Vec<String>,
+    // announcer zones that haven't ended, and the speakers of the live ones by zone id.
+    // a zone only pairs once the maintenance loop has marked it live
+    announcer_zones: Vec<zones::AnnouncerZone>,
+    live_zone_speakers: HashMap<i64, String>,
+    // scheduled events that haven't ended, and copies of the running ones by id; like zones,
+    // an event only applies once the maintenance loop has started it
+    scheduled_events: Vec<schedule::ScheduledEvent>,
+    live_events: HashMap<i64, schedule::ScheduledEvent>,
+    // ranges, peer cap and reintroduction interval; starts from the config, adjustable via the admin API
+    proximity: config::ProximityConfig,
+    map_ranges: Vec<config::MapRanges>,
+    partitions: partitions::MapPartitions,
+    // position rules per game_id
+    games: Arc<games::GameAdapters>,
+    // wall grids by (game_id, map_id), for line-of-sight occlusion
+    collision_grids: HashMap<(i32, i32), occlusion::CollisionGrid>,
+    shared_areas: Vec<config::SharedArea>,
+    audio_regions: Vec<config::AudioRegion>,
+    directional_audio: Option<config::DirectionalAudioConfig>,
+    // listen-only spectators by client id, as the admin API designated them
+    spectators: HashMap<String, spectators::Spectator>,
+    spectators_config: config::SpectatorsConfig,
+    // the refusals clients stored with SetElevatedConsent (from the database, so also of clients
+    // not connected); everyone else allows everything
+    elevated_consents: HashMap<String, ElevatedConsent>,
+    // who each client has muted, so the muted side can be told (and stop sending audio nobody plays)
+    mutes: HashMap<String, HashSet<String>>,
+    // validated self-assigned tags, delivered to peers in NearbyPeerDetails
+    tags: HashMap<String, BTreeMap<String, String>>,
+    // the vehicle each client is aboard; riders of one count as co-located whatever their
+    // coordinates say, since transports in some games desync their passengers' positions
+    vehicles: HashMap<String, String>,
+    name_filter: names::NameFilter,
+    // fingerprints of the identity keys clients registered this session
+    identity_fingerprints: HashMap<String, String>,
+    require_message_signing: bool,
+    turn: Option<config::TurnConfig>,
+    // ICE failures reported by (reporter, peer); enough on either side gets the pair ForceRelay
+    ice_failures: HashMap<(String, String), u32>,
+    // self-reported NAT types, shared with peers so they can pick an ICE strategy
+    nat_types: HashMap<String, NatType>,
+    // send PeerAddresses to clients sharing their public address
+    reflexive_address_hints: bool,
+    error_flood: config::ErrorFloodConfig,
+    accept_unversioned_messages: bool,
+    // the unanswered Ping per client (nonce, when it was queued), and the smoothed round trip
+    // of every client that has answered one
+    rtt_probes: HashMap<String, (u64, Instant)>,
+    rtts: HashMap<String, Duration>,
+    next_ping_nonce: u64,
+    // the latest ReportNetworkQuality of each client, as (rtt_ms, packet_loss_percent); only kept
+    // when network_quality is configured
+    network_quality: Option<config::NetworkQualityConfig>,
+    network_reports: HashMap<String, (u32, f32)>,
+    // addresses kept off after crashing a connection handler, until when
+    panic_reconnect_cooldown: Option<Duration>,
+    panic_cooldowns: HashMap<IpAddr, Instant>,
+    outbound_queue: config::OutboundQueueConfig,
+    registration_timeout: Duration,
+    registration_backoff: Option<backoff::RegistrationBackoff>,
+    duplicates: Option<duplicates::DuplicateAccounts>,
+    storm: storm::StormAbsorber,
+    // clients restored from a state snapshot that haven't reconnected yet. they keep their
+    // position and pairs without a route until they do, or time out
+    restored: HashSet<String>,
+    // fake clients a position trace is playing (config position_trace); they have no route either
+    trace_clients: HashSet<String>,
+    // the region the trusted proxy gave for each client it had one for
+    regions: HashMap<String, String>,
+    // the SessionToken each registered client was given, which a reconnect presents to take its
+    // registration over instead of starting again
+    session_tokens: HashMap<String, String>,
+    // how far each pair's connection has got, and how failed ones are retried (None: they aren't).
+    // shared outside the lock so signaling relay can move pairs on without waiting
+    pairs: Arc<pairs::PairTracker>,
+    pair_retry: Option<config::PairRetryConfig>,
+    // hubs and leaves of the dense clusters, rebalanced by the maintenance loop
+    topology: topology::Topology,
+    topology_hints: Option<config::TopologyHintsConfig>,
+    // which clients are in an sfu area, and the SFU of each area with any
+    sfu: sfu::Sfu,
+    started_at: Instant,
+}
+
+impl ServerState {
+    fn new(
+        config: &config::Config,
+        announcer_zones: Vec<zones::AnnouncerZone>,
+        scheduled_events: Vec<schedule::ScheduledEvent>,
+        collision_grids: Vec<occlusion::CollisionGrid>,
+        elevated_consents: Vec<(String, ElevatedConsent)>,
+    ) -> Self {
+        ServerState {
+            positions: HashMap::new(),
+            last_nearby_lists: HashMap::new(),
+            listed_peers: std::sync::Mutex::new(HashSet::new()),
+            routing: Arc::new(routing::RoutingTable::default()),
+            offers: Arc::new(offers::RecentOffers::default()),
+            inbound_traffic: Arc::new(talkers::InboundTraffic::default()),
+            last_update_time: HashMap::new(),
+            position_history: HashMap::new(),
+            position_history_len: config.position_history_len,
+            position_history_retention_secs: config.position_history_retention_secs,
+            preferences: HashMap::new(),
+            position_privacy: config.position_privacy,
+            last_consistency_report: ConsistencyReport::default(),
+            orphans_repaired_total: 0,
+            udp_sessions: HashMap::new(),
+            udp_port: config.udp_addr.as_deref().and_then(|addr| addr.parse::<SocketAddr>().ok()).map(|addr| addr.port()),
+            echo_sessions: HashMap::new(),
+            echo_ice_servers: config.echo_ice_servers.clone(),
+            announcer_zones,
+            live_zone_speakers: HashMap::new(),
+            scheduled_events,
+            live_events: HashMap::new(),
+            proximity: config.proximity,
+            map_ranges: config.map_ranges.clone(),
+            partitions: partitions::MapPartitions::new(&config.map_population_caps),
+            games: Arc::new(games::GameAdapters::new(config)),
+            collision_grids: collision_grids.into_iter().map(|grid| ((grid.game_id, grid.map_id), grid)).collect(),
+            shared_areas: config.shared_areas.clone(),
+            audio_regions: config.audio_regions.clone(),
+            directional_audio: config.directional_audio.clone(),
+            spectators: HashMap::new(),
+            spectators_config: config.spectators,
+            elevated_consents: elevated_consents.into_iter().collect(),
+            mutes: HashMap::new(),
+            tags: HashMap::new(),
+            vehicles: HashMap::new(),
+            name_filter: names::NameFilter::new(&config.name_filter).expect("name_filter is checked when the config loads"),
+            identity_fingerprints: HashMap::new(),
+            require_message_signing: config.require_message_signing,
+            turn: config.turn.clone(),
+            ice_failures: HashMap::new(),
+            nat_types: HashMap::new(),
+            reflexive_address_hints: config.reflexive_address_hints,
+            error_flood: config.error_flood,
+            accept_unversioned_messages: config.accept_unversioned_messages,
+            panic_reconnect_cooldown: config.panic_reconnect_cooldown_secs.map(Duration::from_secs),
+            panic_cooldowns: HashMap::new(),
+            outbound_queue: config.outbound_queue,
+            registration_timeout: Duration::from_secs(config.registration_timeout_secs),
+            registration_backoff: config.registration_backoff.as_ref().map(backoff::RegistrationBackoff::new),
+            duplicates: config.duplicate_accounts.as_ref().map(duplicates::DuplicateAccounts::new),
+            storm: storm::StormAbsorber::new(&config.reconnect_storm),
+            restored: HashSet::new(),
+            trace_clients: HashSet::new(),
+            regions: HashMap::new(),
+            session_tokens: HashMap::new(),
+            pairs: Arc::new(pairs::PairTracker::default()),
+            pair_retry: config.pair_retry,
+            topology: topology::Topology::default(),
+            topology_hints: config.topology_hints,
+            sfu: sfu::Sfu::new(config.sfu.as_ref()),
+            started_at: Instant::now(),
+            rtt_probes: HashMap::new(),
+            rtts: HashMap::new(),
+            next_ping_nonce: 0,
+            network_quality: config.network_quality,
+            network_reports: HashMap::new(),
+        }
+    }
+
+    // client ids whose per-client entries have lost their owner: no route, a route to a dead
+    // connection, or a position/update time without its counterpart
+    fn orphaned_client_ids(&self) -> (ConsistencyReport, HashSet<String>) {
+        let mut report = ConsistencyReport::default();
+        let mut orphans = HashSet::new();
+
+        for client_id in self.positions.keys() {
+            match self.routing.connection_id(client_id) {
+                None if self.restored.contains(client_id) || self.trace_clients.contains(client_id) || federation::is_remote(client_id) => {}
+                None => {
+                    report.positions_without_route += 1;
+                    orphans.insert(client_id.clone());
+                }
+                Some(connection_id) if !self.routing.has_connection(&connection_id) => {
+                    report.routes_without_connection += 1;
+                    orphans.insert(client_id.clone());
+                }
+                Some(_) => {}
+            }
+            if !self.last_update_time.contains_key(client_id) {
+                report.positions_without_update_time += 1;
+                orphans.insert(client_id.clone());
+            }
+        }
+        for client_id in self.last_update_time.keys() {
+            if !self.positions.contains_key(client_id) {
+                report.update_times_without_position += 1;
+                orphans.insert(client_id.clone());
+            }
+        }
+        for (client_id, peers) in &self.last_nearby_lists {
+            if !self.positions.contains_key(client_id) {
+                report.nearby_lists_without_position += 1;
+                orphans.insert(client_id.clone());
+            }
+            for peer_id in peers {
+                if !self.positions.contains_key(peer_id) {
+                    orphans.insert(peer_id.clone());
+                }
+            }
+        }
+        report.asymmetric_pairs = self.asymmetric_pairs().len();
+        (report, orphans)
+    }
+
+    // periodic sweep: find orphaned entries and drop them so long-gone clients can't linger
+    fn sweep_orphans(&mut self) -> ConsistencyReport {
+        let (report, orphans) = self.orphaned_client_ids();
+        for client_id in &orphans {
+            // a route to a dead connection is stale too; a live route means only side tables were broken
+            let route_is_dead = self
+                .routing
+                .connection_id(client_id)
+                .is_some_and(|connection_id| !self.routing.has_connection(&connection_id));
+            if route_is_dead {
+                self.routing.unroute(client_id);
+            }
+            self.forget_client(client_id);
+        }
+        for (a, b) in self.asymmetric_pairs() {
+            self.separate_pair(&a, &b);
+        }
+        self.orphans_repaired_total += report.total() as u64;
+        self.last_consistency_report = report.clone();
+        report
+    }
+
+    // entry counts of every map keyed by client or connection, all of which should be empty once
+    // every client has left. position_history is left out: it outlives disconnects on purpose
+    #[cfg_attr(not(feature = "soak"), allow(dead_code))]
+    fn map_sizes(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("positions", self.positions.len()),
+            ("last_nearby_lists", self.last_nearby_lists.len()),
+            ("listed_peers", self.listed_peers.lock().unwrap().len()),
+            ("connections", self.routing.connection_count()),
+            ("client_id_to_connection_id", self.routing.route_count()),
+            ("inbound_traffic", self.inbound_traffic.len()),
+            ("last_update_time", self.last_update_time.len()),
+            ("recent_offers", self.offers.len()),
+            ("preferences", self.preferences.len()),
+            ("udp_sessions", self.udp_sessions.len()),
+            ("echo_sessions", self.echo_sessions.len()),
+            ("mutes", self.mutes.len()),
+            ("tags", self.tags.len()),
+            ("vehicles", self.vehicles.len()),
+            ("partitions", self.partitions.len()),
+            ("sfu_members", self.sfu.len()),
+            ("spectators", self.spectators.len()),
+            ("elevated_consents", self.elevated_consents.len()),
+            ("duplicates", self.duplicates.as_ref().map_or(0, |duplicates| duplicates.len())),
+            ("identity_fingerprints", self.identity_fingerprints.len()),
+            ("ice_failures", self.ice_failures.len()),
+            ("nat_types", self.nat_types.len()),
+            ("rtt_probes", self.rtt_probes.len()),
+            ("rtts", self.rtts.len()),
+            ("network_reports", self.network_reports.len()),
+            ("deferred_nearby_updates", self.storm.deferred_len()),
+            ("restored", self.restored.len()),
+            ("trace_clients", self.trace_clients.len()),
+            ("regions", self.regions.len()),
+            ("session_tokens", self.session_tokens.len()),
+            ("remote_clients", federation::remote_count()),
+            ("pairs", self.pairs.len()),
+            ("topology", self.topology.len()),
+        ]
+    }
+
+    // every client with a position, by client id; game_id narrows it to one game
+    fn client_summaries(&self, game_id: Option<i32>) -> Vec<ClientSummary> {
+        let now = Instant::now();
+        let mut clients: Vec<ClientSummary> = self
+            .positions
+            .values()
+            .filter(|pos| game_id.is_none_or(|game_id| game_id == pos.game_id))
+            .map(|pos| {
+                let connection_id = self.routing.connection_id(&pos.client_id);
+                ClientSummary {
+                    client_id: pos.client_id.clone(),
+                    addr: connection_id.as_deref().and_then(|connection_id| self.inbound_traffic.addr(connection_id)),
+                    connection_id,
+                    game_id: pos.game_id,
+                    map_id: pos.map_id,
+                    channel: pos.channel,
+                    x: pos.x,
+                    y: pos.y,
+                    peers: self.last_nearby_lists.get(&pos.client_id).map_or(0, HashSet::len),
+                    last_update_secs: self.last_update_time.get(&pos.client_id).map_or(0, |at| now.duration_since(*at).as_secs()),
+                    partition: self.partitions.partition(&pos.client_id),
+                    region: self.regions.get(&pos.client_id).cloned(),
+                }
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    fn stats(&self) -> ServerStats {
+        ServerStats {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            connections: self.routing.connection_count(),
+            registered_clients: self.routing.route_count(),
+            pairs: self.last_nearby_lists.values().map(HashSet::len).sum::<usize>() / 2,
+            restored_clients: self.restored.len(),
+        }
+    }
+
+    // closes the client's connection (with Closing { Kicked }) and returns its id; None when it
+    // isn't connected. its state goes the usual way once the connection is gone
+    fn kick(&self, client_id: &str) -> Option<String> {
+        let connection_id = self.routing.connection_id(client_id)?;
+        self.routing.connection(&connection_id)?.kick();
+        Some(connection_id)
+    }
+
+    // kicks every connection from the address, registered or not; returns their ids
+    fn kick_address(&self, ip: IpAddr) -> Vec<String> {
+        self.inbound_traffic
+            .connection_ids()
+            .into_iter()
+            .filter(|connection_id| self.inbound_traffic.addr(connection_id).is_some_and(|addr| addr.ip() == ip))
+            .filter_map(|connection_id| {
+                self.routing.connection(&connection_id)?.kick();
+                Some(connection_id)
+            })
+            .collect()
+    }
+
+    fn start_panic_cooldown(&mut self, ip: IpAddr) {
+        let Some(cooldown) = self.panic_reconnect_cooldown else {
+            return;
+        };
+        let now = Instant::now();
+        self.panic_cooldowns.retain(|_, until| *until > now);
+        self.panic_cooldowns.insert(ip, now + cooldown);
+    }
+
+    fn panic_cooldown_remaining(&self, ip: IpAddr) -> Option<Duration> {
+        let until = self.panic_cooldowns.get(&ip)?;
+        Some(until.saturating_duration_since(Instant::now())).filter(|remaining| !remaining.is_zero())
+    }
+
+    // append to the client's history if the position actually changed
+    fn record_position_history(&mut self, pos: &ClientPosition) {
+        if self.position_history_len == 0 {
+            return;
+        }
+        let history = self.position_history.entry(pos.client_id.clone()).or_default();
+        if let Some(last) = history.back() {
+            if last.map_id == pos.map_id && last.x == pos.x && last.y == pos.y && last.channel == pos.channel {
+                return;
+            }
+        }
+        if history.len() >= self.position_history_len {
+            history.pop_front();
+        }
+        history.push_back(PositionRecord {
+            map_id: pos.map_id,
+            x: pos.x,
+            y: pos.y,
+            channel: pos.channel,
+            recorded_at: db::unix_now(),
+        });
+    }
+
+    // drop histories whose newest entry is older than the retention period
+    fn prune_position_history(&mut self) {
+        let cutoff = db::unix_now() - self.position_history_retention_secs;
+        self.position_history
+            .retain(|_, history| history.back().is_some_and(|last| last.recorded_at >= cutoff));
+    }
+
+    // hysteresis-based proximity check to prevent connection flapping
+    // this prevents the "dicey" behavior when walking around the 20-tile boundary:
+    // - new peers are introduced when ≤20 units apart (proximity.introduction_range by default)
+    // - existing peers stay connected until >25 units apart (proximity.disconnection_range)
+    // - this 5-unit buffer prevents constant connect/disconnect when hovering near the boundary
+    // operators and scheduled events can swap in other ranges (see pairing_ranges),
+    // and inside shared areas clients on different channels pair too (any_channel)
+    // symmetric in a and b, so both sides of a pair always reach the same answer
+    // channels and distance are the game's call (see games::GameAdapter)
+    fn should_be_paired(game: &dyn games::GameAdapter, a: &ClientPosition, b: &ClientPosition, currently_paired: bool, ranges: schedule::PairingRanges, any_channel: bool) -> bool {
+        // early exit conditions (cheap comparisons first)
+        if a.client_id == b.client_id { return false; }
+        if a.map_id != b.map_id { return false; }
+        if a.game_id != b.game_id { return false; }
+        if !any_channel && !game.same_channel(a, b) { return false; }
+
+        if currently_paired {
+            game.audible(a, b, ranges.disconnection)
+        } else {
+            game.audible(a, b, ranges.introduction)
+        }
+    }
+
+    // with duplicate accounts merged, alts don't pair and a primary pairs with whoever any client of
+    // its address would pair with
+    fn wants_pair(&self, a: &ClientPosition, b: &ClientPosition, currently_paired: bool) -> bool {
+        if let Some(paired) = self.spectator_pairing(a, b) {
+            return paired;
+        }
+        let Some(duplicates) = self.merged_duplicates() else {
+            return self.wants_pair_at(a, b, currently_paired);
+        };
+        if duplicates.primary_of(&a.client_id).is_some() || duplicates.primary_of(&b.client_id).is_some() {
+            return false;
+        }
+        let merged = |pos: &'_ ClientPosition| -> Vec<ClientPosition> {
+            match duplicates.group(&pos.client_id) {
+                Some(group) => group.iter().filter_map(|id| self.positions.get(id)).cloned().collect(),
+                None => vec![pos.clone()],
+            }
+        };
+        let others = merged(b);
+        merged(a).iter().any(|a| others.iter().any(|b| self.wants_pair_at(a, b, currently_paired)))
+    }
+
+    fn merged_duplicates(&self) -> Option<&duplicates::DuplicateAccounts> {
+        self.duplicates.as_ref().filter(|duplicates| duplicates.policy == config::DuplicatePolicy::Merge)
+    }
+
+    // normal proximity pairing within a map partition, plus speakers reaching everyone inside their
+    // live announcer zones and clients riding the same vehicle. none of it for two clients of one
+    // sfu area, who already hear each other through the SFU
+    fn wants_pair_at(&self, a: &ClientPosition, b: &ClientPosition, currently_paired: bool) -> bool {
+        if self.sfu.together(&a.client_id, &b.client_id) {
+            return false;
+        }
+        if !currently_paired && self.skips_low_quality(&a.client_id, &b.client_id) {
+            return false;
+        }
+        let game = self.games.get(a.game_id);
+        let any_channel = !game.same_channel(a, b) && self.in_shared_area_together(a, b);
+        let ranges = self.pairing_ranges(a, b).scaled(self.facing_weight(a, b));
+        (Self::should_be_paired(game, a, b, currently_paired, ranges, any_channel)
+            && !self.occluded(a, b)
+            && self.partitions.partition(&a.client_id) == self.partitions.partition(&b.client_id))
+            || self.zone_pairs(a, b)
+            || self.aboard_together(a, b)
+    }
+
+    // for pairs with a spectator in them, which go by the spectator's focus alone: it hears every
+    // client within its radius that hasn't opted out, and two spectators never pair. None otherwise
+    fn spectator_pairing(&self, a: &ClientPosition, b: &ClientPosition) -> Option<bool> {
+        let (spectator, other) = match (self.spectators.get(&a.client_id), self.spectators.get(&b.client_id)) {
+            (None, None) => return None,
+            (Some(_), Some(_)) => return Some(false),
+            (Some(spectator), None) => (spectator, b),
+            (None, Some(spectator)) => (spectator, a),
+        };
+        if !self.elevated_consent(&other.client_id).spectators {
+            return Some(false);
+        }
+        let Some(focus) = spectator.focus_position(|client_id| self.positions.get(client_id)) else {
+            return Some(false);
+        };
+        let radius = spectator.radius.unwrap_or(self.spectators_config.default_radius);
+        Some(spectators::in_radius(&focus, other, radius, self.games.get(focus.game_id).coordinate_scale()))
+    }
+
+    // what the client stored, less spectators for the session when it set spectator_opt_out
+    fn elevated_consent(&self, client_id: &str) -> ElevatedConsent {
+        let mut consent = self.elevated_consents.get(client_id).copied().unwrap_or_default();
+        if self.preferences.get(client_id).is_some_and(|preferences| preferences.spectator_opt_out) {
+            consent.spectators = false;
+        }
+        consent
+    }
+
+    // how `other` reaches the client at `pos` beyond ordinary range, if it does
+    fn elevated_role(&self, pos: &ClientPosition, other: &ClientPosition) -> Option<ElevatedRole> {
+        if self.spectators.contains_key(&other.client_id) {
+            return Some(ElevatedRole::Spectator);
+        }
+        self.live_zones()
+            .any(|zone| zone.speaker_id == other.client_id && self.zone_consents(zone, pos, other))
+            .then_some(ElevatedRole::Announcer)
+    }
+
+    fn is_spectator_pair(&self, a: &str, b: &str) -> bool {
+        self.spectators.contains_key(a) || self.spectators.contains_key(b)
+    }
+
+    // both clients are aboard the same vehicle in the same game, wherever their positions put them
+    fn aboard_together(&self, a: &ClientPosition, b: &ClientPosition) -> bool {
+        a.game_id == b.game_id
+            && self
+                .vehicles
+                .get(&a.client_id)
+                .is_some_and(|vehicle_id| self.vehicles.get(&b.client_id) == Some(vehicle_id))
+    }
+
+    // range multiple for the pair under directional audio: whichever of the two faces the other
+    // better carries, and a client without a heading is heard all around
+    fn facing_weight(&self, a: &ClientPosition, b: &ClientPosition) -> f32 {
+        let Some(directional) = &self.directional_audio else {
+            return 1.0;
+        };
+        let weight_a = directional.weight(a, b).unwrap_or(1.0);
+        let weight_b = directional.weight(b, a).unwrap_or(1.0);
+        weight_a.max(weight_b)
+    }
+
+    // a client's (round trip in ms, packet loss in percent) as it last reported them. one that
+    // hasn't reported is taken at its Ping round trip, if it answers them, and no loss
+    fn network_quality_of(&self, client_id: &str) -> (f64, f64) {
+        match self.network_reports.get(client_id) {
+            Some(&(rtt_ms, loss)) => (f64::from(rtt_ms), f64::from(loss)),
+            None => (self.rtts.get(client_id).map_or(0.0, |rtt| rtt.as_secs_f64() * 1000.0), 0.0),
+        }
+    }
+
+    // the pair's combined network quality is past network_quality's limits
+    fn low_quality(&self, a: &str, b: &str) -> bool {
+        self.network_quality
+            .is_some_and(|limits| limits.is_low_quality(self.network_quality_of(a), self.network_quality_of(b)))
+    }
+
+    fn skips_low_quality(&self, a: &str, b: &str) -> bool {
+        self.network_quality.is_some_and(|limits| limits.action == config::LowQualityAction::Skip) && self.low_quality(a, b)
+    }
+
+    // the client's current peers whose pair with it is low quality
+    fn low_quality_peers(&self, client_id: &str) -> HashSet<String> {
+        self.paired_peers(client_id).into_iter().filter(|peer_id| self.low_quality(client_id, peer_id)).collect()
+    }
+
+    // a wall in the map's collision grid (in block mode) stands between the two
+    fn occluded(&self, a: &ClientPosition, b: &ClientPosition) -> bool {
+        self.collision_grids
+            .get(&(a.game_id, a.map_id))
+            .is_some_and(|grid| grid.blocks(a, b, self.games.get(a.game_id).coordinate_scale()))
+    }
+
+    // the gain left after the walls between the two, on maps with an attenuating collision grid
+    fn wall_attenuation(&self, a: &ClientPosition, b: &ClientPosition) -> Option<f32> {
+        let grid = self.collision_grids.get(&(a.game_id, a.map_id))?;
+        grid.attenuation(a, b, self.games.get(a.game_id).coordinate_scale())
+    }
+
+    // both clients stand in the same cross-channel shared area
+    fn in_shared_area_together(&self, a: &ClientPosition, b: &ClientPosition) -> bool {
+        self.shared_areas.iter().any(|area| area.contains(a) && area.contains(b))
+    }
+
+    fn zone_pairs(&self, a: &ClientPosition, b: &ClientPosition) -> bool {
+        self.live_zones().any(|zone| self.zone_consents(zone, a, b))
+    }
+
+    fn live_zones(&self) -> impl Iterator<Item = &zones::AnnouncerZone> {
+        self.announcer_zones.iter().filter(|zone| self.live_zone_speakers.contains_key(&zone.id))
+    }
+
+    // the zone pairs the two and its listener allows announcers
+    fn zone_consents(&self, zone: &zones::AnnouncerZone, a: &ClientPosition, b: &ClientPosition) -> bool {
+        let listener = if a.client_id == zone.speaker_id { b } else { a };
+        zone.pairs(a, b) && self.elevated_consent(&listener.client_id).announcers
+    }
+
+    // ranges of the live event covering both clients (the oldest one if several overlap), else the
+    // ones of the audio region the pair goes by, else those of their map or game, else the current
+    // settings. events fill in from the region's or map's ranges
+    fn pairing_ranges(&self, a: &ClientPosition, b: &ClientPosition) -> schedule::PairingRanges {
+        let map_defaults = self.map_pairing_ranges(a, b);
+        let defaults = self.pair_region(a, b).map_or(map_defaults, |region| region.ranges(map_defaults));
+        self.live_events
+            .values()
+            .filter(|event| event.contains(a) && event.contains(b))
+            .min_by_key(|event| event.id)
+            .map_or(defaults, |event| event.ranges(defaults))
+    }
+
+    fn map_pairing_ranges(&self, a: &ClientPosition, b: &ClientPosition) -> schedule::PairingRanges {
+        let global = self.proximity.ranges();
+        self.map_ranges
+            .iter()
+            .filter(|map_ranges| map_ranges.covers(a) && map_ranges.covers(b))
+            .max_by_key(|map_ranges| map_ranges.map_id.is_some())
+            .map_or(global, |map_ranges| map_ranges.ranges(global))
+    }
+
+    // of the two clients' audio regions (None standing for the map's ranges outside every region),
+    // the one with the smaller introduction range; the earlier listed on a tie, so it's symmetric
+    fn pair_region(&self, a: &ClientPosition, b: &ClientPosition) -> Option<&config::AudioRegion> {
+        if self.audio_regions.is_empty() {
+            return None;
+        }
+        let map_defaults = self.map_pairing_ranges(a, b);
+        let region_of = |pos: &ClientPosition| self.audio_regions.iter().position(|region| region.contains(pos));
+        let introduction = |index: Option<usize>| index.map_or(map_defaults.introduction, |index| self.audio_regions[index].ranges(map_defaults).introduction);
+        [region_of(a), region_of(b)]
+            .into_iter()
+            .min_by(|x, y| introduction(*x).total_cmp(&introduction(*y)).then(x.unwrap_or(usize::MAX).cmp(&y.unwrap_or(usize::MAX))))
+            .flatten()
+            .map(|index| &self.audio_regions[index])
+    }
+
+    // tightest of the server-wide peer cap and those of the live events covering a client
+    fn peer_cap(&self, pos: &ClientPosition) -> Option<usize> {
+        self.live_events
+            .values()
+            .filter(|event| event.contains(pos))
+            .filter_map(|event| event.max_peers)
+            .chain(self.proximity.max_peers)
+            .min()
+    }
+
+    // re-check every client's pairs, after the proximity settings changed
+    fn reevaluate_all_pairs(&mut self) -> Vec<(String, outbound::Sender)> {
+        // in a fixed order, so capped clients fill their slots the same way every time
+        let mut client_ids: Vec<String> = self.positions.keys().cloned().collect();
+        client_ids.sort();
+        let mut notifications = Vec::new();
+        for client_id in client_ids {
+            if let Some(client_tx) = self.routed_sender(&client_id) {
+                notifications.extend(self.reevaluate_pairs(&client_id, &client_tx));
+            }
+        }
+        // neighbours re-evaluated in turn report each other, so collapse to one update per client
+        notifications.sort_by(|a, b| a.0.cmp(&b.0));
+        notifications.dedup_by(|a, b| a.0 == b.0);
+        notifications
+    }
+
+    // start and end scheduled events as of `now` and drop the ended ones.
+    // returns the (game_id, map_id) of every map whose event just started or stopped
+    fn refresh_scheduled_events(&mut self, now: i64) -> Vec<(i32, i32)> {
+        self.scheduled_events.retain(|event| event.ends_at() > now);
+        let live: HashMap<i64, schedule::ScheduledEvent> = self
+            .scheduled_events
+            .iter()
+            .filter(|event| event.is_active(now))
+            .map(|event| (event.id, event.clone()))
+            .collect();
+        let mut changed = Vec::new();
+        for (id, event) in &live {
+            if !self.live_events.contains_key(id) {
+                info!("Scheduled event {} ({:?}) started on game {} map {}", id, event.name, event.game_id, event.map_id);
+                changed.push((event.game_id, event.map_id));
+            }
+        }
+        for (id, event) in &self.live_events {
+            if !live.contains_key(id) {
+                info!("Scheduled event {} ({:?}) ended on game {} map {}", id, event.name, event.game_id, event.map_id);
+                changed.push((event.game_id, event.map_id));
+            }
+        }
+        self.live_events = live;
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+
+    // mark zones live or over as of `now` and drop the ended ones.
+    // returns the speakers whose zones started or stopped, whose pairs need re-evaluating
+    fn refresh_announcer_zones(&mut self, now: i64) -> Vec<String> {
+        self.announcer_zones.retain(|zone| zone.ends_at > now);
+        let live: HashMap<i64, String> = self
+            .announcer_zones
+            .iter()
+            .filter(|zone| zone.is_active(now))
+            .map(|zone| (zone.id, zone.speaker_id.clone()))
+            .collect();
+        let mut changed = Vec::new();
+        for (id, speaker_id) in &live {
+            if !self.live_zone_speakers.contains_key(id) {
+                info!("Announcer zone {} is live (speaker {})", id, speaker_id);
+                changed.push(speaker_id.clone());
+            }
+        }
+        for (id, speaker_id) in &self.live_zone_speakers {
+            if !live.contains_key(id) {
+                info!("Announcer zone {} is over (speaker {})", id, speaker_id);
+                changed.push(speaker_id.clone());
+            }
+        }
+        self.live_zone_speakers = live;
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+
+    // the client's current peers, straight from the authoritative pair structure
+    fn paired_peers(&self, client_id: &str) -> Vec<String> {
+        let mut peers: Vec<String> = self
+            .last_nearby_lists
+            .get(client_id)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default();
+        peers.sort();
+        peers
+    }
+
+    // pair mutations: the only places last_nearby_lists is written, always touching both sides
+    fn introduce_pair(&mut self, a: &str, b: &str) {
+        self.last_nearby_lists.entry(a.to_string()).or_default().insert(b.to_string());
+        self.last_nearby_lists.entry(b.to_string()).or_default().insert(a.to_string());
+        self.pairs.introduced(a, b);
+        metrics::PAIR_CHANGES.with_label_values(&["introduced"]).inc();
+        audit::record(AuditEvent::Introduced { client_id: a.to_string(), peer_id: b.to_string() });
+        self.publish_pair_event(a, b, mapevents::MapEvent::Introduced { client_id: a.to_string(), peer_id: b.to_string() });
+    }
+
+    // pair events go to the first side's map, or the second's once the first is gone
+    fn publish_pair_event(&self, a: &str, b: &str, event: mapevents::MapEvent) {
+        if let Some(pos) = self.positions.get(a).or_else(|| self.positions.get(b)) {
+            mapevents::publish(pos.game_id, pos.map_id, event);
+        }
+    }
+
+    fn separate_pair(&mut self, a: &str, b: &str) {
+        audit::record(AuditEvent::Separated { client_id: a.to_string(), peer_id: b.to_string() });
+        self.publish_pair_event(a, b, mapevents::MapEvent::Separated { client_id: a.to_string(), peer_id: b.to_string() });
+        self.pairs.separated(a, b);
+        metrics::PAIR_CHANGES.with_label_values(&["separated"]).inc();
+        for (from, to) in [(a, b), (b, a)] {
+            if let Some(set) = self.last_nearby_lists.get_mut(from) {
+                set.remove(to);
+                if set.is_empty() {
+                    self.last_nearby_lists.remove(from);
+                }
+            }
+        }
+    }
+
+    // pairs recorded on only one side; always empty unless the pairing invariant was broken
+    fn asymmetric_pairs(&self) -> Vec<(String, String)> {
+        let mut broken = Vec::new();
+        for (client_id, peers) in &self.last_nearby_lists {
+            for peer_id in peers {
+                let reverse = self.last_nearby_lists.get(peer_id).is_some_and(|set| set.contains(client_id));
+                if !reverse {
+                    broken.push((client_id.clone(), peer_id.clone()));
+                }
+            }
+        }
+        broken
+    }
+
+    // distance of `other` as seen from `pos`, bucketed relative to the introduction range
+    fn peer_distance(&self, pos: &ClientPosition, other: &ClientPosition) -> NearbyPeer {
+        // offsets too large for an i64 are clamped; they are far out of range anyway.
+        // float-coordinate games get them back in whole units
+        let game = self.games.get(pos.game_id);
+        let scale = game.coordinate_scale();
+        let dx = (other.x.saturating_sub(pos.x) as f64 / scale).round() as i64;
+        let dy = (other.y.saturating_sub(pos.y) as f64 / scale).round() as i64;
+        let distance = pos.distance_squared(other).sqrt() / scale;
+        // buckets split the range the pair was introduced at, which a map, region or event may set
+        let ranges = self.pairing_ranges(pos, other);
+        let introduction_range = f64::from(ranges.introduction);
+        let bucket = if distance <= introduction_range / 3.0 {
+            DistanceBucket::Near
+        } else if distance <= introduction_range * 2.0 / 3.0 {
+            DistanceBucket::Medium
+        } else {
+            DistanceBucket::Far
+        };
+        let exact = self.position_privacy == PositionPrivacy::Exact;
+        // the plugin's, the walls' and the region's say on the gain, all applied
+        let gains = [
+            game.attenuation(pos, other, ranges.disconnection),
+            self.wall_attenuation(pos, other),
+            self.pair_region(pos, other).and_then(|region| region.falloff.gain(distance, ranges)),
+        ];
+        NearbyPeer {
+            client_id: other.client_id.clone(),
+            bucket,
+            dx: exact.then_some(dx),
+            dy: exact.then_some(dy),
+            tags: self.tags.get(&other.client_id).cloned().unwrap_or_default(),
+            identity_fingerprint: self.identity_fingerprints.get(&other.client_id).cloned(),
+            attenuation: gains.into_iter().flatten().reduce(|gain, other_gain| gain * other_gain),
+            facing_weight: self.directional_audio.as_ref().and_then(|directional| directional.weight(other, pos)),
+            low_quality: self.low_quality(&pos.client_id, &other.client_id),
+            listen_only: self.spectators.contains_key(&other.client_id),
+            elevated_role: self.elevated_role(pos, other),
+        }
+    }
+
+    // number of other clients within audible (disconnection) range, whether or not they've been
+    // introduced, each judged by the ranges that apply to the two of them
+    fn count_in_audible_range(&self, pos: &ClientPosition) -> usize {
+        let game = self.games.get(pos.game_id);
+        self.positions
+            .values()
+            .filter(|other| {
+                other.client_id != pos.client_id
+                    && other.map_id == pos.map_id
+                    && other.game_id == pos.game_id
+                    && (game.same_channel(pos, other) || self.in_shared_area_together(pos, other))
+            })
+            .filter(|other| game.audible(pos, other, self.pairing_ranges(pos, other).disconnection) && !self.occluded(pos, other))
+            .count()
+    }
+
+    // the messages that describe a client's current surroundings, honouring its preferences.
+    // `changed` marks an update caused by a pairing change rather than a periodic/explicit resend:
+    // the first such update with an empty set after one that listed anyone carries AllPeersGone,
+    // so each transition to an empty set is announced exactly once
+    fn nearby_messages(&self, pos: &ClientPosition, changed: bool) -> Vec<ServerMessage> {
+        let mut nearby_list = self.paired_peers(&pos.client_id);
+        // a running echo test looks like one more peer; it has no position, so no details for it
+        if self.echo_sessions.contains_key(&pos.client_id) {
+            nearby_list.insert(0, echo::ECHO_PEER_ID.to_string());
+        }
+        // and so does the SFU of the client's event area
+        if let Some(sfu_peer_id) = self.sfu.peer_id(&pos.client_id) {
+            nearby_list.insert(0, sfu_peer_id);
+        }
+        let mut preferences = self.preferences.get(&pos.client_id).cloned().unwrap_or_default();
+        // opt-in extras are the second thing to go when the server is overloaded
+        if (preferences.peer_details || preferences.area_summary) && overload::should_shed(ShedLevel::Extras, "extras") {
+            preferences = ClientPreferences::default();
+        }
+        let wants_details = preferences.peer_details;
+        let details = wants_details.then(|| {
+            nearby_list
+                .iter()
+                .filter_map(|id| self.positions.get(id))
+                .map(|other| self.peer_distance(pos, other))
+                .collect()
+        });
+
+        let now_empty = nearby_list.is_empty();
+        // relay hints go first so the client never starts a direct attempt to those peers
+        let mut messages: Vec<ServerMessage> = nearby_list
+            .iter()
+            .filter(|peer_id| self.needs_relay(&pos.client_id, peer_id))
+            .filter_map(|peer_id| self.force_relay(&pos.client_id, peer_id))
+            .collect();
+        if let Some(&own) = self.nat_types.get(&pos.client_id) {
+            let peers = nearby_list
+                .iter()
+                .filter_map(|peer_id| self.nat_types.get(peer_id).map(|&nat_type| (peer_id.clone(), nat_type)))
+                .collect();
+            messages.push(ServerMessage::PeerNatTypes { own, peers });
+        }
+        if let Some(own) = self.shared_address(&pos.client_id) {
+            let peers = nearby_list
+                .iter()
+                .filter_map(|peer_id| self.shared_address(peer_id).map(|addr| (peer_id.clone(), addr.to_string())))
+                .collect();
+            messages.push(ServerMessage::PeerAddresses { own: own.to_string(), peers });
+        }
+        if let Some(hint) = self.topology_hint(&pos.client_id) {
+            messages.push(hint);
+        }
+        messages.push(ServerMessage::NearbyPeers(nearby_list));
+        let mut listed_peers = self.listed_peers.lock().unwrap();
+        if !now_empty {
+            listed_peers.insert(pos.client_id.clone());
+        } else if changed && listed_peers.remove(&pos.client_id) {
+            messages.push(ServerMessage::AllPeersGone);
+        }
+        drop(listed_peers);
+        if let Some(details) = details {
+            messages.push(ServerMessage::NearbyPeerDetails(details));
+        }
+        if preferences.area_summary {
+            messages.push(ServerMessage::AreaSummary { nearby_count: self.count_in_audible_range(pos) });
+        }
+        messages
+    }
+
+    // the address a client's connection comes from, when reflexive_address_hints is on and the
+    // client shares it. remote and restored clients have no connection here, so none
+    fn shared_address(&self, client_id: &str) -> Option<SocketAddr> {
+        if !self.reflexive_address_hints || !self.preferences.get(client_id).is_some_and(|preferences| preferences.share_public_address) {
+            return None;
+        }
+        let addr = self.inbound_traffic.addr(&self.routing.connection_id(client_id)?)?;
+        Some(SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+    }
+
+    fn topology_hint(&self, client_id: &str) -> Option<ServerMessage> {
+        let (hub_id, forward_for) = self.topology.hint(client_id)?;
+        Some(ServerMessage::TopologyHint { hub_id, forward_for })
+    }
+
+    // re-elect the hubs of dense clusters; returns a TopologyHint for every client whose part changed
+    fn rebalance_topology(&mut self) -> Vec<(outbound::Sender, ServerMessage)> {
+        let Some(config) = self.topology_hints else {
+            return Vec::new();
+        };
+        // listen-only, slow, remote and fake clients don't forward for anyone
+        let eligible: HashSet<String> = self
+            .last_nearby_lists
+            .keys()
+            .filter(|client_id| {
+                !self.spectators.contains_key(*client_id) && !self.is_slow(client_id) && !federation::is_remote(client_id) && !self.trace_clients.contains(*client_id)
+            })
+            .cloned()
+            .collect();
+        let changed = self.topology.rebalance(&self.last_nearby_lists, &config, |client_id| eligible.contains(client_id));
+        if !changed.is_empty() {
+            info!("Rebalanced topology hints: {} clients changed", changed.len());
+        }
+        changed
+            .into_iter()
+            .filter_map(|client_id| {
+                let tx = self.routed_sender(&client_id)?;
+                let hint = self.topology_hint(&client_id).unwrap_or(ServerMessage::TopologyHint { hub_id: None, forward_for: Vec::new() });
+                Some((tx, hint))
+            })
+            .collect()
+    }
+
+    // player counts grouped by (game, map, channel), optionally limited to one game
+    fn population(&self, game_id: Option<i32>) -> Vec<PopulationEntry> {
+        let mut counts: HashMap<(i32, i32, i32), usize> = HashMap::new();
+        for pos in self.positions.values() {
+            if game_id.is_some_and(|game_id| game_id != pos.game_id) {
+                continue;
+            }
+            *counts.entry((pos.game_id, pos.map_id, pos.channel)).or_default() += 1;
+        }
+        let mut entries: Vec<PopulationEntry> = counts
+            .into_iter()
+            .map(|((game_id, map_id, channel), count)| PopulationEntry { game_id, map_id, channel, count })
+            .collect();
+        entries.sort_by_key(|e| (e.game_id, e.map_id, e.channel));
+        entries
+    }
+
+    // whether a pair should be told to relay: both sides are behind symmetric NATs, or either
+    // side has reported enough ICE failures
+    fn needs_relay(&self, a: &str, b: &str) -> bool {
+        let Some(turn) = &self.turn else {
+            return false;
+        };
+        let symmetric = |client_id| self.nat_types.get(client_id) == Some(&NatType::Symmetric);
+        if symmetric(a) && symmetric(b) {
+            return true;
+        }
+        if self.pairs.gave_up(a, b) {
+            return true;
+        }
+        if self.ice_failures.is_empty() {
+            return false;
+        }
+        [(a, b), (b, a)].into_iter().any(|(from, to)| {
+            self.ice_failures
+                .get(&(from.to_string(), to.to_string()))
+                .is_some_and(|&failures| failures >= turn.relay_after_failures)
+        })
+    }
+
+    // ForceRelay for one side of a pair, with TURN credentials issued to that side
+    fn force_relay(&self, client_id: &str, peer_id: &str) -> Option<ServerMessage> {
+        let turn = self.turn.as_ref()?;
+        Some(ServerMessage::ForceRelay {
+            peer_id: peer_id.to_string(),
+            ice_servers: vec![turn::ice_server(turn, client_id)],
+        })
+    }
+
+    // the pair retries and give-ups now due, as the messages that carry them out: a retry redoes the
+    // pair on both sides, a give-up redoes it once more through TURN when there is TURN
+    fn due_pair_retries(&mut self) -> Vec<(outbound::Sender, ServerMessage)> {
+        let Some(pair_retry) = self.pair_retry else {
+            return Vec::new();
+        };
+        let mut messages = Vec::new();
+        for (a, b, action) in self.pairs.due_retries(Instant::now(), &pair_retry) {
+            match action {
+                pairs::RetryAction::Retry => info!("Retrying pair {} <-> {}", a, b),
+                pairs::RetryAction::GiveUp if self.turn.is_some() => {
+                    info!("Pair {} <-> {} keeps failing, relaying it through TURN and no longer retrying", a, b)
+                }
+                pairs::RetryAction::GiveUp => {
+                    info!("Pair {} <-> {} keeps failing, no longer retrying", a, b);
+                    continue;
+                }
+            }
+            self.offers.clear_between(&a, &b);
+            let relayed = self.needs_relay(&a, &b);
+            for (client_id, peer_id) in [(&a, &b), (&b, &a)] {
+                let Some(client_tx) = self.routed_sender(client_id) else {
+                    continue;
+                };
+                if let Some(force_relay) = self.force_relay(client_id, peer_id).filter(|_| relayed) {
+                    messages.push((client_tx.clone(), force_relay));
+                }
+                messages.push((client_tx, ServerMessage::ReintroducePeer { peer_id: peer_id.clone() }));
+            }
+        }
+        messages
+    }
+
+    // a Ping for every client with rtt_probes enabled; one that was never answered is replaced
+    fn start_rtt_probes(&mut self) -> Vec<(outbound::Sender, ServerMessage)> {
+        let probed: Vec<String> = self
+            .preferences
+            .iter()
+            .filter(|(_, preferences)| preferences.rtt_probes)
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        let mut pings = Vec::new();
+        for client_id in probed {
+            let Some(tx) = self.routed_sender(&client_id) else {
+                continue;
+            };
+            self.next_ping_nonce += 1;
+            let nonce = self.next_ping_nonce;
+            self.rtt_probes.insert(client_id, (nonce, Instant::now()));
+            pings.push((tx, ServerMessage::Ping { nonce }));
+        }
+        pings
+    }
+
+    // record the answer to the client's outstanding Ping, if this is it
+    fn finish_rtt_probe(&mut self, client_id: &str, nonce: u64) -> Option<Duration> {
+        let &(expected, sent_at) = self.rtt_probes.get(client_id)?;
+        if expected != nonce {
+            return None;
+        }
+        self.rtt_probes.remove(client_id);
+        let sample = sent_at.elapsed();
+        // smoothed like TCP's SRTT so one slow answer doesn't mark a client as slow
+        self.rtts
+            .entry(client_id.to_string())
+            .and_modify(|rtt| *rtt = (*rtt * 7 + sample) / 8)
+            .or_insert(sample);
+        Some(sample)
+    }
+
+    fn is_slow(&self, client_id: &str) -> bool {
+        self.proximity
+            .slow_client_rtt_ms
+            .zip(self.rtts.get(client_id))
+            .is_some_and(|(limit_ms, rtt)| rtt.as_millis() > limit_ms as u128)
+    }
+
+    // the sender for a registered client's connection, if it is still live
+    fn routed_sender(&self, client_id: &str) -> Option<outbound::Sender> {
+        self.routing.sender(client_id)
+    }
+
+    // senders for everyone currently paired with a client, e.g. to refresh what they know about it
+    fn paired_peer_senders(&self, client_id: &str) -> Vec<(String, outbound::Sender)> {
+        self.paired_peers(client_id)
+            .into_iter()
+            .filter_map(|peer_id| self.routed_sender(&peer_id).map(|peer_tx| (peer_id, peer_tx)))
+            .collect()
+    }
+
+    // start a UDP fast-path session for a connection, replacing any earlier one (and its key)
+    fn open_udp_session(&mut self, client_id: &str, connection_id: &str) -> (u32, [u8; udp::KEY_LEN]) {
+        self.udp_sessions.retain(|_, session| session.connection_id != connection_id);
+        let mut session_id = udp::random_session_id();
+        while self.udp_sessions.contains_key(&session_id) {
+            session_id = udp::random_session_id();
+        }
+        let session = udp::UdpSession::new(client_id, connection_id);
+        let key = session.key;
+        self.udp_sessions.insert(session_id, session);
+        (session_id, key)
+    }
+
+    // end echo tests that ran their course; returns their clients (if still connected) so they
+    // get a nearby list without the echo peer
+    fn expire_echo_sessions(&mut self) -> Vec<(String, outbound::Sender)> {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .echo_sessions
+            .iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|client_id| {
+                self.echo_sessions.remove(&client_id);
+                info!("Echo test for {} expired", client_id);
+                self.routed_sender(&client_id).map(|tx| (client_id, tx))
+            })
+            .collect()
+    }
+
+    // replace a client's mute list; returns the peers whose muted_by changed
+    fn set_mutes(&mut self, client_id: &str, muted_peer_ids: Vec<String>) -> Vec<String> {
+        let muted: HashSet<String> = muted_peer_ids
+            .into_iter()
+            .filter(|peer_id| peer_id != client_id)
+            .take(MAX_MUTED_PEERS)
+            .collect();
+        let previous = self.mutes.remove(client_id).unwrap_or_default();
+        let mut changed: Vec<String> = muted.symmetric_difference(&previous).cloned().collect();
+        changed.sort();
+        if !muted.is_empty() {
+            self.mutes.insert(client_id.to_string(), muted);
+        }
+        changed
+    }
+
+    // the clients that currently have this one muted
+    fn muted_by(&self, client_id: &str) -> Vec<String> {
+        let mut muters: Vec<String> = self
+            .mutes
+            .iter()
+            .filter(|(_, muted)| muted.contains(client_id))
+            .map(|(muter, _)| muter.clone())
+            .collect();
+        muters.sort();
+        muters
+    }
+
+    // whether a registration presenting this token takes over the client's live one: only with
+    // the token issued to that client_id, and only while it is still registered
+    fn resumes_registration(&self, client_id: &str, token: Option<&str>) -> bool {
+        self.routing.connection_id(client_id).is_some()
+            && self.session_tokens.get(client_id).is_some_and(|issued| admin::token_matches(token, issued))
+    }
+
+    // drop everything keyed by this client_id except the routing entries
+    // (client_id_to_connection_id and connections), which callers handle since ownership differs.
+    // returns the former peers with a live connection, which should be told about the change
+    fn forget_client(&mut self, client_id: &str) -> Vec<(String, outbound::Sender)> {
+        if let Some(pos) = self.positions.remove(client_id) {
+            mapevents::publish(pos.game_id, pos.map_id, mapevents::MapEvent::Left { client_id: client_id.to_string() });
+        }
+        self.last_update_time.remove(client_id);
+        self.listed_peers.lock().unwrap().remove(client_id);
+        self.preferences.remove(client_id);
+        self.offers.remove_involving(client_id);
+        self.echo_sessions.remove(client_id);
+        // the muted peers drop this client from their nearby list, which is where mute state is read against
+        self.mutes.remove(client_id);
+        self.tags.remove(client_id);
+        self.vehicles.remove(client_id);
+        self.partitions.remove(client_id);
+        self.topology.forget(client_id);
+        self.sfu.remove(client_id);
+        self.identity_fingerprints.remove(client_id);
+        self.ice_failures.retain(|(reporter, peer), _| reporter != client_id && peer != client_id);
+        self.nat_types.remove(client_id);
+        self.rtt_probes.remove(client_id);
+        self.rtts.remove(client_id);
+        self.network_reports.remove(client_id);
+        self.storm.forget(client_id);
+        self.restored.remove(client_id);
+        self.trace_clients.remove(client_id);
+        self.regions.remove(client_id);
+        self.session_tokens.remove(client_id);
+        federation::forget_remote(client_id);
+
+        // dissolve all of this client's pairs so peers drop it and it gets reintroduced on reconnect
+        let former_peers = self.paired_peers(client_id);
+        for peer_id in &former_peers {
+            self.separate_pair(client_id, peer_id);
+        }
+        debug_assert!(self.asymmetric_pairs().is_empty(), "pair symmetry broken after removing {}", client_id);
+
+        let mut notifications: Vec<_> = former_peers
+            .into_iter()
+            .filter_map(|peer_id| self.routed_sender(&peer_id).map(|tx| (peer_id, tx)))
+            .collect();
+        // the primary left at its address (maybe newly one) hears without it now, when merged
+        let primary = self.duplicates.as_mut().and_then(|duplicates| duplicates.remove(client_id));
+        if let Some(primary) = primary.filter(|_| self.merged_duplicates().is_some()) {
+            if let Some(primary_tx) = self.routed_sender(&primary) {
+                notifications.extend(self.reevaluate_pairs(&primary, &primary_tx));
+            }
+        }
+        notifications
+    }
+
+    fn snapshot(&self) -> snapshot::Snapshot {
+        // remote clients are their own server's to keep, and fake ones are played again anyway
+        let clients = self
+            .positions
+            .values()
+            .filter(|pos| !federation::is_remote(&pos.client_id) && !self.trace_clients.contains(&pos.client_id))
+            .map(|pos| snapshot::SnapshotClient {
+                position: pos.clone(),
+                peers: self.paired_peers(&pos.client_id),
+                preferences: self.preferences.get(&pos.client_id).cloned(),
+            })
+            .collect();
+        snapshot::Snapshot { saved_at: db::unix_now(), clients }
+    }
+
+    // takes the snapshot's clients back as restored, with every pair whose both sides came back.
+    // their timeouts start now
+    fn restore(&mut self, snapshot: snapshot::Snapshot) -> usize {
+        let now = Instant::now();
+        for client in &snapshot.clients {
+            let client_id = client.position.client_id.clone();
+            self.positions.insert(client_id.clone(), client.position.clone());
+            self.last_update_time.insert(client_id.clone(), now);
+            if let Some(preferences) = &client.preferences {
+                self.preferences.insert(client_id.clone(), preferences.clone());
+            }
+            self.restored.insert(client_id);
+        }
+        for client in &snapshot.clients {
+            let client_id = &client.position.client_id;
+            for peer_id in &client.peers {
+                if client_id < peer_id && self.restored.contains(peer_id) {
+                    self.introduce_pair(client_id, peer_id);
+                }
+            }
+        }
+        snapshot.clients.len()
+    }
+
+    // this server's own clients on the federated maps, for the digest sent to linked servers
+    fn federation_digest(&self) -> Vec<ClientPosition> {
+        self.positions
+            .values()
+            .filter(|pos| federation::shares_map(pos) && self.routing.connection_id(&pos.client_id).is_some())
+            .cloned()
+            .collect()
+    }
+
+    // takes a linked server's digest: its clients get (or keep) their positions here, the ones it
+    // left out are forgotten, and the local clients on the maps where anything changed have their
+    // pairs redone, since pairs with remote clients are only decided from this side
+    fn apply_federation_digest(&mut self, server_id: &str, positions: Vec<ClientPosition>) -> Vec<(String, outbound::Sender)> {
+        let now = Instant::now();
+        let mut notifications = Vec::new();
+        let mut changed_maps = HashSet::new();
+        let listed: HashSet<&str> = positions.iter().map(|pos| pos.client_id.as_str()).collect();
+        for client_id in federation::remote_clients_of(server_id) {
+            if !listed.contains(client_id.as_str()) {
+                if let Some(pos) = self.positions.get(&client_id) {
+                    changed_maps.insert((pos.game_id, pos.map_id));
+                }
+                notifications.extend(self.forget_client(&client_id));
+            }
+        }
+        for pos in positions {
+            // a client connected here (or expected back after a restart) outranks a copy from elsewhere
+            if self.routing.connection_id(&pos.client_id).is_some() || self.restored.contains(&pos.client_id) {
+                continue;
+            }
+            if !federation::claim_remote(&pos.client_id, server_id) {
+                continue;
+            }
+            let previous = self.positions.get(&pos.client_id);
+            if previous != Some(&pos) {
+                let previous_map = previous.map(|previous| (previous.game_id, previous.map_id));
+                if previous_map != Some((pos.game_id, pos.map_id)) {
+                    if let Some((game_id, map_id)) = previous_map {
+                        mapevents::publish(game_id, map_id, mapevents::MapEvent::Left { client_id: pos.client_id.clone() });
+                        changed_maps.insert((game_id, map_id));
+                    }
+                    mapevents::publish(pos.game_id, pos.map_id, mapevents::MapEvent::Joined { client_id: pos.client_id.clone() });
+                }
+                changed_maps.insert((pos.game_id, pos.map_id));
+                self.positions.insert(pos.client_id.clone(), pos.clone());
+            }
+            self.last_update_time.insert(pos.client_id.clone(), now);
+        }
+
+        let affected: Vec<String> = self
+            .positions
+            .values()
+            .filter(|pos| changed_maps.contains(&(pos.game_id, pos.map_id)))
+            .map(|pos| pos.client_id.clone())
+            .collect();
+        for client_id in affected {
+            if let Some(client_tx) = self.routed_sender(&client_id) {
+                notifications.extend(self.reevaluate_pairs(&client_id, &client_tx));
+            }
+        }
+        notifications.sort_by(|a, b| a.0.cmp(&b.0));
+        notifications.dedup_by(|a, b| a.0 == b.0);
+        notifications
+    }
+
+    // drops every client a linked server had here, once its link is gone
+    fn forget_federated_server(&mut self, server_id: &str) -> Vec<(String, outbound::Sender)> {
+        let mut notifications = Vec::new();
+        for client_id in federation::remote_clients_of(server_id) {
+            notifications.extend(self.forget_client(&client_id));
+        }
+        notifications.sort_by(|a, b| a.0.cmp(&b.0));
+        notifications.dedup_by(|a, b| a.0 == b.0);
+        notifications
+    }
+
+    // a repeat of the client's stored position changes nothing: it counts as a sign of life,
+    // without re-evaluating pairs or resending lists. true when the position was one
+    fn refresh_repeated_position(&mut self, pos: &ClientPosition) -> bool {
+        if self.positions.get(&pos.client_id) != Some(pos) {
+            return false;
+        }
+        self.last_update_time.insert(pos.client_id.clone(), Instant::now());
+        metrics::REPEATED_POSITIONS.inc();
+        true
+    }
+
+    // applies a position update and re-evaluates every pair involving the client in one step,
+    // so introductions and removals always land on both sides together.
+    // returns the clients (including the mover) whose peer lists changed and need a NearbyPeers update.
+    fn update_position_and_notify(&mut self, new_pos: ClientPosition, sender_tx: &outbound::Sender) -> Vec<(String, outbound::Sender)> {
+        let client_id = new_pos.client_id.clone();
+        
+        let previous_map = self.positions.get(&client_id).map(|pos| (pos.game_id, pos.map_id));
+        if previous_map != Some((new_pos.game_id, new_pos.map_id)) {
+            if let Some((game_id, map_id)) = previous_map {
+                mapevents::publish(game_id, map_id, mapevents::MapEvent::Left { client_id: client_id.clone() });
+            }
+            mapevents::publish(new_pos.game_id, new_pos.map_id, mapevents::MapEvent::Joined { client_id: client_id.clone() });
+        }
+        if let Some(partition) = self.partitions.place(&new_pos) {
+            if partition > 0 {
+                info!("Client {} placed in overflow partition {} of game {} map {}", client_id, partition, new_pos.game_id, new_pos.map_id);
+            }
+            let _ = sender_tx.try_send(ServerMessage::MapPartition { partition });
+        }
+        let sfu_changed = self.sfu.place(&new_pos, sender_tx);
+        self.positions.insert(client_id.clone(), new_pos.clone());
+        self.last_update_time.insert(client_id.clone(), Instant::now());
+        self.record_position_history(&new_pos);
+        
+        let mut notifications = self.reevaluate_pairs(&client_id, sender_tx);
+        // a merged alt moving changes what its primary hears
+        notifications.extend(self.reevaluate_merged_primary(&client_id));
+        // and a followed client moving takes its spectators' radius along
+        notifications.extend(self.reevaluate_followers(&client_id));
+        // gaining or losing the SFU peer changes the mover's list even when no pair did
+        if sfu_changed && !notifications.iter().any(|(notify_id, _)| *notify_id == client_id) {
+            notifications.push((client_id, sender_tx.clone()));
+        }
+        notifications
+    }
+
+    fn reevaluate_followers(&mut self, client_id: &str) -> Vec<(String, outbound::Sender)> {
+        let followers: Vec<String> = self
+            .spectators
+            .values()
+            .filter(|spectator| spectator.follows(client_id))
+            .map(|spectator| spectator.client_id.clone())
+            .collect();
+        let mut notifications = Vec::new();
+        for follower in followers {
+            if let Some(follower_tx) = self.routed_sender(&follower) {
+                notifications.extend(self.reevaluate_pairs(&follower, &follower_tx));
+            }
+        }
+        notifications
+    }
+
+    // re-check the pairs of the primary a (former) alt merges into, when duplicates are merged
+    fn reevaluate_merged_primary(&mut self, client_id: &str) -> Vec<(String, outbound::Sender)> {
+        let Some(primary) = self.merged_duplicates().and_then(|duplicates| duplicates.primary_of(client_id)).map(str::to_string) else {
+            return Vec::new();
+        };
+        match self.routed_sender(&primary) {
+            Some(primary_tx) => self.reevaluate_pairs(&primary, &primary_tx),
+            None => Vec::new(),
+        }
+    }
+
+    // re-check every pair involving a client at its stored position. returns the clients
+    // (including this one) whose peer lists changed, like update_position_and_notify
+    fn reevaluate_pairs(&mut self, client_id: &str, client_tx: &outbound::Sender) -> Vec<(String, outbound::Sender)> {
+        let client_id = client_id.to_string();
+        let mut notifications = Vec::new();
+        let Some(new_pos) = self.positions.get(&client_id).cloned() else {
+            return notifications;
+        };
+
+        let previous_nearby = self.last_nearby_lists.get(&client_id).cloned().unwrap_or_default();
+        let mut new_peers = Vec::new();
+        let mut lost_peers = Vec::new();
+        for (other_id, other_pos) in &self.positions {
+            if other_id == &client_id {
+                continue;
+            }
+            let was_paired = previous_nearby.contains(other_id);
+            let paired = self.wants_pair(&new_pos, other_pos, was_paired);
+            if paired && !was_paired {
+                new_peers.push(other_id.clone());
+            } else if !paired && was_paired {
+                lost_peers.push(other_id.clone());
+            }
+        }
+        // peers that vanished from positions without a cleanup are dropped too
+        for peer_id in &previous_nearby {
+            if !self.positions.contains_key(peer_id) {
+                lost_peers.push(peer_id.clone());
+            }
+        }
+
+        // peer caps hold back new introductions, but never break existing pairs, announcer zones,
+        // vehicles or spectators. a peer over its own cap is skipped as well. peers separated moments ago come
+        // first, then the nearest, then by id, so jitter at the cap doesn't swap peers around
+        if self.proximity.max_peers.is_some() || self.live_events.values().any(|event| event.max_peers.is_some()) {
+            new_peers.sort_by(|a, b| {
+                let newcomer = |peer_id: &String| !self.pairs.recently_separated(&client_id, peer_id);
+                newcomer(a)
+                    .cmp(&newcomer(b))
+                    .then(new_pos.distance_squared(&self.positions[a]).total_cmp(&new_pos.distance_squared(&self.positions[b])))
+                    .then(a.cmp(b))
+            });
+            let mut peer_count = previous_nearby.len() - lost_peers.len();
+            new_peers.retain(|peer_id| {
+                let other = &self.positions[peer_id];
+                if self.zone_pairs(&new_pos, other) || self.aboard_together(&new_pos, other) || self.is_spectator_pair(&client_id, peer_id) {
+                    return true;
+                }
+                let other_count = self.last_nearby_lists.get(peer_id).map_or(0, |peers| peers.len());
+                let allowed = self.peer_cap(&new_pos).is_none_or(|cap| peer_count < cap)
+                    && self.peer_cap(other).is_none_or(|cap| other_count < cap);
+                if allowed {
+                    peer_count += 1;
+                }
+                allowed
+            });
+        }
+
+        for peer_id in &new_peers {
+            self.introduce_pair(&client_id, peer_id);
+        }
+        for peer_id in &lost_peers {
+            self.separate_pair(&client_id, peer_id);
+        }
+        debug_assert!(self.asymmetric_pairs().is_empty(), "pair symmetry broken after update from {}", client_id);
+
+        // only send updates if there are actually new or lost peers
+        if !new_peers.is_empty() || !lost_peers.is_empty() {
+            notifications.push((client_id.clone(), client_tx.clone()));
+            info!("Client {} peer changes: +{} new peers, -{} lost peers", 
+                  client_id, new_peers.len(), lost_peers.len());
+        }
+
+        // the other side of every changed pair hears about it in the same batch
+        for peer_id in new_peers.iter().chain(lost_peers.iter()) {
+            if let Some(peer_tx) = self.routing.sender(peer_id) {
+                notifications.push((peer_id.clone(), peer_tx));
+                info!("Notifying peer {} of pairing change with {}", peer_id, client_id);
+            }
+        }
+        
+        notifications
+    }
+}
+
+// send fresh nearby lists to clients whose pairings just changed, reading state per client
+// so each gets the latest view
+async fn send_nearby_updates(state: &Arc<RwLock<ServerState>>, notifications: Vec<(String, outbound::Sender)>) {
+    for (notify_client_id, notify_tx) in notifications {
+        let state_read = metrics::timed_read(state, "nearby_updates").await;
+        if let Some(client_pos) = state_read.positions.get(&notify_client_id) {
+            let messages = state_read.nearby_messages(client_pos, true);
+            drop(state_read);
+
+            for response in messages {
+                if let Err(e) = notify_tx.send(response).await {
+                    warn!("Failed to send NearbyPeers update to {}: {}", notify_client_id, e);
+                }
+            }
+        }
+    }
+}
+
+// sends the NearbyPeers updates held back during a reconnect storm as they come due
+async fn send_deferred_nearby_updates(state: Arc<RwLock<ServerState>>) {
+    let mut interval = time::interval(DEFERRED_UPDATE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if metrics::timed_read(&state, "reconnect_storm").await.storm.is_idle() {
+            continue;
+        }
+        let due: Vec<(String, outbound::Sender)> = {
+            let mut state_write = metrics::timed_write(&state, "reconnect_storm").await;
+            let now = Instant::now();
+            let registered = state_write.routing.route_count();
+            state_write.storm.update(now, registered);
+            let due = state_write.storm.take_due(now);
+            due.into_iter().filter_map(|client_id| state_write.routed_sender(&client_id).map(|tx| (client_id, tx))).collect()
+        };
+        send_nearby_updates(&state, due).await;
+    }
+}
+
+async fn handle_connection(
+    state: Arc<RwLock<ServerState>>,
+    db: Arc<Database>,
+    config: Arc<config::Config>,
+    mut raw_stream: TcpStream,
+    addr: SocketAddr,
+) {
+    debug!("New connection attempt from: {}", addr);
+    if !geoip::admit(addr.ip()) {
+        info!("Refusing connection from {}: country not allowed", addr);
+        return;
+    }
+
+    // browsers preflight, load balancers probe and scanners knock with plain HTTP (or worse); answer
+    // or drop those by kind instead of failing the upgrade. only what a person may want to look
+    // into is logged above debug
+    match handshake::sniff(&raw_stream).await {
+        handshake::Sniffed::Http(head) if head.is_upgrade => {}
+        handshake::Sniffed::Http(head) => {
+            let kind = head.kind();
+            metrics::NON_WEBSOCKET_CONNECTIONS.with_label_values(&[kind.name()]).inc();
+            let status = handshake::answer_plain_http(&mut raw_stream, &head, &config.allowed_origins).await;
+            match kind {
+                handshake::RequestKind::PlainHttp => {
+                    info!("Answered {} {} from {} with {} (not a WebSocket upgrade)", head.method, head.path, addr, status)
+                }
+                _ => debug!("Answered {} {} from {} with {} ({})", head.method, head.path, addr, status, kind.name()),
+            }
+            return;
+        }
+        handshake::Sniffed::Tls => {
+            metrics::NON_WEBSOCKET_CONNECTIONS.with_label_values(&["tls"]).inc();
+            info!("Closing connection from {}: TLS handshake on the plain listener (a wss:// url with no TLS terminator in front?)", addr);
+            return;
+        }
+        handshake::Sniffed::NotHttp => {
+            metrics::NON_WEBSOCKET_CONNECTIONS.with_label_values(&["not_http"]).inc();
+            debug!("Closing connection from {}: not HTTP", addr);
+            return;
+        }
+        handshake::Sniffed::Empty => {
+            metrics::NON_WEBSOCKET_CONNECTIONS.with_label_values(&["empty"]).inc();
+            debug!("Connection from {} closed without sending anything", addr);
+            return;
+        }
+        handshake::Sniffed::Unknown => {}
+    }
+
+    let mut wire_protocol = handshake::WireProtocol::Legacy;
+    let mut proxy_identity = None;
+    #[allow(clippy::result_large_err)] // signature fixed by tungstenite's handshake callback
+    let negotiate = |request: &handshake::Request, response: handshake::Response| {
+        if let Some(trusted_proxy) = &config.trusted_proxy {
+            proxy_identity = Some(handshake::check_proxy_identity(request, addr, trusted_proxy)?);
+        }
+        handshake::check_origin(request, &config.allowed_origins)?;
+        let (response, protocol) = handshake::negotiate_subprotocol(request, response, config.allow_legacy_clients)?;
+        wire_protocol = protocol;
+        Ok(response)
+    };
+    let ws_stream = match tokio_tungstenite::accept_hdr_async(raw_stream, negotiate).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            info!("WebSocket handshake failed from {}: {}", addr, e);
+            return;
+        }
+    };
+
+    info!("WebSocket connection established from: {} (protocol {:?})", addr, wire_protocol);
+    let (ws_sender, ws_receiver) = ws_stream.split();
+    serve_client(state, db, addr, wire_protocol.encoding(), proxy_identity, ws_sender, ws_receiver).await;
+}
+
+// transport-independent client session: frames come in on `frames_in`, replies go out on `frames_out`.
+// websocket connections and the experimental webtransport listener both end up here. a connection
+// through a trusted proxy brings the identity the proxy vouched for, which it has to register as
+async fn serve_client<Out, In, E>(
+    state: Arc<RwLock<ServerState>>,
+    db: Arc<Database>,
+    addr: SocketAddr,
+    encoding: codec::Encoding,
+    proxy_identity: Option<handshake::ProxyIdentity>,
+    mut frames_out: Out,
+    mut frames_in: In,
+) where
+    Out: Sink<Message> + Unpin + Send + 'static,
+    In: Stream<Item = Result<Message, E>> + Unpin + Send + 'static,
+    E: std::fmt::Display + Send,
+{
+    // server-generated ID to uniquely identify this WebSocket connection instance
+    let connection_id = Uuid::new_v4().to_string();
+
+    // addresses whose last connection crashed its handler wait out their cooldown
+    let cooldown = metrics::timed_read(&state, "connect").await.panic_cooldown_remaining(addr.ip());
+    if let Some(remaining) = cooldown {
+        warn!("Refusing connection from {}: panic cooldown ({}s left)", addr, remaining.as_secs() + 1);
+        let refusal = ServerMessage::Error(format!("Reconnecting too soon after an error; try again in {}s", remaining.as_secs() + 1));
+        if let Ok(frame) = codec::encode(encoding, &refusal) {
+            let _ = frames_out.send(frame).await;
+        }
+        return;
+    }
+
+    // create a channel for sending messages to this client's WebSocket task
+    let (outbound_queue, send_task_routing) = {
+        let state_read = metrics::timed_read(&state, "connect").await;
+        (state_read.outbound_queue, Arc::clone(&state_read.routing))
+    };
+    let (tx, mut rx) = outbound::channel(&outbound_queue);
+
+    // store the sender tx in the routing table using the connection_id
+    let inbound = {
+        let state_read = metrics::timed_read(&state, "connect").await;
+        state_read.routing.add_connection(&connection_id, tx.clone());
+        state_read.inbound_traffic.open(&connection_id, addr)
+    };
+    info!("Connection established: {} ({})", connection_id, addr);
+    replay::opened(&connection_id);
+
+    // task: sends messages from the channel `rx` to the client's WebSocket `ws_sender`
+    let send_task_connection_id = connection_id.clone(); // clone for the send task
+    let mut send_task = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if chaos::drops(&msg) {
+                continue;
+            }
+            // a stale offer or answer goes back to its sender as an error instead
+            let expired = outbound_queue.signaling_ttl_ms.and_then(|ttl_ms| outbound::expired_signal(&msg, ttl_ms, db::unix_now_ms()));
+            if let Some((kind, sender_id, age_ms)) = expired {
+                let target_id = send_task_routing.client_id(&send_task_connection_id).unwrap_or_default();
+                warn!("Dropping {} from {} to {}: {}ms old by the time it left the queue", kind, sender_id, target_id, age_ms);
+                metrics::SIGNALING_EXPIRED.with_label_values(&[kind]).inc();
+                // never waits: blocking on another queue from a send task could stall both clients
+                if let Some(sender_tx) = send_task_routing.sender(sender_id) {
+                    let _ = sender_tx.try_send(ServerMessage::Error(format!("Your {} to {} expired in a congested queue; negotiate again", kind, target_id)));
+                }
+                continue;
+            }
+            if let Some(delay) = chaos::relay_delay(&msg) {
+                time::sleep(delay).await;
+            }
+            match codec::encode(encoding, &msg) {
+                Ok(frame) => {
+                    replay::sent(&send_task_connection_id, frame.len(), &msg);
+                    if frames_out.send(frame).await.is_err() {
+                        // error sending, client likely disconnected
+                        // log with connection_id as client_id might not be known/relevant here
+                        error!("Failed to send message to {}: transport send error.", send_task_connection_id);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to serialize ServerMessage for {}: {}", send_task_connection_id, e);
+                }
+            }
+        }
+        // when rx closes or send fails, this task ends.
+    });
+
+    // task: receives messages from the client's WebSocket `ws_receiver` and handles them
+    let recv_task_state = Arc::clone(&state);
+    let recv_task_connection_id = connection_id.clone(); // clone for the receive task
+    let recv_task_tx = tx.clone(); // clone tx for sending messages back to this client
+
+    let recv_task = tokio::spawn(crash_reports::connection_scope(&connection_id, addr, async move {
+        let state = recv_task_state;
+        let connection_id = recv_task_connection_id;
+        let tx = recv_task_tx;
+        // store the client-provided ID once received
+        let mut registered_client_id: Option<String> = None;
+        // game of the latest position, for usage accounting
+        let mut game_id: Option<i32> = None;
+        // set once the client switches to signed messages
+        let mut signing: Option<signing::SigningSession> = None;
+        let (require_signing, error_flood, accept_unversioned, routing, offers, pair_tracker, registration_deadline, games) = {
+            let state_read = metrics::timed_read(&state, "connect").await;
+            (
+                state_read.require_message_signing,
+                state_read.error_flood,
+                state_read.accept_unversioned_messages,
+                Arc::clone(&state_read.routing),
+                Arc::clone(&state_read.offers),
+                Arc::clone(&state_read.pairs),
+                Instant::now() + state_read.registration_timeout,
+                Arc::clone(&state_read.games),
+            )
+        };
+        let mut error_budget = flood::ErrorBudget::new(error_flood);
+        // binary frames on a JSON connection are logged once, then only counted
+        let mut warned_binary = false;
+
+        loop {
+            let msg_result = tokio::select! {
+                frame = frames_in.next() => match frame {
+                    Some(frame) => frame,
+                    None => break,
+                },
+                _ = tx.kicked() => {
+                    let reason = tx.close_reason().unwrap_or(CloseReason::Kicked);
+                    info!("Closing connection {} ({}): {:?}",
+                          registered_client_id.as_deref().unwrap_or(&connection_id), addr, reason);
+                    let _ = tx.send(ServerMessage::Closing { reason }).await;
+                    break;
+                }
+                _ = tx.overflowed() => {
+                    warn!("Closing connection {} ({}): outbound queue overflowed",
+                          registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                    break;
+                }
+                _ = time::sleep_until(registration_deadline), if registered_client_id.is_none() => {
+                    info!("Closing connection {} ({}): no UpdatePosition before the registration deadline", connection_id, addr);
+                    metrics::REGISTRATION_TIMEOUTS.inc();
+                    let _ = tx.send(ServerMessage::Closing { reason: CloseReason::RegistrationTimeout }).await;
+                    break;
+                }
+            };
+            let msg = match msg_result {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Transport error receiving from {} ({}): {}",
+                           registered_client_id.as_deref().unwrap_or(&connection_id), addr, e);
+                    break; // exit loop on WebSocket error
+                }
+            };
+            inbound.record(msg.len());
+
+            if msg.is_close() {
+                info!("Received close frame from {} ({})",
+                       registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                break; // exit loop if client sent close frame
+            }
+            if chaos::disconnects() {
+                info!("Chaos: dropping the connection from {} ({})",
+                      registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                break;
+            }
+
+            if let Some(decoded) = codec::decode(encoding, &msg) {
+                let envelope = match decoded {
+                    Ok(envelope) => envelope,
+                    Err(e) => {
+                        error!("Failed to parse message from {} ({}): {}. Message: {}",
+                               registered_client_id.as_deref().unwrap_or(&connection_id), addr, e, codec::describe(&msg));
+                        if error_budget.parse_failed() {
+                            warn!("Closing connection from {} ({}): too many malformed messages",
+                                  registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                            let _ = tx.send(ServerMessage::Error("Too many malformed messages".to_string())).await;
+                            break;
+                        }
+                        if error_budget.allow_error() {
+                            let _ = tx.send(ServerMessage::Error(format!("Invalid message format: {}", e))).await;
+                        }
+                        continue; // skip processing this message
+                    }
+                };
+
+                let mut request = RequestScope::new(&tx, envelope.request_id, &mut error_budget);
+
+                // clients from before the versioned envelope, still running the legacy protocol
+                if envelope.v == 0 {
+                    if !accept_unversioned {
+                        request.error(format!("Unversioned messages are no longer accepted: send \"v\": {}", PROTOCOL_VERSION)).await;
+                        continue;
+                    }
+                    metrics::UNVERSIONED_MESSAGES.inc();
+                }
+
+                // unwrap signed messages; once a connection signs, unsigned ones are refused
+                let client_msg = match signing::accept(signing.as_mut(), envelope.message) {
+                    Ok(signing::Accepted::Signed(inner)) => {
+                        // only the signed copy of the request_id counts
+                        request.request_id = inner.request_id;
+                        inner.message
+                    }
+                    Ok(signing::Accepted::Unsigned(client_msg)) => {
+                        if require_signing
+                            && registered_client_id.is_some()
+                            && !matches!(client_msg, ClientMessage::RequestMessageSigning | ClientMessage::Disconnect)
+                        {
+                            request.error("This server requires signed messages: send RequestMessageSigning".to_string()).await;
+                            continue;
+                        }
+                        client_msg
+                    }
+                    Err(e) => {
+                        warn!("Rejected message from {} ({}): {}",
+                              registered_client_id.as_deref().unwrap_or(&connection_id), addr, e);
+                        request.error(e).await;
+                        continue;
+                    }
+                };
+
+                replay::received(&connection_id, msg.len(), &client_msg);
+
+                let client_msg = match games.admit(client_msg) {
+                    Ok(client_msg) => client_msg,
+                    Err(e) => {
+                        request.error(e).await;
+                        continue;
+                    }
+                };
+
+                // ensure client has registered with UpdatePosition before processing other messages
+                if registered_client_id.is_none() && !matches!(client_msg, ClientMessage::UpdatePosition(_)) {
+                    error!("Received non-UpdatePosition message from unregistered connection {} ({}): {:?}",
+                           connection_id, addr, client_msg);
+                    request.error("Client must send UpdatePosition first.".to_string()).await;
+                    continue;
+                }
+                if let ClientMessage::UpdatePosition(pos) = &client_msg {
+                    game_id = Some(pos.game_id);
+                }
+                if let Some(game_id) = game_id {
+                    usage::record_message(game_id, msg.len());
+                }
+
+                match client_msg {
+                    ClientMessage::UpdatePosition(mut pos) => {
+                        inbound.record_position();
+                        let client_id_from_payload = pos.client_id.clone();
+                        // only the registration reads it; the stored position never carries one
+                        let session_token = pos.session_token.take();
+
+                        // behind a trusted proxy a client is the user it logged in as, whatever id it sends
+                        if let Some(identity) = proxy_identity.as_ref().filter(|identity| registered_client_id.is_none() && identity.user_id != client_id_from_payload) {
+                            warn!("Refusing registration of {} from {}: the proxy identified the user as {}", client_id_from_payload, addr, identity.user_id);
+                            request.error(format!("Register as {}, the user you logged in as", identity.user_id)).await;
+                            continue;
+                        }
+
+                        // refuse banned clients before touching any state
+                        if registered_client_id.is_none() {
+                            // behind the proxy every client has its address, so only user ids are banned
+                            let (client_id, ip) = (client_id_from_payload.clone(), proxy_identity.is_none().then(|| addr.ip().to_string()));
+                            match db.run(move |db| db.active_ban(&client_id, ip.as_deref())).await {
+                                Ok(Some(reason)) => {
+                                    warn!("Rejecting banned client {} ({}): {}", client_id_from_payload, addr, reason);
+                                    audit::record(AuditEvent::BanRejected {
+                                        client_id: client_id_from_payload.clone(),
+                                        ip: addr.ip().to_string(),
+                                        reason: reason.clone(),
+                                    });
+                                    request.error(format!("Banned: {}", reason)).await;
+                                    break;
+                                }
+                                Ok(None) => {}
+                                Err(e) => error!("Ban lookup failed for {} ({}): {}", client_id_from_payload, addr, e),
+                            }
+                        }
+
+                        // scripts get a say in registrations, and whatever else they ask for happens once it's done
+                        let mut script_actions = Vec::new();
+                        if registered_client_id.is_none() && scripting::enabled() {
+                            let events = metrics::timed_read(&state, "script")
+                                .await
+                                .live_events
+                                .values()
+                                .filter(|event| event.contains(&pos))
+                                .map(|event| event.name.clone())
+                                .collect();
+                            let ip = addr.ip().to_string();
+                            script_actions = scripting::run(scripting::Hook::Register, &scripting::Registration {
+                                client_id: client_id_from_payload.clone(),
+                                ip: ip.clone(),
+                                game_id: pos.game_id,
+                                map_id: pos.map_id,
+                                channel: pos.channel,
+                                x: pos.x,
+                                y: pos.y,
+                                events,
+                            });
+                            let refusal = script_actions.iter().find_map(|action| action.refusal(&client_id_from_payload, &ip)).map(str::to_string);
+                            if let Some(reason) = refusal {
+                                warn!("Script refused registration of {} ({}): {}", client_id_from_payload, addr, reason);
+                                scripting::apply(script_actions, &state, &db).await;
+                                request.error(format!("Refused: {}", reason)).await;
+                                break;
+                            }
+                        }
+
+                        let mut state_write = metrics::timed_write(&state, "update_position").await;
+
+                        // Handle first UpdatePosition: Register client_id
+                        let mut mute_state_on_register = None;
+                        let mut spectator_on_register = false;
+                        let mut consent_on_register = None;
+                        let mut storm_arrival = false;
+                        let mut was_restored = false;
+                        let mut resumed = false;
+                        let mut token_on_register = None;
+                        let mut left_remote_peers = Vec::new();
+                        if registered_client_id.is_none() {
+                            // addresses stuck in a reconnect loop are held off before they churn any state
+                            let held_off = state_write.registration_backoff.as_mut().and_then(|backoff| backoff.register(addr.ip(), Instant::now()));
+                            if let Some(delay) = held_off {
+                                drop(state_write);
+                                warn!("Refusing registration of {} from {}: registering too often ({}s hold-off)",
+                                      client_id_from_payload, addr, delay.as_secs_f64().ceil());
+                                metrics::REGISTRATIONS_THROTTLED.inc();
+                                request.error(format!("Reconnecting too often; try again in {}s", delay.as_secs_f64().ceil())).await;
+                                let _ = tx.send(ServerMessage::Closing { reason: CloseReason::RegistrationThrottled }).await;
+                                break;
+                            }
+                            if let Some(duplicates) = state_write.duplicates.as_ref().filter(|duplicates| !duplicates.allows(&client_id_from_payload, addr.ip())) {
+                                let max_per_ip = duplicates.max_per_ip();
+                                drop(state_write);
+                                warn!("Refusing registration of {} from {}: {} clients already registered from that address",
+                                      client_id_from_payload, addr, max_per_ip);
+                                metrics::DUPLICATES_REFUSED.inc();
+                                request.error(format!("At most {} clients may register from one address", max_per_ip)).await;
+                                let _ = tx.send(ServerMessage::Closing { reason: CloseReason::TooManyFromAddress }).await;
+                                break;
+                            }
+                            // a reconnect presenting the live registration's token takes it over as it stands:
+                            // only the route moves, and what was bound to the old connection's queue
+                            let existing_conn_id = state_write.routing.connection_id(&client_id_from_payload);
+                            resumed = state_write.resumes_registration(&client_id_from_payload, session_token.as_deref());
+                            if resumed {
+                                info!("Client {} resumed its registration from connection {} on {}",
+                                      client_id_from_payload, existing_conn_id.as_deref().unwrap_or_default(), connection_id);
+                                metrics::REGISTRATIONS_RESUMED.inc();
+                                state_write.echo_sessions.remove(&client_id_from_payload);
+                                state_write.sfu.remove(&client_id_from_payload);
+                            }
+                            // Check if this client_id is already mapped to another connection
+                            else if let Some(existing_conn_id) = existing_conn_id {
+                                // Simple approach: Log warning, assume client reconnected, update mapping.
+                                warn!("Client ID {} already registered to connection {}. Re-registering to {}",
+                                       client_id_from_payload, existing_conn_id, connection_id);
+                                // Remove old connection's entry if it exists (might be slightly inconsistent if old conn is still cleaning up)
+                                if let Some(_old_tx) = state_write.routing.connection(&existing_conn_id) {
+                                    // Maybe send a disconnect message to the old connection?
+                                    // _old_tx.send(ServerMessage::Error("Disconnected: Replaced by new connection".to_string())).await;
+                                }
+                                // clean up ALL old client data to prevent stale position data issues
+                                state_write.routing.unroute(&client_id_from_payload);
+                                state_write.forget_client(&client_id_from_payload);
+                                // note: not removing from connections as old connection will clean itself up
+                            }
+
+                            // a client that moved over from a federated server leaves its copy from there behind
+                            if federation::is_remote(&client_id_from_payload) {
+                                info!("Client {} moved here from a federated server", client_id_from_payload);
+                                left_remote_peers = state_write.forget_client(&client_id_from_payload);
+                            }
+                            // and one with a trace client's id takes its place
+                            if state_write.trace_clients.contains(&client_id_from_payload) {
+                                info!("Client {} replaces the trace client of that id", client_id_from_payload);
+                                left_remote_peers = state_write.forget_client(&client_id_from_payload);
+                            }
+
+                            state_write.routing.route(&client_id_from_payload, &connection_id);
+                            let primary = state_write.duplicates.as_mut().and_then(|duplicates| duplicates.register(&client_id_from_payload, addr.ip()));
+                            if let Some(primary) = primary {
+                                info!("Client {} registered from the same address as {}", client_id_from_payload, primary);
+                            }
+                            was_restored = state_write.restored.remove(&client_id_from_payload);
+                            if !resumed {
+                                state_write.session_tokens.insert(client_id_from_payload.clone(), Uuid::new_v4().to_string());
+                            }
+                            token_on_register = state_write.session_tokens.get(&client_id_from_payload).cloned();
+                            if let Some(region) = proxy_identity.as_ref().and_then(|identity| identity.region.clone()) {
+                                state_write.regions.insert(client_id_from_payload.clone(), region);
+                            }
+                            let registered = state_write.routing.route_count();
+                            storm_arrival = state_write.storm.registered(Instant::now(), registered);
+                            registered_client_id = Some(client_id_from_payload.clone());
+                            crash_reports::set_client_id(&client_id_from_payload);
+                            inbound.set_client_id(&client_id_from_payload);
+                            info!("Client registered: ID {} mapped to connection {} ({})",
+                                  client_id_from_payload, connection_id, addr);
+                            audit::record(AuditEvent::Registered {
+                                client_id: client_id_from_payload.clone(),
+                                connection_id: connection_id.clone(),
+                                ip: addr.ip().to_string(),
+                                game_id: pos.game_id,
+                                map_id: pos.map_id,
+                            });
+                            // mutes outlive the muted client's connection, so a reconnect learns them again
+                            let muted_by = state_write.muted_by(&client_id_from_payload);
+                            if !muted_by.is_empty() {
+                                mute_state_on_register = Some(ServerMessage::PeerMuteState { muted_by });
+                            }
+                            spectator_on_register = state_write.spectators.contains_key(&client_id_from_payload);
+                            consent_on_register = state_write.elevated_consents.get(&client_id_from_payload).copied();
+                        }
+                        // If already registered, ensure the client_id hasn't changed (or handle as error)
+                        else if registered_client_id.as_ref() != Some(&client_id_from_payload) {
+                            error!("Client {} (connection {}) sent UpdatePosition with conflicting ID {}. Ignoring.",
+                                   registered_client_id.as_deref().unwrap_or_default(), connection_id, client_id_from_payload);
+                            drop(state_write); // Release lock before continuing
+                            continue;
+                        }
+                        // a registration retried on the connection that made it (the client gave up
+                        // waiting on a slow reply) is a repeat of the stored position like any other
+                        else if state_write.refresh_repeated_position(&pos) {
+                            continue;
+                        }
+
+                        // Use optimized update that only sends notifications when nearby lists change
+                        let mut notifications = state_write.update_position_and_notify(pos, &tx);
+                        // a client back from before a restart, or resuming its registration, may still
+                        // have its pairs, so nothing changed; it gets them anyway on its new connection
+                        if (was_restored || resumed) && !notifications.iter().any(|(client_id, _)| *client_id == client_id_from_payload) {
+                            notifications.push((client_id_from_payload.clone(), tx.clone()));
+                        }
+                        for (peer_id, peer_tx) in left_remote_peers {
+                            if !notifications.iter().any(|(client_id, _)| *client_id == peer_id) {
+                                notifications.push((peer_id, peer_tx));
+                            }
+                        }
+                        // during a reconnect storm the fan-out from arrivals is spread out instead of sent now
+                        if storm_arrival {
+                            state_write.storm.defer(notifications.drain(..).map(|(client_id, _)| client_id), Instant::now());
+                        }
+                        
+                        // Release write lock before sending notifications to reduce contention
+                        drop(state_write);
+
+                        if let Some(token) = token_on_register {
+                            let _ = tx.send(ServerMessage::SessionToken { token }).await;
+                        }
+                        // a spectator learns it is one before its first introductions
+                        if spectator_on_register {
+                            let _ = tx.send(ServerMessage::ListenOnly { enabled: true }).await;
+                        }
+                        if let Some(consent) = consent_on_register {
+                            let _ = tx.send(ServerMessage::ElevatedConsent(consent)).await;
+                        }
+                        // Send notifications outside of write lock
+                        send_nearby_updates(&state, notifications).await;
+                        if let Some(mute_state) = mute_state_on_register {
+                            let _ = tx.send(mute_state).await;
+                        }
+                        scripting::apply(script_actions, &state, &db).await;
+                    }
+                    ClientMessage::RequestPeerRefresh => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            // send current nearby peers regardless of cache
+                            let state_read = metrics::timed_read(&state, "peer_refresh").await;
+                        if let Some(client_pos) = state_read.positions.get(sender_id) {
+                            let messages = state_read.nearby_messages(client_pos, false);
+                            drop(state_read);
+                                
+                                for response in messages {
+                                    if let Err(_e) = tx.send(response).await {
+                                        warn!("Failed to send peer refresh to {}: {}", sender_id, _e);
+                                    }
+                                }
+                                info!("Sent peer refresh to {} (explicit request)", sender_id);
+                            }
+                        } else {
+                            error!("RequestPeerRefresh received before client ID registration (connection {}).", connection_id);
+                        }
+                    }
+                    ClientMessage::SendOffer { target_id, offer } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            // the echo peer answers from inside the server
+                            if target_id == echo::ECHO_PEER_ID {
+                                match metrics::timed_read(&state, "offer").await.echo_sessions.get(sender_id) {
+                                    Some(session) => session.signal(echo::Signal::Offer(offer)),
+                                    None => {
+                                        request.error(format!("Client {} not found", target_id)).await;
+                                    }
+                                }
+                                continue;
+                            }
+                            // so does the SFU of the sender's event area
+                            if target_id.starts_with(sfu::SFU_PEER_PREFIX) {
+                                if !metrics::timed_read(&state, "offer").await.sfu.signal(sender_id, &target_id, sfu::Signal::Offer(offer)) {
+                                    request.error(format!("Client {} not found", target_id)).await;
+                                }
+                                continue;
+                            }
+                            let verdict = offers.check(sender_id, &target_id, &offer);
+                            if matches!(verdict, OfferVerdict::Relay | OfferVerdict::GlareWon) {
+                                pair_tracker.advance(sender_id, &target_id, pairs::PairState::Offered);
+                            }
+                            // the greater id rolls its offer back, whichever arrived first: a winning
+                            // offer goes out behind the rollback, a suppressed one's peer already has
+                            if let Some(loser) = verdict.loser(sender_id, &target_id) {
+                                let peer_id = if loser == sender_id { target_id.clone() } else { sender_id.clone() };
+                                info!("Offer glare between {} and {}, telling {} to roll its offer back", sender_id, target_id, loser);
+                                if let Some(loser_tx) = routing.sender(loser) {
+                                    let _ = loser_tx.send(ServerMessage::GlareRollback { peer_id }).await;
+                                }
+                            }
+                            match verdict {
+                                OfferVerdict::Relay | OfferVerdict::GlareWon => {}
+                                OfferVerdict::Duplicate => {
+                                    info!("Dropping duplicate offer from {} to {}", sender_id, target_id);
+                                    continue;
+                                }
+                                OfferVerdict::GlareSuppressed => {
+                                    info!("Suppressing offer from {} to {} (glare, peer's offer wins)", sender_id, target_id);
+                                    continue;
+                                }
+                            }
+
+                            // the target's queue comes from the routing table, outside the state lock
+                            if let Some(target_tx) = routing.sender(&target_id) {
+                                let offer_len = offer.len();
+                                let offer_msg = ServerMessage::ReceiveOffer { sender_id: sender_id.clone(), offer, relayed_at_ms: Some(db::unix_now_ms()) };
+                                if let Err(e) = target_tx.send(offer_msg).await {
+                                    error!("Failed to relay offer from {} to {}: {}", sender_id, target_id, e);
+                                    audit::record(AuditEvent::RelayFailed {
+                                        sender_id: sender_id.clone(),
+                                        target_id: target_id.clone(),
+                                        kind: "offer",
+                                        error: e.to_string(),
+                                    });
+                                    request.error(format!("Failed to send offer to {}", target_id)).await;
+                                } else if let Some(game_id) = game_id {
+                                    usage::record_relay(game_id, offer_len);
+                                }
+                            } else if federation::relay(sender_id, &target_id, federation::Signal::Offer(offer)) {
+                                // on its way to the target's server
+                            } else {
+                                error!("Target client {} not found for offer from {}", target_id, sender_id);
+                                audit::record(AuditEvent::RelayFailed {
+                                    sender_id: sender_id.clone(),
+                                    target_id: target_id.clone(),
+                                    kind: "offer",
+                                    error: "target not connected".to_string(),
+                                });
+                                request.error(format!("Client {} not found", target_id)).await;
+                            }
+                        } else {
+                            error!("SendOffer received before client ID registration (connection {}).", connection_id);
+                        }
+                    }
+                    ClientMessage::SendAnswer { target_id, answer } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            // answers to the SFU's renegotiation offers
+                            if target_id.starts_with(sfu::SFU_PEER_PREFIX) {
+                                if !metrics::timed_read(&state, "answer").await.sfu.signal(sender_id, &target_id, sfu::Signal::Answer(answer)) {
+                                    request.error(format!("Client {} not found", target_id)).await;
+                                }
+                                continue;
+                            }
+                            pair_tracker.advance(sender_id, &target_id, pairs::PairState::Answered);
+                            if let Some(target_tx) = routing.sender(&target_id) {
+                                let answer_len = answer.len();
+                                let answer_msg = ServerMessage::ReceiveAnswer { sender_id: sender_id.clone(), answer, relayed_at_ms: Some(db::unix_now_ms()) };
+                                if let Err(e) = target_tx.send(answer_msg).await {
+                                    error!("Failed to relay answer from {} to {}: {}", sender_id, target_id, e);
+                                    audit::record(AuditEvent::RelayFailed {
+                                        sender_id: sender_id.clone(),
+                                        target_id: target_id.clone(),
+                                        kind: "answer",
+                                        error: e.to_string(),
+                                    });
+                                    request.error(format!("Failed to send answer to {}", target_id)).await;
+                                } else if let Some(game_id) = game_id {
+                                    usage::record_relay(game_id, answer_len);
+                                }
+                            } else if federation::relay(sender_id, &target_id, federation::Signal::Answer(answer)) {
+                                // on its way to the target's server
+                            } else {
+                                error!("Target client {} not found for answer from {}", target_id, sender_id);
+                                audit::record(AuditEvent::RelayFailed {
+                                    sender_id: sender_id.clone(),
+                                    target_id: target_id.clone(),
+                                    kind: "answer",
+                                    error: "target not connected".to_string(),
+                                });
+                                request.error(format!("Client {} not found", target_id)).await;
+                            }
+                         } else {
+                            error!("SendAnswer received before client ID registration (connection {}).", connection_id);
+                        }
+                    }
+                    ClientMessage::SendIceCandidate { target_id, candidate } => {
+                        if overload::should_shed(ShedLevel::IceRelay, "ice_candidate") {
+                            continue;
+                        }
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            if target_id == echo::ECHO_PEER_ID {
+                                if let Some(session) = metrics::timed_read(&state, "ice_candidate").await.echo_sessions.get(sender_id) {
+                                    session.signal(echo::Signal::IceCandidate(candidate));
+                                }
+                                continue;
+                            }
+                            if target_id.starts_with(sfu::SFU_PEER_PREFIX) {
+                                metrics::timed_read(&state, "ice_candidate").await.sfu.signal(sender_id, &target_id, sfu::Signal::IceCandidate(candidate));
+                                continue;
+                            }
+                            if let Some(target_tx) = routing.sender(&target_id) {
+                                let candidate_len = candidate.len();
+                                let candidate_msg = ServerMessage::ReceiveIceCandidate { sender_id: sender_id.clone(), candidate };
+                                if let Err(_e) = target_tx.send(candidate_msg).await {
+                                    // don't log error for every ICE candidate failure, might be too noisy
+                                    // don't notify sender either, usually transient
+                                } else if let Some(game_id) = game_id {
+                                    usage::record_relay(game_id, candidate_len);
+                                }
+                            } else {
+                                // a remote target's goes to its server; otherwise the target isn't
+                                // found or is disconnecting, and ICE is ignored
+                                federation::relay(sender_id, &target_id, federation::Signal::IceCandidate(candidate));
+                            }
+                         } else {
+                            // ignore ICE if client not registered yet
+                            // error!("SendIceCandidate received before client ID registration (connection {}).", connection_id);
+                        }
+                    }
+                    ClientMessage::ReportPeer { target_id, reason } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let (reporter_id, reporter_ip, reported_id, stored_reason) = (sender_id.clone(), addr.ip().to_string(), target_id.clone(), reason.clone());
+                            let stored = db
+                                .run(move |db| {
+                                    let outcome = db.add_report(&reporter_id, &reporter_ip, &reported_id, &stored_reason)?;
+                                    // scripts see how many others have reported the client so far
+                                    let reporters = match outcome {
+                                        db::ReportOutcome::Stored if scripting::enabled() => db.distinct_reporters(&reported_id).unwrap_or_default(),
+                                        _ => 0,
+                                    };
+                                    Ok((outcome, reporters))
+                                })
+                                .await;
+                            match stored {
+                                Ok((db::ReportOutcome::Stored, reporters)) => {
+                                    info!("Client {} reported {}", sender_id, target_id);
+                                    if scripting::enabled() {
+                                        let actions = scripting::run(scripting::Hook::Report, &scripting::Report {
+                                            reporter_id: sender_id.clone(),
+                                            reporter_ip: addr.ip().to_string(),
+                                            reporters,
+                                            target_id,
+                                            reason,
+                                        });
+                                        scripting::apply(actions, &state, &db).await;
+                                    }
+                                }
+                                // already on file; the reporter needn't know it wasn't stored twice
+                                Ok((db::ReportOutcome::Duplicate, _)) => debug!("Client {} reported {} again within the hour", sender_id, target_id),
+                                Ok((db::ReportOutcome::RateLimited, _)) => {
+                                    warn!("Dropping report from {} about {}: too many reports in the last hour", sender_id, target_id);
+                                    request.error("Too many reports; try again later".to_string()).await;
+                                }
+                                Err(e) => {
+                                    error!("Failed to store report from {} about {}: {}", sender_id, target_id, e);
+                                    request.error("Failed to submit report".to_string()).await;
+                                }
+                            }
+                        }
+                    }
+                    ClientMessage::SetPreferences(preferences) => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            info!("Client {} set preferences: {:?}", sender_id, preferences);
+                            let mut state_write = metrics::timed_write(&state, "set_preferences").await;
+                            let opt_out = preferences.spectator_opt_out;
+                            let share_address = preferences.share_public_address;
+                            let previous = state_write.preferences.insert(sender_id.clone(), preferences);
+                            // opting in or out of spectators changes who it pairs with
+                            let mut notifications = Vec::new();
+                            if previous.as_ref().is_some_and(|previous| previous.spectator_opt_out) != opt_out && !state_write.spectators.is_empty() {
+                                notifications = state_write.reevaluate_pairs(sender_id, &tx);
+                            }
+                            // and sharing its address or not changes the PeerAddresses of it and its peers
+                            if previous.is_some_and(|previous| previous.share_public_address) != share_address && state_write.reflexive_address_hints {
+                                for client_id in std::iter::once(sender_id.clone()).chain(state_write.paired_peers(sender_id)) {
+                                    if !notifications.iter().any(|(notify_id, _)| *notify_id == client_id) {
+                                        if let Some(client_tx) = state_write.routed_sender(&client_id) {
+                                            notifications.push((client_id, client_tx));
+                                        }
+                                    }
+                                }
+                            }
+                            drop(state_write);
+                            send_nearby_updates(&state, notifications).await;
+                        }
+                    }
+                    ClientMessage::GetPopulation => {
+                        if overload::should_shed(ShedLevel::Extras, "population") {
+                            request.error("Server busy, try again later".to_string()).await;
+                            continue;
+                        }
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let state_read = metrics::timed_read(&state, "population").await;
+                            let game_id = state_read.positions.get(sender_id).map(|pos| pos.game_id);
+                            let population = state_read.population(game_id);
+                            drop(state_read);
+                            let _ = tx.send(ServerMessage::Population(population)).await;
+                        }
+                    }
+                    ClientMessage::GetServerStats => {
+                        if overload::should_shed(ShedLevel::Extras, "server_stats") {
+                            request.error("Server busy, try again later".to_string()).await;
+                            continue;
+                        }
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let state_read = metrics::timed_read(&state, "server_stats").await;
+                            let game_id = state_read.positions.get(sender_id).map(|pos| pos.game_id);
+                            let online_in_game = state_read.positions.values().filter(|pos| Some(pos.game_id) == game_id).count();
+                            let message = ServerMessage::ServerStats {
+                                uptime_secs: state_read.started_at.elapsed().as_secs(),
+                                online_in_game,
+                                position_updates_per_sec: state_read.inbound_traffic.position_rate(&connection_id).unwrap_or(0.0),
+                            };
+                            drop(state_read);
+                            let _ = tx.send(message).await;
+                        }
+                    }
+                    ClientMessage::ListServers => {
+                        if overload::should_shed(ShedLevel::Extras, "list_servers") {
+                            request.error("Server busy, try again later".to_string()).await;
+                            continue;
+                        }
+                        match registry::list_servers().await {
+                            Ok(servers) => {
+                                let _ = tx.send(ServerMessage::ServerList(servers)).await;
+                            }
+                            Err(e) => request.error(e).await,
+                        }
+                    }
+                    ClientMessage::RequestReintroduction { peer_id } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let state_read = metrics::timed_read(&state, "reintroduction_request").await;
+                            let paired = state_read.last_nearby_lists.get(sender_id).is_some_and(|peers| peers.contains(&peer_id));
+                            let peer_tx = state_read.routed_sender(&peer_id);
+                            drop(state_read);
+                            if paired {
+                                offers.clear_between(sender_id, &peer_id);
+                            }
+
+                            match (paired, peer_tx) {
+                                (true, Some(peer_tx)) => {
+                                    // both sides redo just this pairing
+                                    info!("Reintroducing pair {} <-> {} (requested by {})", sender_id, peer_id, sender_id);
+                                    let _ = tx.send(ServerMessage::ReintroducePeer { peer_id: peer_id.clone() }).await;
+                                    let _ = peer_tx.send(ServerMessage::ReintroducePeer { peer_id: sender_id.clone() }).await;
+                                }
+                                _ => {
+                                    warn!("Client {} requested reintroduction to {} but they are not paired", sender_id, peer_id);
+                                    request.error(format!("Not paired with {}", peer_id)).await;
+                                }
+                            }
+                        }
+                    }
+                    ClientMessage::ReportIceFailure { peer_id } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let mut state_write = metrics::timed_write(&state, "ice_failure").await;
+                            let paired = state_write.last_nearby_lists.get(sender_id).is_some_and(|peers| peers.contains(&peer_id));
+                            if !paired {
+                                drop(state_write);
+                                request.error(format!("Not paired with {}", peer_id)).await;
+                                continue;
+                            }
+                            state_write.pairs.advance(sender_id, &peer_id, pairs::PairState::Failed);
+                            // without TURN servers there is nothing better to suggest
+                            if state_write.turn.is_none() {
+                                continue;
+                            }
+                            let was_relayed = state_write.needs_relay(sender_id, &peer_id);
+                            let failures = state_write.ice_failures.entry((sender_id.clone(), peer_id.clone())).or_default();
+                            *failures += 1;
+                            info!("Client {} reported ICE failure {} with {}", sender_id, failures, peer_id);
+                            if was_relayed || !state_write.needs_relay(sender_id, &peer_id) {
+                                continue;
+                            }
+
+                            // the pair just crossed the threshold: reintroduce it now, relay-only
+                            state_write.offers.clear_between(sender_id, &peer_id);
+                            let mut reintroductions = Vec::new();
+                            for (client_id, other_id) in [(sender_id.as_str(), peer_id.as_str()), (peer_id.as_str(), sender_id.as_str())] {
+                                if let (Some(client_tx), Some(force_relay)) =
+                                    (state_write.routed_sender(client_id), state_write.force_relay(client_id, other_id))
+                                {
+                                    reintroductions.push((client_tx, force_relay, other_id.to_string()));
+                                }
+                            }
+                            drop(state_write);
+
+                            info!("Pair {} <-> {} keeps failing ICE, reintroducing it through TURN", sender_id, peer_id);
+                            for (client_tx, force_relay, other_id) in reintroductions {
+                                let _ = client_tx.send(force_relay).await;
+                                let _ = client_tx.send(ServerMessage::ReintroducePeer { peer_id: other_id }).await;
+                            }
+                        }
+                    }
+                    ClientMessage::ReportPeerConnected { peer_id } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let previous = pair_tracker.advance(sender_id, &peer_id, pairs::PairState::Connected);
+                            match previous {
+                                Some(previous) if previous.state != pairs::PairState::Connected => {
+                                    info!("Client {} connected to {} (was {})", sender_id, peer_id, previous.state.name());
+                                    metrics::PAIR_SETUP.observe(previous.introduced_at.elapsed().as_secs_f64());
+                                }
+                                Some(_) => {}
+                                None => request.error(format!("Not paired with {}", peer_id)).await,
+                            }
+                        }
+                    }
+                    ClientMessage::RequestUdpSession => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let mut state_write = metrics::timed_write(&state, "udp_session").await;
+                            let Some(port) = state_write.udp_port else {
+                                drop(state_write);
+                                request.error("UDP fast-path is not enabled".to_string()).await;
+                                continue;
+                            };
+                            // datagrams carry integer coordinates only
+                            if game_id.is_some_and(|game_id| state_write.games.get(game_id).float_coordinates()) {
+                                drop(state_write);
+                                request.error("UDP fast-path isn't available for float-coordinate games".to_string()).await;
+                                continue;
+                            }
+                            let (session_id, key) = state_write.open_udp_session(sender_id, &connection_id);
+                            drop(state_write);
+                            info!("Opened UDP session {} for {}", session_id, sender_id);
+                            let _ = tx.send(ServerMessage::UdpSession { session_id, key: hex::encode(key), port }).await;
+                        }
+                    }
+                    ClientMessage::RequestEchoPeer => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let mut state_write = metrics::timed_write(&state, "echo_peer").await;
+                            // a repeated request restarts the test with a fresh peer connection
+                            let ice_servers = state_write.echo_ice_servers.clone();
+                            let Some(session) = echo::start(sender_id, tx.clone(), ice_servers) else {
+                                drop(state_write);
+                                request.error("Echo test peer is not available on this server".to_string()).await;
+                                continue;
+                            };
+                            state_write.echo_sessions.insert(sender_id.clone(), session);
+                            drop(state_write);
+                            info!("Started echo test for {}", sender_id);
+                            send_nearby_updates(&state, vec![(sender_id.clone(), tx.clone())]).await;
+                        }
+                    }
+                    ClientMessage::SetMuteState { muted_peer_ids } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let mut state_write = metrics::timed_write(&state, "mute_state").await;
+                            let changed = state_write.set_mutes(sender_id, muted_peer_ids);
+                            let updates: Vec<_> = changed
+                                .iter()
+                                .filter_map(|peer_id| {
+                                    let muted_by = state_write.muted_by(peer_id);
+                                    state_write.routed_sender(peer_id).map(|peer_tx| (peer_tx, ServerMessage::PeerMuteState { muted_by }))
+                                })
+                                .collect();
+                            drop(state_write);
+                            for (peer_tx, update) in updates {
+                                let _ = peer_tx.send(update).await;
+                            }
+                        }
+                    }
+                    ClientMessage::SetTags(tags) => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let tags: BTreeMap<_, _> = tags.into_iter().map(|(key, value)| (key, names::sanitize(&value))).collect();
+                            if let Err(e) = tags::validate(&tags) {
+                                request.error(e).await;
+                                continue;
+                            }
+                            let mut state_write = metrics::timed_write(&state, "tags").await;
+                            let filter = &state_write.name_filter;
+                            if let Some((key, _)) = tags.iter().find(|(key, value)| filter.is_blocked(key) || filter.is_blocked(value)) {
+                                drop(state_write);
+                                info!("Rejected blocked tag {} from {}", key, sender_id);
+                                request.error(format!("Tag {} is not allowed on this server", key)).await;
+                                continue;
+                            }
+                            if tags.is_empty() {
+                                state_write.tags.remove(sender_id);
+                            } else {
+                                state_write.tags.insert(sender_id.clone(), tags);
+                            }
+                            // current peers get details with the new tags
+                            let peers = state_write.paired_peer_senders(sender_id);
+                            drop(state_write);
+                            send_nearby_updates(&state, peers).await;
+                        }
+                    }
+                    ClientMessage::SetVehicle { vehicle_id } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let vehicle_id = vehicle_id.map(|id| id.trim().to_string()).filter(|id| !id.is_empty());
+                            if vehicle_id.as_ref().is_some_and(|id| id.len() > MAX_VEHICLE_ID_LEN) {
+                                request.error(format!("vehicle_id may be at most {} bytes", MAX_VEHICLE_ID_LEN)).await;
+                                continue;
+                            }
+                            let mut state_write = metrics::timed_write(&state, "vehicle").await;
+                            let previous = match &vehicle_id {
+                                Some(id) => state_write.vehicles.insert(sender_id.clone(), id.clone()),
+                                None => state_write.vehicles.remove(sender_id),
+                            };
+                            if previous == vehicle_id {
+                                continue;
+                            }
+                            info!("Client {} {}", sender_id, match &vehicle_id {
+                                Some(id) => format!("boarded vehicle {}", id),
+                                None => "left its vehicle".to_string(),
+                            });
+                            let notifications = state_write.reevaluate_pairs(sender_id, &tx);
+                            drop(state_write);
+                            send_nearby_updates(&state, notifications).await;
+                        }
+                    }
+                    ClientMessage::SetElevatedConsent(consent) => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let client_id = sender_id.clone();
+                            if let Err(e) = db.run(move |db| db.set_elevated_consent(&client_id, consent)).await {
+                                error!("Failed to store the elevated consent of {}: {}", sender_id, e);
+                                request.error("Failed to store consent".to_string()).await;
+                                continue;
+                            }
+                            info!("Client {} set elevated consent: {:?}", sender_id, consent);
+                            let mut state_write = metrics::timed_write(&state, "elevated_consent").await;
+                            if consent == ElevatedConsent::default() {
+                                state_write.elevated_consents.remove(sender_id);
+                            } else {
+                                state_write.elevated_consents.insert(sender_id.clone(), consent);
+                            }
+                            // a refusal drops the pairs it covers straight away
+                            let notifications = state_write.reevaluate_pairs(sender_id, &tx);
+                            drop(state_write);
+                            send_nearby_updates(&state, notifications).await;
+                        }
+                    }
+                    ClientMessage::RequestMessageSigning => {
+                        // a repeated (signed) request rotates the key and restarts the nonces
+                        let session = signing::SigningSession::new();
+                        let key = session.key_hex();
+                        signing = Some(session);
+                        info!("Started message signing for {} ({})",
+                              registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                        let _ = tx.send(ServerMessage::SigningSession { key }).await;
+                    }
+                    ClientMessage::Signed { .. } => {} // unwrapped above
+                    ClientMessage::UpdatePositionFloat(_) => {} // converted above
+                    ClientMessage::SetIdentityKey { public_key } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let fingerprint = match identity::fingerprint(&public_key) {
+                                Ok(fingerprint) => fingerprint,
+                                Err(e) => {
+                                    request.error(e).await;
+                                    continue;
+                                }
+                            };
+                            let mut state_write = metrics::timed_write(&state, "identity_key").await;
+                            // a key swapped mid-session is what an impersonation would look like, so refuse it
+                            match state_write.identity_fingerprints.get(sender_id) {
+                                Some(existing) if *existing == fingerprint => continue,
+                                Some(_) => {
+                                    drop(state_write);
+                                    warn!("Refusing identity key change from {} ({})", sender_id, addr);
+                                    request.error("Identity key is already registered for this session".to_string()).await;
+                                    continue;
+                                }
+                                None => {}
+                            }
+                            info!("Client {} registered identity key {}", sender_id, fingerprint);
+                            state_write.identity_fingerprints.insert(sender_id.clone(), fingerprint);
+                            let peers = state_write.paired_peer_senders(sender_id);
+                            drop(state_write);
+                            send_nearby_updates(&state, peers).await;
+                        }
+                    }
+                    ClientMessage::ReportNatType { nat_type } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let mut state_write = metrics::timed_write(&state, "nat_type").await;
+                            if state_write.nat_types.insert(sender_id.clone(), nat_type) == Some(nat_type) {
+                                continue;
+                            }
+                            info!("Client {} reported NAT type {:?}", sender_id, nat_type);
+                            // the reporter and its peers get fresh introductions (with ForceRelay for symmetric pairs)
+                            let mut notifications = state_write.paired_peer_senders(sender_id);
+                            notifications.push((sender_id.clone(), tx.clone()));
+                            drop(state_write);
+                            send_nearby_updates(&state, notifications).await;
+                        }
+                    }
+                    ClientMessage::ReportNetworkQuality { rtt_ms, packet_loss_percent } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            if !(0.0..=100.0).contains(&packet_loss_percent) {
+                                request.error("packet_loss_percent must be from 0 to 100".to_string()).await;
+                                continue;
+                            }
+                            let mut state_write = metrics::timed_write(&state, "network_quality").await;
+                            if state_write.network_quality.is_none() {
+                                continue;
+                            }
+                            let low_before = state_write.low_quality_peers(sender_id);
+                            state_write.network_reports.insert(sender_id.clone(), (rtt_ms, packet_loss_percent));
+                            // skipped pairs may be good enough now (or newly skipped ones are left out)
+                            let mut notifications = state_write.reevaluate_pairs(sender_id, &tx);
+                            // and the two sides of every pair that crossed the limit see the new low_quality
+                            let low_after = state_write.low_quality_peers(sender_id);
+                            for peer_id in low_before.symmetric_difference(&low_after) {
+                                if let Some(peer_tx) = state_write.routed_sender(peer_id) {
+                                    notifications.push((peer_id.clone(), peer_tx));
+                                }
+                            }
+                            if low_before != low_after {
+                                notifications.push((sender_id.clone(), tx.clone()));
+                            }
+                            notifications.sort_by(|x, y| x.0.cmp(&y.0));
+                            notifications.dedup_by(|x, y| x.0 == y.0);
+                            drop(state_write);
+                            send_nearby_updates(&state, notifications).await;
+                        }
+                    }
+                    ClientMessage::Pong { nonce } => {
+                        if let Some(sender_id) = registered_client_id.as_ref() {
+                            let mut state_write = metrics::timed_write(&state, "pong").await;
+                            // a late or unsolicited Pong is simply not counted
+                            if let Some(rtt) = state_write.finish_rtt_probe(sender_id, nonce) {
+                                metrics::CLIENT_RTT.observe(rtt.as_secs_f64());
+                            }
+                        }
+                    }
+                    ClientMessage::Disconnect => {
+                        info!("Received Disconnect message from {} ({})",
+                               registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                        break; // exit the loop
+                    }
+                }
+            } else if msg.is_binary() {
+                // only JSON connections get here; the binary encodings are negotiated as subprotocols
+                metrics::UNEXPECTED_BINARY_FRAMES.inc();
+                if !warned_binary {
+                    warned_binary = true;
+                    warn!("Ignoring binary frames from {} ({}): the connection negotiated JSON",
+                          registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                    if error_budget.allow_error() {
+                        let hint = format!("Binary frames need the {} or {} subprotocol; ignoring them", SUBPROTOCOL_V1_CBOR, SUBPROTOCOL_V1_PROTO);
+                        let _ = tx.send(ServerMessage::Error(hint)).await;
+                    }
+                }
+                if error_budget.binary_frame() {
+                    warn!("Closing connection from {} ({}): kept sending binary frames",
+                          registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+                    let _ = tx.send(ServerMessage::Closing { reason: CloseReason::UnexpectedBinaryFrames }).await;
+                    break;
+                }
+            } // ignore Ping/Pong etc for now
+        }
+        if error_budget.suppressed() > 0 {
+            info!("Suppressed {} error replies to {} ({})", error_budget.suppressed(),
+                  registered_client_id.as_deref().unwrap_or(&connection_id), addr);
+        }
+
+        // return the connection_id and the registered client_id (if any) when the loop exits
+        (connection_id, registered_client_id)
+    }));
+
+    // wait for the receiving task to finish. a panic in it still gets the full cleanup below
+    let (disconnected_connection_id, disconnected_client_id_option, panicked) = match recv_task.await {
+        Ok((connection_id, client_id)) => (connection_id, client_id, false),
+        Err(e) => {
+            error!("Receive task panicked for connection {} ({}): {}", connection_id, addr, e);
+            metrics::CONNECTION_PANICS.inc();
+            // the task took its registered client_id with it; the route still says who it was
+            let client_id = metrics::timed_read(&state, "disconnect").await.routing.client_id(&connection_id);
+            (connection_id, client_id, true)
+        }
+    };
+
+    // clean up state
+    let mut former_peers = Vec::new();
+    {
+        let mut state_write = metrics::timed_write(&state, "disconnect").await;
+        state_write.routing.remove_connection(&disconnected_connection_id);
+        state_write.inbound_traffic.close(&disconnected_connection_id);
+        state_write.udp_sessions.retain(|_, session| session.connection_id != disconnected_connection_id);
+        if panicked {
+            state_write.start_panic_cooldown(addr.ip());
+        }
+
+        // if a client_id was registered for this connection, remove its mappings
+        if let Some(disconnected_client_id) = disconnected_client_id_option {
+            audit::record(AuditEvent::Disconnected {
+                client_id: disconnected_client_id.clone(),
+                connection_id: disconnected_connection_id.clone(),
+                reason: if panicked { "panicked" } else { "closed" },
+            });
+            // only tear down client state if the client_id -> connection_id mapping still points to *this* connection
+            // (if the client reconnected quickly, that state now belongs to the new session)
+            if state_write.routing.unroute_connection(&disconnected_client_id, &disconnected_connection_id) {
+                former_peers = state_write.forget_client(&disconnected_client_id);
+            }
+            info!("Client disconnected and cleaned up: ID {} (connection {}) ({}). Removed from all peer caches for proper reintroduction on reconnect.",
+                  disconnected_client_id, disconnected_connection_id, addr);
+        } else {
+            // client disconnected before sending UpdatePosition or task panicked
+            info!("Unregistered connection disconnected and cleaned up: {} ({})",
+                  disconnected_connection_id, addr);
+        }
+    }
+
+    // peers learn immediately that this client is gone
+    send_nearby_updates(&state, former_peers).await;
+
+    // the send task gets a moment to flush what is already queued (a Closing reason, say), and is
+    // stopped if the client isn't taking it
+    tx.close();
+    if time::timeout(CLOSE_FLUSH_TIMEOUT, &mut send_task).await.is_err() {
+        send_task.abort();
+    }
+    replay::closed(&disconnected_connection_id);
+}
+
+// ties one client message to its outcome when the client attached a request_id: errors go out as
+// RequestError, and a message that didn't fail is acked when the scope drops at the end of handling
+// (however the handler exits), so the ack always follows any other reply. plain Error replies
+// come out of the connection's error budget
+struct RequestScope<'a> {
+    tx: outbound::Sender,
+    request_id: Option<String>,
+    failed: bool,
+    error_budget: &'a mut flood::ErrorBudget,
+}
+
+impl<'a> RequestScope<'a> {
+    fn new(tx: &outbound::Sender, request_id: Option<String>, error_budget: &'a mut flood::ErrorBudget) -> Self {
+        RequestScope {
+            tx: tx.clone(),
+            request_id,
+            failed: false,
+            error_budget,
+        }
+    }
+
+    async fn error(&mut self, error: String) {
+        let message = match &self.request_id {
+            Some(request_id) => {
+                self.failed = true;
+                ServerMessage::RequestError { request_id: request_id.clone(), error }
+            }
+            None if self.error_budget.allow_error() => ServerMessage::Error(error),
+            None => return,
+        };
+        let _ = self.tx.send(message).await;
+    }
+}
+
+impl Drop for RequestScope<'_> {
+    fn drop(&mut self) {
+        if let Some(request_id) = self.request_id.take().filter(|_| !self.failed) {
+            let _ = self.tx.try_send(ServerMessage::Ack { request_id });
+        }
+    }
+}
+
+// background task to check for client timeouts and periodic reintroductions
+async fn check_timeouts_and_reintroduce(state: Arc<RwLock<ServerState>>, overload_config: config::OverloadConfig) {
+    let mut interval = time::interval(MAINTENANCE_INTERVAL);
+    const TIMEOUT_DURATION: Duration = Duration::from_secs(15);
+    let mut overload_detector = OverloadDetector::new(overload_config);
+    let mut since_reintroduction = Duration::ZERO;
+    let mut reintroduction_round: u64 = 0;
+    let mut pair_churn = pairgraph::ChurnWindow::default();
+    
+    loop {
+        let scheduled = interval.tick().await;
+        let loop_lag = scheduled.elapsed();
+        metrics::LOOP_LAG.set(loop_lag.as_secs_f64());
+        let mut timed_out_clients = Vec::new();
+        let mut reintroduction_notifications = Vec::new();
+
+        // echo tests running out and announcer zones starting or ending change pairings on their own.
+        // applied first, so the periodic resend below snapshots the new lists
+        let mut scheduled_changes;
+        {
+            let mut state_write = metrics::timed_write(&state, "maintenance").await;
+            scheduled_changes = state_write.expire_echo_sessions();
+            // speakers of zones that just started or ended get their pairs redone without waiting for them to move
+            let now = db::unix_now();
+            for speaker_id in state_write.refresh_announcer_zones(now) {
+                if let Some(speaker_tx) = state_write.routed_sender(&speaker_id) {
+                    scheduled_changes.extend(state_write.reevaluate_pairs(&speaker_id, &speaker_tx));
+                }
+            }
+            // and everyone on a map whose event just started or ended
+            for (game_id, map_id) in state_write.refresh_scheduled_events(now) {
+                let affected: Vec<String> = state_write
+                    .positions
+                    .values()
+                    .filter(|pos| pos.game_id == game_id && pos.map_id == map_id)
+                    .map(|pos| pos.client_id.clone())
+                    .collect();
+                for client_id in affected {
+                    if let Some(client_tx) = state_write.routed_sender(&client_id) {
+                        scheduled_changes.extend(state_write.reevaluate_pairs(&client_id, &client_tx));
+                    }
+                }
+            }
+        }
+        // a client can appear once per re-evaluated neighbour; one update each is enough
+        scheduled_changes.sort_by(|a, b| a.0.cmp(&b.0));
+        scheduled_changes.dedup_by(|a, b| a.0 == b.0);
+        send_nearby_updates(&state, scheduled_changes).await;
+        
+        let state_read = metrics::timed_read(&state, "maintenance").await; // read lock to check times
+
+        // sample outbound queue pressure for the overload detector
+        state_read.inbound_traffic.update_rates();
+        let senders = state_read.routing.senders();
+        let max_queue_fill = senders
+            .iter()
+            .map(|tx| tx.len() as f64 / tx.capacity() as f64)
+            .fold(0.0, f64::max);
+        metrics::OUTBOUND_QUEUE_FILL_MAX.set(max_queue_fill);
+        metrics::OUTBOUND_QUEUED.set(senders.iter().map(|tx| tx.len() as i64).sum());
+        // remote clients are billed by their own server
+        usage::record_connected(
+            state_read.positions.values().filter(|pos| !federation::is_remote(&pos.client_id)).map(|pos| pos.game_id),
+            MAINTENANCE_INTERVAL,
+        );
+        overload_detector.evaluate(&LoadSignals {
+            max_queue_fill,
+            max_lock_wait: Duration::from_micros(metrics::take_max_lock_wait_micros()),
+            loop_lag,
+        });
+
+        // check for timeouts
+        for (client_id, last_time) in state_read.last_update_time.iter() {
+            if last_time.elapsed() > TIMEOUT_DURATION {
+                info!("Client {} timed out (last update {:?})", client_id, last_time.elapsed());
+                timed_out_clients.push(client_id.clone());
+            }
+        }
+        
+        let pair_counts = state_read.pairs.counts();
+        for pair_state in pairs::PairState::ALL {
+            metrics::PAIRS.with_label_values(&[pair_state.name()]).set(pair_counts.get(&pair_state).copied().unwrap_or(0) as i64);
+        }
+        pairgraph::observe(&state_read.last_nearby_lists, state_read.positions.keys(), &mut pair_churn);
+
+        // periodic reintroductions - resend every client with a pair that isn't connected yet its
+        // current pairs every proximity.reintroduction_interval_secs (5 by default), once that pair
+        // hasn't been sent to it for reintroduction_cooldown_secs (30). clients whose pairs are all
+        // reported connected, and clients without pairs, are left alone
+        // this handles stale connection states gracefully - clients ignore duplicate introductions
+        // these are the first thing shed under overload
+        since_reintroduction += MAINTENANCE_INTERVAL;
+        let reintroduction_interval = Duration::from_secs(state_read.proximity.reintroduction_interval_secs);
+        let reintroduction_cooldown = Duration::from_secs(state_read.proximity.reintroduction_cooldown_secs);
+        let reintroductions_due = !reintroduction_interval.is_zero() && since_reintroduction >= reintroduction_interval;
+        if reintroductions_due {
+            since_reintroduction = Duration::ZERO;
+        }
+        if reintroductions_due && !overload::should_shed(ShedLevel::Reintroductions, "reintroduction") {
+            // slow clients sit out every other round and go last in the ones they get
+            reintroduction_round += 1;
+            let now = Instant::now();
+            for (client_id, client_pos) in state_read.positions.iter() {
+                if reintroduction_round.is_multiple_of(2) && state_read.is_slow(client_id) {
+                    continue;
+                }
+                let peers = state_read.last_nearby_lists.get(client_id).into_iter().flatten();
+                if !state_read.pairs.resend_due(client_id, peers, reintroduction_cooldown, now) {
+                    continue;
+                }
+                if let Some(tx) = state_read.routing.sender(client_id) {
+                    let messages = state_read.nearby_messages(client_pos, false);
+                    reintroduction_notifications.push((client_id.clone(), tx, messages));
+                }
+            }
+            reintroduction_notifications.sort_by_key(|(client_id, _, _)| state_read.is_slow(client_id));
+        }
+        
+        drop(state_read); // release read lock
+
+        let pings;
+        let pair_retries;
+        let topology_hints;
+        {
+            let mut state_write = metrics::timed_write(&state, "maintenance").await;
+            state_write.offers.prune();
+            state_write.pairs.prune_separated();
+            let now = Instant::now();
+            for (client_id, _, _) in &reintroduction_notifications {
+                let peers = state_write.paired_peers(client_id);
+                state_write.pairs.resent(client_id, peers, now);
+            }
+            state_write.prune_position_history();
+            if let Some(backoff) = state_write.registration_backoff.as_mut() {
+                backoff.prune(Instant::now());
+            }
+            let report = state_write.sweep_orphans();
+            if report.total() > 0 {
+                warn!("Consistency sweep repaired {} orphaned entries: {:?}", report.total(), report);
+            }
+            pings = state_write.start_rtt_probes();
+            pair_retries = state_write.due_pair_retries();
+            topology_hints = state_write.rebalance_topology();
+        }
+
+        // handle timeouts
+        let mut former_peers = Vec::new();
+        if !timed_out_clients.is_empty() {
+            let mut state_write = metrics::timed_write(&state, "timeouts").await; // write lock to remove
+            for client_id in timed_out_clients {
+                if state_write.restored.contains(&client_id) {
+                    info!("Restored client {} didn't reconnect after the restart", client_id);
+                    former_peers.extend(state_write.forget_client(&client_id));
+                    continue;
+                }
+                // its server stopped sending digests without the link dropping
+                if federation::is_remote(&client_id) {
+                    info!("Remote client {} is no longer in its server's digests", client_id);
+                    former_peers.extend(state_write.forget_client(&client_id));
+                    continue;
+                }
+                if state_write.trace_clients.contains(&client_id) {
+                    info!("Trace client {} stopped moving", client_id);
+                    former_peers.extend(state_write.forget_client(&client_id));
+                    continue;
+                }
+                warn!("Disconnecting timed out client: {}", client_id);
+                
+                former_peers.extend(state_write.forget_client(&client_id));
+                
+                if let Some(connection_id) = state_write.routing.unroute(&client_id) {
+                    // removing the connection sender will cause the send_task for that client to terminate,
+                    // eventually leading to the handle_connection task finishing and cleaning up fully.
+                    state_write.routing.remove_connection(&connection_id);
+                    info!("Removed timed out client state: {} (connection {})", client_id, connection_id);
+                    audit::record(AuditEvent::Disconnected { client_id: client_id.clone(), connection_id, reason: "timed_out" });
+                } else {
+                    warn!("Could not find connection ID for timed out client {} during cleanup.", client_id);
+                }
+            }
+        }
+        send_nearby_updates(&state, former_peers).await;
+        
+        // send periodic reintroductions to all clients
+        for (_client_id, tx, messages) in reintroduction_notifications {
+            for response in messages {
+                if let Err(_e) = tx.send(response).await {
+                    // don't log this as error - client may have disconnected, that's normal
+                    // warn!("Failed to send periodic reintroduction to {}: {}", client_id, e);
+                }
+            }
+        }
+
+        for (tx, message) in pair_retries {
+            let _ = tx.send(message).await;
+        }
+        for (tx, hint) in topology_hints {
+            let _ = tx.send(hint).await;
+        }
+
+        // round-trip probes for the clients that asked for them; a full queue just skips a round
+        for (tx, ping) in pings {
+            let _ = tx.try_send(ping);
+        }
+    }
+}
+
+// the whole server: loads the config, starts every listener and background task, then accepts
+// websocket connections until the process is stopped
+pub async fn run() {
+    // `prox-chat-server schema [--typescript]` prints the protocol definitions, `prox-chat-server
+    // replay <file> <url>` plays a recording back (see replay.rs), `prox-chat-server admin ...`
+    // manages a running server through its admin API (see admin_cli.rs), `prox-chat-server
+    // check` vets a deploy's config (see check.rs) and `prox-chat-server simulate` previews the
+    // introductions a set of positions gets (see simulate.rs), instead of serving
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|command| command == "check") {
+        check::run(&args[1..]);
+        return;
+    }
+    if args.first().is_some_and(|command| command == "simulate") {
+        simulate::run(&args[1..]);
+        return;
+    }
+    if args.first().is_some_and(|command| command == "admin") {
+        admin_cli::run(&args[1..]).await;
+        return;
+    }
+    if args.first().is_some_and(|command| command == "schema") {
+        schema::run(&args[1..]);
+        return;
+    }
+    if args.first().is_some_and(|command| command == "replay") {
+        replay::run(&args[1..]).await;
+        return;
+    }
+    #[cfg(feature = "soak")]
+    if args.first().is_some_and(|command| command == "soak") {
+        soak::run(&args[1..]).await;
+        return;
+    }
+
+    // initialize logging
+    crash_reports::init_logging();
+
+    // load config, then layer any overrides stored in the database on top
+    let config_path = config::config_path();
+    let mut raw_config = config::load_raw(&config_path).expect("Failed to load config");
+    let file_config = config::from_raw(raw_config.clone()).expect("Failed to load config");
+    let db = Database::open(file_config.database_path.as_deref()).expect("Failed to open database");
+    let overrides = db.config_overrides().expect("Failed to read config overrides");
+    config::apply_overrides(&mut raw_config, &overrides);
+    let config = config::from_raw(raw_config).expect("Invalid config after applying overrides");
+    let db = Arc::new(db);
+    let config = Arc::new(config);
+    let _crash_reports = config.sentry.as_ref().map(crash_reports::init);
+    if let Some(log_file) = &config.log_file {
+        logfile::init(log_file).expect("Failed to set up the log file");
+    }
+
+    if let Some(scripting_config) = &config.scripting {
+        #[cfg(feature = "scripting")]
+        scripting::init(scripting_config).expect("Failed to load scripts");
+        #[cfg(not(feature = "scripting"))]
+        warn!("scripting is configured ({}) but this build lacks the scripting feature, ignoring", scripting_config.scripts_dir);
+    }
+
+    // game adapters pick the audibility plugin up when the state is created
+    if let Some(plugin_config) = &config.wasm_plugin {
+        #[cfg(feature = "wasm-plugins")]
+        plugin::init(plugin_config).expect("Failed to load the WASM plugin");
+        #[cfg(not(feature = "wasm-plugins"))]
+        warn!("wasm_plugin is configured ({}) but this build lacks the wasm-plugins feature, ignoring", plugin_config.path);
+    }
+
+    #[cfg(not(feature = "sfu"))]
+    if let Some(sfu_config) = &config.sfu {
+        warn!("sfu is configured ({} areas) but this build lacks the sfu feature, ignoring", sfu_config.areas.len());
+    }
+
+    // create shared state
+    let announcer_zones = db.announcer_zones().expect("Failed to read announcer zones");
+    let scheduled_events = db.scheduled_events().expect("Failed to read scheduled events");
+    let collision_grids = db.collision_grids().expect("Failed to read collision grids");
+    let elevated_consents = db.elevated_consents().expect("Failed to read elevated consent");
+    let state = Arc::new(RwLock::new(ServerState::new(&config, announcer_zones, scheduled_events, collision_grids, elevated_consents)));
+
+    // clients of a server that was just restarted get to keep their pairs
+    if let Some(snapshot_config) = &config.state_snapshot {
+        match snapshot::load(snapshot_config) {
+            Ok(Some(snapshot)) => {
+                let restored = state.write().await.restore(snapshot);
+                info!("Restored {} clients from state snapshot {}", restored, snapshot_config.path);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Ignoring state snapshot: {}", e),
+        }
+    }
+
+    metrics::init();
+
+    // admin API runs on its own listener so it can be kept off the public interface
+    if config.admin_addr.is_some() {
+        let admin_state = Arc::clone(&state);
+        let admin_config = (*config).clone();
+        let admin_db = Arc::clone(&db);
+        tokio::spawn(async move {
+            admin::serve(admin_config, admin_state, admin_db).await;
+        });
+    }
+
+    // the same admin operations over gRPC
+    #[cfg(feature = "grpc")]
+    if config.grpc_addr.is_some() {
+        let grpc_state = Arc::clone(&state);
+        let grpc_config = (*config).clone();
+        let grpc_db = Arc::clone(&db);
+        tokio::spawn(async move {
+            grpc::serve(grpc_config, grpc_state, grpc_db).await;
+        });
+    }
+    #[cfg(not(feature = "grpc"))]
+    if let Some(grpc_addr) = &config.grpc_addr {
+        warn!("grpc_addr is configured ({}) but this build lacks the grpc feature, ignoring", grpc_addr);
+    }
+
+    // experimental signaling over WebTransport, alongside the websocket listener
+    if let Some(webtransport_config) = config.webtransport.clone() {
+        #[cfg(feature = "webtransport")]
+        {
+            let webtransport_state = Arc::clone(&state);
+            let webtransport_db = Arc::clone(&db);
+            tokio::spawn(async move {
+                webtransport::serve(webtransport_config, webtransport_state, webtransport_db).await;
+            });
+        }
+        #[cfg(not(feature = "webtransport"))]
+        warn!(
+            "webtransport is configured ({}) but this build lacks the webtransport feature, ignoring",
+            webtransport_config.listen_addr
+        );
+    }
+
+    // positions can also arrive as signed UDP datagrams from clients that opted in
+    if let Some(udp_addr) = config.udp_addr.clone() {
+        let udp_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            udp::serve(udp_addr, udp_state).await;
+        });
+    }
+
+    // dev mode: fake clients walking a recorded trace
+    if let Some(position_trace) = config.position_trace.clone() {
+        let trace_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            trace::play(position_trace, trace_state).await;
+        });
+    }
+
+    if let Some(stun_addr) = config.stun_addr.clone() {
+        tokio::spawn(stun::serve(stun_addr));
+    }
+
+    if let Some(turn_server) = config.turn_server.clone() {
+        #[cfg(feature = "turn-server")]
+        {
+            let turn = config.turn.clone().expect("turn_server requires turn, checked when the config loads");
+            let routing = Arc::clone(&metrics::timed_read(&state, "turn_server").await.routing);
+            tokio::spawn(relay::serve(turn_server, turn, routing));
+        }
+        #[cfg(not(feature = "turn-server"))]
+        warn!("turn_server is configured ({}) but this build lacks the turn-server feature, ignoring", turn_server.listen_addr);
+    }
+
+    if let Some(usage_export) = config.usage_export.clone() {
+        tokio::spawn(usage::export(usage_export));
+    }
+
+    if let Some(audit_log) = config.audit_log.clone() {
+        if matches!(audit_log, config::AuditLogConfig::Database) && config.database_path.is_none() {
+            warn!("audit_log writes to the database, but no database_path is set: the log won't survive a restart");
+        }
+        audit::init(audit_log, Arc::clone(&db));
+    }
+
+    if let Some(registry_config) = config.registry.clone() {
+        registry::init(registry_config, Arc::clone(&state));
+    }
+
+    if let Some(federation_config) = config.federation.clone() {
+        federation::init(federation_config, Arc::clone(&state));
+    }
+
+    if let Some(replay_recording) = config.replay_recording.clone() {
+        replay::init(replay_recording);
+    }
+
+    if let Some(geoip_config) = &config.geoip {
+        #[cfg(feature = "geoip")]
+        geoip::init(geoip_config).expect("Failed to load GeoIP database");
+        #[cfg(not(feature = "geoip"))]
+        warn!("geoip is configured ({}) but this build lacks the geoip feature, ignoring", geoip_config.database_path);
+    }
+
+    if let Some(chaos_config) = config.chaos.clone() {
+        #[cfg(feature = "chaos")]
+        chaos::init(chaos_config);
+        #[cfg(not(feature = "chaos"))]
+        warn!("chaos is configured ({:?}) but this build lacks the chaos feature, ignoring", chaos_config);
+    }
+
+    // spawn the timeout checking task
+    let timeout_state = Arc::clone(&state);
+    let overload_config = config.overload.clone();
+    tokio::spawn(async move {
+        check_timeouts_and_reintroduce(timeout_state, overload_config).await;
+    });
+    tokio::spawn(send_deferred_nearby_updates(Arc::clone(&state)));
+
+    // create WebSocket server, on the socket we were handed if started through socket activation
+    let listener = match activation::inherited_listener() {
+        Some(inherited) => {
+            let listener = TcpListener::from_std(inherited).expect("Failed to use inherited listener");
+            info!("WebSocket server listening on inherited socket: {:?}", listener.local_addr());
+            listener
+        }
+        None => {
+            let addr = config.listen_addr.as_str();
+            let listener = TcpListener::bind(addr).await.expect("Failed to bind");
+            info!("WebSocket server listening on: {}", addr);
+            listener
+        }
+    };
+
+    // under systemd (Type=notify), report ready and keep the watchdog fed from a task of its own,
+    // so waiting on the state lock never holds up accepting connections
+    let systemd = systemd::Notifier::from_env().map(Arc::new);
+    if let Some(systemd) = &systemd {
+        systemd.ready();
+        if let Some(interval) = systemd.watchdog_interval() {
+            tokio::spawn(feed_watchdog(Arc::clone(systemd), interval, Arc::clone(&state)));
+        }
+    }
+
+    // accept connections until told to stop
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
+            }
+        };
+        let state = Arc::clone(&state);
+        let db = Arc::clone(&db);
+        let config = Arc::clone(&config);
+        tokio::spawn(async move {
+            handle_connection(state, db, config, stream, addr).await;
+        });
+    }
+
+    if let Some(systemd) = &systemd {
+        systemd.stopping();
+    }
+
+    if let Some(snapshot_config) = &config.state_snapshot {
+        let snapshot = metrics::timed_read(&state, "snapshot").await.snapshot();
+        match snapshot::save(snapshot_config, &snapshot) {
+            Ok(()) => info!("Saved {} clients to state snapshot {}", snapshot.clients.len(), snapshot_config.path),
+            Err(e) => error!("Failed to save state snapshot: {}", e),
+        }
+    }
+
+    // new connections go to the next server (or wait for it in the backlog) while ours wind down
+    drop(listener);
+    if config.shutdown_drain_secs > 0 {
+        drain_connections(&state, Duration::from_secs(config.shutdown_drain_secs)).await;
+    }
+}
+
+// pings systemd's watchdog every interval, but not while the state lock is held for a whole
+// interval, so a deadlock gets us restarted
+async fn feed_watchdog(systemd: Arc<systemd::Notifier>, interval: Duration, state: Arc<RwLock<ServerState>>) {
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if time::timeout(interval, state.read()).await.is_ok() {
+            systemd.watchdog();
+        } else {
+            warn!("State lock not available within {:?}, skipping watchdog ping", interval);
+        }
+    }
+}
+
+// tells every connection the server is going away, so clients reconnect to the next process,
+// and waits for them to close, up to the deadline
+async fn drain_connections(state: &Arc<RwLock<ServerState>>, limit: Duration) {
+    let deadline = Instant::now() + limit;
+    let senders = metrics::timed_read(state, "drain").await.routing.senders();
+    info!("Asking {} connections to reconnect to the next server", senders.len());
+    for tx in senders {
+        tx.close_for(CloseReason::ServerRestart);
+    }
+    let mut ticker = time::interval(Duration::from_millis(250));
+    loop {
+        ticker.tick().await;
+        let open = state.read().await.routing.connection_count();
+        if open == 0 {
+            info!("All connections closed");
+            return;
+        }
+        if Instant::now() >= deadline {
+            info!("Drain deadline reached with {} connections still open", open);
+            return;
+        }
+    }
+}
+
+// ctrl-c, or SIGTERM where there is one (what service managers stop the server with)
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
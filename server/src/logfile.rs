@@ -0,0 +1,135 @@
+// log output to a file alongside the console (config log_file), for deployments without a log
+// shipper. the file has its own filter, in RUST_LOG syntax, independent of the console's. it is
+// rotated once it grows past max_size_mb or gets older than max_age_hours: the current file becomes
+// <path>.1, older ones shift up and anything past keep_files is deleted
+use crate::config::LogFileConfig;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+// set once the config is loaded; lines logged before that only reach the console
+static FILE_LOGGER: OnceLock<env_logger::Logger> = OnceLock::new();
+
+// the console logger, plus the file logger once there is one
+pub struct TeeLogger {
+    console: env_logger::Logger,
+}
+
+impl TeeLogger {
+    pub fn new(console: env_logger::Logger) -> Self {
+        TeeLogger { console }
+    }
+
+    pub fn filter(&self) -> LevelFilter {
+        self.console.filter()
+    }
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata) || FILE_LOGGER.get().is_some_and(|file| file.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        self.console.log(record);
+        if let Some(file) = FILE_LOGGER.get() {
+            file.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = FILE_LOGGER.get() {
+            file.flush();
+        }
+    }
+}
+
+pub fn init(config: &LogFileConfig) -> Result<(), String> {
+    let file = RotatingFile::open(config).map_err(|e| format!("Failed to open log file {}: {}", config.path, e))?;
+    let logger = env_logger::Builder::new()
+        .parse_filters(&config.level)
+        .write_style(env_logger::WriteStyle::Never)
+        .target(env_logger::Target::Pipe(Box::new(file)))
+        .build();
+    // the file may want lines the console filters out
+    log::set_max_level(log::max_level().max(logger.filter()));
+    FILE_LOGGER.set(logger).map_err(|_| "log file set up twice".to_string())?;
+    log::info!("Logging to {} at {}", config.path, config.level);
+    Ok(())
+}
+
+struct RotatingFile {
+    path: String,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep_files: usize,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl RotatingFile {
+    fn open(config: &LogFileConfig) -> io::Result<Self> {
+        let (file, size) = open_append(&config.path)?;
+        Ok(RotatingFile {
+            path: config.path.clone(),
+            max_size: config.max_size_mb.map(|mb| mb * 1024 * 1024),
+            max_age: config.max_age_hours.map(|hours| Duration::from_secs(hours * 3600)),
+            keep_files: config.keep_files,
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn due(&self) -> bool {
+        self.max_size.is_some_and(|max| self.size >= max) || self.max_age.is_some_and(|max| self.opened_at.elapsed() >= max)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(format!("{}.{}", self.path, self.keep_files));
+            for n in (1..self.keep_files).rev() {
+                let _ = fs::rename(format!("{}.{}", self.path, n), format!("{}.{}", self.path, n + 1));
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        let (file, size) = open_append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due() {
+            // keep writing to the old file rather than lose lines, and try again after another full file
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", self.path, e);
+                self.size = 0;
+                self.opened_at = Instant::now();
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &str) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
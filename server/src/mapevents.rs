@@ -0,0 +1,61 @@
+// live join/leave/introduction events per map, for tools like stream overlays and population bots
+// that would rather not speak the client protocol (admin GET /maps/{game_id}/{map_id}/stream, as
+// server-sent events). nothing is kept: a subscriber sees what happens while it is connected
+use crate::db::unix_now;
+use serde::Serialize;
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+// events buffered per subscriber; one that falls further behind skips ahead and is told how far
+const CHANNEL_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MapEvent {
+    // the client's first position on this map, from registering or moving here
+    Joined { client_id: String },
+    // moved to another map or disconnected
+    Left { client_id: String },
+    Introduced { client_id: String, peer_id: String },
+    Separated { client_id: String, peer_id: String },
+}
+
+impl MapEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapEvent::Joined { .. } => "joined",
+            MapEvent::Left { .. } => "left",
+            MapEvent::Introduced { .. } => "introduced",
+            MapEvent::Separated { .. } => "separated",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MapEventRecord {
+    pub game_id: i32,
+    pub map_id: i32,
+    // unix seconds
+    pub at: i64,
+    #[serde(flatten)]
+    pub event: MapEvent,
+}
+
+fn channel() -> &'static broadcast::Sender<MapEventRecord> {
+    static CHANNEL: OnceLock<broadcast::Sender<MapEventRecord>> = OnceLock::new();
+    CHANNEL.get_or_init(|| broadcast::channel(CHANNEL_LEN).0)
+}
+
+pub fn publish(game_id: i32, map_id: i32, event: MapEvent) {
+    let channel = channel();
+    // the usual case: nobody is listening
+    if channel.receiver_count() == 0 {
+        return;
+    }
+    let _ = channel.send(MapEventRecord { game_id, map_id, at: unix_now(), event });
+}
+
+// every map's events; subscribers filter for the map they want
+pub fn subscribe() -> broadcast::Receiver<MapEventRecord> {
+    channel().subscribe()
+}
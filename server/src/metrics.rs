@@ -0,0 +1,351 @@
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::time::Instant;
+
+// all server metrics live in one registry, rendered in text format by the admin API's /metrics
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).expect("metric registered twice");
+    metric
+}
+
+pub static SERVER_OVERLOADED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_server_overloaded", "1 while the overload detector considers the server overloaded").unwrap())
+});
+
+pub static SHED_LEVEL: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_shed_level", "Current load-shedding tier (0 = nothing shed)").unwrap())
+});
+
+pub static RECONNECT_STORM: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_reconnect_storm", "1 while a reconnect storm is being absorbed").unwrap())
+});
+
+pub static DEFERRED_NEARBY_UPDATES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_deferred_nearby_updates_total", "NearbyPeers updates held back and spread out during reconnect storms").unwrap())
+});
+
+pub static SHED_MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(Opts::new("proxchat_shed_messages_total", "Messages skipped by load shedding"), &["kind"]).unwrap(),
+    )
+});
+
+pub static UNVERSIONED_MESSAGES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new("proxchat_unversioned_messages_total", "Client messages sent without a protocol version (pre-versioning clients)")
+            .unwrap(),
+    )
+});
+
+pub static OUTBOUND_QUEUE_FILL_MAX: LazyLock<prometheus::Gauge> = LazyLock::new(|| {
+    register(
+        prometheus::Gauge::new("proxchat_outbound_queue_fill_max", "Fullest per-connection outbound queue at the last check (0-1)")
+            .unwrap(),
+    )
+});
+
+pub static OUTBOUND_QUEUED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_outbound_queued_messages", "Messages waiting in all outbound queues at the last check").unwrap())
+});
+
+pub static OUTBOUND_OVERFLOWS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("proxchat_outbound_overflows_total", "Sends that found an outbound queue full, by what the overflow policy did"),
+            &["action"],
+        )
+        .unwrap(),
+    )
+});
+
+// site is the code path taking the lock (a client message type, "maintenance", "admin", ...),
+// mode is read or write
+pub static STATE_LOCK_WAIT: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new("proxchat_state_lock_wait_seconds", "Time spent waiting for the state lock, by call site")
+                .buckets(vec![0.00001, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0]),
+            &["site", "mode"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static UNEXPECTED_BINARY_FRAMES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_unexpected_binary_frames_total", "Binary frames received on connections that negotiated JSON").unwrap())
+});
+
+pub static GEOIP_CONNECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("proxchat_geoip_connections_total", "Incoming connections by country (ISO code or \"unknown\") and whether they were let in"),
+            &["country", "outcome"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static NON_WEBSOCKET_CONNECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("proxchat_non_websocket_connections_total", "Connections to the signaling listener that weren't WebSocket upgrades, by what they were"),
+            &["kind"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static REGISTRATION_TIMEOUTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_registration_timeouts_total", "Connections closed for not sending UpdatePosition in time").unwrap())
+});
+
+pub static REGISTRATIONS_THROTTLED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_registrations_throttled_total", "Registrations refused because their address was held off by registration_backoff").unwrap())
+});
+
+pub static CONNECTION_PANICS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_connection_panics_total", "Connection handlers that panicked (their state was still cleaned up)").unwrap())
+});
+
+pub static CLIENT_RTT: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new("proxchat_client_rtt_seconds", "Websocket round trips measured with Ping/Pong")
+                .buckets(vec![0.01, 0.025, 0.05, 0.1, 0.2, 0.4, 0.8, 1.6, 3.2]),
+        )
+        .unwrap(),
+    )
+});
+
+pub static CONNECTION_INBOUND_MESSAGE_RATE: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new("proxchat_connection_inbound_messages_per_second", "Frames per second each connection sent, sampled every maintenance tick")
+                .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0]),
+        )
+        .unwrap(),
+    )
+});
+
+pub static CONNECTION_INBOUND_BYTE_RATE: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new("proxchat_connection_inbound_bytes_per_second", "Bytes per second each connection sent, sampled every maintenance tick")
+                .buckets(vec![100.0, 500.0, 1000.0, 5000.0, 10000.0, 50000.0, 100000.0, 500000.0, 1000000.0]),
+        )
+        .unwrap(),
+    )
+});
+
+pub static PAIRS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(IntGaugeVec::new(Opts::new("proxchat_pairs", "Introduced pairs by connection state at the last check"), &["state"]).unwrap())
+});
+
+pub static PAIR_CHANGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(Opts::new("proxchat_pair_changes_total", "Pairs introduced and separated"), &["change"]).unwrap())
+});
+
+pub static PAIR_CHURN: LazyLock<prometheus::Gauge> = LazyLock::new(|| {
+    register(prometheus::Gauge::new("proxchat_pair_churn_per_minute", "Pairs introduced plus pairs separated over about the last minute").unwrap())
+});
+
+pub static PEERS_PER_CLIENT: LazyLock<prometheus::Gauge> = LazyLock::new(|| {
+    register(prometheus::Gauge::new("proxchat_peers_per_client", "Average number of peers of the clients with a position").unwrap())
+});
+
+pub static ISOLATED_CLIENTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_isolated_clients", "Clients with a position but no peers").unwrap())
+});
+
+pub static MAX_CLIQUE_SIZE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_max_clique_size", "Clients in the largest group that are all paired with each other (at least, while the search is capped)").unwrap())
+});
+
+pub static MAX_CLIQUE_SEARCH_CAPPED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new("proxchat_max_clique_search_capped", "1 when the last largest-group search ran out of steps, so proxchat_max_clique_size is a lower bound")
+            .unwrap(),
+    )
+});
+
+pub static PAIR_SETUP: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new("proxchat_pair_setup_seconds", "Time from introduction to the first connected report of a pair")
+                .buckets(vec![0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0]),
+        )
+        .unwrap(),
+    )
+});
+
+pub static PLUGIN_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new("proxchat_plugin_failures_total", "Audibility plugin calls that trapped or ran out of fuel (the built-in rules answered)")
+            .unwrap(),
+    )
+});
+
+pub static SCRIPT_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_script_errors_total", "Script hook calls that failed or hit max_operations").unwrap())
+});
+
+pub static SIGNALING_EXPIRED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("proxchat_signaling_expired_total", "Relayed offers and answers dropped for waiting past signaling_ttl_ms in an outbound queue"),
+            &["kind"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static DUPLICATE_IPS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_duplicate_ips", "Addresses with more than one client registered (with duplicate_accounts)").unwrap())
+});
+
+pub static DUPLICATE_CLIENTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_duplicate_clients", "Clients registered from an address that already had one (with duplicate_accounts)").unwrap())
+});
+
+pub static DUPLICATES_REFUSED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_duplicate_registrations_refused_total", "Registrations refused by the duplicate_accounts cap").unwrap())
+});
+
+pub static OVERFLOW_CLIENTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_overflow_partition_clients", "Clients placed in an overflow partition of a map with map_population_caps").unwrap())
+});
+
+pub static TOPOLOGY_HUBS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_topology_hubs", "Clients elected to forward audio for others in dense clusters (with topology_hints)").unwrap())
+});
+
+pub static TOPOLOGY_LEAVES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_topology_leaves", "Clients told to go through a hub instead of the full mesh (with topology_hints)").unwrap())
+});
+
+pub static SFU_CLIENTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("proxchat_sfu_clients", "Clients in an sfu area, connecting to the built-in SFU instead of their peers").unwrap())
+});
+
+pub static STUN_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(Opts::new("proxchat_stun_requests_total", "Datagrams to the built-in STUN server, answered or ignored"), &["result"]).unwrap(),
+    )
+});
+
+pub static TURN_AUTH_REJECTED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_turn_auth_rejected_total", "Requests to the built-in TURN relay with an expired or malformed username").unwrap())
+});
+
+pub static TURN_QUOTA_EXCEEDED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("proxchat_turn_quota_exceeded_total", "Built-in TURN relay allocations closed or refused for a client past its relay quota, by window"),
+            &["window"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static REGISTRATIONS_RESUMED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_registrations_resumed_total", "Registrations that took a live one over with its SessionToken instead of starting again").unwrap())
+});
+
+pub static REPEATED_POSITIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_repeated_positions_total", "UpdatePositions (retried registrations included) repeating the stored position, taken as keepalives").unwrap())
+});
+
+pub static UDP_BAD_SIGNATURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("proxchat_udp_bad_signatures_total", "Position datagrams dropped for a signature that didn't match their session's key").unwrap())
+});
+
+pub static LOOP_LAG: LazyLock<prometheus::Gauge> = LazyLock::new(|| {
+    register(prometheus::Gauge::new("proxchat_maintenance_loop_lag_seconds", "How late the last maintenance tick ran").unwrap())
+});
+
+// longest lock wait since the overload detector last looked, in microseconds
+static MAX_LOCK_WAIT_MICROS: AtomicU64 = AtomicU64::new(0);
+
+// take the state lock for reading, recording the wait under this call site
+pub async fn timed_read<'a, T>(lock: &'a RwLock<T>, site: &'static str) -> RwLockReadGuard<'a, T> {
+    let started = Instant::now();
+    let guard = lock.read().await;
+    observe_lock_wait(site, "read", started);
+    guard
+}
+
+// take the state lock for writing, recording the wait under this call site
+pub async fn timed_write<'a, T>(lock: &'a RwLock<T>, site: &'static str) -> RwLockWriteGuard<'a, T> {
+    let started = Instant::now();
+    let guard = lock.write().await;
+    observe_lock_wait(site, "write", started);
+    guard
+}
+
+fn observe_lock_wait(site: &'static str, mode: &'static str, started: Instant) {
+    let waited = started.elapsed();
+    STATE_LOCK_WAIT.with_label_values(&[site, mode]).observe(waited.as_secs_f64());
+    MAX_LOCK_WAIT_MICROS.fetch_max(waited.as_micros() as u64, Ordering::Relaxed);
+}
+
+// read and reset the longest lock wait seen since the previous call
+pub fn take_max_lock_wait_micros() -> u64 {
+    MAX_LOCK_WAIT_MICROS.swap(0, Ordering::Relaxed)
+}
+
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+// register every metric up front so /metrics lists them even before they first change
+pub fn init() {
+    LazyLock::force(&SERVER_OVERLOADED);
+    LazyLock::force(&SHED_LEVEL);
+    LazyLock::force(&RECONNECT_STORM);
+    LazyLock::force(&DEFERRED_NEARBY_UPDATES);
+    LazyLock::force(&SHED_MESSAGES);
+    LazyLock::force(&UNVERSIONED_MESSAGES);
+    LazyLock::force(&CONNECTION_PANICS);
+    LazyLock::force(&NON_WEBSOCKET_CONNECTIONS);
+    LazyLock::force(&REGISTRATION_TIMEOUTS);
+    LazyLock::force(&REGISTRATIONS_THROTTLED);
+    LazyLock::force(&UNEXPECTED_BINARY_FRAMES);
+    LazyLock::force(&OUTBOUND_QUEUE_FILL_MAX);
+    LazyLock::force(&STATE_LOCK_WAIT);
+    LazyLock::force(&CLIENT_RTT);
+    LazyLock::force(&CONNECTION_INBOUND_MESSAGE_RATE);
+    LazyLock::force(&CONNECTION_INBOUND_BYTE_RATE);
+    LazyLock::force(&LOOP_LAG);
+    LazyLock::force(&PAIRS);
+    LazyLock::force(&PAIR_CHANGES);
+    LazyLock::force(&PAIR_CHURN);
+    LazyLock::force(&PEERS_PER_CLIENT);
+    LazyLock::force(&ISOLATED_CLIENTS);
+    LazyLock::force(&MAX_CLIQUE_SIZE);
+    LazyLock::force(&MAX_CLIQUE_SEARCH_CAPPED);
+    LazyLock::force(&PAIR_SETUP);
+    LazyLock::force(&PLUGIN_FAILURES);
+    LazyLock::force(&SCRIPT_ERRORS);
+    LazyLock::force(&SIGNALING_EXPIRED);
+    LazyLock::force(&DUPLICATE_IPS);
+    LazyLock::force(&DUPLICATE_CLIENTS);
+    LazyLock::force(&DUPLICATES_REFUSED);
+    LazyLock::force(&OVERFLOW_CLIENTS);
+    LazyLock::force(&TOPOLOGY_HUBS);
+    LazyLock::force(&TOPOLOGY_LEAVES);
+    LazyLock::force(&SFU_CLIENTS);
+    LazyLock::force(&STUN_REQUESTS);
+    LazyLock::force(&TURN_AUTH_REJECTED);
+    LazyLock::force(&TURN_QUOTA_EXCEEDED);
+    LazyLock::force(&REPEATED_POSITIONS);
+    LazyLock::force(&REGISTRATIONS_RESUMED);
+    LazyLock::force(&UDP_BAD_SIGNATURES);
+}
@@ -0,0 +1,107 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+// operator-supplied blocklist for names and labels clients choose for themselves (tags today).
+// matching runs on a folded copy of the text: lowercased, with confusables and leetspeak
+// ("ｆυск", "h4x0r") mapped back to plain ascii, so lookalike spellings still match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NameFilterConfig {
+    // words rejected anywhere in a name
+    pub denylist: Vec<String>,
+    // case-insensitive regexes matched against the folded name
+    pub patterns: Vec<String>,
+}
+
+pub struct NameFilter {
+    denylist: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl NameFilter {
+    pub fn new(config: &NameFilterConfig) -> Result<Self, String> {
+        let patterns = config
+            .patterns
+            .iter()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("invalid pattern {:?}: {}", pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let denylist = config
+            .denylist
+            .iter()
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+        Ok(NameFilter { denylist, patterns })
+    }
+
+    // true if the name hits the denylist or a pattern
+    pub fn is_blocked(&self, name: &str) -> bool {
+        if self.denylist.is_empty() && self.patterns.is_empty() {
+            return false;
+        }
+        match decancer::cure(name, decancer::Options::default()) {
+            Ok(cured) => {
+                // denylist comparisons see through leetspeak themselves; regexes get it spelled out
+                let unleeted = unleet(&cured);
+                self.denylist.iter().any(|word| cured.contains(word))
+                    || self.patterns.iter().any(|pattern| pattern.is_match(&cured) || pattern.is_match(&unleeted))
+            }
+            // only fails on text it can't fold at all, which no legitimate name needs
+            Err(_) => true,
+        }
+    }
+}
+
+// the digits and symbols leetspeak stands in for letters, read as those letters
+fn unleet(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | '!' | '|' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' | '+' => 't',
+            '8' => 'b',
+            '9' => 'g',
+            c => c,
+        })
+        .collect()
+}
+
+// drops invisible and direction-changing characters (zero-width joiners, bidi overrides, soft
+// hyphens) and collapses whitespace runs, so a name renders as what it spells
+pub fn sanitize(name: &str) -> String {
+    name.split_whitespace()
+        .map(|word| word.chars().filter(|&c| !is_invisible(c)).collect::<String>())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}'
+            | '\u{034F}'
+            | '\u{061C}'
+            | '\u{115F}'
+            | '\u{1160}'
+            | '\u{17B4}'
+            | '\u{17B5}'
+            | '\u{180B}'..='\u{180F}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{206F}'
+            | '\u{3164}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FEFF}'
+            | '\u{FFA0}'
+            | '\u{E0000}'..='\u{E007F}'
+    )
+}
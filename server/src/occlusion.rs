@@ -0,0 +1,162 @@
+use proxchat_protocol::ClientPosition;
+use serde::{Deserialize, Serialize};
+
+// per-map wall grids uploaded through the admin API (PUT /maps/{game_id}/{map_id}/collision), so
+// players in the next room over don't hear each other clearly. one cell per unit of the map's
+// coordinates, starting at (origin_x, origin_y); everything outside the grid is open. in "block"
+// mode two clients only pair with a clear line of sight between their cells, in "attenuate" mode
+// they pair as usual and every wall cell the line crosses takes wall_attenuation off the gain sent
+// in NearbyPeer.attenuation
+
+// grids bigger than this are refused; a map that large wants coarser units
+const MAX_CELLS: usize = 16 * 1024 * 1024;
+
+// lines longer than this (in cells) count as clear without walking them; they're far beyond any
+// pairing range, and only announcer zones pair clients that far apart
+const MAX_LINE: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OcclusionMode {
+    #[default]
+    Block,
+    Attenuate,
+}
+
+// the grid as uploaded and stored. rows go from origin_y upwards in y, each from origin_x
+// upwards in x; '#' is a wall, anything else open. short rows are open past their end
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollisionUpload {
+    #[serde(default)]
+    pub origin_x: i64,
+    #[serde(default)]
+    pub origin_y: i64,
+    pub rows: Vec<String>,
+    #[serde(default)]
+    pub mode: OcclusionMode,
+    // share of the gain each wall cell takes away, 0 to 1; attenuate mode only
+    #[serde(default = "default_wall_attenuation")]
+    pub wall_attenuation: f32,
+}
+
+fn default_wall_attenuation() -> f32 {
+    0.5
+}
+
+// what the admin API shows of a grid, without the rows
+#[derive(Debug, Clone, Serialize)]
+pub struct CollisionSummary {
+    pub game_id: i32,
+    pub map_id: i32,
+    pub origin_x: i64,
+    pub origin_y: i64,
+    pub width: usize,
+    pub height: usize,
+    pub wall_cells: usize,
+    pub mode: OcclusionMode,
+    pub wall_attenuation: f32,
+}
+
+pub struct CollisionGrid {
+    pub game_id: i32,
+    pub map_id: i32,
+    pub upload: CollisionUpload,
+    width: usize,
+    height: usize,
+    walls: Vec<bool>,
+}
+
+impl CollisionGrid {
+    pub fn new(game_id: i32, map_id: i32, upload: CollisionUpload) -> Result<Self, String> {
+        if !(0.0..=1.0).contains(&upload.wall_attenuation) {
+            return Err("wall_attenuation must be between 0 and 1".to_string());
+        }
+        let height = upload.rows.len();
+        let width = upload.rows.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+        if width == 0 || height == 0 {
+            return Err("a collision grid needs at least one cell".to_string());
+        }
+        if width.saturating_mul(height) > MAX_CELLS {
+            return Err(format!("a collision grid may have at most {} cells", MAX_CELLS));
+        }
+        let mut walls = vec![false; width * height];
+        for (y, row) in upload.rows.iter().enumerate() {
+            for (x, cell) in row.chars().enumerate() {
+                walls[y * width + x] = cell == '#';
+            }
+        }
+        Ok(CollisionGrid { game_id, map_id, upload, width, height, walls })
+    }
+
+    pub fn summary(&self) -> CollisionSummary {
+        CollisionSummary {
+            game_id: self.game_id,
+            map_id: self.map_id,
+            origin_x: self.upload.origin_x,
+            origin_y: self.upload.origin_y,
+            width: self.width,
+            height: self.height,
+            wall_cells: self.walls.iter().filter(|wall| **wall).count(),
+            mode: self.upload.mode,
+            wall_attenuation: self.upload.wall_attenuation,
+        }
+    }
+
+    fn is_wall(&self, x: i64, y: i64) -> bool {
+        let (Some(x), Some(y)) = (x.checked_sub(self.upload.origin_x), y.checked_sub(self.upload.origin_y)) else {
+            return false;
+        };
+        match (usize::try_from(x), usize::try_from(y)) {
+            (Ok(x), Ok(y)) if x < self.width && y < self.height => self.walls[y * self.width + x],
+            _ => false,
+        }
+    }
+
+    // wall cells on the Bresenham line between the clients' cells, not counting their own.
+    // `scale` is the game's stored coordinate steps per unit (see games::GameAdapter)
+    pub fn walls_between(&self, a: &ClientPosition, b: &ClientPosition, scale: f64) -> usize {
+        let cell = |pos: &ClientPosition| ((pos.x as f64 / scale).floor() as i64, (pos.y as f64 / scale).floor() as i64);
+        // walked from the lesser cell, since lines through a tie between two cells would take a
+        // different one depending on direction, and a pair has to get the same answer both ways
+        let (start, end) = (cell(a).min(cell(b)), cell(a).max(cell(b)));
+        let ((mut x, mut y), (x_end, y_end)) = (start, end);
+        if x_end.abs_diff(x) > MAX_LINE || y_end.abs_diff(y) > MAX_LINE {
+            return 0;
+        }
+        let dx = x_end.abs_diff(x) as i128;
+        let dy = -(y_end.abs_diff(y) as i128);
+        let step_x = if x < x_end { 1 } else { -1 };
+        let step_y = if y < y_end { 1 } else { -1 };
+        let mut error = dx + dy;
+        let mut walls = 0;
+        while (x, y) != (x_end, y_end) {
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+            if (x, y) != (x_end, y_end) && self.is_wall(x, y) {
+                walls += 1;
+            }
+        }
+        walls
+    }
+
+    // whether the grid keeps the two from pairing
+    pub fn blocks(&self, a: &ClientPosition, b: &ClientPosition, scale: f64) -> bool {
+        self.upload.mode == OcclusionMode::Block && self.walls_between(a, b, scale) > 0
+    }
+
+    // the gain left after the walls between them, in attenuate mode
+    pub fn attenuation(&self, a: &ClientPosition, b: &ClientPosition, scale: f64) -> Option<f32> {
+        if self.upload.mode != OcclusionMode::Attenuate {
+            return None;
+        }
+        let walls = self.walls_between(a, b, scale).min(i32::MAX as usize) as i32;
+        Some((1.0 - self.upload.wall_attenuation).powi(walls))
+    }
+}
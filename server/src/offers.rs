@@ -0,0 +1,110 @@
+// offers relayed in the last few seconds, per (sender, target), for dropping resent offers and
+// resolving glare. kept out of ServerState's RwLock like the routing table, so relaying an offer
+// never waits behind a position update holding the write lock
+use log::warn;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// identical offers resent within this window are dropped (renegotiation storms after flaky reconnects)
+const OFFER_DEDUP_WINDOW: Duration = Duration::from_secs(3);
+// offers crossing in both directions within this window are treated as glare
+const OFFER_GLARE_WINDOW: Duration = Duration::from_secs(2);
+
+// last offer relayed for a (sender, target) pair
+struct RecentOffer {
+    sent_at: Instant,
+    offer_hash: u64,
+}
+
+pub enum OfferVerdict {
+    Relay,
+    Duplicate,
+    // both sides offered at once; this one loses the tie-break and is dropped
+    GlareSuppressed,
+    // both sides offered at once and this one wins the tie-break, but the loser's offer was
+    // relayed first: this one goes through too, and the loser is told to roll its own back
+    GlareWon,
+}
+
+impl OfferVerdict {
+    // the client of the two that has to roll its offer back, when this offer settled glare.
+    // always the greater id, whichever of the offers arrived first
+    pub fn loser<'a>(&self, sender_id: &'a str, target_id: &'a str) -> Option<&'a str> {
+        match self {
+            OfferVerdict::GlareWon => Some(target_id),
+            OfferVerdict::GlareSuppressed => Some(sender_id),
+            OfferVerdict::Relay | OfferVerdict::Duplicate => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RecentOffers {
+    offers: Mutex<HashMap<(String, String), RecentOffer>>,
+}
+
+impl RecentOffers {
+    // decide whether an offer should be relayed, recording it if so.
+    // glare is resolved deterministically: the client with the greater id backs off, whichever
+    // offer arrived first. when the greater id's offer is already out, the smaller id's is relayed
+    // anyway and the greater id rolls its offer back, so exactly one offer gets answered
+    pub fn check(&self, sender_id: &str, target_id: &str, offer: &str) -> OfferVerdict {
+        let mut hasher = DefaultHasher::new();
+        offer.hash(&mut hasher);
+        let offer_hash = hasher.finish();
+        let now = Instant::now();
+        let mut offers = self.offers.lock().expect("recent offers lock poisoned");
+
+        let key = (sender_id.to_string(), target_id.to_string());
+        if let Some(previous) = offers.get(&key) {
+            if previous.offer_hash == offer_hash && now.duration_since(previous.sent_at) < OFFER_DEDUP_WINDOW {
+                return OfferVerdict::Duplicate;
+            }
+        }
+
+        let reverse_key = (target_id.to_string(), sender_id.to_string());
+        if let Some(reverse) = offers.get(&reverse_key) {
+            if now.duration_since(reverse.sent_at) < OFFER_GLARE_WINDOW {
+                warn!("Offer glare between {} and {}: both sides offered within {:?}",
+                      sender_id, target_id, OFFER_GLARE_WINDOW);
+                if sender_id > target_id {
+                    return OfferVerdict::GlareSuppressed;
+                }
+                // the loser's offer is settled by its rollback; a resend of it now meets this one
+                offers.remove(&reverse_key);
+                offers.insert(key, RecentOffer { sent_at: now, offer_hash });
+                return OfferVerdict::GlareWon;
+            }
+        }
+
+        offers.insert(key, RecentOffer { sent_at: now, offer_hash });
+        OfferVerdict::Relay
+    }
+
+    // drop offer records older than both windows
+    pub fn prune(&self) {
+        let max_age = OFFER_DEDUP_WINDOW.max(OFFER_GLARE_WINDOW);
+        self.offers.lock().expect("recent offers lock poisoned").retain(|_, offer| offer.sent_at.elapsed() < max_age);
+    }
+
+    pub fn remove_involving(&self, client_id: &str) {
+        self.offers
+            .lock()
+            .expect("recent offers lock poisoned")
+            .retain(|(sender, target), _| sender != client_id && target != client_id);
+    }
+
+    // forget offers between two clients so a renegotiation isn't mistaken for a duplicate
+    pub fn clear_between(&self, a: &str, b: &str) {
+        let mut offers = self.offers.lock().expect("recent offers lock poisoned");
+        offers.remove(&(a.to_string(), b.to_string()));
+        offers.remove(&(b.to_string(), a.to_string()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.offers.lock().expect("recent offers lock poisoned").len()
+    }
+}
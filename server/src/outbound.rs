@@ -0,0 +1,297 @@
+// the per-connection queue of messages waiting for the send task. a bounded channel like tokio's,
+// except that what happens when it is full is up to the operator (config outbound_queue.overflow)
+use crate::config::{OutboundQueueConfig, OverflowPolicy};
+use crate::metrics;
+use proxchat_protocol::{CloseReason, ServerMessage};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+struct Shared {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // a message was queued, or the last sender went away
+    readable: Notify,
+    // a message was taken, or the receiver went away
+    writable: Notify,
+    // the disconnect policy tripped; the connection's receive loop closes it
+    overflowed: Notify,
+    // an operator asked for the connection to be closed; its receive loop does that too
+    kicked: Notify,
+    // set along with `kicked`, for paths that act for the connection without being its receive loop
+    was_kicked: AtomicBool,
+    // what the receive loop tells the client when `kicked` fires
+    close_reason: Mutex<Option<CloseReason>>,
+}
+
+struct Queue {
+    messages: VecDeque<ServerMessage>,
+    senders: usize,
+    receiver_alive: bool,
+    // no more messages are taken; the receiver ends once it has drained the rest
+    closed: bool,
+}
+
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+}
+
+// the receiver is gone (the connection closed), or the disconnect policy is closing it
+#[derive(Debug)]
+pub struct Closed;
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("connection closed")
+    }
+}
+
+#[derive(Debug)]
+pub enum TrySendError {
+    // only under the block policy; the others never leave a sender waiting
+    Full,
+    Closed,
+}
+
+pub fn channel(config: &OutboundQueueConfig) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue { messages: VecDeque::with_capacity(config.capacity), senders: 1, receiver_alive: true, closed: false }),
+        capacity: config.capacity,
+        policy: config.overflow,
+        readable: Notify::new(),
+        writable: Notify::new(),
+        overflowed: Notify::new(),
+        kicked: Notify::new(),
+        was_kicked: AtomicBool::new(false),
+        close_reason: Mutex::new(None),
+    });
+    (Sender { shared: Arc::clone(&shared) }, Receiver { shared })
+}
+
+// what a full queue does with one more message
+enum Overflow {
+    Queued,
+    Wait,
+    Closed,
+}
+
+impl Sender {
+    pub async fn send(&self, message: ServerMessage) -> Result<(), Closed> {
+        let mut message = Some(message);
+        let mut blocked = false;
+        loop {
+            // registered before the check, so a message taken in between still wakes us
+            let writable = self.shared.writable.notified();
+            match self.push(&mut message) {
+                Overflow::Queued => return Ok(()),
+                Overflow::Closed => return Err(Closed),
+                Overflow::Wait => {
+                    if !blocked {
+                        metrics::OUTBOUND_OVERFLOWS.with_label_values(&["blocked"]).inc();
+                        blocked = true;
+                    }
+                }
+            }
+            writable.await;
+        }
+    }
+
+    pub fn try_send(&self, message: ServerMessage) -> Result<(), TrySendError> {
+        match self.push(&mut Some(message)) {
+            Overflow::Queued => Ok(()),
+            Overflow::Wait => Err(TrySendError::Full),
+            Overflow::Closed => Err(TrySendError::Closed),
+        }
+    }
+
+    // messages waiting for the send task
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().expect("outbound queue lock poisoned").messages.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    // refuses further messages; the receiver returns None once the queued ones are out
+    pub fn close(&self) {
+        self.shared.queue.lock().expect("outbound queue lock poisoned").closed = true;
+        self.shared.readable.notify_one();
+        self.shared.writable.notify_waiters();
+    }
+
+    // resolves once the disconnect policy has given up on this connection
+    pub async fn overflowed(&self) {
+        self.shared.overflowed.notified().await
+    }
+
+    // asks the connection's receive loop to close it, telling the client why
+    pub fn kick(&self) {
+        self.shared.was_kicked.store(true, Ordering::Relaxed);
+        self.close_for(CloseReason::Kicked);
+    }
+
+    // the same for any other reason the server has for closing the connection
+    pub fn close_for(&self, reason: CloseReason) {
+        *self.shared.close_reason.lock().expect("close reason lock poisoned") = Some(reason);
+        self.shared.kicked.notify_one();
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        *self.shared.close_reason.lock().expect("close reason lock poisoned")
+    }
+
+    // the connection was kicked (or banned) and is on its way out
+    pub fn is_kicked(&self) -> bool {
+        self.shared.was_kicked.load(Ordering::Relaxed)
+    }
+
+    pub async fn kicked(&self) {
+        self.shared.kicked.notified().await
+    }
+
+    // takes the message out of `message` unless the caller has to wait for room
+    fn push(&self, message: &mut Option<ServerMessage>) -> Overflow {
+        let mut queue = self.shared.queue.lock().expect("outbound queue lock poisoned");
+        if !queue.receiver_alive || queue.closed {
+            return Overflow::Closed;
+        }
+        if queue.messages.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::Block => return Overflow::Wait,
+                OverflowPolicy::Disconnect => {
+                    // stop taking messages at all; the connection is about to close
+                    queue.receiver_alive = false;
+                    queue.messages.clear();
+                    drop(queue);
+                    metrics::OUTBOUND_OVERFLOWS.with_label_values(&["disconnected"]).inc();
+                    self.shared.overflowed.notify_one();
+                    self.shared.writable.notify_waiters();
+                    return Overflow::Closed;
+                }
+                OverflowPolicy::DropOldestNoncritical => {
+                    if let Some(oldest) = queue.messages.iter().position(|queued| !is_critical(queued)) {
+                        queue.messages.remove(oldest);
+                    } else if message.as_ref().is_some_and(|message| !is_critical(message)) {
+                        // nothing older to give up, so the new message goes instead
+                        message.take();
+                        metrics::OUTBOUND_OVERFLOWS.with_label_values(&["dropped"]).inc();
+                        return Overflow::Queued;
+                    } else {
+                        // a queue full of signaling can't lose any of it
+                        return Overflow::Wait;
+                    }
+                    metrics::OUTBOUND_OVERFLOWS.with_label_values(&["dropped"]).inc();
+                }
+            }
+        }
+        if let Some(message) = message.take() {
+            queue.messages.push_back(message);
+        }
+        drop(queue);
+        self.shared.readable.notify_one();
+        Overflow::Queued
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.shared.queue.lock().expect("outbound queue lock poisoned").senders += 1;
+        Sender { shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().expect("outbound queue lock poisoned");
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            drop(queue);
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl Receiver {
+    // None once every sender is gone or the queue is closed, and it has drained
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        loop {
+            let readable = self.shared.readable.notified();
+            {
+                let mut queue = self.shared.queue.lock().expect("outbound queue lock poisoned");
+                if let Some(message) = queue.messages.pop_front() {
+                    drop(queue);
+                    self.shared.writable.notify_one();
+                    return Some(message);
+                }
+                if queue.senders == 0 || queue.closed {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().expect("outbound queue lock poisoned");
+        queue.receiver_alive = false;
+        queue.messages.clear();
+        drop(queue);
+        self.shared.writable.notify_waiters();
+    }
+}
+
+// the kind, sender and age of a relayed offer or answer older than `ttl_ms` as of `now_ms`
+pub fn expired_signal(message: &ServerMessage, ttl_ms: u64, now_ms: u64) -> Option<(&'static str, &str, u64)> {
+    let (kind, sender_id, relayed_at_ms) = match message {
+        ServerMessage::ReceiveOffer { sender_id, relayed_at_ms: Some(at), .. } => ("offer", sender_id, *at),
+        ServerMessage::ReceiveAnswer { sender_id, relayed_at_ms: Some(at), .. } => ("answer", sender_id, *at),
+        _ => return None,
+    };
+    let age_ms = now_ms.saturating_sub(relayed_at_ms);
+    (age_ms > ttl_ms).then_some((kind, sender_id.as_str(), age_ms))
+}
+
+// messages the client can't do without: signaling relays, peer set changes and replies it is
+// waiting on. the rest are either resent on the next update or only informational
+fn is_critical(message: &ServerMessage) -> bool {
+    match message {
+        ServerMessage::NearbyPeers(_)
+        | ServerMessage::AllPeersGone
+        | ServerMessage::ReintroducePeer { .. }
+        | ServerMessage::ForceRelay { .. }
+        | ServerMessage::UdpSession { .. }
+        | ServerMessage::PeerMuteState { .. }
+        | ServerMessage::SigningSession { .. }
+        | ServerMessage::Closing { .. }
+        | ServerMessage::ListenOnly { .. }
+        | ServerMessage::ElevatedConsent(_)
+        | ServerMessage::MapPartition { .. }
+        | ServerMessage::TopologyHint { .. }
+        | ServerMessage::ReceiveOffer { .. }
+        | ServerMessage::ReceiveAnswer { .. }
+        | ServerMessage::ReceiveIceCandidate { .. }
+        | ServerMessage::GlareRollback { .. }
+        | ServerMessage::SessionToken { .. }
+        | ServerMessage::Ack { .. }
+        | ServerMessage::RequestError { .. } => true,
+        ServerMessage::NearbyPeerDetails(_)
+        | ServerMessage::AreaSummary { .. }
+        | ServerMessage::Population(_)
+        | ServerMessage::ServerList(_)
+        | ServerMessage::ServerStats { .. }
+        | ServerMessage::PeerNatTypes { .. }
+        | ServerMessage::PeerAddresses { .. }
+        | ServerMessage::Ping { .. }
+        | ServerMessage::Notice { .. }
+        | ServerMessage::Error(_) => false,
+    }
+}
@@ -0,0 +1,136 @@
+use crate::config::OverloadConfig;
+use crate::metrics;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+// load-shedding tiers, cheapest to lose first. each tier also sheds everything below it.
+// offers and answers are never shed: dropping them guarantees a failed connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShedLevel {
+    None = 0,
+    // the periodic 5-second NearbyPeers resend
+    Reintroductions = 1,
+    // opt-in extras: peer details, area summaries, population queries
+    Extras = 2,
+    // trickled ICE candidates (signaling relay goes last)
+    IceRelay = 3,
+}
+
+impl ShedLevel {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ShedLevel::Reintroductions,
+            2 => ShedLevel::Extras,
+            3 => ShedLevel::IceRelay,
+            _ => ShedLevel::None,
+        }
+    }
+}
+
+// current tier, read from hot paths without touching the state lock
+static CURRENT_SHED_LEVEL: AtomicU8 = AtomicU8::new(ShedLevel::None as u8);
+
+// true if messages of this kind should currently be skipped; counts the skip under `kind`
+pub fn should_shed(level: ShedLevel, kind: &str) -> bool {
+    let shed = level != ShedLevel::None && ShedLevel::from_u8(CURRENT_SHED_LEVEL.load(Ordering::Relaxed)) >= level;
+    if shed {
+        metrics::SHED_MESSAGES.with_label_values(&[kind]).inc();
+    }
+    shed
+}
+
+// load signals sampled once per maintenance tick
+pub struct LoadSignals {
+    pub max_queue_fill: f64,
+    pub max_lock_wait: Duration,
+    pub loop_lag: Duration,
+}
+
+// flips the overloaded gauge and escalates shedding the longer overload persists
+pub struct OverloadDetector {
+    config: OverloadConfig,
+    consecutive_overloaded: u32,
+}
+
+impl OverloadDetector {
+    pub fn new(config: OverloadConfig) -> Self {
+        OverloadDetector { config, consecutive_overloaded: 0 }
+    }
+
+    pub fn evaluate(&mut self, signals: &LoadSignals) {
+        let overloaded = signals.max_queue_fill > self.config.max_queue_fill
+            || signals.max_lock_wait > Duration::from_millis(self.config.max_lock_wait_ms)
+            || signals.loop_lag > Duration::from_millis(self.config.max_loop_lag_ms);
+
+        let previous = ShedLevel::from_u8(CURRENT_SHED_LEVEL.load(Ordering::Relaxed));
+        let level = if overloaded {
+            self.consecutive_overloaded += 1;
+            // one bad tick sheds reintroductions, a sustained overload works up the tiers
+            let wanted = match self.consecutive_overloaded {
+                1..=2 => ShedLevel::Reintroductions,
+                3..=5 => ShedLevel::Extras,
+                _ => ShedLevel::IceRelay,
+            };
+            wanted.min(self.config.shedding)
+        } else {
+            self.consecutive_overloaded = 0;
+            ShedLevel::None
+        };
+
+        metrics::SERVER_OVERLOADED.set(overloaded as i64);
+        metrics::SHED_LEVEL.set(level as i64);
+        CURRENT_SHED_LEVEL.store(level as u8, Ordering::Relaxed);
+
+        if overloaded && self.consecutive_overloaded == 1 {
+            warn!("Server overloaded: queue fill {:.2}, lock wait {:?}, loop lag {:?}",
+                  signals.max_queue_fill, signals.max_lock_wait, signals.loop_lag);
+        }
+        if level != previous {
+            info!("Load shedding level changed: {:?} -> {:?}", previous, level);
+        }
+    }
+}
+
+// prometheus alerting rules matching the configured thresholds, for operators to drop into their rule files
+pub fn alert_rules(config: &OverloadConfig) -> String {
+    format!(
+        r#"groups:
+  - name: proxchat
+    rules:
+      - alert: ProxChatServerOverloaded
+        expr: proxchat_server_overloaded == 1
+        for: 1m
+        labels:
+          severity: warning
+        annotations:
+          summary: "ProxChat signaling server is overloaded and shedding load"
+      - alert: ProxChatMaintenanceLoopLag
+        expr: proxchat_maintenance_loop_lag_seconds > {loop_lag}
+        for: 2m
+        labels:
+          severity: warning
+        annotations:
+          summary: "ProxChat maintenance loop is running late"
+      - alert: ProxChatStateLockContention
+        expr: histogram_quantile(0.99, sum by (le) (rate(proxchat_state_lock_wait_seconds_bucket[5m]))) > {lock_wait}
+        for: 5m
+        labels:
+          severity: warning
+        annotations:
+          summary: "ProxChat state lock p99 wait is above threshold"
+      - alert: ProxChatOutboundQueuesBackingUp
+        expr: proxchat_outbound_queue_fill_max > {queue_fill}
+        for: 5m
+        labels:
+          severity: critical
+        annotations:
+          summary: "ProxChat outbound client queues are backing up"
+"#,
+        loop_lag = config.max_loop_lag_ms as f64 / 1000.0,
+        lock_wait = config.max_lock_wait_ms as f64 / 1000.0,
+        queue_fill = config.max_queue_fill,
+    )
+}
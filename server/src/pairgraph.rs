@@ -0,0 +1,97 @@
+// the shape of the pair graph, exported every maintenance tick so the effect of range tuning shows
+// up in numbers: peers per client, clients left with none, the largest group in which everyone
+// hears everyone (the biggest full mesh any client has to carry) and how fast pairs come and go.
+// the largest group is searched for on a copy of the graph, off the state lock and the runtime's
+// workers, and within a step budget, so a pathological graph costs a capped metric, not a stall
+use crate::metrics;
+use std::collections::{HashMap, HashSet, VecDeque};
+use tokio::time::{Duration, Instant};
+
+const CHURN_WINDOW: Duration = Duration::from_secs(60);
+// search steps (branches tried) per largest-group search; past it the best found so far stands
+const CLIQUE_SEARCH_BUDGET: usize = 100_000;
+
+// introductions plus separations over about the last minute, from samples of their running total
+#[derive(Default)]
+pub struct ChurnWindow {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl ChurnWindow {
+    pub fn per_minute(&mut self, now: Instant, total: u64) -> f64 {
+        self.samples.push_back((now, total));
+        while self.samples.len() > 1 && now.duration_since(self.samples[0].0) > CHURN_WINDOW {
+            self.samples.pop_front();
+        }
+        let (since, first) = self.samples[0];
+        let elapsed = now.duration_since(since);
+        if elapsed.is_zero() {
+            return 0.0;
+        }
+        total.saturating_sub(first) as f64 * 60.0 / elapsed.as_secs_f64()
+    }
+}
+
+// sets the graph gauges from the current pairs of every client with a position. the largest group
+// is left to a blocking task with its own copy of the graph, and lands when that is done
+pub fn observe<'a>(graph: &HashMap<String, HashSet<String>>, clients: impl Iterator<Item = &'a String>, churn: &mut ChurnWindow) {
+    let mut client_count = 0;
+    let mut isolated = 0;
+    let mut peers = 0;
+    for client_id in clients {
+        client_count += 1;
+        match graph.get(client_id) {
+            Some(client_peers) => peers += client_peers.len(),
+            None => isolated += 1,
+        }
+    }
+    metrics::PEERS_PER_CLIENT.set(if client_count == 0 { 0.0 } else { peers as f64 / client_count as f64 });
+    metrics::ISOLATED_CLIENTS.set(isolated);
+    let changes = metrics::PAIR_CHANGES.with_label_values(&["introduced"]).get() + metrics::PAIR_CHANGES.with_label_values(&["separated"]).get();
+    metrics::PAIR_CHURN.set(churn.per_minute(Instant::now(), changes));
+
+    let graph = graph.clone();
+    tokio::task::spawn_blocking(move || {
+        let (size, capped) = max_clique_size(&graph, CLIQUE_SEARCH_BUDGET);
+        metrics::MAX_CLIQUE_SIZE.set(size as i64);
+        metrics::MAX_CLIQUE_SEARCH_CAPPED.set(i64::from(capped));
+    });
+}
+
+// Bron-Kerbosch with a pivot, cut short wherever the candidates left can't beat the best so far.
+// exponential in theory, but proximity graphs are made of overlapping circles, where it mostly
+// isn't; the budget covers the rest. returns the size, and whether the budget ran out first
+pub fn max_clique_size(graph: &HashMap<String, HashSet<String>>, budget: usize) -> (usize, bool) {
+    let mut best = 0;
+    let mut steps = budget;
+    expand(graph, 0, graph.keys().map(String::as_str).collect(), &mut best, &mut steps);
+    (best, steps == 0)
+}
+
+fn expand<'a>(graph: &'a HashMap<String, HashSet<String>>, size: usize, mut candidates: HashSet<&'a str>, best: &mut usize, steps: &mut usize) {
+    if size + candidates.len() <= *best {
+        return;
+    }
+    if *steps == 0 {
+        // whatever this branch holds is a clique as it stands
+        *best = (*best).max(size);
+        return;
+    }
+    *steps -= 1;
+    let peers_of = |client_id: &str| graph.get(client_id);
+    // every clique through the pivot's peers is found again through the pivot, so skip them
+    let pivot = candidates
+        .iter()
+        .copied()
+        .max_by_key(|client_id| peers_of(client_id).map_or(0, |peers| peers.iter().filter(|peer| candidates.contains(peer.as_str())).count()));
+    let Some(pivot) = pivot else {
+        *best = size;
+        return;
+    };
+    let branches: Vec<&str> = candidates.iter().copied().filter(|client_id| !peers_of(pivot).is_some_and(|peers| peers.contains(*client_id))).collect();
+    for client_id in branches {
+        let within: HashSet<&str> = candidates.iter().copied().filter(|other| peers_of(client_id).is_some_and(|peers| peers.contains(*other))).collect();
+        expand(graph, size + 1, within, best, steps);
+        candidates.remove(client_id);
+    }
+}
@@ -0,0 +1,261 @@
+// where each introduced pair has got to: introduced, an offer relayed, an answer relayed, then
+// connected or failed as one side reports (ReportPeerConnected / ReportIceFailure). the maintenance
+// loop only resends NearbyPeers to clients with a pair that isn't connected yet, and no more than
+// once per cooldown for each, so settled clients stop getting the periodic rebroadcast and clients
+// that never report get it every cooldown.
+// with pair_retry configured, failed and timed out attempts are retried with backoff until
+// max_retries, after which the pair is given up on: no more resends, and relayed through TURN.
+// the tracker has its own lock, kept out of ServerState's RwLock like the routing table, so the
+// offer and answer relay can move a pair on without taking the state write lock
+use crate::config::PairRetryConfig;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use tokio::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PairState {
+    Introduced,
+    Offered,
+    Answered,
+    Connected,
+    Failed,
+}
+
+impl PairState {
+    pub const ALL: [PairState; 5] = [PairState::Introduced, PairState::Offered, PairState::Answered, PairState::Connected, PairState::Failed];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PairState::Introduced => "introduced",
+            PairState::Offered => "offered",
+            PairState::Answered => "answered",
+            PairState::Connected => "connected",
+            PairState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PairRecord {
+    pub state: PairState,
+    pub introduced_at: Instant,
+    // when it reached its current state
+    pub since: Instant,
+    // the introduction or the latest retry
+    pub attempt_started: Instant,
+    pub retries: u32,
+    pub gave_up: bool,
+    // when each side (in key order) was last sent the pair, by its introduction or a periodic resend
+    sent_at: [Instant; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAction {
+    Retry,
+    GiveUp,
+}
+
+// a pair as the admin API lists it
+#[derive(Debug, Clone, Serialize)]
+pub struct PairSummary {
+    pub client_id: String,
+    pub peer_id: String,
+    pub state: PairState,
+    pub age_secs: u64,
+    pub state_secs: u64,
+    pub retries: u32,
+    pub gave_up: bool,
+}
+
+// how long a separated pair keeps first claim on a free slot of a capped client, so jitter at
+// the edge of the range brings the same peer back rather than swapping in another
+const REJOIN_PREFERENCE: Duration = Duration::from_secs(15);
+
+#[derive(Default)]
+pub struct PairTracker {
+    inner: Mutex<Pairs>,
+}
+
+// keyed by the two client ids in sorted order, so either side finds the same record
+#[derive(Default)]
+struct Pairs {
+    pairs: HashMap<(String, String), PairRecord>,
+    // when each recently split pair was separated, until REJOIN_PREFERENCE has passed
+    separated_at: HashMap<(String, String), Instant>,
+}
+
+// which of sent_at is `a`'s
+fn side(a: &str, b: &str) -> usize {
+    usize::from(a > b)
+}
+
+fn key(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+impl PairTracker {
+    fn lock(&self) -> MutexGuard<'_, Pairs> {
+        self.inner.lock().expect("pair tracker lock poisoned")
+    }
+
+    pub fn introduced(&self, a: &str, b: &str) {
+        let now = Instant::now();
+        let record = PairRecord {
+            state: PairState::Introduced,
+            introduced_at: now,
+            since: now,
+            attempt_started: now,
+            retries: 0,
+            gave_up: false,
+            sent_at: [now; 2],
+        };
+        let mut inner = self.lock();
+        inner.separated_at.remove(&key(a, b));
+        inner.pairs.insert(key(a, b), record);
+    }
+
+    pub fn separated(&self, a: &str, b: &str) {
+        let mut inner = self.lock();
+        if inner.pairs.remove(&key(a, b)).is_some() {
+            inner.separated_at.insert(key(a, b), Instant::now());
+        }
+    }
+
+    // the two were paired until moments ago
+    pub fn recently_separated(&self, a: &str, b: &str) -> bool {
+        self.lock().separated_at.get(&key(a, b)).is_some_and(|at| at.elapsed() < REJOIN_PREFERENCE)
+    }
+
+    pub fn prune_separated(&self) {
+        self.lock().separated_at.retain(|_, at| at.elapsed() < REJOIN_PREFERENCE);
+    }
+
+    // moves the pair on; returns the record as it was, None when the two aren't paired.
+    // signaling only moves a pair forward, a report sets it outright
+    pub fn advance(&self, a: &str, b: &str, state: PairState) -> Option<PairRecord> {
+        let mut inner = self.lock();
+        let record = inner.pairs.get_mut(&key(a, b))?;
+        let previous = *record;
+        let forward = match state {
+            PairState::Offered => record.state == PairState::Introduced,
+            PairState::Answered => record.state == PairState::Offered,
+            PairState::Connected | PairState::Failed => record.state != state,
+            PairState::Introduced => false,
+        };
+        if forward {
+            record.state = state;
+            record.since = Instant::now();
+            // one that made it after all is retried again should it fail later
+            if state == PairState::Connected {
+                record.gave_up = false;
+            }
+        }
+        Some(previous)
+    }
+
+    pub fn get(&self, a: &str, b: &str) -> Option<PairRecord> {
+        self.lock().pairs.get(&key(a, b)).copied()
+    }
+
+    pub fn gave_up(&self, a: &str, b: &str) -> bool {
+        self.get(a, b).is_some_and(|record| record.gave_up)
+    }
+
+    // whether any of the client's pairs is still short of connected (and not given up on) and
+    // hasn't been sent to it within `cooldown`
+    pub fn resend_due(&self, client_id: &str, peers: impl IntoIterator<Item = impl AsRef<str>>, cooldown: Duration, now: Instant) -> bool {
+        let inner = self.lock();
+        peers.into_iter().any(|peer_id| {
+            inner.pairs.get(&key(client_id, peer_id.as_ref())).is_none_or(|record| {
+                record.state != PairState::Connected
+                    && !record.gave_up
+                    && now.saturating_duration_since(record.sent_at[side(client_id, peer_id.as_ref())]) >= cooldown
+            })
+        })
+    }
+
+    // the client was just sent these pairs again
+    pub fn resent(&self, client_id: &str, peers: impl IntoIterator<Item = impl AsRef<str>>, now: Instant) {
+        let mut inner = self.lock();
+        for peer_id in peers {
+            if let Some(record) = inner.pairs.get_mut(&key(client_id, peer_id.as_ref())) {
+                record.sent_at[side(client_id, peer_id.as_ref())] = now;
+            }
+        }
+    }
+
+    // pairs whose attempt failed, or timed out, and whose backoff has run: each is either retried
+    // (back to introduced, as a new attempt) or, past max_retries, given up on
+    pub fn due_retries(&self, now: Instant, config: &PairRetryConfig) -> Vec<(String, String, RetryAction)> {
+        let mut due = Vec::new();
+        for ((a, b), record) in self.lock().pairs.iter_mut() {
+            if record.gave_up || record.state == PairState::Connected {
+                continue;
+            }
+            let stalled_at = match (record.state, config.connect_timeout_secs) {
+                (PairState::Failed, _) => record.since,
+                (_, Some(timeout)) => record.attempt_started + Duration::from_secs(timeout),
+                (_, None) => continue,
+            };
+            if now < stalled_at {
+                continue;
+            }
+            if record.retries >= config.max_retries {
+                record.gave_up = true;
+                due.push((a.clone(), b.clone(), RetryAction::GiveUp));
+                continue;
+            }
+            let backoff = config.initial_backoff_secs.saturating_mul(1 << record.retries.min(16)).min(config.max_backoff_secs);
+            if now < stalled_at + Duration::from_secs(backoff) {
+                continue;
+            }
+            record.retries += 1;
+            record.state = PairState::Introduced;
+            record.since = now;
+            record.attempt_started = now;
+            record.sent_at = [now; 2];
+            due.push((a.clone(), b.clone(), RetryAction::Retry));
+        }
+        due
+    }
+
+    pub fn counts(&self) -> HashMap<PairState, usize> {
+        let mut counts = HashMap::new();
+        for record in self.lock().pairs.values() {
+            *counts.entry(record.state).or_default() += 1;
+        }
+        counts
+    }
+
+    // every pair, or the ones involving client_id
+    pub fn summaries(&self, client_id: Option<&str>) -> Vec<PairSummary> {
+        let now = Instant::now();
+        let mut summaries: Vec<PairSummary> = self
+            .lock()
+            .pairs
+            .iter()
+            .filter(|((a, b), _)| client_id.is_none_or(|client_id| a == client_id || b == client_id))
+            .map(|((a, b), record)| PairSummary {
+                client_id: a.clone(),
+                peer_id: b.clone(),
+                state: record.state,
+                age_secs: now.duration_since(record.introduced_at).as_secs(),
+                state_secs: now.duration_since(record.since).as_secs(),
+                retries: record.retries,
+                gave_up: record.gave_up,
+            })
+            .collect();
+        summaries.sort_by(|x, y| (&x.client_id, &x.peer_id).cmp(&(&y.client_id, &y.peer_id)));
+        summaries
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().pairs.len()
+    }
+}
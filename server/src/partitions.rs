@@ -0,0 +1,85 @@
+// audio partitions of maps with a population cap (config map_population_caps). a client arriving on
+// such a map joins the first partition with room, so past the cap late arrivals fill overflow
+// partitions instead of piling into one mesh. clients in different partitions of a map don't pair
+// by proximity. a client keeps its partition until it leaves the map, so nobody is shuffled when
+// someone in the main partition leaves; the free place goes to the next arrival
+use crate::config::MapPopulationCap;
+use crate::metrics;
+use proxchat_protocol::ClientPosition;
+use std::collections::HashMap;
+
+pub struct MapPartitions {
+    caps: Vec<MapPopulationCap>,
+    // the (game_id, map_id) and partition of every client on a capped map
+    assigned: HashMap<String, ((i32, i32), u32)>,
+    // clients in each partition of each capped map, trailing empty partitions trimmed
+    counts: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl MapPartitions {
+    pub fn new(caps: &[MapPopulationCap]) -> Self {
+        MapPartitions { caps: caps.to_vec(), assigned: HashMap::new(), counts: HashMap::new() }
+    }
+
+    // a map's entry beats its game's
+    fn cap(&self, pos: &ClientPosition) -> Option<usize> {
+        self.caps
+            .iter()
+            .filter(|cap| cap.covers(pos))
+            .max_by_key(|cap| cap.map_id.is_some())
+            .map(|cap| cap.max_population)
+    }
+
+    // places a client that arrived on its position's map. returns its partition when it changed:
+    // on arriving at a capped map, and back to 0 on leaving an overflow partition for an uncapped map
+    pub fn place(&mut self, pos: &ClientPosition) -> Option<u32> {
+        let map = (pos.game_id, pos.map_id);
+        let previous = self.assigned.get(&pos.client_id).copied();
+        if previous.is_some_and(|(previous_map, _)| previous_map == map) {
+            return None;
+        }
+        self.remove(&pos.client_id);
+        let Some(cap) = self.cap(pos) else {
+            return previous.is_some_and(|(_, partition)| partition > 0).then_some(0);
+        };
+        let counts = self.counts.entry(map).or_default();
+        let partition = counts.iter().position(|&count| count < cap).unwrap_or(counts.len());
+        if partition == counts.len() {
+            counts.push(0);
+        }
+        counts[partition] += 1;
+        self.assigned.insert(pos.client_id.clone(), (map, partition as u32));
+        self.update_metrics();
+        Some(partition as u32)
+    }
+
+    pub fn remove(&mut self, client_id: &str) {
+        let Some((map, partition)) = self.assigned.remove(client_id) else {
+            return;
+        };
+        if let Some(counts) = self.counts.get_mut(&map) {
+            counts[partition as usize] -= 1;
+            while counts.last() == Some(&0) {
+                counts.pop();
+            }
+            if counts.is_empty() {
+                self.counts.remove(&map);
+            }
+        }
+        self.update_metrics();
+    }
+
+    // 0, the main partition, for clients on uncapped maps
+    pub fn partition(&self, client_id: &str) -> u32 {
+        self.assigned.get(client_id).map_or(0, |&(_, partition)| partition)
+    }
+
+    pub fn len(&self) -> usize {
+        self.assigned.len()
+    }
+
+    fn update_metrics(&self) {
+        let overflow: usize = self.counts.values().map(|counts| counts.iter().skip(1).sum::<usize>()).sum();
+        metrics::OVERFLOW_CLIENTS.set(overflow as i64);
+    }
+}
@@ -0,0 +1,131 @@
+// operator-supplied audibility rules (the wasm-plugins feature): a WASM module exporting
+//
+//   audible(game_id: i32, map_id: i32, ax: i64, ay: i64, a_channel: i32,
+//           bx: i64, by: i64, b_channel: i32, range: f64) -> f32
+//
+// answers whether b is audible to a and how loud: negative (or NaN) for not at all, otherwise a
+// gain, clamped to 0..1 and passed on to clients as NearbyPeer.attenuation. coordinates and range
+// are as the server stores them (thousandths for float-coordinate games). it's only asked about
+// two clients on the same game and map whose channels the game lets hear each other, and it
+// must answer the same for (a, b) as for (b, a) or pairs flap. the module gets no imports and
+// a fuel budget per call; a call that traps or runs out falls back to the built-in rules
+use crate::config::WasmPluginConfig;
+use crate::games::GameAdapter;
+use crate::metrics;
+use log::{info, warn};
+use proxchat_protocol::ClientPosition;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use wasmtime::{Engine, Instance, Module, Store, TypedFunc};
+
+type AudibleArgs = (i32, i32, i64, i64, i32, i64, i64, i32, f64);
+
+pub struct WasmPlugin {
+    game_ids: Vec<i32>,
+    fuel_per_call: u64,
+    // calls come in under the state lock, so one instance taken in turn is enough
+    instance: Mutex<(Store<()>, TypedFunc<AudibleArgs, f32>)>,
+}
+
+static PLUGIN: OnceLock<WasmPlugin> = OnceLock::new();
+
+pub fn init(config: &WasmPluginConfig) -> Result<(), String> {
+    let mut engine_config = wasmtime::Config::new();
+    engine_config.consume_fuel(true);
+    let engine = Engine::new(&engine_config).map_err(|e| format!("Failed to set up the WASM engine: {}", e))?;
+    let module = Module::from_file(&engine, &config.path).map_err(|e| format!("Failed to load WASM plugin {}: {}", config.path, e))?;
+    let mut store = Store::new(&engine, ());
+    // the start function, if any, runs on the same budget as a call
+    store.set_fuel(config.fuel_per_call).map_err(|e| e.to_string())?;
+    let instance = Instance::new(&mut store, &module, &[]).map_err(|e| format!("Failed to instantiate WASM plugin {}: {}", config.path, e))?;
+    let audible = instance
+        .get_typed_func::<AudibleArgs, f32>(&mut store, "audible")
+        .map_err(|e| format!("WASM plugin {} has no usable `audible` export: {}", config.path, e))?;
+    if config.game_ids.is_empty() {
+        info!("WASM plugin loaded: {} (every game)", config.path);
+    } else {
+        info!("WASM plugin loaded: {} (games {:?})", config.path, config.game_ids);
+    }
+    let _ = PLUGIN.set(WasmPlugin {
+        game_ids: config.game_ids.clone(),
+        fuel_per_call: config.fuel_per_call,
+        instance: Mutex::new((store, audible)),
+    });
+    Ok(())
+}
+
+pub fn get() -> Option<&'static WasmPlugin> {
+    PLUGIN.get()
+}
+
+impl WasmPlugin {
+    pub fn game_ids(&self) -> &[i32] {
+        &self.game_ids
+    }
+
+    // the module's answer for the pair; None when the call failed
+    fn audible(&self, a: &ClientPosition, b: &ClientPosition, range: f64) -> Option<f32> {
+        let mut instance = self.instance.lock().unwrap_or_else(PoisonError::into_inner);
+        let (store, audible) = &mut *instance;
+        store.set_fuel(self.fuel_per_call).ok()?;
+        match audible.call(&mut *store, (a.game_id, a.map_id, a.x, a.y, a.channel, b.x, b.y, b.channel, range)) {
+            Ok(gain) => Some(gain),
+            Err(e) => {
+                // only the first few, a broken plugin fails on every call
+                if metrics::PLUGIN_FAILURES.get() < 10 {
+                    warn!("WASM plugin call failed for {} and {}: {}", a.client_id, b.client_id, e);
+                }
+                metrics::PLUGIN_FAILURES.inc();
+                None
+            }
+        }
+    }
+}
+
+// the plugin's answers over another adapter's coordinates and channels
+pub struct PluginAdapter {
+    plugin: &'static WasmPlugin,
+    inner: Arc<dyn GameAdapter>,
+}
+
+impl PluginAdapter {
+    pub fn new(plugin: &'static WasmPlugin, inner: Arc<dyn GameAdapter>) -> Self {
+        PluginAdapter { plugin, inner }
+    }
+
+    fn stored_range(&self, range: f32) -> f64 {
+        f64::from(range) * self.inner.coordinate_scale()
+    }
+}
+
+impl GameAdapter for PluginAdapter {
+    fn coordinate_scale(&self) -> f64 {
+        self.inner.coordinate_scale()
+    }
+
+    fn float_coordinates(&self) -> bool {
+        self.inner.float_coordinates()
+    }
+
+    fn normalize(&self, pos: ClientPosition) -> ClientPosition {
+        self.inner.normalize(pos)
+    }
+
+    fn same_channel(&self, a: &ClientPosition, b: &ClientPosition) -> bool {
+        self.inner.same_channel(a, b)
+    }
+
+    fn audible(&self, a: &ClientPosition, b: &ClientPosition, range: f32) -> bool {
+        match self.plugin.audible(a, b, self.stored_range(range)) {
+            Some(gain) => gain >= 0.0,
+            None => self.inner.audible(a, b, range),
+        }
+    }
+
+    fn attenuation(&self, a: &ClientPosition, b: &ClientPosition, range: f32) -> Option<f32> {
+        match self.plugin.audible(a, b, self.stored_range(range)) {
+            Some(gain) if gain >= 0.0 => Some(gain.min(1.0)),
+            Some(_) => Some(0.0),
+            None => self.inner.attenuation(a, b, range),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{
+    ClientEnvelope, ClientMessage, ClientPosition, ClientPreferences, DistanceBucket, ElevatedRole, NearbyPeer, PopulationEntry, ServerMessage,
+};
+use prost::Message as _;
+use proxchat_protocol::{compat, PROTOCOL_VERSION};
+
+// types generated from proto/proxchat.proto by build.rs
+pub mod pb {
+    include!(concat!(env!("OUT_DIR"), "/proxchat.v1.rs"));
+}
+
+use pb::client_envelope::Message as Inbound;
+use pb::server_envelope::Message as Outbound;
+
+pub fn decode_client(bytes: &[u8]) -> Result<ClientEnvelope, String> {
+    let envelope = pb::ClientEnvelope::decode(bytes).map_err(|e| e.to_string())?;
+    let message = envelope.message.ok_or_else(|| "empty ClientEnvelope".to_string())?;
+    let message = match message {
+        Inbound::UpdatePosition(pos) => ClientMessage::UpdatePosition(ClientPosition {
+            client_id: pos.client_id,
+            map_id: pos.map_id,
+            x: pos.x,
+            y: pos.y,
+            channel: pos.channel,
+            game_id: pos.game_id,
+            heading: pos.heading.map(|heading| (heading % 360) as u16),
+            session_token: pos.session_token,
+        }),
+        Inbound::RequestPeerRefresh(_) => ClientMessage::RequestPeerRefresh,
+        Inbound::SendOffer(m) => ClientMessage::SendOffer { target_id: m.target_id, offer: m.offer },
+        Inbound::SendAnswer(m) => ClientMessage::SendAnswer { target_id: m.target_id, answer: m.answer },
+        Inbound::SendIceCandidate(m) => ClientMessage::SendIceCandidate { target_id: m.target_id, candidate: m.candidate },
+        Inbound::ReportPeer(m) => ClientMessage::ReportPeer { target_id: m.target_id, reason: m.reason },
+        Inbound::SetPreferences(m) => ClientMessage::SetPreferences(ClientPreferences {
+            peer_details: m.peer_details,
+            area_summary: m.area_summary,
+            rtt_probes: m.rtt_probes,
+            spectator_opt_out: m.spectator_opt_out,
+            share_public_address: m.share_public_address,
+        }),
+        Inbound::GetPopulation(_) => ClientMessage::GetPopulation,
+        Inbound::RequestReintroduction(m) => ClientMessage::RequestReintroduction { peer_id: m.peer_id },
+        Inbound::Disconnect(_) => ClientMessage::Disconnect,
+        // a JSON message may carry its own request_id; the envelope's wins
+        Inbound::Json(text) => {
+            let value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+            let inner = compat::parse_client_envelope(value)?;
+            return Ok(ClientEnvelope {
+                request_id: envelope.request_id.or(inner.request_id),
+                ..inner
+            });
+        }
+    };
+    // protobuf evolves through field numbers; typed messages are always the current version
+    Ok(ClientEnvelope {
+        v: PROTOCOL_VERSION,
+        message,
+        request_id: envelope.request_id,
+    })
+}
+
+fn bucket_to_pb(bucket: DistanceBucket) -> pb::DistanceBucket {
+    match bucket {
+        DistanceBucket::Near => pb::DistanceBucket::Near,
+        DistanceBucket::Medium => pb::DistanceBucket::Medium,
+        DistanceBucket::Far => pb::DistanceBucket::Far,
+    }
+}
+
+fn elevated_role_to_pb(role: ElevatedRole) -> pb::ElevatedRole {
+    match role {
+        ElevatedRole::Spectator => pb::ElevatedRole::Spectator,
+        ElevatedRole::Announcer => pb::ElevatedRole::Announcer,
+    }
+}
+
+fn nearby_peer_to_pb(peer: NearbyPeer) -> pb::NearbyPeer {
+    pb::NearbyPeer {
+        client_id: peer.client_id,
+        bucket: bucket_to_pb(peer.bucket) as i32,
+        dx: peer.dx,
+        dy: peer.dy,
+        tags: peer.tags.into_iter().collect(),
+        identity_fingerprint: peer.identity_fingerprint,
+        attenuation: peer.attenuation,
+        facing_weight: peer.facing_weight,
+        low_quality: peer.low_quality,
+        listen_only: peer.listen_only,
+        elevated_role: peer.elevated_role.map(|role| elevated_role_to_pb(role) as i32),
+    }
+}
+
+fn population_entry_to_pb(entry: PopulationEntry) -> pb::PopulationEntry {
+    pb::PopulationEntry {
+        game_id: entry.game_id,
+        map_id: entry.map_id,
+        channel: entry.channel,
+        count: entry.count as u64,
+    }
+}
+
+pub fn encode_server(message: &ServerMessage) -> Result<Vec<u8>, String> {
+    let outbound = match message.clone() {
+        ServerMessage::NearbyPeers(peer_ids) => Outbound::NearbyPeers(pb::NearbyPeers { peer_ids }),
+        ServerMessage::AllPeersGone => Outbound::AllPeersGone(pb::Empty {}),
+        ServerMessage::NearbyPeerDetails(peers) => Outbound::NearbyPeerDetails(pb::NearbyPeerDetails {
+            peers: peers.into_iter().map(nearby_peer_to_pb).collect(),
+        }),
+        ServerMessage::AreaSummary { nearby_count } => Outbound::AreaSummary(pb::AreaSummary {
+            nearby_count: nearby_count as u64,
+        }),
+        ServerMessage::Population(entries) => Outbound::Population(pb::Population {
+            entries: entries.into_iter().map(population_entry_to_pb).collect(),
+        }),
+        ServerMessage::ReintroducePeer { peer_id } => Outbound::ReintroducePeer(pb::ReintroducePeer { peer_id }),
+        ServerMessage::ReceiveOffer { sender_id, offer, relayed_at_ms } => Outbound::ReceiveOffer(pb::ReceiveOffer { sender_id, offer, relayed_at_ms }),
+        ServerMessage::ReceiveAnswer { sender_id, answer, relayed_at_ms } => {
+            Outbound::ReceiveAnswer(pb::ReceiveAnswer { sender_id, answer, relayed_at_ms })
+        }
+        ServerMessage::ReceiveIceCandidate { sender_id, candidate } => {
+            Outbound::ReceiveIceCandidate(pb::ReceiveIceCandidate { sender_id, candidate })
+        }
+        message @ (ServerMessage::ServerList(_)
+        | ServerMessage::ServerStats { .. }
+        | ServerMessage::UdpSession { .. }
+        | ServerMessage::PeerMuteState { .. }
+        | ServerMessage::ForceRelay { .. }
+        | ServerMessage::PeerNatTypes { .. }
+        | ServerMessage::PeerAddresses { .. }
+        | ServerMessage::SigningSession { .. }
+        | ServerMessage::Ping { .. }
+        | ServerMessage::Closing { .. }
+        | ServerMessage::Notice { .. }
+        | ServerMessage::ListenOnly { .. }
+        | ServerMessage::ElevatedConsent(_)
+        | ServerMessage::MapPartition { .. }
+        | ServerMessage::TopologyHint { .. }
+        | ServerMessage::GlareRollback { .. }
+        | ServerMessage::SessionToken { .. }
+        | ServerMessage::Ack { .. }
+        | ServerMessage::RequestError { .. }) => {
+            Outbound::Json(serde_json::to_string(&message).map_err(|e| e.to_string())?)
+        }
+        ServerMessage::Error(error) => Outbound::Error(error),
+    };
+    Ok(pb::ServerEnvelope { message: Some(outbound) }.encode_to_vec())
+}
@@ -0,0 +1,121 @@
+// the public server registry (config registry), for community server browsers. every interval the
+// server POSTs its announcement (name, region, url, population, game ids) to the registry url, and
+// ListServers from a client is answered from a GET of the same url, cached for list_cache_secs so
+// a room full of clients opening the browser makes one request
+use crate::config::RegistryConfig;
+use crate::{federation, metrics, ServerState};
+use log::{info, warn};
+use proxchat_protocol::{ListedServer, PROTOCOL_VERSION};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{self, Duration, Instant};
+
+// how long one registry request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct Announcement<'a> {
+    name: &'a str,
+    region: &'a str,
+    url: &'a str,
+    population: usize,
+    game_ids: Vec<i32>,
+    protocol_version: u32,
+}
+
+struct Registry {
+    config: RegistryConfig,
+    http: reqwest::Client,
+    // the last listing and when it was fetched
+    listing: Mutex<Option<(Instant, Vec<ListedServer>)>>,
+}
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+// starts announcing; ListServers works from here on
+pub fn init(config: RegistryConfig, state: Arc<RwLock<ServerState>>) {
+    let http = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(http) => http,
+        Err(e) => {
+            warn!("Not announcing to the server registry, its HTTP client failed to start: {}", e);
+            return;
+        }
+    };
+    if REGISTRY.set(Registry { config, http, listing: Mutex::new(None) }).is_err() {
+        return;
+    }
+    tokio::spawn(announce_loop(state));
+}
+
+async fn announce_loop(state: Arc<RwLock<ServerState>>) {
+    let Some(registry) = REGISTRY.get() else {
+        return;
+    };
+    let config = &registry.config;
+    info!("Announcing as {:?} ({}) to {} every {}s", config.name, config.region, config.url, config.interval_secs);
+    let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+    // failures are logged once until an announcement goes through again
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        let (population, mut game_ids) = {
+            // federated servers announce their own clients
+            let state = metrics::timed_read(&state, "registry").await;
+            let local: Vec<i32> =
+                state.positions.values().filter(|pos| !federation::is_remote(&pos.client_id)).map(|pos| pos.game_id).collect();
+            (local.len(), local.into_iter().collect::<BTreeSet<i32>>())
+        };
+        game_ids.extend(&config.game_ids);
+        let announcement = Announcement {
+            name: &config.name,
+            region: &config.region,
+            url: &config.public_url,
+            population,
+            game_ids: game_ids.into_iter().collect(),
+            protocol_version: PROTOCOL_VERSION,
+        };
+        let result = registry.http.post(&config.url).json(&announcement).send().await.and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) if failing => {
+                info!("Announcing to the server registry works again");
+                failing = false;
+            }
+            Ok(_) => {}
+            Err(e) if !failing => {
+                warn!("Failed to announce to the server registry at {}: {}", config.url, e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+// the registry's servers, from the cache while it is fresh
+pub async fn list_servers() -> Result<Vec<ListedServer>, String> {
+    let registry = REGISTRY.get().ok_or_else(|| "This server isn't part of a server registry".to_string())?;
+    let mut listing = registry.listing.lock().await;
+    let max_age = Duration::from_secs(registry.config.list_cache_secs);
+    if let Some((fetched_at, servers)) = listing.as_ref() {
+        if fetched_at.elapsed() < max_age {
+            return Ok(servers.clone());
+        }
+    }
+    let fetched: Result<Vec<ListedServer>, reqwest::Error> =
+        async { registry.http.get(&registry.config.url).send().await?.error_for_status()?.json().await }.await;
+    match fetched {
+        Ok(servers) => {
+            *listing = Some((Instant::now(), servers.clone()));
+            Ok(servers)
+        }
+        Err(e) => {
+            warn!("Failed to fetch the server list from {}: {}", registry.config.url, e);
+            // an outdated list beats none
+            match listing.as_ref() {
+                Some((_, servers)) => Ok(servers.clone()),
+                None => Err("The server registry is unavailable, try again later".to_string()),
+            }
+        }
+    }
+}
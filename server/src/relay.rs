@@ -0,0 +1,235 @@
+// the built-in TURN relay (the turn-server feature, config turn_server), so a single-binary
+// deployment can still connect symmetric-NAT clients without running coturn next to it. it checks
+// the same short-lived credentials turn::ice_server hands out with ForceRelay, with the turn
+// section's shared_secret: the username carries its expiry, the password is its HMAC.
+// with hourly_quota_bytes or daily_quota_bytes set, what each client relays is metered against
+// them, keyed by the client id in its credential, so the relay can't serve as a free general one
+use crate::config::{TurnConfig, TurnServerConfig};
+use crate::db::unix_now;
+use crate::routing::RoutingTable;
+use crate::{metrics, turn};
+use log::{error, info, warn};
+use proxchat_protocol::ServerMessage;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use webrtc_turn::allocation::five_tuple::FiveTuple;
+use webrtc_turn::allocation::AllocationInfo;
+use webrtc_turn::auth::{generate_auth_key, AuthHandler};
+use webrtc_turn::relay::relay_range::RelayAddressGeneratorRanges;
+use webrtc_turn::server::config::{ConnConfig, ServerConfig};
+use webrtc_turn::server::Server;
+use webrtc_util::vnet::net::Net;
+
+// how often live allocations are metered; a client can go this far past its quota before it's cut off
+const METER_INTERVAL: Duration = Duration::from_secs(10);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// a client has relayed all its quota allows for the window
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub client_id: String,
+    // "hourly" or "daily"
+    pub window: &'static str,
+    pub limit: u64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "QuotaExceeded: {} has relayed its {} quota of {} bytes through TURN", self.client_id, self.window, self.limit)
+    }
+}
+
+// bytes relayed in a window that starts over once its length has passed
+#[derive(Clone, Copy)]
+struct Window {
+    started: Instant,
+    bytes: u64,
+}
+
+impl Window {
+    fn used(&self, length: Duration, now: Instant) -> u64 {
+        if now.duration_since(self.started) < length { self.bytes } else { 0 }
+    }
+
+    fn add(&mut self, bytes: u64, length: Duration, now: Instant) {
+        if now.duration_since(self.started) >= length {
+            *self = Window { started: now, bytes: 0 };
+        }
+        self.bytes = self.bytes.saturating_add(bytes);
+    }
+}
+
+// each client's hour and day of relaying
+struct Quotas {
+    hourly: Option<u64>,
+    daily: Option<u64>,
+    usage: Mutex<HashMap<String, [Window; 2]>>,
+}
+
+impl Quotas {
+    fn enabled(&self) -> bool {
+        self.hourly.is_some() || self.daily.is_some()
+    }
+
+    fn check(&self, client_id: &str) -> Result<(), QuotaExceeded> {
+        let now = Instant::now();
+        let usage = self.usage.lock().expect("relay quota lock poisoned");
+        let Some([hour, day]) = usage.get(client_id) else {
+            return Ok(());
+        };
+        for (window, used, limit) in [("hourly", hour.used(HOUR, now), self.hourly), ("daily", day.used(DAY, now), self.daily)] {
+            if let Some(limit) = limit.filter(|limit| used >= *limit) {
+                return Err(QuotaExceeded { client_id: client_id.to_string(), window, limit });
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&self, client_id: &str, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut usage = self.usage.lock().expect("relay quota lock poisoned");
+        let [hour, day] = usage.entry(client_id.to_string()).or_insert([Window { started: now, bytes: 0 }; 2]);
+        hour.add(bytes, HOUR, now);
+        day.add(bytes, DAY, now);
+    }
+
+    // clients with nothing relayed in the last day
+    fn prune(&self) {
+        let now = Instant::now();
+        self.usage.lock().expect("relay quota lock poisoned").retain(|_, [_, day]| day.used(DAY, now) > 0);
+    }
+}
+
+// the client id in a "<expiry unix time>:<client_id>" username
+fn client_id(username: &str) -> &str {
+    username.split_once(':').map_or(username, |(_, client_id)| client_id)
+}
+
+struct SharedSecretAuth {
+    shared_secret: String,
+    quotas: Arc<Quotas>,
+}
+
+impl AuthHandler for SharedSecretAuth {
+    // the long-term credential key for "<expiry unix time>:<client_id>", unless it has expired or
+    // the client has used up its relay quota
+    fn auth_handle(&self, username: &str, realm: &str, src_addr: SocketAddr) -> Result<Vec<u8>, webrtc_turn::Error> {
+        let expiry = username.split(':').next().and_then(|expiry| expiry.parse::<i64>().ok());
+        if expiry.is_none_or(|expiry| expiry <= unix_now()) {
+            metrics::TURN_AUTH_REJECTED.inc();
+            return Err(webrtc_turn::Error::Other(format!("expired or malformed TURN username {} from {}", username, src_addr)));
+        }
+        if let Err(e) = self.quotas.check(client_id(username)) {
+            metrics::TURN_QUOTA_EXCEEDED.with_label_values(&[e.window]).inc();
+            return Err(webrtc_turn::Error::Other(format!("{} (from {})", e, src_addr)));
+        }
+        let password = turn::credential(&self.shared_secret, username);
+        Ok(generate_auth_key(username, realm, &password))
+    }
+}
+
+// runs for the life of the process; the relay's own tasks do the work, and this one meters them
+// when there are quotas. a client past its quota is told why over its signaling connection
+pub async fn serve(config: TurnServerConfig, turn: TurnConfig, routing: Arc<RoutingTable>) {
+    let socket = match UdpSocket::bind(&config.listen_addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind TURN relay on {}: {}", config.listen_addr, e);
+            return;
+        }
+    };
+    let relay_ip: IpAddr = config.relay_ip.parse().expect("relay_ip is checked when the config loads");
+    let quotas = Arc::new(Quotas { hourly: config.hourly_quota_bytes, daily: config.daily_quota_bytes, usage: Mutex::new(HashMap::new()) });
+    let (closed_tx, closed_rx) = mpsc::channel(1024);
+    let server = Server::new(ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn: Arc::new(socket),
+            relay_addr_generator: Box::new(RelayAddressGeneratorRanges {
+                relay_address: relay_ip,
+                min_port: config.min_relay_port,
+                max_port: config.max_relay_port,
+                max_retries: 10,
+                address: "0.0.0.0".to_string(),
+                net: Arc::new(Net::new(None)),
+            }),
+        }],
+        realm: config.realm.clone(),
+        auth_handler: Arc::new(SharedSecretAuth { shared_secret: turn.shared_secret, quotas: Arc::clone(&quotas) }),
+        // the crate's default
+        channel_bind_timeout: Duration::from_secs(0),
+        // what an allocation relayed after it was last metered is counted when it closes
+        alloc_close_notify: quotas.enabled().then_some(closed_tx),
+    })
+    .await;
+    let server = match server {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            error!("Failed to start TURN relay on {}: {}", config.listen_addr, e);
+            return;
+        }
+    };
+    info!("TURN relay listening on: {} (relaying from {})", config.listen_addr, relay_ip);
+    if quotas.enabled() {
+        meter(server, quotas, closed_rx, routing).await;
+    } else {
+        std::future::pending::<()>().await;
+    }
+}
+
+// counts what every allocation has relayed since it was last seen against its client's quotas,
+// and closes the allocations of clients that have gone past them
+async fn meter(server: Arc<Server>, quotas: Arc<Quotas>, mut closed: mpsc::Receiver<AllocationInfo>, routing: Arc<RoutingTable>) {
+    // relayed bytes of each live allocation as last counted
+    let mut counted: HashMap<FiveTuple, usize> = HashMap::new();
+    let mut interval = time::interval(METER_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(info) = closed.recv() => {
+                let already = counted.remove(&info.five_tuple).unwrap_or(0);
+                quotas.record(client_id(&info.username), info.relayed_bytes.saturating_sub(already) as u64);
+            }
+            _ = interval.tick() => {
+                let allocations = match server.get_allocations_info(None).await {
+                    Ok(allocations) => allocations,
+                    Err(e) => {
+                        error!("Failed to meter TURN relay allocations: {}", e);
+                        return;
+                    }
+                };
+                let mut usernames: HashMap<&str, Vec<&str>> = HashMap::new();
+                for (five_tuple, info) in &allocations {
+                    let already = counted.insert(*five_tuple, info.relayed_bytes).unwrap_or(0);
+                    quotas.record(client_id(&info.username), info.relayed_bytes.saturating_sub(already) as u64);
+                    usernames.entry(client_id(&info.username)).or_default().push(&info.username);
+                }
+                for (client_id, usernames) in usernames {
+                    let Err(e) = quotas.check(client_id) else {
+                        continue;
+                    };
+                    warn!("Closing TURN relay allocations of {}: {}", client_id, e);
+                    metrics::TURN_QUOTA_EXCEEDED.with_label_values(&[e.window]).inc();
+                    if let Some(tx) = routing.sender(client_id) {
+                        let _ = tx.try_send(ServerMessage::Error(e.to_string()));
+                    }
+                    // closing waits on the close notifications this loop drains, so it can't wait here
+                    for username in usernames {
+                        let server = Arc::clone(&server);
+                        let username = username.to_string();
+                        tokio::spawn(async move {
+                            let _ = server.delete_allocations_by_username(username).await;
+                        });
+                    }
+                }
+                quotas.prune();
+            }
+        }
+    }
+}
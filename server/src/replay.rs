@@ -0,0 +1,313 @@
+use crate::config::ReplayRecordingConfig;
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use proxchat_protocol::{ClientEnvelope, ClientMessage, ClientPosition, ClientPositionFloat, ServerMessage, SUBPROTOCOL_V1};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+
+// captured messages waiting to be written; past this, new ones are dropped with a warning
+const QUEUE_LEN: usize = 4096;
+
+const USAGE: &str = "usage: prox-chat-server replay <recording.jsonl> <ws://dev-server:port> [--speed <factor>]";
+
+// one line of a replay file. sessions and client ids are numbered in order of appearance, times are
+// milliseconds since recording started, and SDP, ICE candidates, keys and free text are replaced
+// by their length. only what clients sent is replayed; Sent lines are there to compare against
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ReplayEvent {
+    Opened { session: u64 },
+    Received { session: u64, size: usize, message: ClientMessage },
+    Sent { session: u64, message_type: String, size: usize },
+    Closed { session: u64 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReplayRecord {
+    t_ms: u64,
+    #[serde(flatten)]
+    event: ReplayEvent,
+}
+
+// what the connection handlers hand over; anonymized by the writer
+enum Captured {
+    Opened,
+    Received { size: usize, message: ClientMessage },
+    Sent { message_type: String, size: usize },
+    Closed,
+}
+
+struct Recorder {
+    queue: mpsc::Sender<(Instant, String, Captured)>,
+    started: Instant,
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+// start the writer task; nothing is captured before this
+pub fn init(config: ReplayRecordingConfig) {
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    if RECORDER.set(Recorder { queue: tx, started: Instant::now() }).is_err() {
+        return;
+    }
+    tokio::spawn(write_loop(config, rx));
+}
+
+fn capture(connection_id: &str, captured: Captured) {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    if recorder.queue.try_send((Instant::now(), connection_id.to_string(), captured)).is_err() {
+        warn!("Replay recording queue full, dropping message");
+    }
+}
+
+pub fn opened(connection_id: &str) {
+    capture(connection_id, Captured::Opened);
+}
+
+pub fn received(connection_id: &str, size: usize, message: &ClientMessage) {
+    if RECORDER.get().is_some() {
+        capture(connection_id, Captured::Received { size, message: message.clone() });
+    }
+}
+
+pub fn sent(connection_id: &str, size: usize, message: &ServerMessage) {
+    if RECORDER.get().is_some() {
+        let message_type = serde_json::to_value(message)
+            .ok()
+            .and_then(|value| value.get("type").and_then(|t| t.as_str()).map(str::to_string))
+            .unwrap_or_default();
+        capture(connection_id, Captured::Sent { message_type, size });
+    }
+}
+
+pub fn closed(connection_id: &str) {
+    capture(connection_id, Captured::Closed);
+}
+
+// stand-ins for connection and client ids, in order of appearance
+#[derive(Default)]
+struct Aliases {
+    sessions: HashMap<String, u64>,
+    next_session: u64,
+    clients: HashMap<String, String>,
+}
+
+impl Aliases {
+    fn session(&mut self, connection_id: &str) -> u64 {
+        if let Some(session) = self.sessions.get(connection_id) {
+            return *session;
+        }
+        self.next_session += 1;
+        self.sessions.insert(connection_id.to_string(), self.next_session);
+        self.next_session
+    }
+
+    fn client(&mut self, client_id: &str) -> String {
+        let next = self.clients.len() + 1;
+        self.clients.entry(client_id.to_string()).or_insert_with(|| format!("client-{}", next)).clone()
+    }
+
+    // every id replaced by its alias, every SDP body, candidate, key and free-text field by its length
+    fn anonymize(&mut self, message: ClientMessage) -> ClientMessage {
+        match message {
+            ClientMessage::UpdatePosition(pos) => {
+                ClientMessage::UpdatePosition(ClientPosition { client_id: self.client(&pos.client_id), ..pos })
+            }
+            ClientMessage::UpdatePositionFloat(pos) => {
+                ClientMessage::UpdatePositionFloat(ClientPositionFloat { client_id: self.client(&pos.client_id), ..pos })
+            }
+            ClientMessage::SendOffer { target_id, offer } => {
+                ClientMessage::SendOffer { target_id: self.client(&target_id), offer: redacted(&offer) }
+            }
+            ClientMessage::SendAnswer { target_id, answer } => {
+                ClientMessage::SendAnswer { target_id: self.client(&target_id), answer: redacted(&answer) }
+            }
+            ClientMessage::SendIceCandidate { target_id, candidate } => {
+                ClientMessage::SendIceCandidate { target_id: self.client(&target_id), candidate: redacted(&candidate) }
+            }
+            ClientMessage::ReportPeer { target_id, reason } => {
+                ClientMessage::ReportPeer { target_id: self.client(&target_id), reason: redacted(&reason) }
+            }
+            ClientMessage::RequestReintroduction { peer_id } => ClientMessage::RequestReintroduction { peer_id: self.client(&peer_id) },
+            ClientMessage::ReportIceFailure { peer_id } => ClientMessage::ReportIceFailure { peer_id: self.client(&peer_id) },
+            ClientMessage::ReportPeerConnected { peer_id } => ClientMessage::ReportPeerConnected { peer_id: self.client(&peer_id) },
+            ClientMessage::SetMuteState { muted_peer_ids } => {
+                ClientMessage::SetMuteState { muted_peer_ids: muted_peer_ids.iter().map(|id| self.client(id)).collect() }
+            }
+            ClientMessage::SetTags(tags) => {
+                ClientMessage::SetTags(tags.into_iter().map(|(key, value)| (key, redacted(&value))).collect())
+            }
+            ClientMessage::SetIdentityKey { public_key } => ClientMessage::SetIdentityKey { public_key: redacted(&public_key) },
+            ClientMessage::Signed { nonce, mac, message } => {
+                ClientMessage::Signed { nonce, mac: redacted(&mac), message: redacted(&message) }
+            }
+            message @ (ClientMessage::RequestPeerRefresh
+            | ClientMessage::SetPreferences(_)
+            | ClientMessage::GetPopulation
+            | ClientMessage::ListServers
+            | ClientMessage::GetServerStats
+            | ClientMessage::ReportNatType { .. }
+            | ClientMessage::ReportNetworkQuality { .. }
+            | ClientMessage::Pong { .. }
+            | ClientMessage::RequestUdpSession
+            | ClientMessage::RequestEchoPeer
+            | ClientMessage::SetVehicle { .. }
+            | ClientMessage::SetElevatedConsent(_)
+            | ClientMessage::RequestMessageSigning
+            | ClientMessage::Disconnect) => message,
+        }
+    }
+}
+
+fn redacted(text: &str) -> String {
+    format!("<redacted {} bytes>", text.len())
+}
+
+async fn write_loop(config: ReplayRecordingConfig, mut rx: mpsc::Receiver<(Instant, String, Captured)>) {
+    info!("Recording signaling sessions for replay to {}", config.path);
+    let started = RECORDER.get().map_or_else(Instant::now, |recorder| recorder.started);
+    let mut aliases = Aliases::default();
+    let mut batch = Vec::new();
+    while rx.recv_many(&mut batch, QUEUE_LEN).await > 0 {
+        let mut lines = String::new();
+        for (at, connection_id, captured) in batch.drain(..) {
+            let session = aliases.session(&connection_id);
+            let event = match captured {
+                Captured::Opened => ReplayEvent::Opened { session },
+                Captured::Received { size, message } => {
+                    ReplayEvent::Received { session, size, message: aliases.anonymize(message) }
+                }
+                Captured::Sent { message_type, size } => ReplayEvent::Sent { session, message_type, size },
+                Captured::Closed => {
+                    aliases.sessions.remove(&connection_id);
+                    ReplayEvent::Closed { session }
+                }
+            };
+            let record = ReplayRecord { t_ms: at.duration_since(started).as_millis() as u64, event };
+            if let Ok(line) = serde_json::to_string(&record) {
+                lines.push_str(&line);
+                lines.push('\n');
+            }
+        }
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()));
+        if let Err(e) = result {
+            error!("Failed to write replay recording to {}: {}", config.path, e);
+        }
+    }
+}
+
+// `prox-chat-server replay <file> <url> [--speed <factor>]`: opens one connection per recorded
+// session and sends what its client sent, at the recorded times (divided by the speed factor).
+// replies are logged as they arrive, to compare with the recording's Sent lines
+pub async fn run(args: &[String]) {
+    let (path, url, speed) = match args {
+        [path, url] => (path, url, 1.0),
+        [path, url, flag, speed] if flag == "--speed" => match speed.parse::<f64>() {
+            Ok(speed) if speed > 0.0 => (path, url, speed),
+            _ => usage(),
+        },
+        _ => usage(),
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    if let Err(e) = play(path, url, speed).await {
+        error!("Replay failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+// a recorded session: when it connected, and what its client sent when (None closes it)
+#[derive(Default)]
+struct Script {
+    opened_ms: u64,
+    steps: Vec<(u64, Option<ClientMessage>)>,
+}
+
+async fn play(path: &str, url: &str, speed: f64) -> Result<(), String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut scripts: BTreeMap<u64, Script> = BTreeMap::new();
+    for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ReplayRecord =
+            serde_json::from_str(&line).map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+        match record.event {
+            ReplayEvent::Opened { session } => scripts.entry(session).or_default().opened_ms = record.t_ms,
+            ReplayEvent::Received { session, message, .. } => {
+                scripts.entry(session).or_default().steps.push((record.t_ms, Some(message)))
+            }
+            ReplayEvent::Closed { session } => scripts.entry(session).or_default().steps.push((record.t_ms, None)),
+            ReplayEvent::Sent { .. } => {}
+        }
+    }
+    info!("Replaying {} sessions from {} against {} at {}x", scripts.len(), path, url, speed);
+
+    let started = Instant::now();
+    let at = move |t_ms: u64| started + Duration::from_secs_f64(t_ms as f64 / 1000.0 / speed);
+    let sessions = scripts.into_iter().map(|(session, script)| {
+        let url = url.to_string();
+        tokio::spawn(async move {
+            time::sleep_until(at(script.opened_ms)).await;
+            if let Err(e) = play_session(session, &url, script, at, started).await {
+                warn!("Session {}: {}", session, e);
+            }
+        })
+    });
+    futures_util::future::join_all(sessions).await;
+    info!("Replay finished after {:.1}s", started.elapsed().as_secs_f64());
+    Ok(())
+}
+
+async fn play_session(
+    session: u64,
+    url: &str,
+    script: Script,
+    at: impl Fn(u64) -> Instant,
+    started: Instant,
+) -> Result<(), String> {
+    let mut request = url.into_client_request().map_err(|e| e.to_string())?;
+    request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL_V1));
+    let (stream, _) = tokio_tungstenite::connect_async(request).await.map_err(|e| format!("connect failed: {}", e))?;
+    let (mut frames_out, mut frames_in) = stream.split();
+    let replies = tokio::spawn(async move {
+        while let Some(Ok(frame)) = frames_in.next().await {
+            if let Message::Text(text) = frame {
+                let message_type = serde_json::from_str::<serde_json::Value>(&text)
+                    .ok()
+                    .and_then(|value| value.get("type").and_then(|t| t.as_str()).map(str::to_string))
+                    .unwrap_or_default();
+                info!("[{:>8}ms] session {} <- {} ({} bytes)", started.elapsed().as_millis(), session, message_type, text.len());
+            }
+        }
+    });
+    for (t_ms, message) in script.steps {
+        time::sleep_until(at(t_ms)).await;
+        let Some(message) = message else {
+            break;
+        };
+        let text = serde_json::to_string(&ClientEnvelope::from(message)).map_err(|e| e.to_string())?;
+        frames_out.send(Message::text(text)).await.map_err(|e| format!("send failed: {}", e))?;
+    }
+    let _ = frames_out.close().await;
+    let _ = replies.await;
+    Ok(())
+}
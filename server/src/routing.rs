@@ -0,0 +1,72 @@
+// who is connected and how to reach them: connection ids to their outbound queues, and registered
+// client ids to the connection they're on. kept out of ServerState's RwLock so signaling relay
+// never waits behind a position update holding the write lock. routes are still changed with the
+// state write lock held, to keep them in step with the per-client maps
+use crate::outbound;
+use dashmap::DashMap;
+
+#[derive(Default)]
+pub struct RoutingTable {
+    connections: DashMap<String, outbound::Sender>,
+    routes: DashMap<String, String>,
+}
+
+impl RoutingTable {
+    pub fn add_connection(&self, connection_id: &str, tx: outbound::Sender) {
+        self.connections.insert(connection_id.to_string(), tx);
+    }
+
+    pub fn remove_connection(&self, connection_id: &str) -> Option<outbound::Sender> {
+        self.connections.remove(connection_id).map(|(_, tx)| tx)
+    }
+
+    pub fn has_connection(&self, connection_id: &str) -> bool {
+        self.connections.contains_key(connection_id)
+    }
+
+    pub fn connection(&self, connection_id: &str) -> Option<outbound::Sender> {
+        self.connections.get(connection_id).map(|tx| tx.clone())
+    }
+
+    // every connection's queue, registered or not
+    pub fn senders(&self) -> Vec<outbound::Sender> {
+        self.connections.iter().map(|entry| entry.value().clone()).collect()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn route(&self, client_id: &str, connection_id: &str) {
+        self.routes.insert(client_id.to_string(), connection_id.to_string());
+    }
+
+    pub fn unroute(&self, client_id: &str) -> Option<String> {
+        self.routes.remove(client_id).map(|(_, connection_id)| connection_id)
+    }
+
+    // drops the route only if it still points at this connection (a reconnect may have replaced it)
+    pub fn unroute_connection(&self, client_id: &str, connection_id: &str) -> bool {
+        self.routes.remove_if(client_id, |_, routed| routed == connection_id).is_some()
+    }
+
+    pub fn connection_id(&self, client_id: &str) -> Option<String> {
+        self.routes.get(client_id).map(|connection_id| connection_id.clone())
+    }
+
+    // the registered client's outbound queue. during a disconnect the route can briefly outlive
+    // the connection, which reads as not connected
+    pub fn sender(&self, client_id: &str) -> Option<outbound::Sender> {
+        let connection_id = self.connection_id(client_id)?;
+        self.connection(&connection_id)
+    }
+
+    // the client registered on this connection, if any
+    pub fn client_id(&self, connection_id: &str) -> Option<String> {
+        self.routes.iter().find(|route| route.value() == connection_id).map(|route| route.key().clone())
+    }
+
+    pub fn route_count(&self) -> usize {
+        self.routes.len()
+    }
+}
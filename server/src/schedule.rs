@@ -0,0 +1,108 @@
+use proxchat_protocol::ClientPosition;
+use serde::{Deserialize, Serialize};
+
+// pairing distances in tiles: introduce within `introduction`, keep until beyond `disconnection`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PairingRanges {
+    pub introduction: f32,
+    pub disconnection: f32,
+}
+
+impl PairingRanges {
+    pub fn scaled(self, factor: f32) -> Self {
+        PairingRanges { introduction: self.introduction * factor, disconnection: self.disconnection * factor }
+    }
+}
+
+// inclusive tile bounds within a map
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Area {
+    pub x_min: i64,
+    pub y_min: i64,
+    pub x_max: i64,
+    pub y_max: i64,
+}
+
+impl Area {
+    pub fn is_inverted(&self) -> bool {
+        self.x_min > self.x_max || self.y_min > self.y_max
+    }
+
+    pub fn contains(&self, x: i64, y: i64) -> bool {
+        (self.x_min..=self.x_max).contains(&x) && (self.y_min..=self.y_max).contains(&y)
+    }
+}
+
+// a community gathering scheduled through the admin API: while it runs, clients inside its
+// map (or area of the map) pair with its ranges instead of the defaults and/or are capped at
+// max_peers new introductions. stored in the database so it survives restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledEvent {
+    // assigned by the database
+    #[serde(skip_deserializing)]
+    pub id: i64,
+    #[serde(default)]
+    pub name: String,
+    pub game_id: i32,
+    pub map_id: i32,
+    // None covers every channel of the map
+    #[serde(default)]
+    pub channel: Option<i32>,
+    // None covers the whole map
+    #[serde(default)]
+    pub area: Option<Area>,
+    // unix seconds
+    pub starts_at: i64,
+    pub duration_secs: i64,
+    // unset fields keep the server defaults. with only introduction_range set, the
+    // disconnection range keeps the default gap above it
+    #[serde(default)]
+    pub introduction_range: Option<f32>,
+    #[serde(default)]
+    pub disconnection_range: Option<f32>,
+    // at most this many peers per client; existing pairs are kept, only new introductions stop
+    #[serde(default)]
+    pub max_peers: Option<usize>,
+}
+
+impl ScheduledEvent {
+    pub fn validate(&self, defaults: PairingRanges) -> Result<(), String> {
+        if self.duration_secs <= 0 {
+            return Err("duration_secs must be positive".to_string());
+        }
+        if self.area.is_some_and(|area| area.is_inverted()) {
+            return Err("area bounds are inverted (x_min > x_max or y_min > y_max)".to_string());
+        }
+        if self.introduction_range.is_none() && self.disconnection_range.is_none() && self.max_peers.is_none() {
+            return Err("event overrides nothing: set introduction_range, disconnection_range or max_peers".to_string());
+        }
+        let ranges = self.ranges(defaults);
+        if !(ranges.introduction > 0.0 && ranges.disconnection >= ranges.introduction) {
+            return Err("ranges must be positive with disconnection_range >= introduction_range".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn ends_at(&self) -> i64 {
+        self.starts_at.saturating_add(self.duration_secs)
+    }
+
+    pub fn is_active(&self, now: i64) -> bool {
+        self.starts_at <= now && now < self.ends_at()
+    }
+
+    pub fn contains(&self, pos: &ClientPosition) -> bool {
+        pos.game_id == self.game_id
+            && pos.map_id == self.map_id
+            && self.channel.is_none_or(|channel| channel == pos.channel)
+            && self.area.is_none_or(|area| area.contains(pos.x, pos.y))
+    }
+
+    pub fn ranges(&self, defaults: PairingRanges) -> PairingRanges {
+        let introduction = self.introduction_range.unwrap_or(defaults.introduction);
+        let disconnection = self
+            .disconnection_range
+            .unwrap_or(introduction + (defaults.disconnection - defaults.introduction));
+        PairingRanges { introduction, disconnection }
+    }
+}
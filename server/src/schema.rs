@@ -0,0 +1,128 @@
+// `prox-chat-server schema [--typescript]`: the wire types of proxchat-protocol as one JSON Schema
+// document (draft 2020-12, every type under $defs), or as TypeScript declarations generated from
+// that same schema. the C# client and web tools generate their bindings from this output, so the
+// protocol crate stays the only place the message shapes are written down
+use proxchat_protocol::{ClientEnvelope, ClientMessage, ServerEnvelope, ServerMessage, PROTOCOL_VERSION};
+use schemars::generate::SchemaSettings;
+use serde_json::{json, Map, Value};
+
+const USAGE: &str = "usage: prox-chat-server schema [--typescript]";
+
+pub fn run(args: &[String]) {
+    let schema = json_schema();
+    match args {
+        [] => println!("{}", serde_json::to_string_pretty(&schema).expect("schema serializes")),
+        [flag] if flag == "--typescript" => print!("{}", typescript(&schema)),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+pub fn json_schema() -> Value {
+    let mut generator = SchemaSettings::draft2020_12().into_generator();
+    generator.subschema_for::<ClientEnvelope>();
+    generator.subschema_for::<ServerEnvelope>();
+    generator.subschema_for::<ClientMessage>();
+    generator.subschema_for::<ServerMessage>();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": format!("proxchat protocol v{}", PROTOCOL_VERSION),
+        "$defs": generator.take_definitions(true),
+    })
+}
+
+fn typescript(schema: &Value) -> String {
+    let mut out = format!(
+        "// generated by `prox-chat-server schema --typescript` (proxchat protocol v{}); do not edit\n\nexport const PROTOCOL_VERSION = {};\n",
+        PROTOCOL_VERSION, PROTOCOL_VERSION
+    );
+    let empty = Map::new();
+    let definitions = schema["$defs"].as_object().unwrap_or(&empty);
+    for (name, definition) in definitions {
+        out.push_str(&format!("\nexport type {} = {};\n", name, ts_type(definition, 0)));
+    }
+    out
+}
+
+// the TypeScript for one schema; only the constructs schemars produces for serde types are handled,
+// anything else becomes unknown
+fn ts_type(schema: &Value, depth: usize) -> String {
+    let Some(schema) = schema.as_object() else {
+        return "unknown".to_string();
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        return reference.rsplit('/').next().unwrap_or(reference).to_string();
+    }
+    if let Some(value) = schema.get("const") {
+        return value.to_string();
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        return union(values.iter().map(Value::to_string).collect());
+    }
+    // a flattened tagged enum: the variants, each combined with the fields next to them
+    if let Some(variants) = schema.get("oneOf").or_else(|| schema.get("anyOf")).and_then(Value::as_array) {
+        let variants = union(variants.iter().map(|variant| ts_type(variant, depth)).collect());
+        return match schema.get("properties") {
+            Some(_) => format!("{} & ({})", object(schema, depth), variants),
+            None => variants,
+        };
+    }
+    match schema.get("type") {
+        Some(Value::String(kind)) => primitive(kind, schema, depth),
+        Some(Value::Array(kinds)) => union(kinds.iter().filter_map(Value::as_str).map(|kind| primitive(kind, schema, depth)).collect()),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn primitive(kind: &str, schema: &Map<String, Value>, depth: usize) -> String {
+    match kind {
+        "string" => "string".to_string(),
+        // 64-bit values (Ping nonces) lose precision past 2^53 as a JS number
+        "integer" | "number" => "number".to_string(),
+        "boolean" => "boolean".to_string(),
+        "null" => "null".to_string(),
+        "array" => {
+            let item = schema.get("items").map_or_else(|| "unknown".to_string(), |items| ts_type(items, depth));
+            if item.contains(' ') {
+                format!("({})[]", item)
+            } else {
+                format!("{}[]", item)
+            }
+        }
+        "object" => object(schema, depth),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn object(schema: &Map<String, Value>, depth: usize) -> String {
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let Some(properties) = properties.filter(|properties| !properties.is_empty()) else {
+        // a map; serde only produces string keys
+        return match schema.get("additionalProperties") {
+            Some(Value::Object(values)) => format!("Record<string, {}>", ts_type(&Value::Object(values.clone()), depth)),
+            _ => "Record<string, unknown>".to_string(),
+        };
+    };
+    let required: Vec<&str> = schema.get("required").and_then(Value::as_array).map_or_else(Vec::new, |required| {
+        required.iter().filter_map(Value::as_str).collect()
+    });
+    let indent = "  ".repeat(depth + 1);
+    let mut out = "{\n".to_string();
+    for (name, property) in properties {
+        let optional = if required.contains(&name.as_str()) { "" } else { "?" };
+        out.push_str(&format!("{}{}{}: {};\n", indent, name, optional, ts_type(property, depth + 1)));
+    }
+    out.push_str(&"  ".repeat(depth));
+    out.push('}');
+    out
+}
+
+fn union(members: Vec<String>) -> String {
+    if members.is_empty() {
+        "never".to_string()
+    } else {
+        members.join(" | ")
+    }
+}
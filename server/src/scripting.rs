@@ -0,0 +1,292 @@
+// operator policy in Rhai scripts (the scripting feature). every *.rhai file in scripts_dir may
+// define any of
+//   on_register(client)  client_id, ip, game_id, map_id, channel, x, y, and events: the names of
+//                        the live events around the client
+//   on_report(report)    reporter_id, reporter_ip, target_id, reason, and reporters: how many
+//                        different clients have reported the target so far
+//   on_admin(command)    method, path, status and body of a change made through the admin API
+// and act through kick(client_id), ban(client_id, reason, secs), ban_ip(ip, reason, secs) (secs
+// <= 0 bans for good), notify(client_id, text) and, in on_register, reject(reason). now() is the
+// unix time. scripts run in file name order and are reloaded when they change; a hook that fails
+// or runs past max_operations is logged and the next script carries on
+use crate::db::Database;
+use crate::{admin, metrics, ServerState};
+use log::{info, warn};
+use proxchat_protocol::ServerMessage;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+#[cfg(feature = "scripting")]
+use {
+    crate::config::ScriptingConfig,
+    log::error,
+    std::cell::RefCell,
+    std::collections::BTreeMap,
+    std::path::PathBuf,
+    std::sync::{OnceLock, PoisonError},
+    std::time::SystemTime,
+};
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub enum Hook {
+    Register,
+    Report,
+    Admin,
+}
+
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+impl Hook {
+    fn function(self) -> &'static str {
+        match self {
+            Hook::Register => "on_register",
+            Hook::Report => "on_report",
+            Hook::Admin => "on_admin",
+        }
+    }
+}
+
+// what a script asked for, carried out by apply once the hook has returned
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub enum Action {
+    Kick { client_id: String },
+    Ban { client_id: Option<String>, ip: Option<String>, reason: String, duration_secs: Option<i64> },
+    Notify { client_id: String, text: String },
+    // refuse the registration on_register was asked about
+    Reject { reason: String },
+}
+
+impl Action {
+    // why a registering client is turned away, if this refuses (or bans) it
+    pub fn refusal(&self, client_id: &str, ip: &str) -> Option<&str> {
+        match self {
+            Action::Reject { reason } => Some(reason),
+            Action::Ban { client_id: banned_id, ip: banned_ip, reason, .. }
+                if banned_id.as_deref() == Some(client_id) || banned_ip.as_deref() == Some(ip) =>
+            {
+                Some(reason)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Registration {
+    pub client_id: String,
+    pub ip: String,
+    pub game_id: i32,
+    pub map_id: i32,
+    pub channel: i32,
+    pub x: i64,
+    pub y: i64,
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub reporter_id: String,
+    pub reporter_ip: String,
+    pub target_id: String,
+    pub reason: String,
+    pub reporters: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdminCommand {
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    // the request's JSON body, () without one
+    pub body: Value,
+}
+
+#[cfg(feature = "scripting")]
+struct Scripts {
+    engine: rhai::Engine,
+    dir: PathBuf,
+    loaded: std::sync::RwLock<BTreeMap<PathBuf, LoadedScript>>,
+}
+
+// a file's modification time when last seen, and the script compiled from it. a file that
+// stopped compiling keeps its previous version; one that never compiled has none
+#[cfg(feature = "scripting")]
+struct LoadedScript {
+    modified: SystemTime,
+    ast: Option<Arc<rhai::AST>>,
+}
+
+#[cfg(feature = "scripting")]
+static SCRIPTS: OnceLock<Scripts> = OnceLock::new();
+
+#[cfg(feature = "scripting")]
+thread_local! {
+    // actions asked for by the hook running on this thread; a hook runs start to finish on one
+    static REQUESTED: RefCell<Vec<Action>> = const { RefCell::new(Vec::new()) };
+}
+
+#[cfg(feature = "scripting")]
+fn request(action: Action) {
+    REQUESTED.with_borrow_mut(|requested| requested.push(action));
+}
+
+#[cfg(feature = "scripting")]
+fn ban_duration(secs: i64) -> Option<i64> {
+    (secs > 0).then_some(secs)
+}
+
+#[cfg(feature = "scripting")]
+pub fn init(config: &ScriptingConfig) -> Result<(), String> {
+    let dir = PathBuf::from(&config.scripts_dir);
+    if !dir.is_dir() {
+        return Err(format!("scripts_dir {} is not a directory", dir.display()));
+    }
+    let mut engine = rhai::Engine::new();
+    engine.set_max_operations(config.max_operations);
+    engine.on_print(|text| info!("script: {}", text));
+    engine.register_fn("kick", |client_id: &str| request(Action::Kick { client_id: client_id.to_string() }));
+    engine.register_fn("ban", |client_id: &str, reason: &str, secs: i64| {
+        request(Action::Ban { client_id: Some(client_id.to_string()), ip: None, reason: reason.to_string(), duration_secs: ban_duration(secs) })
+    });
+    engine.register_fn("ban_ip", |ip: &str, reason: &str, secs: i64| {
+        request(Action::Ban { client_id: None, ip: Some(ip.to_string()), reason: reason.to_string(), duration_secs: ban_duration(secs) })
+    });
+    engine.register_fn("notify", |client_id: &str, text: &str| {
+        request(Action::Notify { client_id: client_id.to_string(), text: text.to_string() })
+    });
+    engine.register_fn("reject", |reason: &str| request(Action::Reject { reason: reason.to_string() }));
+    engine.register_fn("now", crate::db::unix_now);
+
+    let scripts = Scripts { engine, dir, loaded: Default::default() };
+    scripts.reload();
+    let _ = SCRIPTS.set(scripts);
+
+    let interval = std::time::Duration::from_secs(config.reload_interval_secs);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let _ = tokio::task::spawn_blocking(|| SCRIPTS.get().map(Scripts::reload)).await;
+        }
+    });
+    Ok(())
+}
+
+#[cfg(feature = "scripting")]
+impl Scripts {
+    fn reload(&self) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read scripts_dir {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+        let present: BTreeMap<PathBuf, SystemTime> = entries
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "rhai"))
+            .filter_map(|entry| Some((entry.path(), entry.metadata().and_then(|metadata| metadata.modified()).ok()?)))
+            .collect();
+
+        let mut loaded = self.loaded.write().unwrap_or_else(PoisonError::into_inner);
+        loaded.retain(|path, _| {
+            let kept = present.contains_key(path);
+            if !kept {
+                info!("Script {} removed", path.display());
+            }
+            kept
+        });
+        for (path, modified) in present {
+            if loaded.get(&path).is_some_and(|script| script.modified == modified) {
+                continue;
+            }
+            match self.engine.compile_file(path.clone()) {
+                Ok(ast) => {
+                    info!("Script {} loaded", path.display());
+                    loaded.insert(path, LoadedScript { modified, ast: Some(Arc::new(ast)) });
+                }
+                Err(e) => {
+                    error!("Script {} doesn't compile, {}: {}", path.display(), if loaded.contains_key(&path) { "keeping the previous version" } else { "skipping it" }, e);
+                    loaded.entry(path).or_insert(LoadedScript { modified, ast: None }).modified = modified;
+                }
+            }
+        }
+    }
+
+    fn run(&self, hook: Hook, payload: &impl Serialize) -> Vec<Action> {
+        let payload = match rhai::serde::to_dynamic(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to hand {} its argument: {}", hook.function(), e);
+                return Vec::new();
+            }
+        };
+        // copied out so a reload doesn't wait for the hooks
+        let scripts: Vec<(PathBuf, Arc<rhai::AST>)> = self
+            .loaded
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(|(path, script)| Some((path.clone(), Arc::clone(script.ast.as_ref()?))))
+            .collect();
+
+        REQUESTED.with_borrow_mut(Vec::clear);
+        for (path, ast) in scripts {
+            if !ast.iter_functions().any(|function| function.name == hook.function() && function.params.len() == 1) {
+                continue;
+            }
+            let options = rhai::CallFnOptions::new().eval_ast(false);
+            let result = self.engine.call_fn_with_options::<rhai::Dynamic>(options, &mut rhai::Scope::new(), &ast, hook.function(), (payload.clone(),));
+            if let Err(e) = result {
+                metrics::SCRIPT_ERRORS.inc();
+                warn!("Script {} failed in {}: {}", path.display(), hook.function(), e);
+            }
+        }
+        REQUESTED.with_borrow_mut(std::mem::take)
+    }
+}
+
+pub fn enabled() -> bool {
+    #[cfg(feature = "scripting")]
+    return SCRIPTS.get().is_some();
+    #[cfg(not(feature = "scripting"))]
+    false
+}
+
+// runs the hook in every script defining it and returns what they asked for; nothing without scripts
+pub fn run(hook: Hook, payload: &impl Serialize) -> Vec<Action> {
+    #[cfg(feature = "scripting")]
+    if let Some(scripts) = SCRIPTS.get() {
+        return scripts.run(hook, payload);
+    }
+    let _ = (hook, payload);
+    Vec::new()
+}
+
+// carries out what scripts asked for; rejections are the caller's to act on
+pub async fn apply(actions: Vec<Action>, state: &Arc<RwLock<ServerState>>, db: &Arc<Database>) {
+    for action in actions {
+        match action {
+            Action::Kick { client_id } => {
+                if metrics::timed_read(state, "script").await.kick(&client_id).is_some() {
+                    info!("Script kicked {}", client_id);
+                }
+            }
+            Action::Ban { client_id, ip, reason, duration_secs } => {
+                if let Err(e) = admin::ban_and_kick(state, db, client_id.as_deref(), ip.as_deref(), &reason, duration_secs).await {
+                    warn!("Script ban of client {:?} ip {:?} failed: {}", client_id, ip, e);
+                }
+            }
+            Action::Notify { client_id, text } => {
+                let sender = metrics::timed_read(state, "script").await.routed_sender(&client_id);
+                if let Some(tx) = sender {
+                    let _ = tx.send(ServerMessage::Notice { text }).await;
+                }
+            }
+            Action::Reject { .. } => {}
+        }
+    }
+}
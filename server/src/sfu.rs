@@ -0,0 +1,404 @@
+// the built-in SFU for big gatherings (config sfu). clients inside one of its event areas don't
+// pair with each other: each is introduced to the area's SFU peer instead, sends its audio there
+// once and gets everyone else's forwarded back, one track per speaker. that keeps a 100+ person
+// event at one upstream per client where a mesh would need a hundred. clients outside the area
+// still pair with those inside by proximity, as usual.
+// one task per area with members, started with its first one and ended with its last
+use crate::config::{SfuArea, SfuConfig};
+use crate::{metrics, outbound};
+use log::info;
+use proxchat_protocol::ClientPosition;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+// an area's SFU peer is this followed by the area's name. '!' sorts before any GUID, so clients
+// that only offer to peers with smaller ids take the initiator role with it, as with the echo peer
+pub const SFU_PEER_PREFIX: &str = "!sfu:";
+
+// signaling a client sends to its area's SFU peer
+#[cfg_attr(not(feature = "sfu"), allow(dead_code))]
+pub enum Signal {
+    Offer(String),
+    // to an offer the SFU sent for new or departed speakers
+    Answer(String),
+    IceCandidate(String),
+}
+
+#[cfg_attr(not(feature = "sfu"), allow(dead_code))]
+enum Command {
+    Join { client_id: String, client_tx: outbound::Sender },
+    Leave { client_id: String },
+    Signal { client_id: String, signal: Signal },
+}
+
+// dropping it ends the area's task and closes every connection to it
+struct Room {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Room {
+    fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+}
+
+pub struct Sfu {
+    areas: Vec<SfuArea>,
+    ice_servers: Vec<String>,
+    rooms: HashMap<String, Room>,
+    // the area of every client inside one
+    members: HashMap<String, String>,
+}
+
+impl Sfu {
+    // without the sfu feature the areas are ignored, and their clients mesh like everyone else
+    pub fn new(config: Option<&SfuConfig>) -> Self {
+        let config = config.filter(|_| cfg!(feature = "sfu")).cloned().unwrap_or_default();
+        Sfu { areas: config.areas, ice_servers: config.ice_servers, rooms: HashMap::new(), members: HashMap::new() }
+    }
+
+    // moves a client that sent a position in or out of the areas. true when its area changed,
+    // which changes its nearby list whether or not any pair did
+    pub fn place(&mut self, pos: &ClientPosition, client_tx: &outbound::Sender) -> bool {
+        let area = self.areas.iter().find(|area| area.contains(pos)).map(|area| area.name.clone());
+        if self.members.get(&pos.client_id) == area.as_ref() {
+            return false;
+        }
+        self.remove(&pos.client_id);
+        let Some(area) = area else {
+            return true;
+        };
+        info!("Client {} entered sfu area {}", pos.client_id, area);
+        let room = self.rooms.entry(area.clone()).or_insert_with(|| start(&area, self.ice_servers.clone()));
+        room.send(Command::Join { client_id: pos.client_id.clone(), client_tx: client_tx.clone() });
+        self.members.insert(pos.client_id.clone(), area);
+        metrics::SFU_CLIENTS.set(self.members.len() as i64);
+        true
+    }
+
+    pub fn remove(&mut self, client_id: &str) {
+        let Some(area) = self.members.remove(client_id) else {
+            return;
+        };
+        if self.members.values().any(|other| *other == area) {
+            if let Some(room) = self.rooms.get(&area) {
+                room.send(Command::Leave { client_id: client_id.to_string() });
+            }
+        } else {
+            self.rooms.remove(&area);
+        }
+        metrics::SFU_CLIENTS.set(self.members.len() as i64);
+    }
+
+    // the SFU peer a client in an area is introduced to
+    pub fn peer_id(&self, client_id: &str) -> Option<String> {
+        self.members.get(client_id).map(|area| format!("{}{}", SFU_PEER_PREFIX, area))
+    }
+
+    // both in one area: they hear each other through the SFU and don't pair
+    pub fn together(&self, a: &str, b: &str) -> bool {
+        self.members.get(a).is_some_and(|area| self.members.get(b) == Some(area))
+    }
+
+    // hands signaling for `target_id` to the client's area; false when that isn't its SFU peer
+    pub fn signal(&self, client_id: &str, target_id: &str, signal: Signal) -> bool {
+        let Some(room) = self.members.get(client_id).filter(|area| target_id.strip_prefix(SFU_PEER_PREFIX) == Some(area.as_str())).and_then(|area| self.rooms.get(area)) else {
+            return false;
+        };
+        room.send(Command::Signal { client_id: client_id.to_string(), signal });
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+}
+
+#[cfg(feature = "sfu")]
+fn start(area: &str, ice_servers: Vec<String>) -> Room {
+    let (commands, commands_rx) = mpsc::unbounded_channel();
+    tokio::spawn(rtc::run(area.to_string(), ice_servers, commands_rx));
+    Room { commands }
+}
+
+// unreachable: Sfu::new keeps no areas without the feature
+#[cfg(not(feature = "sfu"))]
+fn start(area: &str, _ice_servers: Vec<String>) -> Room {
+    log::warn!("sfu area {} has a member, but this build lacks the sfu feature", area);
+    let (commands, _) = mpsc::unbounded_channel();
+    Room { commands }
+}
+
+// the WebRTC side. every member gets one peer connection carrying its audio up and a track per
+// other member's audio down; a member joining or leaving adds or removes a track on everyone
+// else's connection, and the SFU renegotiates those with offers of its own
+#[cfg(feature = "sfu")]
+mod rtc {
+    use super::{Command, Signal, SFU_PEER_PREFIX};
+    use crate::{outbound, ServerMessage};
+    use log::{info, warn};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+    use webrtc::api::interceptor_registry::register_default_interceptors;
+    use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS};
+    use webrtc::api::APIBuilder;
+    use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+    use webrtc::ice_transport::ice_server::RTCIceServer;
+    use webrtc::interceptor::registry::Registry;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+    use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+    use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+    use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+    use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+
+    // the tracks each speaker's packets go out on, by speaker then listener. read by the
+    // forwarding tasks for every packet, so only ever locked briefly
+    type Forwards = Arc<Mutex<HashMap<String, HashMap<String, Arc<TrackLocalStaticRTP>>>>>;
+
+    struct Member {
+        client_tx: outbound::Sender,
+        // None until the client's first offer
+        connection: Option<Arc<RTCPeerConnection>>,
+        // what carries each other member's audio to this one
+        senders: HashMap<String, Arc<RTCRtpSender>>,
+    }
+
+    // handles the area's commands in order, so candidates sent while an answer is being built
+    // simply wait in the channel
+    pub async fn run(area: String, ice_servers: Vec<String>, mut commands: mpsc::UnboundedReceiver<Command>) {
+        let peer_id = format!("{}{}", SFU_PEER_PREFIX, area);
+        let forwards: Forwards = Arc::default();
+        let mut members: HashMap<String, Member> = HashMap::new();
+        while let Some(command) = commands.recv().await {
+            match command {
+                Command::Join { client_id, client_tx } => {
+                    members.insert(client_id, Member { client_tx, connection: None, senders: HashMap::new() });
+                }
+                Command::Leave { client_id } => {
+                    if let Some(connection) = members.remove(&client_id).and_then(|member| member.connection) {
+                        let _ = connection.close().await;
+                    }
+                    unpublish(&client_id, &mut members, &forwards, &peer_id).await;
+                }
+                Command::Signal { client_id, signal: Signal::Offer(offer) } => {
+                    if let Err(e) = accept_offer(&client_id, &offer, &mut members, &forwards, &ice_servers, &peer_id).await {
+                        warn!("SFU {} failed to answer {}: {}", area, client_id, e);
+                        if let Some(member) = members.get(&client_id) {
+                            let _ = member.client_tx.send(ServerMessage::Error(format!("SFU connection failed: {}", e))).await;
+                        }
+                    }
+                }
+                Command::Signal { client_id, signal: Signal::Answer(answer) } => {
+                    let Some(connection) = members.get(&client_id).and_then(|member| member.connection.as_ref()) else {
+                        continue;
+                    };
+                    let result = match serde_json::from_str::<RTCSessionDescription>(&answer) {
+                        Ok(answer) => connection.set_remote_description(answer).await.map_err(|e| e.to_string()),
+                        Err(e) => Err(format!("invalid answer: {}", e)),
+                    };
+                    if let Err(e) = result {
+                        warn!("SFU {} rejected answer from {}: {}", area, client_id, e);
+                    }
+                }
+                Command::Signal { client_id, signal: Signal::IceCandidate(candidate) } => {
+                    let Some(connection) = members.get(&client_id).and_then(|member| member.connection.as_ref()) else {
+                        continue;
+                    };
+                    match serde_json::from_str::<RTCIceCandidateInit>(&candidate) {
+                        Ok(candidate) => {
+                            if let Err(e) = connection.add_ice_candidate(candidate).await {
+                                warn!("SFU {} rejected ICE candidate from {}: {}", area, client_id, e);
+                            }
+                        }
+                        Err(e) => warn!("Unparseable ICE candidate from {} for SFU {}: {}", client_id, area, e),
+                    }
+                }
+            }
+        }
+        for connection in members.into_values().filter_map(|member| member.connection) {
+            let _ = connection.close().await;
+        }
+        info!("SFU {} closed", area);
+    }
+
+    // a first offer connects the client: it gets a track for every member already connected, and
+    // they get one for it. later offers (ICE restarts, the client's own renegotiation) are simply answered
+    async fn accept_offer(
+        client_id: &str,
+        offer: &str,
+        members: &mut HashMap<String, Member>,
+        forwards: &Forwards,
+        ice_servers: &[String],
+        peer_id: &str,
+    ) -> Result<(), String> {
+        let offer: RTCSessionDescription = serde_json::from_str(offer).map_err(|e| format!("invalid offer: {}", e))?;
+        let Some(member) = members.get(client_id) else {
+            return Ok(());
+        };
+        if let Some(connection) = member.connection.clone() {
+            let answer = answer(&connection, offer).await?;
+            let _ = member.client_tx.send(ServerMessage::ReceiveAnswer { sender_id: peer_id.to_string(), answer, relayed_at_ms: None }).await;
+            return Ok(());
+        }
+
+        let connection = new_connection(ice_servers).await?;
+        forward(client_id, &connection, forwards);
+        let speakers: Vec<String> = members
+            .iter()
+            .filter(|(other_id, other)| other_id.as_str() != client_id && other.connection.is_some())
+            .map(|(other_id, _)| other_id.clone())
+            .collect();
+        let member = members.get_mut(client_id).expect("checked above");
+        // added before the remote description so they pair with the offered sections where they can
+        for speaker in &speakers {
+            subscribe(client_id, member, &connection, speaker, forwards).await?;
+        }
+        let answer = answer(&connection, offer).await?;
+        member.connection = Some(Arc::clone(&connection));
+        let _ = member.client_tx.send(ServerMessage::ReceiveAnswer { sender_id: peer_id.to_string(), answer, relayed_at_ms: None }).await;
+        info!("SFU {} connected {}", peer_id, client_id);
+
+        for listener_id in speakers {
+            let Some(listener) = members.get_mut(&listener_id) else {
+                continue;
+            };
+            let Some(listener_connection) = listener.connection.clone() else {
+                continue;
+            };
+            let result = match subscribe(&listener_id, listener, &listener_connection, client_id, forwards).await {
+                Ok(()) => renegotiate(listener, &listener_connection, peer_id).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("SFU {} failed to add {} to {}: {}", peer_id, client_id, listener_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    // takes a departed member's audio off everyone else's connection
+    async fn unpublish(client_id: &str, members: &mut HashMap<String, Member>, forwards: &Forwards, peer_id: &str) {
+        {
+            let mut forwards = forwards.lock().unwrap();
+            forwards.remove(client_id);
+            for listeners in forwards.values_mut() {
+                listeners.remove(client_id);
+            }
+        }
+        for (listener_id, listener) in members.iter_mut() {
+            let (Some(sender), Some(connection)) = (listener.senders.remove(client_id), listener.connection.clone()) else {
+                continue;
+            };
+            let result = match connection.remove_track(&sender).await {
+                Ok(()) => renegotiate(listener, &connection, peer_id).await,
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = result {
+                warn!("SFU {} failed to remove {} from {}: {}", peer_id, client_id, listener_id, e);
+            }
+        }
+    }
+
+    async fn new_connection(ice_servers: &[String]) -> Result<Arc<RTCPeerConnection>, String> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs().map_err(|e| e.to_string())?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine).map_err(|e| e.to_string())?;
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+        let mut configuration = RTCConfiguration::default();
+        if !ice_servers.is_empty() {
+            configuration.ice_servers = vec![RTCIceServer {
+                urls: ice_servers.to_vec(),
+                ..Default::default()
+            }];
+        }
+        Ok(Arc::new(api.new_peer_connection(configuration).await.map_err(|e| e.to_string())?))
+    }
+
+    // a track on the listener's connection carrying the speaker's audio. its stream id is the
+    // speaker's client id, so clients can tell whose voice is whose
+    async fn subscribe(
+        listener_id: &str,
+        listener: &mut Member,
+        connection: &RTCPeerConnection,
+        speaker_id: &str,
+        forwards: &Forwards,
+    ) -> Result<(), String> {
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: 48000,
+                channels: 2,
+                ..Default::default()
+            },
+            format!("audio-{}", speaker_id),
+            speaker_id.to_owned(),
+        ));
+        let sender = connection
+            .add_track(Arc::clone(&track) as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .map_err(|e| e.to_string())?;
+        // RTCP has to be read for the interceptors to work
+        let rtcp = Arc::clone(&sender);
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; 1500];
+            while rtcp.read(&mut buffer).await.is_ok() {}
+        });
+        listener.senders.insert(speaker_id.to_string(), sender);
+        forwards.lock().unwrap().entry(speaker_id.to_string()).or_default().insert(listener_id.to_string(), track);
+        Ok(())
+    }
+
+    // copies the speaker's audio to every listener's track for it, until the speaker's track ends
+    fn forward(speaker_id: &str, connection: &RTCPeerConnection, forwards: &Forwards) {
+        let speaker_id = speaker_id.to_string();
+        let forwards = Arc::clone(forwards);
+        connection.on_track(Box::new(move |track, _receiver, _transceiver| {
+            let speaker_id = speaker_id.clone();
+            let forwards = Arc::clone(&forwards);
+            Box::pin(async move {
+                if track.kind() != RTPCodecType::Audio {
+                    return;
+                }
+                tokio::spawn(async move {
+                    while let Ok((packet, _)) = track.read_rtp().await {
+                        let listeners: Vec<Arc<TrackLocalStaticRTP>> =
+                            forwards.lock().unwrap().get(&speaker_id).map(|listeners| listeners.values().cloned().collect()).unwrap_or_default();
+                        for listener in listeners {
+                            // a listener that went away just drops the packet
+                            let _ = listener.write_rtp(&packet).await;
+                        }
+                    }
+                });
+            })
+        }));
+    }
+
+    // answers with the candidates gathered in, so the SFU never trickles
+    async fn answer(connection: &RTCPeerConnection, offer: RTCSessionDescription) -> Result<String, String> {
+        connection.set_remote_description(offer).await.map_err(|e| e.to_string())?;
+        let answer = connection.create_answer(None).await.map_err(|e| e.to_string())?;
+        let mut gathering_complete = connection.gathering_complete_promise().await;
+        connection.set_local_description(answer).await.map_err(|e| e.to_string())?;
+        let _ = gathering_complete.recv().await;
+        let answer = connection.local_description().await.ok_or_else(|| "no local description".to_string())?;
+        serde_json::to_string(&answer).map_err(|e| e.to_string())
+    }
+
+    // offers the listener its changed set of tracks; it answers with SendAnswer to the SFU peer
+    async fn renegotiate(listener: &Member, connection: &RTCPeerConnection, peer_id: &str) -> Result<(), String> {
+        let offer = connection.create_offer(None).await.map_err(|e| e.to_string())?;
+        let mut gathering_complete = connection.gathering_complete_promise().await;
+        connection.set_local_description(offer).await.map_err(|e| e.to_string())?;
+        let _ = gathering_complete.recv().await;
+        let offer = connection.local_description().await.ok_or_else(|| "no local description".to_string())?;
+        let offer = serde_json::to_string(&offer).map_err(|e| e.to_string())?;
+        let _ = listener.client_tx.send(ServerMessage::ReceiveOffer { sender_id: peer_id.to_string(), offer, relayed_at_ms: None }).await;
+        Ok(())
+    }
+}
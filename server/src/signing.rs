@@ -0,0 +1,69 @@
+use crate::{udp, ClientEnvelope, ClientMessage};
+use proxchat_protocol::compat;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// signed-message mode for one connection. after RequestMessageSigning the server hands out a
+// key and from then on only accepts ClientMessage::Signed, where
+//   message = the wrapped ClientMessage as JSON
+//   mac     = hex(HMAC-SHA256(key, nonce as 8 big-endian bytes || message))
+// and every nonce is higher than the last accepted one. frames injected or replayed by anything
+// sitting on a proxied or downgraded transport fail one of the two checks.
+pub struct SigningSession {
+    key: [u8; udp::KEY_LEN],
+    last_nonce: Option<u64>,
+}
+
+impl SigningSession {
+    pub fn new() -> Self {
+        SigningSession {
+            key: udp::random_key(),
+            last_nonce: None,
+        }
+    }
+
+    pub fn key_hex(&self) -> String {
+        hex::encode(self.key)
+    }
+
+    // verify a Signed envelope and return the message inside it (with its own request_id, if any)
+    pub fn open(&mut self, nonce: u64, mac: &str, message: &str) -> Result<ClientEnvelope, String> {
+        let tag = hex::decode(mac).map_err(|_| "Signed message has a malformed mac".to_string())?;
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts any key length");
+        hmac.update(&nonce.to_be_bytes());
+        hmac.update(message.as_bytes());
+        if hmac.verify_slice(&tag).is_err() {
+            return Err("Signed message has a bad mac".to_string());
+        }
+        if self.last_nonce.is_some_and(|last| nonce <= last) {
+            return Err(format!("Replayed or reordered signed message (nonce {})", nonce));
+        }
+        let inner = serde_json::from_str(message)
+            .map_err(|e| e.to_string())
+            .and_then(compat::parse_client_envelope)
+            .map_err(|e| format!("Invalid signed message: {}", e))?;
+        if matches!(inner.message, ClientMessage::Signed { .. }) {
+            return Err("Signed messages can't be nested".to_string());
+        }
+        self.last_nonce = Some(nonce);
+        Ok(inner)
+    }
+}
+
+pub enum Accepted {
+    // opened from a Signed envelope, with the request_id signed along with it
+    Signed(ClientEnvelope),
+    // sent by a connection that doesn't sign
+    Unsigned(ClientMessage),
+}
+
+// a connection's message as it may be handled: Signed ones need a session, and once there is one
+// nothing else is accepted
+pub fn accept(session: Option<&mut SigningSession>, message: ClientMessage) -> Result<Accepted, String> {
+    match (message, session) {
+        (ClientMessage::Signed { nonce, mac, message }, Some(session)) => session.open(nonce, &mac, &message).map(Accepted::Signed),
+        (ClientMessage::Signed { .. }, None) => Err("No signing session: send RequestMessageSigning first".to_string()),
+        (_, Some(_)) => Err("Unsigned message rejected: this session must sign every message".to_string()),
+        (message, None) => Ok(Accepted::Unsigned(message)),
+    }
+}
@@ -0,0 +1,188 @@
+// `prox-chat-server simulate --positions <file.json>`: places a set of positions on a server
+// state built from the config (with the database's overrides, zones, events and grids, like
+// check.rs) and prints who would be introduced to whom, without serving. lets operators preview
+// what a change to ranges, zones or caps does to a known crowd before deploying it.
+// positions join in file order, as if those clients had connected one after another, since
+// peer caps favour whoever got there first
+use crate::config::{self, Config};
+use crate::db::{self, Database};
+use crate::{outbound, ServerState};
+use proxchat_protocol::{ClientPosition, ClientPositionFloat};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const USAGE: &str = "usage: prox-chat-server simulate --positions <file.json> [--dot] [--at <unix seconds>]
+       <file.json> holds an array of positions as clients send them in UpdatePosition(Float);
+       --dot prints the graph for graphviz, --at evaluates announcer zones and scheduled events
+       at that time rather than now. reads the config file the server would (PROXCHAT_CONFIG, else config.json)";
+
+// integer coordinates as most games send them, or float ones for float_coordinate_games
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PositionEntry {
+    Fixed(ClientPosition),
+    Float(ClientPositionFloat),
+}
+
+pub fn run(args: &[String]) {
+    let mut positions_path = None;
+    let mut dot = false;
+    let mut at = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--positions" => positions_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--dot" => dot = true,
+            "--at" => at = Some(args.next().and_then(|at| at.parse::<i64>().ok()).unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+    let Some(positions_path) = positions_path else {
+        usage();
+    };
+
+    let (config, db) = load_config().unwrap_or_else(|e| fail(e));
+    let mut state = match &db {
+        Some(db) => {
+            let zones = db.announcer_zones().unwrap_or_else(|e| fail(format!("announcer zones: {}", e)));
+            let events = db.scheduled_events().unwrap_or_else(|e| fail(format!("scheduled events: {}", e)));
+            let grids = db.collision_grids().unwrap_or_else(|e| fail(format!("collision grids: {}", e)));
+            let consents = db.elevated_consents().unwrap_or_else(|e| fail(format!("elevated consent: {}", e)));
+            ServerState::new(&config, zones, events, grids, consents)
+        }
+        None => ServerState::new(&config, Vec::new(), Vec::new(), Vec::new(), Vec::new()),
+    };
+    let now = at.unwrap_or_else(db::unix_now);
+    state.refresh_scheduled_events(now);
+    state.refresh_announcer_zones(now);
+
+    let positions = load_positions(positions_path, &state).unwrap_or_else(|e| fail(e));
+    // nothing reads what the state sends, so one queue with no receiver serves everyone
+    let (tx, _) = outbound::channel(&config.outbound_queue);
+    for pos in &positions {
+        state.update_position_and_notify(pos.clone(), &tx);
+    }
+
+    // the last position given for a client is where it ends up
+    let clients: BTreeMap<&str, &ClientPosition> = positions.iter().map(|pos| (pos.client_id.as_str(), pos)).collect();
+    if dot {
+        print_dot(&state, &clients);
+    } else {
+        print_text(&state, &clients);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+// the config as the server would run with it, and its database opened read-only when there is one
+fn load_config() -> Result<(Config, Option<Database>), String> {
+    let path = config::config_path();
+    let mut raw = config::load_raw(&path)?;
+    let config = config::from_raw(raw.clone()).map_err(|e| format!("{}: {}", path, e))?;
+    let Some(database_path) = config.database_path.clone().filter(|database_path| Path::new(database_path).exists()) else {
+        return Ok((config, None));
+    };
+    let db = Database::open_read_only(&database_path).map_err(|e| format!("database {}: {}", database_path, e))?;
+    // older tables can't be read as this build expects, and migrating is the server's job
+    match db.pending_migrations() {
+        Ok(0) => {}
+        Ok(pending) => {
+            eprintln!("database {} needs {} migrations; simulating without its overrides, zones, events and grids", database_path, pending);
+            return Ok((config, None));
+        }
+        Err(e) => return Err(format!("database {}: {}", database_path, e)),
+    }
+    let overrides = db.config_overrides().map_err(|e| format!("config overrides in {}: {}", database_path, e))?;
+    config::apply_overrides(&mut raw, &overrides);
+    let config = config::from_raw(raw).map_err(|e| format!("config with the database's overrides: {}", e))?;
+    Ok((config, Some(db)))
+}
+
+// the positions in the file, fixed point and normalized the way the server stores them
+fn load_positions(path: &str, state: &ServerState) -> Result<Vec<ClientPosition>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let entries: Vec<PositionEntry> = serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path, e))?;
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let pos = match entry {
+                PositionEntry::Fixed(pos) if state.games.get(pos.game_id).float_coordinates() => ClientPositionFloat {
+                    client_id: pos.client_id,
+                    map_id: pos.map_id,
+                    x: pos.x as f32,
+                    y: pos.y as f32,
+                    channel: pos.channel,
+                    game_id: pos.game_id,
+                    heading: pos.heading.map(f32::from),
+                    session_token: None,
+                }
+                .to_fixed(),
+                PositionEntry::Fixed(pos) => pos,
+                PositionEntry::Float(pos) if state.games.get(pos.game_id).float_coordinates() => pos.to_fixed(),
+                PositionEntry::Float(pos) => {
+                    return Err(format!("{}: position {} ({}): game {} uses integer coordinates", path, index, pos.client_id, pos.game_id));
+                }
+            };
+            Ok(state.games.get(pos.game_id).normalize(pos))
+        })
+        .collect()
+}
+
+fn print_text(state: &ServerState, clients: &BTreeMap<&str, &ClientPosition>) {
+    let mut pairs = 0;
+    for client_id in clients.keys() {
+        let peers = state.paired_peers(client_id);
+        pairs += peers.len();
+        if peers.is_empty() {
+            println!("{}: no peers", client_id);
+        } else {
+            println!("{}: {}", client_id, peers.join(", "));
+        }
+    }
+    println!("{} clients, {} pairs", clients.len(), pairs / 2);
+}
+
+// one cluster per map, nodes labelled with where they stand, and pairs a live announcer zone
+// covers drawn dashed
+fn print_dot(state: &ServerState, clients: &BTreeMap<&str, &ClientPosition>) {
+    let mut maps: BTreeMap<(i32, i32), Vec<&ClientPosition>> = BTreeMap::new();
+    for pos in clients.values() {
+        maps.entry((pos.game_id, pos.map_id)).or_default().push(pos);
+    }
+    println!("graph introductions {{");
+    for ((game_id, map_id), positions) in &maps {
+        println!("  subgraph \"cluster_{}_{}\" {{", game_id, map_id);
+        println!("    label=\"game {} map {}\";", game_id, map_id);
+        for pos in positions {
+            let label = format!("{}\n({}, {}) ch {}", pos.client_id, pos.x, pos.y, pos.channel);
+            println!("    {} [label={}];", quoted(&pos.client_id), quoted(&label));
+        }
+        println!("  }}");
+    }
+    for (client_id, pos) in clients {
+        for peer_id in state.paired_peers(client_id) {
+            // each pair once
+            if peer_id.as_str() <= *client_id {
+                continue;
+            }
+            let zone = clients.get(peer_id.as_str()).is_some_and(|peer| state.zone_pairs(pos, peer));
+            let attributes = if zone { " [label=\"announcer\", style=dashed]" } else { "" };
+            println!("  {} -- {}{};", quoted(client_id), quoted(&peer_id), attributes);
+        }
+    }
+    println!("}}");
+}
+
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
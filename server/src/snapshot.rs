@@ -0,0 +1,46 @@
+// positions and pairs written to disk on shutdown and read back on start (config state_snapshot), so
+// clients coming back after a quick restart find their peers as they left them instead of going
+// through separation and reintroduction. restored clients that don't come back time out like any other
+use crate::config::StateSnapshotConfig;
+use crate::db;
+use proxchat_protocol::{ClientPosition, ClientPreferences};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub saved_at: i64, // unix seconds
+    pub clients: Vec<SnapshotClient>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotClient {
+    pub position: ClientPosition,
+    pub peers: Vec<String>,
+    #[serde(default)]
+    pub preferences: Option<ClientPreferences>,
+}
+
+// written next to the target first, so a shutdown cut short never leaves half a snapshot
+pub fn save(config: &StateSnapshotConfig, snapshot: &Snapshot) -> Result<(), String> {
+    let json = serde_json::to_vec(snapshot).map_err(|e| e.to_string())?;
+    let partial = format!("{}.partial", config.path);
+    fs::write(&partial, json).map_err(|e| format!("Failed to write {}: {}", partial, e))?;
+    fs::rename(&partial, &config.path).map_err(|e| format!("Failed to move snapshot to {}: {}", config.path, e))
+}
+
+// None when there is no snapshot, or it is too old to be worth restoring
+pub fn load(config: &StateSnapshotConfig) -> Result<Option<Snapshot>, String> {
+    let json = match fs::read(&config.path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", config.path, e)),
+    };
+    let snapshot: Snapshot = serde_json::from_slice(&json).map_err(|e| format!("Invalid snapshot {}: {}", config.path, e))?;
+    let age = db::unix_now() - snapshot.saved_at;
+    if age > config.max_age_secs as i64 {
+        log::info!("Not restoring state snapshot {}: saved {}s ago (max_age_secs {})", config.path, age, config.max_age_secs);
+        return Ok(None);
+    }
+    Ok(Some(snapshot))
+}
@@ -0,0 +1,213 @@
+// `prox-chat-server soak [--cycles N] [--clients N]` (the soak feature): churns client sessions
+// through an in-process server, the same serve_client real connections use, and after every cycle
+// checks that each per-client map is back to its baseline size and that the maintenance loop's
+// orphan sweep had nothing to repair. RSS is reported as it goes, so slow growth shows up too
+use crate::config::{Config, OverloadConfig};
+use crate::db::Database;
+use crate::{check_timeouts_and_reintroduce, codec, send_deferred_nearby_updates, serve_client, ServerState};
+use futures::channel::mpsc;
+use futures_util::{future, sink, StreamExt};
+use log::{error, info, warn};
+use proxchat_protocol::{ClientEnvelope, ClientMessage, ClientPosition, ClientPreferences, ElevatedConsent, NatType};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite::Message;
+
+const USAGE: &str = "usage: prox-chat-server soak [--cycles <n>] [--clients <n per cycle>]";
+
+// RSS growth past this share of the post-warm-up figure is reported as a likely leak
+const RSS_GROWTH_WARNING: f64 = 0.25;
+
+pub async fn run(args: &[String]) {
+    let mut cycles: u64 = 1000;
+    let mut clients: u64 = 50;
+    for pair in args.chunks(2) {
+        let value = pair.get(1).and_then(|value| value.parse().ok()).filter(|value| *value > 0);
+        match (pair[0].as_str(), value) {
+            ("--cycles", Some(value)) => cycles = value,
+            ("--clients", Some(value)) => clients = value,
+            _ => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+    // the per-connection info logs would drown out the progress lines
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("error,prox_chat_server::soak=info")).init();
+    if let Err(e) = soak(cycles, clients).await {
+        error!("Soak test failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn soak(cycles: u64, clients: u64) -> Result<(), String> {
+    let db = Arc::new(Database::open(None).map_err(|e| e.to_string())?);
+    // position history deliberately outlives disconnects (for ten minutes by default), which
+    // would pass for a leak in the RSS figures
+    let config = Config { position_history_len: 0, ..Config::default() };
+    let state = Arc::new(RwLock::new(ServerState::new(&config, Vec::new(), Vec::new(), Vec::new(), Vec::new())));
+    tokio::spawn(check_timeouts_and_reintroduce(Arc::clone(&state), OverloadConfig::default()));
+    tokio::spawn(send_deferred_nearby_updates(Arc::clone(&state)));
+    let baseline = state.read().await.map_sizes();
+
+    info!("Soaking {} cycles of {} clients", cycles, clients);
+    let report_every = (cycles / 20).max(1);
+    let mut warm_rss = None;
+    for cycle in 0..cycles {
+        let sessions = (0..clients).map(|client| {
+            let script = script(cycle, client, clients);
+            play(Arc::clone(&state), Arc::clone(&db), script)
+        });
+        future::join_all(sessions).await;
+
+        let state_read = state.read().await;
+        let leaked: Vec<String> = state_read
+            .map_sizes()
+            .iter()
+            .zip(&baseline)
+            .filter(|((_, size), (_, base))| size != base)
+            .map(|((name, size), (_, base))| format!("{} has {} entries (baseline {})", name, size, base))
+            .collect();
+        if !leaked.is_empty() {
+            return Err(format!("state leaked after cycle {}: {}", cycle + 1, leaked.join(", ")));
+        }
+        if state_read.orphans_repaired_total > 0 {
+            return Err(format!(
+                "the orphan sweep repaired {} entries by cycle {} (last sweep: {:?})",
+                state_read.orphans_repaired_total,
+                cycle + 1,
+                state_read.last_consistency_report
+            ));
+        }
+        drop(state_read);
+
+        if (cycle + 1) % report_every == 0 || cycle + 1 == cycles {
+            let rss = rss_bytes();
+            info!("cycle {}/{}: {} sessions, rss {}", cycle + 1, cycles, (cycle + 1) * clients, format_rss(rss));
+            // allocator pools fill during the first reports; later growth is what matters
+            if (cycle + 1) / report_every == 2 {
+                warm_rss = rss;
+            }
+        }
+    }
+
+    if let (Some(warm), Some(end)) = (warm_rss, rss_bytes()) {
+        let growth = end as f64 / warm as f64 - 1.0;
+        if growth > RSS_GROWTH_WARNING {
+            warn!("RSS grew {:.0}% after warm-up ({} -> {}): something may be leaking outside the state maps",
+                  growth * 100.0, format_rss(Some(warm)), format_rss(Some(end)));
+        }
+    }
+    info!("Soak test passed: every state map back to baseline after {} sessions", cycles * clients);
+    Ok(())
+}
+
+// what one client sends during a cycle, and whether it says Disconnect before going away
+struct Script {
+    frames: Vec<Message>,
+    clean_exit: bool,
+}
+
+// register, move around a small area (so clients pair and separate), use the per-client features
+// that leave state behind, signal a neighbour, then leave cleanly or drop the connection
+fn script(cycle: u64, client: u64, clients: u64) -> Script {
+    let mut rng = Rng(cycle.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ client.wrapping_add(1));
+    let client_id = format!("soak-{}-{}", cycle, client);
+    // three clients on, usually, the same map
+    let peer_id = format!("soak-{}-{}", cycle, (client + 3) % clients);
+    let mut pos = ClientPosition {
+        client_id,
+        map_id: (client % 3) as i32,
+        x: rng.below(30) as i64,
+        y: rng.below(30) as i64,
+        channel: 0,
+        game_id: 0,
+        heading: None,
+        session_token: None,
+    };
+    let mut messages = vec![ClientMessage::UpdatePosition(pos.clone())];
+    messages.push(ClientMessage::SetPreferences(ClientPreferences { peer_details: true, area_summary: true, rtt_probes: true, spectator_opt_out: false, share_public_address: false }));
+    let nat_types = [NatType::Open, NatType::FullCone, NatType::Symmetric, NatType::Unknown];
+    messages.push(ClientMessage::ReportNatType { nat_type: nat_types[rng.below(nat_types.len() as u64) as usize] });
+    messages.push(ClientMessage::ReportNetworkQuality { rtt_ms: 20 + rng.below(400) as u32, packet_loss_percent: rng.below(20) as f32 });
+    messages.push(ClientMessage::SetElevatedConsent(ElevatedConsent { spectators: rng.below(2) == 0, announcers: true }));
+    messages.push(ClientMessage::SetTags(BTreeMap::from([("role".to_string(), "soak".to_string())])));
+    messages.push(ClientMessage::SetMuteState { muted_peer_ids: vec![peer_id.clone()] });
+    for _ in 0..5 {
+        pos.x += rng.below(9) as i64 - 4;
+        pos.y += rng.below(9) as i64 - 4;
+        messages.push(ClientMessage::UpdatePosition(pos.clone()));
+    }
+    messages.push(ClientMessage::SendOffer { target_id: peer_id.clone(), offer: "soak offer".to_string() });
+    messages.push(ClientMessage::SendAnswer { target_id: peer_id.clone(), answer: "soak answer".to_string() });
+    messages.push(ClientMessage::SendIceCandidate { target_id: peer_id.clone(), candidate: "soak candidate".to_string() });
+    messages.push(ClientMessage::ReportPeerConnected { peer_id: peer_id.clone() });
+    messages.push(ClientMessage::ReportIceFailure { peer_id: peer_id.clone() });
+    messages.push(ClientMessage::RequestReintroduction { peer_id });
+    messages.push(ClientMessage::RequestPeerRefresh);
+    messages.push(ClientMessage::GetPopulation);
+    messages.push(ClientMessage::GetServerStats);
+    let clean_exit = rng.below(2) == 0;
+    if clean_exit {
+        messages.push(ClientMessage::Disconnect);
+    }
+    let frames = messages
+        .into_iter()
+        .map(|message| Message::text(serde_json::to_string(&ClientEnvelope::from(message)).expect("client messages serialize")))
+        .collect();
+    Script { frames, clean_exit }
+}
+
+// one session: frames go in a few milliseconds apart so the cycle's clients interleave; replies are
+// dropped. returns once serve_client has cleaned up after the connection
+async fn play(state: Arc<RwLock<ServerState>>, db: Arc<Database>, script: Script) {
+    let (frames_tx, frames_rx) = mpsc::unbounded::<Message>();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let session = tokio::spawn(serve_client(
+        state,
+        db,
+        addr,
+        codec::Encoding::Json,
+        None,
+        sink::drain(),
+        frames_rx.map(Ok::<_, Infallible>),
+    ));
+    for frame in script.frames {
+        if frames_tx.unbounded_send(frame).is_err() {
+            break;
+        }
+        time::sleep(Duration::from_millis(1)).await;
+    }
+    if script.clean_exit {
+        let _ = frames_tx.unbounded_send(Message::Close(None));
+    }
+    drop(frames_tx);
+    let _ = session.await;
+}
+
+// resident set size from /proc (Linux only)
+fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+fn format_rss(rss: Option<u64>) -> String {
+    rss.map_or_else(|| "unknown".to_string(), |bytes| format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)))
+}
+
+// xorshift64, seeded per client so a failing run can be reproduced
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
@@ -0,0 +1,67 @@
+// listen-only spectators: a streamer's broadcast client, a caster, a tournament observer. each
+// is paired with every client within its radius of a focal point, wherever its own position is,
+// and with nobody else. the focus either follows a client around or sits at a fixed point.
+// designated by client id through the admin API and kept (across the spectator's reconnects)
+// until removed. clients with the spectator_opt_out preference are never paired with one
+use proxchat_protocol::ClientPosition;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpectatorFocus {
+    // wherever this client is; nothing is in range while it has no position
+    Follow { client_id: String },
+    Point { game_id: i32, map_id: i32, x: i64, y: i64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Spectator {
+    // taken from the path
+    #[serde(skip_deserializing)]
+    pub client_id: String,
+    pub focus: SpectatorFocus,
+    // in the game's units, like ranges; the config's spectators.default_radius when left out
+    #[serde(default)]
+    pub radius: Option<f32>,
+}
+
+impl Spectator {
+    pub fn validate(&self, max_radius: f32) -> Result<(), String> {
+        if let SpectatorFocus::Follow { client_id } = &self.focus {
+            if *client_id == self.client_id {
+                return Err("a spectator can't follow itself".to_string());
+            }
+        }
+        match self.radius {
+            Some(radius) if !(radius > 0.0 && radius <= max_radius) => Err(format!("radius must be above 0 and at most {}", max_radius)),
+            _ => Ok(()),
+        }
+    }
+
+    // the followed client (from `positions`) or the fixed point, as a position to measure from
+    pub fn focus_position<'a>(&self, followed: impl FnOnce(&str) -> Option<&'a ClientPosition>) -> Option<ClientPosition> {
+        match &self.focus {
+            SpectatorFocus::Follow { client_id } => followed(client_id).cloned(),
+            &SpectatorFocus::Point { game_id, map_id, x, y } => Some(ClientPosition {
+                client_id: String::new(),
+                map_id,
+                x,
+                y,
+                channel: 0,
+                game_id,
+                heading: None,
+                session_token: None,
+            }),
+        }
+    }
+
+    pub fn follows(&self, client_id: &str) -> bool {
+        matches!(&self.focus, SpectatorFocus::Follow { client_id: followed } if followed == client_id)
+    }
+}
+
+// `pos` is within `radius` of the focus, on its map of its game (any channel). `scale` is the
+// game's coordinate scale
+pub fn in_radius(focus: &ClientPosition, pos: &ClientPosition, radius: f32, scale: f64) -> bool {
+    focus.game_id == pos.game_id && focus.map_id == pos.map_id && focus.distance_squared(pos).sqrt() / scale <= f64::from(radius)
+}
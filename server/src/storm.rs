@@ -0,0 +1,102 @@
+// reconnect storm absorption (config reconnect_storm). when a large share of the registered clients
+// arrived within the last window_secs (a restart or a network blip brings everyone back at once),
+// the NearbyPeers updates each arrival sets off are held back and spread over spread_ms with jitter.
+// a client owed several updates in that time gets one, built from the pairs as they are when it goes out
+use crate::config::ReconnectStormConfig;
+use crate::metrics;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub struct StormAbsorber {
+    window: Duration,
+    min_registrations: usize,
+    registered_fraction: f64,
+    spread: Duration,
+    // registrations within the window, oldest first
+    registrations: VecDeque<Instant>,
+    active: bool,
+    // client id -> when its held-back update goes out
+    deferred: HashMap<String, Instant>,
+}
+
+impl StormAbsorber {
+    pub fn new(config: &ReconnectStormConfig) -> Self {
+        StormAbsorber {
+            window: Duration::from_secs(config.window_secs),
+            min_registrations: config.min_registrations,
+            registered_fraction: config.registered_fraction,
+            spread: Duration::from_millis(config.spread_ms),
+            registrations: VecDeque::new(),
+            active: false,
+            deferred: HashMap::new(),
+        }
+    }
+
+    // counts a registration (`registered` includes it); true while a storm is on
+    pub fn registered(&mut self, now: Instant, registered: usize) -> bool {
+        if self.min_registrations == 0 {
+            return false;
+        }
+        self.registrations.push_back(now);
+        self.update(now, registered)
+    }
+
+    // also called between registrations, so a storm ends once arrivals die down
+    pub fn update(&mut self, now: Instant, registered: usize) -> bool {
+        while self.registrations.front().is_some_and(|at| now.saturating_duration_since(*at) > self.window) {
+            self.registrations.pop_front();
+        }
+        let recent = self.registrations.len();
+        let storm = self.min_registrations > 0
+            && recent >= self.min_registrations
+            && recent as f64 >= self.registered_fraction * registered as f64;
+        if storm && !self.active {
+            warn!("Reconnect storm: {} of {} clients registered in the last {}s, spreading their NearbyPeers updates over {}ms",
+                  recent, registered, self.window.as_secs(), self.spread.as_millis());
+        } else if !storm && self.active {
+            info!("Reconnect storm over ({} updates still held back)", self.deferred.len());
+        }
+        self.active = storm;
+        metrics::RECONNECT_STORM.set(storm as i64);
+        storm
+    }
+
+    // holds back these clients' updates; one already waiting keeps its place
+    pub fn defer(&mut self, client_ids: impl IntoIterator<Item = String>, now: Instant) {
+        for client_id in client_ids {
+            self.deferred.entry(client_id).or_insert_with(|| {
+                metrics::DEFERRED_NEARBY_UPDATES.inc();
+                now + self.spread.mul_f64(random())
+            });
+        }
+    }
+
+    // the clients whose held-back update is due
+    pub fn take_due(&mut self, now: Instant) -> Vec<String> {
+        let due: Vec<String> = self.deferred.iter().filter(|(_, at)| **at <= now).map(|(client_id, _)| client_id.clone()).collect();
+        for client_id in &due {
+            self.deferred.remove(client_id);
+        }
+        due
+    }
+
+    // nothing held back and no storm to watch for
+    pub fn is_idle(&self) -> bool {
+        !self.active && self.deferred.is_empty()
+    }
+
+    pub fn forget(&mut self, client_id: &str) {
+        self.deferred.remove(client_id);
+    }
+
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.len()
+    }
+}
+
+// uniform in [0, 1), from the 48 random bits in front of a v4 uuid's version nibble
+fn random() -> f64 {
+    (Uuid::new_v4().as_u128() >> 80) as f64 / (1u64 << 48) as f64
+}
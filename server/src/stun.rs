@@ -0,0 +1,90 @@
+use crate::metrics;
+use log::{error, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+
+// a minimal STUN server (RFC 5389) on config stun_addr: it answers Binding requests with the
+// address they came from, which is all ICE needs of a STUN server to find a client's server-
+// reflexive candidate. clients list it as "stun:<host>:<port>". no authentication, no other
+// methods, and a reply barely bigger than the request, so it's useless for reflection attacks
+const HEADER_LEN: usize = 20;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+// requests past this are no Binding request worth answering
+const MAX_REQUEST_LEN: usize = 548;
+
+pub async fn serve(addr: String) {
+    let socket = match UdpSocket::bind(&addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind STUN server on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("STUN server listening on: {}", addr);
+
+    let mut buffer = [0u8; MAX_REQUEST_LEN];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("STUN receive error: {}", e);
+                continue;
+            }
+        };
+        let Some(response) = binding_response(&buffer[..len], from) else {
+            metrics::STUN_REQUESTS.with_label_values(&["ignored"]).inc();
+            continue;
+        };
+        metrics::STUN_REQUESTS.with_label_values(&["answered"]).inc();
+        if let Err(e) = socket.send_to(&response, from).await {
+            warn!("Failed to answer STUN request from {}: {}", from, e);
+        }
+    }
+}
+
+// the success response to a well-formed Binding request from `from`; None for anything else.
+// the request's attributes are ignored, since a Binding request to a server needs none
+pub fn binding_response(request: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+    if request.len() < HEADER_LEN || request[0] & 0xC0 != 0 {
+        return None;
+    }
+    let message_type = u16::from_be_bytes([request[0], request[1]]);
+    let length = u16::from_be_bytes([request[2], request[3]]) as usize;
+    let cookie = u32::from_be_bytes(request[4..8].try_into().unwrap());
+    if message_type != BINDING_REQUEST || cookie != MAGIC_COOKIE || !length.is_multiple_of(4) || HEADER_LEN + length != request.len() {
+        return None;
+    }
+    let transaction_id = &request[8..HEADER_LEN];
+
+    // XOR-MAPPED-ADDRESS: the port xored with the cookie's top half, the address with the cookie
+    // (and for IPv6 the transaction id after it)
+    let mut attribute = vec![0u8];
+    let port = from.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+    mask.extend_from_slice(transaction_id);
+    match from.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            attribute.push(0x01);
+            attribute.extend_from_slice(&port.to_be_bytes());
+            attribute.extend(ip.octets().iter().zip(&mask).map(|(byte, mask)| byte ^ mask));
+        }
+        IpAddr::V6(ip) => {
+            attribute.push(0x02);
+            attribute.extend_from_slice(&port.to_be_bytes());
+            attribute.extend(ip.octets().iter().zip(&mask).map(|(byte, mask)| byte ^ mask));
+        }
+    }
+
+    let mut response = Vec::with_capacity(HEADER_LEN + 4 + attribute.len());
+    response.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+    response.extend_from_slice(&(4 + attribute.len() as u16).to_be_bytes());
+    response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    response.extend_from_slice(transaction_id);
+    response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    response.extend_from_slice(&(attribute.len() as u16).to_be_bytes());
+    response.extend_from_slice(&attribute);
+    Some(response)
+}
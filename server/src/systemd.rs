@@ -0,0 +1,95 @@
+// sd_notify(3) for running as a Type=notify systemd service: READY=1 once the listener is up,
+// STOPPING=1 on shutdown, and WATCHDOG=1 pings from a task of their own while the state lock can
+// be taken. with WatchdogSec set, a deadlocked state lock or a wedged runtime stops the pings and
+// systemd restarts the service. everything here is a no-op when NOTIFY_SOCKET isn't set
+use log::warn;
+use std::env;
+use std::time::Duration;
+
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+    #[cfg(unix)]
+    path: String,
+    // ping interval: half of WatchdogSec, as sd_watchdog_enabled(3) recommends
+    watchdog_interval: Option<Duration>,
+}
+
+impl Notifier {
+    // None when systemd didn't ask for notifications
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        let watchdog_interval = watchdog_usec().map(|usec| Duration::from_micros(usec / 2));
+        Self::open(path, watchdog_interval)
+    }
+
+    #[cfg(unix)]
+    fn open(path: String, watchdog_interval: Option<Duration>) -> Option<Notifier> {
+        match std::os::unix::net::UnixDatagram::unbound() {
+            Ok(socket) => Some(Notifier { socket, path, watchdog_interval }),
+            Err(e) => {
+                warn!("Failed to open a socket for systemd notifications: {}", e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn open(_path: String, _watchdog_interval: Option<Duration>) -> Option<Notifier> {
+        None
+    }
+
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    pub fn watchdog(&self) {
+        self.send("WATCHDOG=1");
+    }
+
+    #[cfg(unix)]
+    fn send(&self, state: &str) {
+        if let Err(e) = self.send_to_socket(state.as_bytes()) {
+            warn!("Failed to notify systemd ({}): {}", state, e);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, _state: &str) {}
+
+    // a leading @ names a socket in the abstract namespace
+    #[cfg(target_os = "linux")]
+    fn send_to_socket(&self, message: &[u8]) -> std::io::Result<usize> {
+        use std::os::linux::net::SocketAddrExt;
+        match self.path.strip_prefix('@') {
+            Some(name) => {
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                self.socket.send_to_addr(message, &addr)
+            }
+            None => self.socket.send_to(message, &self.path),
+        }
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn send_to_socket(&self, message: &[u8]) -> std::io::Result<usize> {
+        self.socket.send_to(message, &self.path)
+    }
+}
+
+// WATCHDOG_USEC, if it is meant for this process
+fn watchdog_usec() -> Option<u64> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    env::var("WATCHDOG_USEC").ok()?.parse().ok().filter(|usec| *usec > 0)
+}
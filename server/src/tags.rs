@@ -0,0 +1,31 @@
+use std::collections::BTreeMap;
+
+// limits on the free-form tags clients attach to themselves (clan, role, ...)
+pub const MAX_TAGS: usize = 4;
+pub const MAX_KEY_LEN: usize = 16;
+pub const MAX_VALUE_LEN: usize = 32;
+
+// keys are short lowercase identifiers; values are any printable text within the length cap
+pub fn validate(tags: &BTreeMap<String, String>) -> Result<(), String> {
+    if tags.len() > MAX_TAGS {
+        return Err(format!("At most {} tags are allowed", MAX_TAGS));
+    }
+    for (key, value) in tags {
+        let key_ok = !key.is_empty()
+            && key.len() <= MAX_KEY_LEN
+            && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+        if !key_ok {
+            return Err(format!(
+                "Invalid tag key {:?}: use 1-{} characters from a-z, 0-9, '_' and '-'",
+                key, MAX_KEY_LEN
+            ));
+        }
+        if value.trim().is_empty() || value.chars().count() > MAX_VALUE_LEN || value.chars().any(char::is_control) {
+            return Err(format!(
+                "Invalid value for tag {}: use 1-{} printable characters",
+                key, MAX_VALUE_LEN
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+// inbound traffic per connection, for the admin API's /top-talkers and the position update rate
+// GetServerStats reports. receive loops count every frame without taking the state lock; the
+// maintenance loop turns the counts into rates once per tick
+use crate::metrics;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+#[derive(Default)]
+pub struct InboundTraffic {
+    connections: DashMap<String, Arc<Counters>>,
+}
+
+pub struct Counters {
+    addr: SocketAddr,
+    connected_at: Instant,
+    client_id: Mutex<Option<String>>,
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    positions: AtomicU64,
+    rate: Mutex<Rate>,
+}
+
+// the counts at the previous tick, and the rates since the one before
+struct Rate {
+    frames: u64,
+    bytes: u64,
+    positions: u64,
+    at: Instant,
+    frames_per_sec: f64,
+    bytes_per_sec: f64,
+    positions_per_sec: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TalkerOrder {
+    #[default]
+    Messages,
+    Bytes,
+}
+
+#[derive(Serialize)]
+pub struct Talker {
+    connection_id: String,
+    client_id: Option<String>,
+    addr: SocketAddr,
+    connected_secs: u64,
+    // over the last maintenance interval
+    messages_per_sec: f64,
+    bytes_per_sec: f64,
+    messages_total: u64,
+    bytes_total: u64,
+}
+
+impl InboundTraffic {
+    pub fn open(&self, connection_id: &str, addr: SocketAddr) -> Arc<Counters> {
+        let now = Instant::now();
+        let counters = Arc::new(Counters {
+            addr,
+            connected_at: now,
+            client_id: Mutex::new(None),
+            frames: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            positions: AtomicU64::new(0),
+            rate: Mutex::new(Rate { frames: 0, bytes: 0, positions: 0, at: now, frames_per_sec: 0.0, bytes_per_sec: 0.0, positions_per_sec: 0.0 }),
+        });
+        self.connections.insert(connection_id.to_string(), Arc::clone(&counters));
+        counters
+    }
+
+    pub fn close(&self, connection_id: &str) {
+        self.connections.remove(connection_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn connection_ids(&self) -> Vec<String> {
+        self.connections.iter().map(|entry| entry.key().clone()).collect()
+    }
+
+    pub fn addr(&self, connection_id: &str) -> Option<SocketAddr> {
+        self.connections.get(connection_id).map(|counters| counters.addr)
+    }
+
+    // position updates per second over the last maintenance interval, over any transport
+    pub fn position_rate(&self, connection_id: &str) -> Option<f64> {
+        self.connections.get(connection_id).map(|counters| counters.rate.lock().expect("talker rate lock poisoned").positions_per_sec)
+    }
+
+    // a position that came in over UDP rather than the connection itself
+    pub fn record_position(&self, connection_id: &str) {
+        if let Some(counters) = self.connections.get(connection_id) {
+            counters.record_position();
+        }
+    }
+
+    // called once per maintenance tick
+    pub fn update_rates(&self) {
+        let now = Instant::now();
+        for entry in self.connections.iter() {
+            let counters = entry.value();
+            let frames = counters.frames.load(Ordering::Relaxed);
+            let bytes = counters.bytes.load(Ordering::Relaxed);
+            let positions = counters.positions.load(Ordering::Relaxed);
+            let mut rate = counters.rate.lock().expect("talker rate lock poisoned");
+            let elapsed = now.duration_since(rate.at).as_secs_f64();
+            if elapsed <= 0.0 {
+                continue;
+            }
+            rate.frames_per_sec = (frames - rate.frames) as f64 / elapsed;
+            rate.bytes_per_sec = (bytes - rate.bytes) as f64 / elapsed;
+            rate.positions_per_sec = (positions - rate.positions) as f64 / elapsed;
+            rate.frames = frames;
+            rate.bytes = bytes;
+            rate.positions = positions;
+            rate.at = now;
+            metrics::CONNECTION_INBOUND_MESSAGE_RATE.observe(rate.frames_per_sec);
+            metrics::CONNECTION_INBOUND_BYTE_RATE.observe(rate.bytes_per_sec);
+        }
+    }
+
+    // the busiest connections first
+    pub fn top(&self, limit: usize, order: TalkerOrder) -> Vec<Talker> {
+        let mut talkers: Vec<Talker> = self
+            .connections
+            .iter()
+            .map(|entry| {
+                let counters = entry.value();
+                let rate = counters.rate.lock().expect("talker rate lock poisoned");
+                Talker {
+                    connection_id: entry.key().clone(),
+                    client_id: counters.client_id.lock().expect("talker client id lock poisoned").clone(),
+                    addr: counters.addr,
+                    connected_secs: counters.connected_at.elapsed().as_secs(),
+                    messages_per_sec: rate.frames_per_sec,
+                    bytes_per_sec: rate.bytes_per_sec,
+                    messages_total: counters.frames.load(Ordering::Relaxed),
+                    bytes_total: counters.bytes.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        let key = |talker: &Talker| match order {
+            TalkerOrder::Messages => (talker.messages_per_sec, talker.messages_total),
+            TalkerOrder::Bytes => (talker.bytes_per_sec, talker.bytes_total),
+        };
+        talkers.sort_by(|a, b| key(b).partial_cmp(&key(a)).unwrap_or(std::cmp::Ordering::Equal));
+        talkers.truncate(limit);
+        talkers
+    }
+}
+
+impl Counters {
+    // one received frame, whether or not it parsed
+    pub fn record(&self, bytes: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_position(&self) {
+        self.positions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_client_id(&self, client_id: &str) {
+        *self.client_id.lock().expect("talker client id lock poisoned") = Some(client_id.to_string());
+    }
+}
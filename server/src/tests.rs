@@ -0,0 +1,652 @@
+// unit tests of ServerState's pairing and signaling bookkeeping, on a state built from the default config
+use super::*;
+
+fn state() -> ServerState {
+    ServerState::new(&config::Config::default(), Vec::new(), Vec::new(), Vec::new(), Vec::new())
+}
+
+fn pos(client_id: &str, x: i64, y: i64) -> ClientPosition {
+    ClientPosition { client_id: client_id.to_string(), map_id: 1, x, y, channel: 0, game_id: 0, heading: None, session_token: None }
+}
+
+fn sender() -> outbound::Sender {
+    outbound::channel(&config::OutboundQueueConfig::default()).0
+}
+
+fn paired(state: &ServerState, a: &str, b: &str) -> bool {
+    state.last_nearby_lists.get(a).is_some_and(|peers| peers.contains(b))
+}
+
+// every pair on both sides, and each one within range as hysteresis allows: inside the
+// disconnection range when paired, outside the introduction range when not
+fn assert_pairs_consistent(state: &ServerState) {
+    assert!(state.asymmetric_pairs().is_empty(), "asymmetric pairs: {:?}", state.asymmetric_pairs());
+    let ranges = state.proximity.ranges();
+    for a in state.positions.values() {
+        for b in state.positions.values().filter(|b| b.client_id != a.client_id) {
+            let distance = a.distance_squared(b).sqrt();
+            if paired(state, &a.client_id, &b.client_id) {
+                assert!(distance <= f64::from(ranges.disconnection), "{} and {} paired {} apart", a.client_id, b.client_id, distance);
+            } else {
+                assert!(distance > f64::from(ranges.introduction), "{} and {} unpaired {} apart", a.client_id, b.client_id, distance);
+            }
+        }
+    }
+}
+
+// a small deterministic generator, so a failing walk can be replayed
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, below: i64) -> i64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % below as u64) as i64
+    }
+}
+
+#[test]
+fn glare_lets_the_smaller_id_through_when_it_offers_first() {
+    let offers = offers::RecentOffers::default();
+    assert!(matches!(offers.check("a", "b", "offer from a"), OfferVerdict::Relay));
+    assert!(matches!(offers.check("b", "a", "offer from b"), OfferVerdict::GlareSuppressed));
+}
+
+#[test]
+fn glare_rolls_the_greater_id_back_whichever_offer_arrives_first() {
+    let offers = offers::RecentOffers::default();
+    assert_eq!(offers.check("a", "b", "offer from a").loser("a", "b"), None);
+    assert_eq!(offers.check("b", "a", "offer from b").loser("b", "a"), Some("b"));
+
+    let offers = offers::RecentOffers::default();
+    assert_eq!(offers.check("b", "a", "offer from b").loser("b", "a"), None);
+    assert_eq!(offers.check("a", "b", "offer from a").loser("a", "b"), Some("b"));
+}
+
+#[test]
+fn glare_lets_the_smaller_id_through_when_it_offers_second() {
+    let offers = offers::RecentOffers::default();
+    assert!(matches!(offers.check("b", "a", "offer from b"), OfferVerdict::Relay));
+    // relayed as well, with b told to roll its offer back
+    assert!(matches!(offers.check("a", "b", "offer from a"), OfferVerdict::GlareWon));
+    // and b resending the offer it rolled back loses like any other
+    assert!(matches!(offers.check("b", "a", "offer from b"), OfferVerdict::GlareSuppressed));
+}
+
+#[test]
+fn resent_offers_are_dropped() {
+    let offers = offers::RecentOffers::default();
+    assert!(matches!(offers.check("a", "b", "offer"), OfferVerdict::Relay));
+    assert!(matches!(offers.check("a", "b", "offer"), OfferVerdict::Duplicate));
+    assert!(matches!(offers.check("a", "b", "another offer"), OfferVerdict::Relay));
+}
+
+#[test]
+fn introduce_and_separate_touch_both_sides() {
+    let mut state = state();
+    state.introduce_pair("a", "b");
+    assert!(paired(&state, "a", "b") && paired(&state, "b", "a"));
+    assert!(state.asymmetric_pairs().is_empty());
+    state.separate_pair("b", "a");
+    assert!(!paired(&state, "a", "b") && !paired(&state, "b", "a"));
+    assert!(state.last_nearby_lists.is_empty());
+}
+
+#[test]
+fn forgetting_a_client_dissolves_all_its_pairs() {
+    let mut state = state();
+    let tx = sender();
+    for (client_id, x) in [("a", 0), ("b", 5), ("c", 10)] {
+        state.update_position_and_notify(pos(client_id, x, 0), &tx);
+    }
+    assert!(paired(&state, "a", "b") && paired(&state, "b", "c") && paired(&state, "a", "c"));
+    state.forget_client("b");
+    assert!(!paired(&state, "a", "b") && !paired(&state, "c", "b"));
+    assert!(!state.last_nearby_lists.contains_key("b"));
+    assert!(paired(&state, "a", "c"));
+    assert_pairs_consistent(&state);
+}
+
+#[test]
+fn two_clients_moving_in_turn_stay_symmetric() {
+    let mut state = state();
+    let tx = sender();
+    state.update_position_and_notify(pos("a", 0, 0), &tx);
+    // in range, then into the hysteresis band from either side, then out of it
+    let moves = [("b", 15, true), ("a", -7, true), ("b", 16, true), ("a", -10, false), ("b", 5, true), ("a", 25, true), ("b", -1, false)];
+    for (client_id, x, expect_paired) in moves {
+        state.update_position_and_notify(pos(client_id, x, 0), &tx);
+        assert_eq!(paired(&state, "a", "b"), expect_paired, "after {} moved to {}", client_id, x);
+        assert_pairs_consistent(&state);
+    }
+}
+
+#[test]
+fn three_clients_walking_at_random_stay_symmetric() {
+    let mut state = state();
+    let tx = sender();
+    let mut rng = Lcg(7);
+    let mut xy: HashMap<&str, (i64, i64)> = HashMap::from([("a", (0, 0)), ("b", (10, 0)), ("c", (0, 10))]);
+    for step in 0..2000 {
+        let client_id = ["a", "b", "c"][rng.next(3) as usize];
+        let (x, y) = xy[client_id];
+        let moved = ((x + rng.next(9) - 4).clamp(-30, 30), (y + rng.next(9) - 4).clamp(-30, 30));
+        xy.insert(client_id, moved);
+        state.update_position_and_notify(pos(client_id, moved.0, moved.1), &tx);
+        // now and then one leaves and comes straight back, as a reconnect would
+        if step % 97 == 0 {
+            state.forget_client(client_id);
+            state.update_position_and_notify(pos(client_id, moved.0, moved.1), &tx);
+        }
+        assert_pairs_consistent(&state);
+    }
+}
+
+#[test]
+fn should_be_paired_is_symmetric() {
+    let state = state();
+    let game = state.games.get(0);
+    let ranges = state.proximity.ranges();
+    let mut rng = Lcg(11);
+    for _ in 0..2000 {
+        let mut a = pos("a", rng.next(61) - 30, rng.next(61) - 30);
+        let mut b = pos("b", rng.next(61) - 30, rng.next(61) - 30);
+        a.channel = rng.next(2) as i32;
+        b.channel = rng.next(2) as i32;
+        for (currently_paired, any_channel) in [(false, false), (true, false), (false, true), (true, true)] {
+            assert_eq!(
+                ServerState::should_be_paired(game, &a, &b, currently_paired, ranges, any_channel),
+                ServerState::should_be_paired(game, &b, &a, currently_paired, ranges, any_channel),
+                "{:?} and {:?}",
+                a,
+                b
+            );
+        }
+    }
+}
+
+fn all_peers_gone(messages: &[ServerMessage]) -> usize {
+    messages.iter().filter(|message| matches!(message, ServerMessage::AllPeersGone)).count()
+}
+
+#[test]
+fn all_peers_gone_follows_the_last_peer_leaving_once() {
+    let mut state = state();
+    let tx = sender();
+    state.update_position_and_notify(pos("a", 0, 0), &tx);
+    state.update_position_and_notify(pos("b", 5, 0), &tx);
+    assert_eq!(all_peers_gone(&state.nearby_messages(&pos("a", 0, 0), true)), 0);
+
+    state.update_position_and_notify(pos("b", 50, 0), &tx);
+    let messages = state.nearby_messages(&pos("a", 0, 0), true);
+    assert!(matches!(messages.last(), Some(ServerMessage::AllPeersGone)));
+    assert_eq!(all_peers_gone(&messages), 1);
+    // later updates of the still empty set (tags, NAT types, preferences) don't repeat it
+    assert_eq!(all_peers_gone(&state.nearby_messages(&pos("a", 0, 0), true)), 0);
+
+    // and the next time the set empties, it comes again
+    state.update_position_and_notify(pos("b", 5, 0), &tx);
+    state.nearby_messages(&pos("a", 0, 0), true);
+    state.forget_client("b");
+    assert_eq!(all_peers_gone(&state.nearby_messages(&pos("a", 0, 0), true)), 1);
+}
+
+#[test]
+fn all_peers_gone_is_never_part_of_a_resend() {
+    let mut state = state();
+    let tx = sender();
+    state.update_position_and_notify(pos("a", 0, 0), &tx);
+    assert_eq!(all_peers_gone(&state.nearby_messages(&pos("a", 0, 0), true)), 0, "a client that never had peers");
+
+    state.update_position_and_notify(pos("b", 5, 0), &tx);
+    state.nearby_messages(&pos("a", 0, 0), true);
+    state.update_position_and_notify(pos("b", 50, 0), &tx);
+    // periodic and requested resends of the empty set come without it...
+    assert_eq!(all_peers_gone(&state.nearby_messages(&pos("a", 0, 0), false)), 0);
+    assert_eq!(all_peers_gone(&state.nearby_messages(&pos("a", 0, 0), false)), 0);
+    // ...and leave it to the update for the change itself
+    assert_eq!(all_peers_gone(&state.nearby_messages(&pos("a", 0, 0), true)), 1);
+}
+
+#[test]
+fn positions_are_admitted_by_their_game_whatever_the_transport() {
+    let config = config::Config { float_coordinate_games: vec![7], ..config::Config::default() };
+    let games = games::GameAdapters::new(&config);
+    assert!(games.admit(ClientMessage::UpdatePosition(pos("a", 1, 2))).is_ok());
+    // what a datagram carries for a float-coordinate game is refused like a websocket UpdatePosition
+    let float_game = ClientPosition { game_id: 7, ..pos("a", 1, 2) };
+    assert!(games.admit(ClientMessage::UpdatePosition(float_game)).is_err());
+}
+
+#[test]
+fn a_repeated_position_only_refreshes_the_client() {
+    let mut state = state();
+    let tx = sender();
+    state.update_position_and_notify(pos("a", 0, 0), &tx);
+    state.update_position_and_notify(pos("b", 5, 0), &tx);
+    let before = state.last_update_time["a"];
+    assert!(state.refresh_repeated_position(&pos("a", 0, 0)));
+    assert!(state.last_update_time["a"] >= before);
+    assert!(!state.refresh_repeated_position(&pos("a", 1, 0)));
+    assert!(paired(&state, "a", "b"));
+}
+
+#[test]
+fn distance_buckets_and_audible_counts_follow_the_maps_ranges() {
+    // the global introduction range is 20; map 1 of game 0 hears out to 90
+    let mut config = config::Config::default();
+    config.map_ranges = vec![config::MapRanges {
+        game_id: 0,
+        map_id: Some(1),
+        introduction_range: 90.0,
+        disconnection_range: Some(100.0),
+        disconnection_buffer_percent: None,
+    }];
+    let mut state = ServerState::new(&config, Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let (a, b) = (pos("a", 0, 0), pos("b", 25, 0));
+    assert!(matches!(state.peer_distance(&a, &b).bucket, DistanceBucket::Near));
+    assert!(matches!(state.peer_distance(&a, &pos("b", 50, 0)).bucket, DistanceBucket::Medium));
+    assert!(matches!(state.peer_distance(&a, &pos("b", 80, 0)).bucket, DistanceBucket::Far));
+
+    let tx = sender();
+    state.update_position_and_notify(a.clone(), &tx);
+    state.update_position_and_notify(pos("b", 95, 0), &tx);
+    assert_eq!(state.count_in_audible_range(&a), 1);
+    state.update_position_and_notify(pos("b", 105, 0), &tx);
+    assert_eq!(state.count_in_audible_range(&a), 0);
+}
+
+#[test]
+fn peer_distance_at_opposite_ends_of_the_map_is_far_and_clamped() {
+    let config = config::Config { position_privacy: config::PositionPrivacy::Exact, ..config::Config::default() };
+    let state = ServerState::new(&config, Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let (a, b) = (pos("a", i64::MIN, i64::MAX), pos("b", i64::MAX, i64::MIN));
+    let game = state.games.get(0);
+    for (from, to) in [(&a, &b), (&b, &a)] {
+        let peer = state.peer_distance(from, to);
+        assert!(matches!(peer.bucket, DistanceBucket::Far));
+        assert!(!ServerState::should_be_paired(game, from, to, false, state.proximity.ranges(), false));
+        // the offsets saturate at the ends of the range, pointing the right way
+        let expected = if to.x > from.x { (i64::MAX, i64::MIN) } else { (i64::MIN, i64::MAX) };
+        assert_eq!((peer.dx, peer.dy), (Some(expected.0), Some(expected.1)));
+    }
+}
+
+// every client paired with every other
+fn complete_graph(clients: usize) -> HashMap<String, HashSet<String>> {
+    let ids: Vec<String> = (0..clients).map(|i| format!("c{}", i)).collect();
+    ids.iter().map(|id| (id.clone(), ids.iter().filter(|other| *other != id).cloned().collect())).collect()
+}
+
+#[test]
+fn largest_group_search_finds_the_full_mesh() {
+    let mut graph = complete_graph(6);
+    // and a stray pair beside it
+    graph.insert("x".to_string(), HashSet::from(["y".to_string()]));
+    graph.insert("y".to_string(), HashSet::from(["x".to_string()]));
+    assert_eq!(pairgraph::max_clique_size(&graph, 100_000), (6, false));
+}
+
+#[test]
+fn largest_group_search_stops_at_its_budget_with_a_lower_bound() {
+    // many overlapping triangles: plenty of branches, none of them bigger than three
+    let mut graph: HashMap<String, HashSet<String>> = HashMap::new();
+    for i in 0..200 {
+        for j in [i + 1, i + 2] {
+            let (a, b) = (format!("c{}", i), format!("c{}", j % 200));
+            graph.entry(a.clone()).or_default().insert(b.clone());
+            graph.entry(b).or_default().insert(a);
+        }
+    }
+    let (size, capped) = pairgraph::max_clique_size(&graph, 5);
+    assert!(capped);
+    assert!((1..=3).contains(&size));
+    assert_eq!(pairgraph::max_clique_size(&graph, 100_000), (3, false));
+}
+
+#[test]
+fn trusted_proxy_refuses_per_address_limits() {
+    let proxied = |extra: serde_json::Value| {
+        let mut raw = serde_json::json!({ "trusted_proxy": { "proxy_addrs": ["10.0.0.1"] } });
+        raw.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        config::from_raw(raw)
+    };
+    assert!(proxied(serde_json::json!({})).is_ok());
+    assert!(proxied(serde_json::json!({ "registration_backoff": {} })).is_err());
+    assert!(proxied(serde_json::json!({ "duplicate_accounts": {} })).is_err());
+}
+
+#[test]
+fn ban_lookup_without_an_address_checks_only_the_client_id() {
+    let db = db::Database::open(None).unwrap();
+    db.add_ban(None, Some("10.0.0.1"), "proxy banned by address", None).unwrap();
+    assert!(db.active_ban("alice", Some("10.0.0.1")).unwrap().is_some());
+    assert!(db.active_ban("alice", None).unwrap().is_none());
+    db.add_ban(Some("alice"), None, "banned", None).unwrap();
+    assert_eq!(db.active_ban("alice", None).unwrap().as_deref(), Some("banned"));
+}
+
+#[test]
+fn admin_tokens_match_only_exactly() {
+    assert!(admin::token_matches(Some("secret"), "secret"));
+    assert!(!admin::token_matches(Some("secreT"), "secret"));
+    assert!(!admin::token_matches(Some("secret2"), "secret"));
+    assert!(!admin::token_matches(Some(""), "secret"));
+    assert!(!admin::token_matches(None, "secret"));
+}
+
+#[test]
+fn admin_api_beyond_loopback_needs_tls_and_authentication() {
+    let tls = || config::AdminTlsConfig { cert_path: "cert.pem".to_string(), key_path: "key.pem".to_string(), client_ca_path: None };
+    let mut config = config::Config { admin_addr: Some("127.0.0.1:8081".to_string()), ..config::Config::default() };
+    assert!(!admin::exposed_insecurely(&config));
+    config.admin_addr = Some("0.0.0.0:8081".to_string());
+    assert!(admin::exposed_insecurely(&config));
+    config.admin_token = Some("secret".to_string());
+    assert!(admin::exposed_insecurely(&config), "a token over plain HTTP");
+    config.admin_tls = Some(tls());
+    assert!(!admin::exposed_insecurely(&config));
+    config.admin_token = None;
+    assert!(admin::exposed_insecurely(&config), "TLS with nobody authenticated");
+    config.admin_tls = Some(config::AdminTlsConfig { client_ca_path: Some("ca.pem".to_string()), ..tls() });
+    assert!(!admin::exposed_insecurely(&config));
+}
+
+fn signed(session: &signing::SigningSession, nonce: u64, message: &str) -> ClientMessage {
+    use hmac::{Hmac, Mac};
+    let key = hex::decode(session.key_hex()).unwrap();
+    let mut hmac = Hmac::<sha2::Sha256>::new_from_slice(&key).unwrap();
+    hmac.update(&nonce.to_be_bytes());
+    hmac.update(message.as_bytes());
+    ClientMessage::Signed { nonce, mac: hex::encode(hmac.finalize().into_bytes()), message: message.to_string() }
+}
+
+fn accept_signed(session: &mut signing::SigningSession, nonce: u64, message: &str) -> Result<signing::Accepted, String> {
+    let message = signed(session, nonce, message);
+    signing::accept(Some(session), message)
+}
+
+const PING: &str = r#"{"v": 1, "type": "RequestPeerRefresh", "request_id": "r1"}"#;
+
+#[test]
+fn signed_messages_open_with_their_request_id() {
+    let mut session = signing::SigningSession::new();
+    match accept_signed(&mut session, 1, PING) {
+        Ok(signing::Accepted::Signed(inner)) => {
+            assert!(matches!(inner.message, ClientMessage::RequestPeerRefresh));
+            assert_eq!(inner.request_id.as_deref(), Some("r1"));
+        }
+        _ => panic!("a valid signed message was refused"),
+    }
+}
+
+#[test]
+fn signed_messages_with_a_bad_mac_are_refused() {
+    let mut session = signing::SigningSession::new();
+    let other = signing::SigningSession::new();
+    assert!(signing::accept(Some(&mut session), signed(&other, 1, PING)).is_err());
+    let ClientMessage::Signed { mac, .. } = signed(&session, 1, PING) else { unreachable!() };
+    // signed for a different message, or a different nonce
+    let tampered = ClientMessage::Signed { nonce: 1, mac: mac.clone(), message: PING.replace("r1", "r2") };
+    assert!(signing::accept(Some(&mut session), tampered).is_err());
+    let renumbered = ClientMessage::Signed { nonce: 2, mac, message: PING.to_string() };
+    assert!(signing::accept(Some(&mut session), renumbered).is_err());
+    assert!(signing::accept(Some(&mut session), ClientMessage::Signed { nonce: 1, mac: "not hex".to_string(), message: PING.to_string() }).is_err());
+}
+
+#[test]
+fn signed_messages_with_a_replayed_or_lower_nonce_are_refused() {
+    let mut session = signing::SigningSession::new();
+    assert!(accept_signed(&mut session, 5, PING).is_ok());
+    assert!(accept_signed(&mut session, 5, PING).is_err());
+    assert!(accept_signed(&mut session, 4, PING).is_err());
+    assert!(accept_signed(&mut session, 6, PING).is_ok());
+}
+
+#[test]
+fn unsigned_messages_are_refused_once_a_connection_signs() {
+    assert!(matches!(signing::accept(None, ClientMessage::RequestPeerRefresh), Ok(signing::Accepted::Unsigned(_))));
+    let mut session = signing::SigningSession::new();
+    assert!(signing::accept(Some(&mut session), ClientMessage::RequestPeerRefresh).is_err());
+    // and signed ones without a session
+    assert!(signing::accept(None, signed(&session, 1, PING)).is_err());
+}
+
+fn name_filter() -> names::NameFilter {
+    names::NameFilter::new(&names::NameFilterConfig { denylist: vec![" Admin ".to_string()], patterns: vec![r"^mod(erator)?$".to_string()] }).unwrap()
+}
+
+#[test]
+fn name_filter_folds_case_width_and_leetspeak() {
+    let filter = name_filter();
+    for name in ["admin", "ADMIN", "the_admin_42", "ＡＤＭＩＮ", "4dm1n", "@dmin", "moderator", "M0D"] {
+        assert!(filter.is_blocked(name), "{:?} got through", name);
+    }
+    for name in ["adam", "mode", "moderators club", "player one"] {
+        assert!(!filter.is_blocked(name), "{:?} was blocked", name);
+    }
+}
+
+#[test]
+fn name_filter_catches_lookalikes_from_other_scripts() {
+    let filter = name_filter();
+    // cyrillic а and і, greek α, mathematical bold
+    for name in ["аdmіn", "αdmin", "𝐚𝐝𝐦𝐢𝐧", "ⓐⓓⓜⓘⓝ"] {
+        assert!(filter.is_blocked(name), "{:?} got through", name);
+    }
+}
+
+#[test]
+fn sanitized_names_lose_invisible_characters() {
+    let filter = name_filter();
+    for name in ["ad\u{200B}min", "ad\u{200D}min", "a\u{00AD}dmin", "\u{202E}admin\u{202C}", "adm\u{FEFF}in", "admin\u{E0041}"] {
+        assert_eq!(names::sanitize(name), "admin", "{:?}", name);
+        assert!(filter.is_blocked(&names::sanitize(name)), "{:?} got through", name);
+    }
+    assert_eq!(names::sanitize("  two \u{200B}  words\t"), "two words");
+    // a name of nothing but invisible characters is nothing
+    assert_eq!(names::sanitize("\u{200B}\u{3164}"), "");
+}
+
+#[test]
+fn an_empty_name_filter_blocks_nothing() {
+    let filter = names::NameFilter::new(&names::NameFilterConfig::default()).unwrap();
+    assert!(!filter.is_blocked("admin"));
+    assert!(names::NameFilter::new(&names::NameFilterConfig { denylist: Vec::new(), patterns: vec!["(".to_string()] }).is_err());
+}
+
+fn error_flood() -> config::ErrorFloodConfig {
+    config::ErrorFloodConfig { max_errors: 2, max_parse_failures: 3, max_binary_frames: 2, window_secs: 10 }
+}
+
+#[tokio::test(start_paused = true)]
+async fn error_replies_stop_at_the_budget_and_refill_with_the_next_window() {
+    let mut budget = flood::ErrorBudget::new(error_flood());
+    assert!(budget.allow_error());
+    assert!(budget.allow_error());
+    assert!(!budget.allow_error());
+    time::advance(Duration::from_secs(9)).await;
+    assert!(!budget.allow_error());
+    time::advance(Duration::from_secs(1)).await;
+    assert!(budget.allow_error());
+    assert!(budget.allow_error());
+    assert!(!budget.allow_error());
+    // what was dropped is counted across windows
+    assert_eq!(budget.suppressed(), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn malformed_frames_close_the_connection_only_within_one_window() {
+    let mut budget = flood::ErrorBudget::new(error_flood());
+    assert!(!budget.parse_failed());
+    assert!(!budget.parse_failed());
+    time::advance(Duration::from_secs(10)).await;
+    // the new window starts the count over
+    assert!(!budget.parse_failed());
+    assert!(!budget.parse_failed());
+    assert!(budget.parse_failed());
+
+    assert!(!budget.binary_frame());
+    assert!(budget.binary_frame());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_zero_limit_never_closes_the_connection() {
+    let mut budget = flood::ErrorBudget::new(config::ErrorFloodConfig { max_parse_failures: 0, max_binary_frames: 0, ..error_flood() });
+    for _ in 0..1000 {
+        assert!(!budget.parse_failed());
+        assert!(!budget.binary_frame());
+    }
+}
+
+fn collision_grid(origin: (i64, i64), rows: &[&str], mode: occlusion::OcclusionMode) -> occlusion::CollisionGrid {
+    let upload = occlusion::CollisionUpload {
+        origin_x: origin.0,
+        origin_y: origin.1,
+        rows: rows.iter().map(|row| row.to_string()).collect(),
+        mode,
+        wall_attenuation: 0.5,
+    };
+    occlusion::CollisionGrid::new(0, 1, upload).unwrap()
+}
+
+// a wall three cells tall in the middle of a five by five room
+const ROOM: &[&str] = &[".....", "..#..", "..#..", "..#..", "....."];
+
+// walls counted both ways along the line, which have to agree
+fn walls(grid: &occlusion::CollisionGrid, a: (i64, i64), b: (i64, i64)) -> usize {
+    let (a, b) = (pos("a", a.0, a.1), pos("b", b.0, b.1));
+    let walls = grid.walls_between(&a, &b, 1.0);
+    assert_eq!(walls, grid.walls_between(&b, &a, 1.0), "{:?} and {:?} disagree", a, b);
+    walls
+}
+
+#[test]
+fn occlusion_counts_walls_across_straight_and_diagonal_lines() {
+    let grid = collision_grid((0, 0), ROOM, occlusion::OcclusionMode::Block);
+    assert_eq!(walls(&grid, (0, 2), (4, 2)), 1);
+    assert_eq!(walls(&grid, (0, 0), (4, 0)), 0, "along the open top row");
+    assert_eq!(walls(&grid, (0, 0), (4, 4)), 1, "rising diagonal");
+    assert_eq!(walls(&grid, (0, 4), (4, 0)), 1, "falling diagonal");
+    assert_eq!(walls(&grid, (2, 0), (2, 4)), 3, "straight down the wall");
+}
+
+#[test]
+fn occlusion_walks_steep_and_shallow_slopes() {
+    let grid = collision_grid((0, 0), ROOM, occlusion::OcclusionMode::Block);
+    // steep, and staying left of the wall
+    assert_eq!(walls(&grid, (0, 0), (1, 4)), 0);
+    assert_eq!(walls(&grid, (1, 4), (0, 0)), 0);
+    // steep through it, either way up
+    assert!(walls(&grid, (1, 0), (3, 4)) > 0);
+    assert!(walls(&grid, (1, 4), (3, 0)) > 0);
+    // shallow, through the tie at (2, 0.5): the same cell whichever client walks it
+    assert_eq!(walls(&grid, (0, 0), (4, 1)), 1);
+    assert_eq!(walls(&grid, (0, 1), (4, 3)), 1);
+    // and clear past the wall's end
+    assert_eq!(walls(&grid, (0, 3), (4, 4)), 0);
+}
+
+#[test]
+fn occlusion_skips_the_endpoints_own_cells() {
+    let grid = collision_grid((0, 0), ROOM, occlusion::OcclusionMode::Block);
+    assert_eq!(walls(&grid, (2, 2), (2, 2)), 0, "same cell");
+    assert_eq!(walls(&grid, (2, 1), (2, 2)), 0, "neighbours inside the wall");
+    assert_eq!(walls(&grid, (2, 1), (2, 3)), 1, "only the cell between them");
+    assert_eq!(walls(&grid, (2, 2), (4, 2)), 0, "standing in the wall");
+}
+
+#[test]
+fn occlusion_treats_everything_outside_the_grid_as_open() {
+    // one wall cell, away from the origin and on negative coordinates
+    let grid = collision_grid((-10, -10), &["#"], occlusion::OcclusionMode::Block);
+    assert_eq!(walls(&grid, (-20, -10), (20, -10)), 1);
+    assert_eq!(walls(&grid, (-20, -9), (20, -9)), 0, "the row above the grid");
+    assert_eq!(walls(&grid, (-20, -11), (20, -11)), 0, "the row below it");
+    assert_eq!(walls(&grid, (-11, -20), (-11, 20)), 0, "the column left of it");
+    // short rows are open past their end
+    let grid = collision_grid((0, 0), &["#", "...#"], occlusion::OcclusionMode::Block);
+    assert_eq!(walls(&grid, (0, 0), (9, 0)), 0);
+    assert_eq!(walls(&grid, (0, 1), (9, 1)), 1);
+}
+
+#[test]
+fn occlusion_gives_up_on_lines_beyond_any_range() {
+    let grid = collision_grid((0, 0), ROOM, occlusion::OcclusionMode::Block);
+    assert_eq!(walls(&grid, (i64::MIN, 2), (i64::MAX, 2)), 0);
+    assert_eq!(walls(&grid, (2, i64::MIN), (2, i64::MAX)), 0);
+}
+
+#[test]
+fn occlusion_scales_coordinates_to_cells() {
+    let grid = collision_grid((0, 0), ROOM, occlusion::OcclusionMode::Block);
+    let (a, b) = (pos("a", 5, 25), pos("b", 45, 25));
+    assert_eq!(grid.walls_between(&a, &b, 10.0), 1);
+    // cells are floored, so -1 is the cell left of the grid rather than its first
+    let (a, b) = (pos("a", -1, 20), pos("b", 25, 20));
+    assert_eq!(grid.walls_between(&a, &b, 10.0), 0);
+}
+
+#[test]
+fn occlusion_blocks_or_attenuates_by_mode() {
+    let (a, b) = (pos("a", 0, 2), pos("b", 4, 2));
+    let block = collision_grid((0, 0), ROOM, occlusion::OcclusionMode::Block);
+    assert!(block.blocks(&a, &b, 1.0));
+    assert_eq!(block.attenuation(&a, &b, 1.0), None);
+    let attenuate = collision_grid((0, 0), ROOM, occlusion::OcclusionMode::Attenuate);
+    assert!(!attenuate.blocks(&a, &b, 1.0));
+    assert_eq!(attenuate.attenuation(&a, &b, 1.0), Some(0.5));
+    assert_eq!(attenuate.attenuation(&pos("a", 2, 0), &pos("b", 2, 4), 1.0), Some(0.125));
+}
+
+#[test]
+fn closing_for_a_restart_is_not_a_kick() {
+    let tx = sender();
+    assert_eq!(tx.close_reason(), None);
+    tx.close_for(CloseReason::ServerRestart);
+    assert_eq!(tx.close_reason(), Some(CloseReason::ServerRestart));
+    assert!(!tx.is_kicked(), "its UDP session keeps working while it drains");
+    tx.kick();
+    assert_eq!(tx.close_reason(), Some(CloseReason::Kicked));
+    assert!(tx.is_kicked());
+}
+
+// registers the client the way the connection handler does, with a route and a session token
+fn register(state: &mut ServerState, client_id: &str, tx: &outbound::Sender) -> String {
+    let connection_id = format!("conn-{}", client_id);
+    state.routing.add_connection(&connection_id, tx.clone());
+    state.routing.route(client_id, &connection_id);
+    let token = Uuid::new_v4().to_string();
+    state.session_tokens.insert(client_id.to_string(), token.clone());
+    state.update_position_and_notify(pos(client_id, 0, 0), tx);
+    token
+}
+
+#[test]
+fn only_a_clients_own_current_token_resumes_its_registration() {
+    let mut state = state();
+    let tx = sender();
+    let alice = register(&mut state, "alice", &tx);
+    let bob = register(&mut state, "bob", &tx);
+    assert!(state.resumes_registration("alice", Some(&alice)));
+    // someone else's token, or none
+    assert!(!state.resumes_registration("alice", Some(&bob)));
+    assert!(!state.resumes_registration("alice", None));
+    assert!(!state.resumes_registration("alice", Some("")));
+    // nor a token for a client that isn't registered
+    assert!(!state.resumes_registration("carol", Some(&alice)));
+}
+
+#[test]
+fn a_stale_session_token_does_not_resume_a_registration() {
+    let mut state = state();
+    let tx = sender();
+    let stale = register(&mut state, "alice", &tx);
+    // registering again without the token starts over with a new one
+    let current = register(&mut state, "alice", &tx);
+    assert!(!state.resumes_registration("alice", Some(&stale)));
+    assert!(state.resumes_registration("alice", Some(&current)));
+    // and a client that left takes its token with it
+    state.routing.unroute("alice");
+    state.forget_client("alice");
+    assert!(!state.resumes_registration("alice", Some(&current)));
+}
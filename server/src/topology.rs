@@ -0,0 +1,131 @@
+// mesh-to-star hints for dense crowds (config topology_hints). a full mesh of n clients has every
+// one of them sending n-1 audio streams, which stops working well past a dozen or so. in every
+// cluster of paired clients (a connected component of the pair graph) past min_cluster_size, the
+// maintenance loop elects hubs and gives each other member a hub it is paired with: leaves send
+// to and receive from their hub only, and hubs forward between their leaves and the other hubs.
+// hubs and assignments stick between rebalances while they still fit, so clients aren't
+// rewired every few seconds
+use crate::config::TopologyHintsConfig;
+use crate::metrics;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+#[derive(Default)]
+pub struct Topology {
+    // the hub of every leaf, and the leaves of every hub
+    hub_of: HashMap<String, String>,
+    leaves: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Topology {
+    // a client's part in its cluster's star: its hub when it's a leaf, the leaves it forwards for
+    // when it's a hub. None for clients in an ordinary mesh
+    pub fn hint(&self, client_id: &str) -> Option<(Option<String>, Vec<String>)> {
+        if let Some(hub) = self.hub_of.get(client_id) {
+            return Some((Some(hub.clone()), Vec::new()));
+        }
+        self.leaves.get(client_id).map(|leaves| (None, leaves.iter().cloned().collect()))
+    }
+
+    // re-elect hubs over the current pair graph. `eligible` says who can forward for others
+    // (not listen-only, not slow). returns the clients whose hint changed, including ones that
+    // lost theirs
+    pub fn rebalance(
+        &mut self,
+        graph: &HashMap<String, HashSet<String>>,
+        config: &TopologyHintsConfig,
+        eligible: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        let mut hub_of = HashMap::new();
+        let mut leaves: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for cluster in clusters(graph) {
+            if cluster.len() < config.min_cluster_size {
+                continue;
+            }
+            let hubs_needed = cluster.len().div_ceil(config.max_leaves_per_hub + 1);
+            // sitting hubs first, then the best connected
+            let mut candidates: Vec<&String> = cluster.iter().filter(|client_id| eligible(client_id)).collect();
+            candidates.sort_by_key(|client_id| (!self.leaves.contains_key(*client_id), Reverse(graph[*client_id].len()), *client_id));
+            candidates.truncate(hubs_needed);
+            for hub in &candidates {
+                leaves.insert((*hub).clone(), BTreeSet::new());
+            }
+            // leaves keep their hub while it's still one, still paired and not full
+            let mut members: Vec<&String> = cluster.iter().filter(|client_id| !leaves.contains_key(*client_id)).collect();
+            members.sort_by_key(|client_id| (!self.hub_of.get(*client_id).is_some_and(|hub| leaves.contains_key(hub)), *client_id));
+            for leaf in members {
+                let peers = &graph[leaf];
+                let has_room = |hub: &String| peers.contains(hub) && leaves.get(hub).is_some_and(|hub_leaves| hub_leaves.len() < config.max_leaves_per_hub);
+                let hub = self.hub_of.get(leaf).filter(|hub| has_room(hub)).cloned().or_else(|| {
+                    leaves
+                        .iter()
+                        .filter(|(hub, _)| has_room(hub))
+                        .min_by_key(|(hub, hub_leaves)| (hub_leaves.len(), *hub))
+                        .map(|(hub, _)| hub.clone())
+                });
+                // one paired with no hub with room stays in the mesh
+                if let Some(hub) = hub {
+                    leaves.get_mut(&hub).expect("hub was elected").insert(leaf.clone());
+                    hub_of.insert(leaf.clone(), hub);
+                }
+            }
+        }
+        let previous = std::mem::replace(self, Topology { hub_of, leaves });
+        let mut changed: Vec<String> = previous
+            .hub_of
+            .keys()
+            .chain(previous.leaves.keys())
+            .chain(self.hub_of.keys())
+            .chain(self.leaves.keys())
+            .filter(|client_id| previous.hint(client_id) != self.hint(client_id))
+            .cloned()
+            .collect();
+        changed.sort();
+        changed.dedup();
+        metrics::TOPOLOGY_HUBS.set(self.leaves.len() as i64);
+        metrics::TOPOLOGY_LEAVES.set(self.hub_of.len() as i64);
+        changed
+    }
+
+    // a client that left: its leaves go back to the mesh until the next rebalance finds them a hub
+    pub fn forget(&mut self, client_id: &str) {
+        if let Some(hub) = self.hub_of.remove(client_id) {
+            if let Some(leaves) = self.leaves.get_mut(&hub) {
+                leaves.remove(client_id);
+            }
+        }
+        for leaf in self.leaves.remove(client_id).into_iter().flatten() {
+            self.hub_of.remove(&leaf);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hub_of.len() + self.leaves.len()
+    }
+}
+
+// connected components of the pair graph, each sorted, in a fixed order
+fn clusters(graph: &HashMap<String, HashSet<String>>) -> Vec<Vec<String>> {
+    let mut client_ids: Vec<&String> = graph.keys().collect();
+    client_ids.sort();
+    let mut seen = HashSet::new();
+    let mut clusters = Vec::new();
+    for start in client_ids {
+        if !seen.insert(start.clone()) {
+            continue;
+        }
+        let mut cluster = vec![start.clone()];
+        let mut queue = vec![start];
+        while let Some(client_id) = queue.pop() {
+            for peer_id in graph.get(client_id).into_iter().flatten() {
+                if graph.contains_key(peer_id) && seen.insert(peer_id.clone()) {
+                    cluster.push(peer_id.clone());
+                    queue.push(peer_id);
+                }
+            }
+        }
+        cluster.sort();
+        clusters.push(cluster);
+    }
+    clusters
+}
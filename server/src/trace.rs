@@ -0,0 +1,160 @@
+// dev mode (config position_trace): plays a recorded trace of timestamped positions into the live
+// state as fake clients, so client developers can try crowd behaviour locally without a dozen
+// friends walking around. fake clients pair with real ones like any other, but have no
+// connection: they never answer offers, so their peers just see connections that don't come up.
+// a fake client that stops appearing in the trace times out like a silent real one would
+//
+// JSON lines: {"t_ms": 0, "client_id": "a", "game_id": 0, "map_id": 1, "x": 10, "y": 12, "channel": 0}
+// (channel and heading optional), or CSV with the columns t_ms,client_id,game_id,map_id,x,y[,channel]
+// and an optional header line. t_ms counts from the start of the trace; coordinates are in game
+// units, with fractions for float_coordinate_games
+use crate::config::{OutboundQueueConfig, PositionTraceConfig};
+use crate::{metrics, outbound, send_nearby_updates, ServerState};
+use log::{error, info, warn};
+use proxchat_protocol::{ClientPosition, ClientPositionFloat};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{self, Duration, Instant};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TraceRecord {
+    t_ms: u64,
+    client_id: String,
+    game_id: i32,
+    map_id: i32,
+    x: f64,
+    y: f64,
+    #[serde(default)]
+    channel: i32,
+    #[serde(default)]
+    heading: Option<f32>,
+}
+
+pub async fn play(config: PositionTraceConfig, state: Arc<RwLock<ServerState>>) {
+    let records = match load(&config.path) {
+        Ok(records) if records.is_empty() => {
+            warn!("Position trace {} has no positions", config.path);
+            return;
+        }
+        Ok(records) => records,
+        Err(e) => {
+            error!("Failed to load position trace {}: {}", config.path, e);
+            return;
+        }
+    };
+    let clients: HashSet<&str> = records.iter().map(|record| record.client_id.as_str()).collect();
+    warn!(
+        "Playing position trace {} into the live state: {} positions of {} fake clients",
+        config.path,
+        records.len(),
+        clients.len()
+    );
+
+    // nothing reads what fake clients are sent
+    let (tx, _) = outbound::channel(&OutboundQueueConfig::default());
+    loop {
+        let start = Instant::now();
+        for record in &records {
+            time::sleep_until(start + Duration::from_secs_f64(record.t_ms as f64 / 1000.0 / config.speed)).await;
+            let notifications = {
+                let mut state_write = metrics::timed_write(&state, "position_trace").await;
+                // a real client with the same id wins
+                if state_write.routing.connection_id(&record.client_id).is_some() {
+                    continue;
+                }
+                let pos = to_position(record, &state_write);
+                state_write.trace_clients.insert(record.client_id.clone());
+                let mut notifications = state_write.update_position_and_notify(pos, &tx);
+                notifications.retain(|(client_id, _)| !state_write.trace_clients.contains(client_id));
+                notifications
+            };
+            send_nearby_updates(&state, notifications).await;
+        }
+        if !config.repeat {
+            info!("Position trace {} ended", config.path);
+            return;
+        }
+        // everyone leaves before the trace starts over, so it plays out the same every time
+        let notifications = {
+            let mut state_write = metrics::timed_write(&state, "position_trace").await;
+            let trace_clients: Vec<String> = state_write.trace_clients.iter().cloned().collect();
+            let mut notifications = Vec::new();
+            for client_id in trace_clients {
+                notifications.extend(state_write.forget_client(&client_id));
+            }
+            notifications.sort_by(|a, b| a.0.cmp(&b.0));
+            notifications.dedup_by(|a, b| a.0 == b.0);
+            notifications
+        };
+        send_nearby_updates(&state, notifications).await;
+        info!("Position trace {} starting over", config.path);
+    }
+}
+
+// the trace's records in time order
+fn load(path: &str) -> Result<Vec<TraceRecord>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let csv = Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let mut records = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = if csv { parse_csv(line) } else { serde_json::from_str(line).map_err(|e| e.to_string()) };
+        match record {
+            Ok(record) => records.push(record),
+            // a header names its columns
+            Err(_) if csv && records.is_empty() && line.starts_with("t_ms") => {}
+            Err(e) => return Err(format!("line {}: {}", index + 1, e)),
+        }
+    }
+    // stable, so positions sharing a time keep their order
+    records.sort_by_key(|record| record.t_ms);
+    Ok(records)
+}
+
+fn parse_csv(line: &str) -> Result<TraceRecord, String> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if !(6..=7).contains(&fields.len()) {
+        return Err(format!("expected 6 or 7 columns, found {}", fields.len()));
+    }
+    let number = |index: usize, name: &str| fields[index].parse::<f64>().map_err(|_| format!("{} isn't a number: {}", name, fields[index]));
+    let integer = |index: usize, name: &str| fields[index].parse::<i64>().map_err(|_| format!("{} isn't a whole number: {}", name, fields[index]));
+    Ok(TraceRecord {
+        t_ms: u64::try_from(integer(0, "t_ms")?).map_err(|_| "t_ms can't be negative".to_string())?,
+        client_id: fields[1].to_string(),
+        game_id: integer(2, "game_id")? as i32,
+        map_id: integer(3, "map_id")? as i32,
+        x: number(4, "x")?,
+        y: number(5, "y")?,
+        channel: if fields.len() > 6 { integer(6, "channel")? as i32 } else { 0 },
+        heading: None,
+    })
+}
+
+// the record as the server stores positions of its game
+fn to_position(record: &TraceRecord, state: &ServerState) -> ClientPosition {
+    let game = state.games.get(record.game_id);
+    let float = ClientPositionFloat {
+        client_id: record.client_id.clone(),
+        map_id: record.map_id,
+        x: record.x as f32,
+        y: record.y as f32,
+        channel: record.channel,
+        game_id: record.game_id,
+        heading: record.heading,
+        session_token: None,
+    };
+    let pos = if game.float_coordinates() {
+        float.to_fixed()
+    } else {
+        let fixed = float.to_fixed();
+        ClientPosition { x: record.x.round() as i64, y: record.y.round() as i64, ..fixed }
+    };
+    game.normalize(pos)
+}
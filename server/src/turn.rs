@@ -0,0 +1,27 @@
+use crate::config::TurnConfig;
+use crate::db::unix_now;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use proxchat_protocol::IceServer;
+use sha1::Sha1;
+
+// short-lived TURN credentials in the TURN REST API scheme that coturn checks with use-auth-secret:
+//   username   = "<expiry unix time>:<client_id>"
+//   credential = base64(HMAC-SHA1(shared_secret, username))
+// so the TURN server needs no per-client accounts and a leaked credential expires on its own
+pub fn ice_server(config: &TurnConfig, client_id: &str) -> IceServer {
+    let username = format!("{}:{}", unix_now() + config.credential_ttl_secs as i64, client_id);
+    let credential = credential(&config.shared_secret, &username);
+    IceServer {
+        urls: config.urls.clone(),
+        username: Some(username),
+        credential: Some(credential),
+    }
+}
+
+// also what the built-in relay (src/relay.rs) checks a username against
+pub fn credential(shared_secret: &str, username: &str) -> String {
+    let mut hmac = Hmac::<Sha1>::new_from_slice(shared_secret.as_bytes()).expect("hmac accepts any key length");
+    hmac.update(username.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hmac.finalize().into_bytes())
+}
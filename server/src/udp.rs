@@ -0,0 +1,174 @@
+use crate::{metrics, send_nearby_updates, usage, ClientMessage, ClientPosition, ServerMessage, ServerState};
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+// position datagram, all integers big-endian:
+//   0  session_id u32    from the UdpSession reply
+//   4  seq        u32    increases with every datagram; stale or replayed ones are dropped
+//   8  map_id     i32
+//  12  x          i32    (positions beyond the i32 range, and float-coordinate games, go over the websocket)
+//  16  y          i32
+//  20  channel    i32
+//  24  game_id    i32
+//  28  tag        [u8; 16]  HMAC-SHA256(key, bytes 0..28), truncated
+// losing one is harmless since the next update supersedes it, so there are no acks or resends.
+pub const DATAGRAM_LEN: usize = 44;
+const SIGNED_LEN: usize = 28;
+pub const KEY_LEN: usize = 32;
+
+// a client's fast-path session. it only speaks for the client while the websocket
+// connection that opened it still owns the client's route.
+pub struct UdpSession {
+    pub client_id: String,
+    pub connection_id: String,
+    pub key: [u8; KEY_LEN],
+    last_seq: Option<u32>,
+}
+
+impl UdpSession {
+    pub fn new(client_id: &str, connection_id: &str) -> Self {
+        UdpSession {
+            client_id: client_id.to_string(),
+            connection_id: connection_id.to_string(),
+            key: random_key(),
+            last_seq: None,
+        }
+    }
+}
+
+// v4 uuids carry 122 random bits each, so two make a full-strength hmac key
+pub fn random_key() -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    key[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+    key[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+    key
+}
+
+pub fn random_session_id() -> u32 {
+    let bytes = Uuid::new_v4();
+    let bytes = bytes.as_bytes();
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+pub async fn serve(addr: String, state: Arc<RwLock<ServerState>>) {
+    let socket = match UdpSocket::bind(&addr).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind UDP fast-path on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("UDP position fast-path listening on: {}", addr);
+
+    // one byte of slack so oversized datagrams are noticed instead of silently truncated to a valid length
+    let mut buffer = [0u8; DATAGRAM_LEN + 1];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("UDP receive error: {}", e);
+                continue;
+            }
+        };
+        if len != DATAGRAM_LEN {
+            continue;
+        }
+        handle_datagram(&state, &buffer[..DATAGRAM_LEN], from).await;
+    }
+}
+
+fn read_u32(datagram: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(datagram[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_i32(datagram: &[u8], offset: usize) -> i32 {
+    i32::from_be_bytes(datagram[offset..offset + 4].try_into().unwrap())
+}
+
+async fn handle_datagram(state: &Arc<RwLock<ServerState>>, datagram: &[u8], from: SocketAddr) {
+    let session_id = read_u32(datagram, 0);
+    let seq = read_u32(datagram, 4);
+
+    // the signature is checked under the read lock, so forged datagrams guessing at session ids
+    // never queue for the write lock
+    let key = match metrics::timed_read(state, "udp_position").await.udp_sessions.get(&session_id) {
+        Some(session) => session.key,
+        None => return,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("hmac accepts any key length");
+    mac.update(&datagram[..SIGNED_LEN]);
+    if mac.verify_truncated_left(&datagram[SIGNED_LEN..]).is_err() {
+        // counted rather than logged one by one, since anyone can send these
+        metrics::UDP_BAD_SIGNATURES.inc();
+        debug!("Dropping UDP datagram with a bad signature for session {} from {}", session_id, from);
+        return;
+    }
+
+    let mut state_write = metrics::timed_write(state, "udp_position").await;
+    // the session may have been replaced while the lock was released
+    let Some(session) = state_write.udp_sessions.get_mut(&session_id).filter(|session| session.key == key) else {
+        return;
+    };
+    if session.last_seq.is_some_and(|last| seq <= last) {
+        return;
+    }
+    session.last_seq = Some(seq);
+    let client_id = session.client_id.clone();
+    let connection_id = session.connection_id.clone();
+
+    if state_write.routing.connection_id(&client_id).as_ref() != Some(&connection_id) {
+        // the client re-registered elsewhere; its new connection has to ask for a new session
+        state_write.udp_sessions.remove(&session_id);
+        return;
+    }
+    let Some(tx) = state_write.routing.connection(&connection_id) else {
+        return;
+    };
+    // the proxy identity, ban and script checks ran when the websocket registered the client, and
+    // the session only speaks for that id. a client banned or kicked since is refused here too,
+    // rather than moving until its connection is gone
+    if tx.is_kicked() {
+        state_write.udp_sessions.remove(&session_id);
+        return;
+    }
+
+    // datagrams have no room for a heading; the last one sent over the socket stands
+    let heading = state_write.positions.get(&client_id).and_then(|pos| pos.heading);
+    let position = ClientPosition {
+        client_id,
+        map_id: read_i32(datagram, 8),
+        x: read_i32(datagram, 12).into(),
+        y: read_i32(datagram, 16).into(),
+        channel: read_i32(datagram, 20),
+        game_id: read_i32(datagram, 24),
+        heading,
+        session_token: None,
+    };
+    usage::record_message(position.game_id, datagram.len());
+    state_write.inbound_traffic.record_position(&connection_id);
+    // the same checks as a websocket UpdatePosition: datagrams carry integer coordinates, so a
+    // float-coordinate game's position is refused, and the session with it so the client is told once
+    let position = match state_write.games.admit(ClientMessage::UpdatePosition(position)) {
+        Ok(ClientMessage::UpdatePosition(position)) => position,
+        Ok(_) => unreachable!("admit keeps the message kind"),
+        Err(e) => {
+            state_write.udp_sessions.remove(&session_id);
+            drop(state_write);
+            warn!("Dropping UDP position for session {} from {}: {}", session_id, from, e);
+            let _ = tx.send(ServerMessage::Error(e)).await;
+            return;
+        }
+    };
+    if state_write.refresh_repeated_position(&position) {
+        return;
+    }
+    let notifications = state_write.update_position_and_notify(position, &tx);
+    drop(state_write);
+    send_nearby_updates(state, notifications).await;
+}
@@ -0,0 +1,152 @@
+use crate::config::{UsageExportConfig, UsageFormat};
+use crate::db::unix_now;
+use log::{error, info};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{LazyLock, Mutex};
+use tokio::time::{self, Duration};
+
+// per-game usage for one accounting period, so shared deployments can see which community
+// generates the load. connection time is sampled on the maintenance tick; message and relay
+// counts are exact. a period runs from startup (or the previous export) until the next export
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct GameUsage {
+    pub connection_secs: u64,
+    // frames received from clients (websocket and UDP), and their payload size
+    pub messages: u64,
+    pub message_bytes: u64,
+    // offers, answers and ICE candidates passed on to another client, and their payload size
+    pub relayed_messages: u64,
+    pub relayed_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageRow {
+    pub game_id: i32,
+    #[serde(flatten)]
+    pub usage: GameUsage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    // unix seconds
+    pub period_start: i64,
+    pub period_end: i64,
+    pub games: Vec<UsageRow>,
+}
+
+struct Ledger {
+    period_start: i64,
+    games: BTreeMap<i32, GameUsage>,
+}
+
+static LEDGER: LazyLock<Mutex<Ledger>> = LazyLock::new(|| {
+    Mutex::new(Ledger {
+        period_start: unix_now(),
+        games: BTreeMap::new(),
+    })
+});
+
+fn with_game(game_id: i32, update: impl FnOnce(&mut GameUsage)) {
+    update(LEDGER.lock().unwrap().games.entry(game_id).or_default());
+}
+
+pub fn record_message(game_id: i32, bytes: usize) {
+    with_game(game_id, |usage| {
+        usage.messages += 1;
+        usage.message_bytes += bytes as u64;
+    });
+}
+
+pub fn record_relay(game_id: i32, bytes: usize) {
+    with_game(game_id, |usage| {
+        usage.relayed_messages += 1;
+        usage.relayed_bytes += bytes as u64;
+    });
+}
+
+// every given client (one game id each) counts as connected for `elapsed`
+pub fn record_connected(game_ids: impl Iterator<Item = i32>, elapsed: Duration) {
+    let mut ledger = LEDGER.lock().unwrap();
+    for game_id in game_ids {
+        ledger.games.entry(game_id).or_default().connection_secs += elapsed.as_secs();
+    }
+}
+
+fn report(ledger: &Ledger) -> UsageReport {
+    UsageReport {
+        period_start: ledger.period_start,
+        period_end: unix_now(),
+        games: ledger.games.iter().map(|(&game_id, &usage)| UsageRow { game_id, usage }).collect(),
+    }
+}
+
+// the period so far, left running
+pub fn snapshot() -> UsageReport {
+    report(&LEDGER.lock().unwrap())
+}
+
+// close the current period and start a new one
+fn take_period() -> UsageReport {
+    let mut ledger = LEDGER.lock().unwrap();
+    let closed = report(&ledger);
+    ledger.period_start = closed.period_end;
+    ledger.games.clear();
+    closed
+}
+
+pub const CSV_HEADER: &str =
+    "period_start,period_end,game_id,connection_secs,messages,message_bytes,relayed_messages,relayed_bytes\n";
+
+// one line per game, without the header
+pub fn to_csv(report: &UsageReport) -> String {
+    report
+        .games
+        .iter()
+        .map(|row| {
+            format!(
+                "{},{},{},{},{},{},{},{}\n",
+                report.period_start,
+                report.period_end,
+                row.game_id,
+                row.usage.connection_secs,
+                row.usage.messages,
+                row.usage.message_bytes,
+                row.usage.relayed_messages,
+                row.usage.relayed_bytes
+            )
+        })
+        .collect()
+}
+
+// closes a period every interval_secs and appends it to the export file: csv rows (header
+// written when the file is new) or one JSON report per line
+pub async fn export(config: UsageExportConfig) {
+    info!("Exporting usage to {} every {}s as {:?}", config.path, config.interval_secs, config.format);
+    let mut interval = time::interval(Duration::from_secs(config.interval_secs));
+    interval.tick().await; // the first tick fires immediately
+    loop {
+        interval.tick().await;
+        let report = take_period();
+        if let Err(e) = append(&config, &report) {
+            error!("Failed to write usage export to {}: {}", config.path, e);
+        }
+    }
+}
+
+fn append(config: &UsageExportConfig, report: &UsageReport) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&config.path)?;
+    match config.format {
+        UsageFormat::Csv => {
+            if file.metadata()?.len() == 0 {
+                file.write_all(CSV_HEADER.as_bytes())?;
+            }
+            file.write_all(to_csv(report).as_bytes())
+        }
+        UsageFormat::Json => {
+            let line = serde_json::to_string(report).map_err(std::io::Error::other)?;
+            writeln!(file, "{}", line)
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::codec::Encoding;
+use crate::config::WebTransportConfig;
+use crate::db::Database;
+use crate::handshake::{self, WireProtocol};
+use crate::{geoip, serve_client, ServerState};
+use futures_util::{sink, stream};
+use log::{error, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio_tungstenite::tungstenite::Message;
+use wtransport::endpoint::IncomingSession;
+use wtransport::error::StreamReadExactError;
+use wtransport::tls::Sha256DigestFmt;
+use wtransport::{Endpoint, Identity, RecvStream, SendStream, ServerConfig};
+
+// largest frame a client may send; anything bigger is treated as a broken stream
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+// experimental WebTransport (HTTP/3 over QUIC) listener. QUIC recovers from loss without
+// stalling the whole connection, which helps signaling on flaky Wi-Fi.
+//
+// a session opens one bidirectional stream and exchanges frames on it, each a 4-byte
+// big-endian length followed by one message. the encoding is picked with the `protocol`
+// query parameter on the session URL (e.g. /?protocol=proxchat.v1.cbor), defaulting to
+// proxchat.v1 JSON; there is no legacy mode here since no old client speaks WebTransport.
+pub async fn serve(config: WebTransportConfig, state: Arc<RwLock<ServerState>>, db: Arc<Database>) {
+    let addr: SocketAddr = match config.listen_addr.parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("Invalid WebTransport listen_addr {}: {}", config.listen_addr, e);
+            return;
+        }
+    };
+    let identity = match load_identity(&config).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            error!("Failed to set up WebTransport certificate: {}", e);
+            return;
+        }
+    };
+    let server_config = ServerConfig::builder()
+        .with_bind_address(addr)
+        .with_identity(identity)
+        .keep_alive_interval(Some(Duration::from_secs(3)))
+        .build();
+    let endpoint = match Endpoint::server(server_config) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Failed to bind WebTransport listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("WebTransport listener on: {} (experimental)", addr);
+
+    loop {
+        let incoming = endpoint.accept().await;
+        let state = Arc::clone(&state);
+        let db = Arc::clone(&db);
+        tokio::spawn(async move {
+            handle_session(incoming, state, db).await;
+        });
+    }
+}
+
+async fn load_identity(config: &WebTransportConfig) -> Result<Identity, String> {
+    match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => Identity::load_pemfiles(cert_path, key_path).await.map_err(|e| e.to_string()),
+        (None, None) => {
+            let identity = Identity::self_signed(["localhost", "127.0.0.1", "::1"]).map_err(|e| e.to_string())?;
+            // browsers accept a short-lived self-signed certificate when given its hash
+            if let Some(certificate) = identity.certificate_chain().as_slice().first() {
+                warn!(
+                    "No WebTransport certificate configured, using a self-signed one (sha-256 {})",
+                    certificate.hash().fmt(Sha256DigestFmt::DottedHex)
+                );
+            }
+            Ok(identity)
+        }
+        _ => Err("webtransport needs both cert_path and key_path, or neither".to_string()),
+    }
+}
+
+// encoding requested by the session URL, None for unknown protocols
+fn requested_protocol(path: &str) -> Option<WireProtocol> {
+    let query = path.split_once('?').map(|(_, query)| query).unwrap_or("");
+    let token = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("protocol="))
+        .unwrap_or(handshake::SUBPROTOCOL_V1);
+    WireProtocol::from_token(token)
+}
+
+async fn handle_session(incoming: IncomingSession, state: Arc<RwLock<ServerState>>, db: Arc<Database>) {
+    let request = match incoming.await {
+        Ok(request) => request,
+        Err(e) => {
+            info!("WebTransport session setup failed: {}", e);
+            return;
+        }
+    };
+    let addr = request.remote_address();
+    if !geoip::admit(addr.ip()) {
+        info!("Refusing WebTransport session from {}: country not allowed", addr);
+        request.forbidden().await;
+        return;
+    }
+    let Some(protocol) = requested_protocol(request.path()) else {
+        warn!("Refusing WebTransport session from {}: unsupported protocol in {}", addr, request.path());
+        request.not_found().await;
+        return;
+    };
+    let connection = match request.accept().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            info!("WebTransport session from {} failed: {}", addr, e);
+            return;
+        }
+    };
+    let (send, recv) = match connection.accept_bi().await {
+        Ok(streams) => streams,
+        Err(e) => {
+            info!("WebTransport session from {} closed before opening a stream: {}", addr, e);
+            return;
+        }
+    };
+
+    info!("WebTransport session established from: {} (protocol {:?})", addr, protocol);
+    let encoding = protocol.encoding();
+    let frames_out = Box::pin(sink::unfold(send, |mut send, frame: Message| async move {
+        write_frame(&mut send, &frame).await.map(|()| send)
+    }));
+    let frames_in = Box::pin(stream::unfold(recv, move |mut recv| async move {
+        read_frame(&mut recv, encoding).await.map(|frame| (frame, recv))
+    }));
+    serve_client(state, db, addr, encoding, None, frames_out, frames_in).await;
+    // keep the connection alive until the session is done with its streams
+    drop(connection);
+}
+
+async fn write_frame(send: &mut SendStream, frame: &Message) -> Result<(), String> {
+    let payload: &[u8] = match frame {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(bytes) => bytes,
+        _ => return Ok(()),
+    };
+    let len = u32::try_from(payload.len()).map_err(|_| "frame too large".to_string())?;
+    send.write_all(&len.to_be_bytes()).await.map_err(|e| e.to_string())?;
+    send.write_all(payload).await.map_err(|e| e.to_string())
+}
+
+// next frame from the stream; None once the client finishes it cleanly
+async fn read_frame(recv: &mut RecvStream, encoding: Encoding) -> Option<Result<Message, String>> {
+    let mut len = [0u8; 4];
+    match recv.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(StreamReadExactError::FinishedEarly(0)) => return None,
+        Err(e) => return Some(Err(e.to_string())),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Some(Err(format!("frame of {} bytes exceeds limit", len)));
+    }
+    let mut payload = vec![0u8; len];
+    if let Err(e) = recv.read_exact(&mut payload).await {
+        return Some(Err(e.to_string()));
+    }
+    // JSON sessions carry text, like websocket text frames
+    Some(Ok(match encoding {
+        Encoding::Json => match String::from_utf8(payload) {
+            Ok(text) => Message::Text(text.into()),
+            Err(e) => return Some(Err(e.to_string())),
+        },
+        Encoding::Cbor | Encoding::Protobuf => Message::Binary(payload.into()),
+    }))
+}
@@ -0,0 +1,63 @@
+use proxchat_protocol::ClientPosition;
+use serde::{Deserialize, Serialize};
+
+// a map region where one designated speaker (arena announcer, event host) is paired with everyone
+// inside it regardless of distance, between starts_at and ends_at. created through the admin API
+// and stored in the database so scheduled zones survive restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnouncerZone {
+    // assigned by the database
+    #[serde(skip_deserializing)]
+    pub id: i64,
+    #[serde(default)]
+    pub name: String,
+    pub speaker_id: String,
+    pub game_id: i32,
+    pub map_id: i32,
+    // None covers every channel of the map
+    #[serde(default)]
+    pub channel: Option<i32>,
+    // inclusive tile bounds
+    pub x_min: i64,
+    pub y_min: i64,
+    pub x_max: i64,
+    pub y_max: i64,
+    // unix seconds; the zone is live for starts_at <= now < ends_at
+    pub starts_at: i64,
+    pub ends_at: i64,
+}
+
+impl AnnouncerZone {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.speaker_id.is_empty() {
+            return Err("speaker_id must not be empty".to_string());
+        }
+        if self.x_min > self.x_max || self.y_min > self.y_max {
+            return Err("zone bounds are inverted (x_min > x_max or y_min > y_max)".to_string());
+        }
+        if self.ends_at <= self.starts_at {
+            return Err("ends_at must be after starts_at".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_active(&self, now: i64) -> bool {
+        self.starts_at <= now && now < self.ends_at
+    }
+
+    pub fn contains(&self, pos: &ClientPosition) -> bool {
+        pos.game_id == self.game_id
+            && pos.map_id == self.map_id
+            && self.channel.is_none_or(|channel| channel == pos.channel)
+            && (self.x_min..=self.x_max).contains(&pos.x)
+            && (self.y_min..=self.y_max).contains(&pos.y)
+    }
+
+    // the speaker reaches every listener while both are inside the zone
+    pub fn pairs(&self, a: &ClientPosition, b: &ClientPosition) -> bool {
+        (a.client_id == self.speaker_id || b.client_id == self.speaker_id)
+            && a.client_id != b.client_id
+            && self.contains(a)
+            && self.contains(b)
+    }
+}